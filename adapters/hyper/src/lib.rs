@@ -1,26 +1,38 @@
-use http_body_util::BodyExt;
-use hyper::body::Incoming;
+use bytes::Bytes;
+use futures_core::Stream;
+use http::uri::Scheme;
+use hyper::body::{Body as HttpBody, Frame, Incoming, SizeHint};
 use hyper::service::Service;
 use hyper::{Request, Response};
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo};
 use std::convert::Infallible;
 use std::future::Future;
 use std::pin::Pin;
+use std::task::{Context, Poll};
 use tokio::net::TcpListener;
-use xeno_core::{App, CoreRequest, CoreResponse, Error};
+use xeno_core::extract::{ConnectInfo, RemoteAddr, TlsInfo};
+use xeno_core::logging::{self, Level};
+use xeno_core::memory::{MemoryBudget, Reservation};
+use xeno_core::{App, Body, CoreRequest, CoreResponse, Error};
+
+mod tls;
+
+pub use tls::TlsConfig;
 
 const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024; // 2MB
 
 pub struct HyperAdapter<C> {
     app: App<C>,
     max_body_size: usize,
+    memory_budget: Option<MemoryBudget>,
 }
 
 impl<C: Send + Sync + Clone + 'static> HyperAdapter<C> {
     pub fn new(app: App<C>) -> Self {
         Self {
-            app,
+            app: app.freeze(),
             max_body_size: DEFAULT_MAX_BODY_SIZE,
+            memory_budget: None,
         }
     }
 
@@ -29,22 +41,105 @@ impl<C: Send + Sync + Clone + 'static> HyperAdapter<C> {
         self
     }
 
+    /// Caps the request body bytes held by all in-flight requests together,
+    /// shedding new uploads once `budget` is used up.
+    pub fn with_memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    fn service(&self, connect_info: ConnectInfo) -> HyperService<C> {
+        HyperService {
+            app: self.app.clone(),
+            max_body_size: self.max_body_size,
+            memory_budget: self.memory_budget.clone(),
+            connect_info,
+        }
+    }
+
     pub async fn serve(self, addr: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Traffic only arrives once the warm-up tasks have run.
+        self.app.run_warmup().await;
         let listener = TcpListener::bind(addr).await?;
         println!("Server running on http://{}", addr);
 
         loop {
-            let (stream, _) = listener.accept().await?;
-            let app = self.app.clone();
-            let max_body_size = self.max_body_size;
-            let service = HyperService { app, max_body_size };
+            let (stream, remote_addr) = listener.accept().await?;
+            let service = self.service(ConnectInfo {
+                remote_addr,
+                local_addr: stream.local_addr().ok(),
+                scheme: Scheme::HTTP,
+                tls: None,
+            });
 
             tokio::spawn(async move {
                 if let Err(err) = hyper::server::conn::http1::Builder::new()
                     .serve_connection(TokioIo::new(stream), service)
                     .await
                 {
-                    eprintln!("Error serving connection: {:?}", err);
+                    logging::global().log(
+                        Level::Warn,
+                        "connection error",
+                        format!("Error serving connection: {:?}", err),
+                    );
+                }
+            });
+        }
+    }
+
+    /// Serves HTTPS, negotiating HTTP/2 or HTTP/1.1 through ALPN.
+    pub async fn serve_tls(
+        self,
+        addr: &str,
+        config: TlsConfig,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let acceptor = tls::Acceptor::new(config)?;
+        self.app.run_warmup().await;
+        let listener = TcpListener::bind(addr).await?;
+        println!("Server running on https://{}", addr);
+
+        loop {
+            let (stream, remote_addr) = listener.accept().await?;
+            let acceptor = acceptor.clone();
+            let mut service = self.service(ConnectInfo {
+                remote_addr,
+                local_addr: stream.local_addr().ok(),
+                scheme: Scheme::HTTPS,
+                tls: None,
+            });
+
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        logging::global().log(
+                            Level::Warn,
+                            "tls handshake failed",
+                            format!("TLS handshake with {} failed: {}", remote_addr, err),
+                        );
+                        return;
+                    }
+                };
+                let (_, session) = stream.get_ref();
+                service.connect_info.tls = Some(TlsInfo {
+                    server_name: session.server_name().map(str::to_string),
+                    alpn_protocol: session
+                        .alpn_protocol()
+                        .map(|p| String::from_utf8_lossy(p).into_owned()),
+                    client_cert: session
+                        .peer_certificates()
+                        .and_then(|certs| certs.first())
+                        .map(|cert| Bytes::copy_from_slice(cert)),
+                });
+                if let Err(err) = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    logging::global().log(
+                        Level::Warn,
+                        "connection error",
+                        format!("Error serving connection: {:?}", err),
+                    );
                 }
             });
         }
@@ -53,6 +148,8 @@ impl<C: Send + Sync + Clone + 'static> HyperAdapter<C> {
     async fn convert_request(
         req: Request<Incoming>,
         max_body_size: usize,
+        reservation: Option<&Reservation>,
+        budget: Option<&MemoryBudget>,
     ) -> Result<CoreRequest, Error> {
         let (parts, body) = req.into_parts();
 
@@ -66,49 +163,24 @@ impl<C: Send + Sync + Clone + 'static> HyperAdapter<C> {
             if length > max_body_size {
                 return Err(Error::payload_too_large());
             }
+            if let Some(budget) = budget {
+                budget.admit(length)?;
+            }
         }
 
-        let body_bytes = body
-            .collect()
-            .await
-            .map(|buf| buf.to_bytes())
-            .map_err(|_| Error::bad_request("Failed to read request body"))?;
-
-        if body_bytes.len() > max_body_size {
-            return Err(Error::payload_too_large());
-        }
-
-        let core_req = CoreRequest::from_parts(parts, body_bytes);
-        Ok(core_req)
-    }
-
-    fn convert_response(res: CoreResponse) -> Response<String> {
-        let (parts, body) = res.into_parts();
-        let body_str = String::from_utf8_lossy(&body).to_string();
-        Response::from_parts(parts, body_str)
-    }
-
-    fn error_to_response(error: Error) -> Response<String> {
-        let status = error.status_code();
-
-        #[cfg(debug_assertions)]
-        let message = error.debug_message();
-
-        #[cfg(not(debug_assertions))]
-        let message = error.safe_message().to_string();
-
-        let body = serde_json::json!({
-            "error": message,
-            "status": status.as_u16(),
-            "timestamp": chrono::Utc::now().to_rfc3339()
+        let body = Body::from_stream(IncomingStream {
+            body,
+            remaining: max_body_size,
         });
+        let body = match reservation {
+            Some(reservation) => reservation.track(body),
+            None => body,
+        };
+        Ok(CoreRequest::from_parts(parts, body))
+    }
 
-        Response::builder()
-            .status(status)
-            .header("content-type", "application/json; charset=utf-8")
-            .header("x-request-id", uuid::Uuid::new_v4().to_string())
-            .body(body.to_string())
-            .unwrap()
+    fn convert_response(res: CoreResponse) -> Response<ResponseBody> {
+        res.map(ResponseBody)
     }
 }
 
@@ -117,6 +189,7 @@ impl<C: Send + Sync + Clone + 'static> Clone for HyperAdapter<C> {
         Self {
             app: self.app.clone(),
             max_body_size: self.max_body_size,
+            memory_budget: self.memory_budget.clone(),
         }
     }
 }
@@ -124,25 +197,45 @@ impl<C: Send + Sync + Clone + 'static> Clone for HyperAdapter<C> {
 struct HyperService<C> {
     app: App<C>,
     max_body_size: usize,
+    memory_budget: Option<MemoryBudget>,
+    connect_info: ConnectInfo,
 }
 
 impl<C: Send + Sync + Clone + 'static> Service<Request<Incoming>> for HyperService<C> {
-    type Response = Response<String>;
+    type Response = Response<ResponseBody>;
     type Error = Infallible;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
         let app = self.app.clone();
         let max_body_size = self.max_body_size;
+        let budget = self.memory_budget.clone();
+        let connect_info = self.connect_info.clone();
         Box::pin(async move {
-            let core_req = match HyperAdapter::<C>::convert_request(req, max_body_size).await {
-                Ok(req) => req,
+            // Held until the handler is done with the body.
+            let reservation = budget.as_ref().map(MemoryBudget::reservation);
+            let core_req = match HyperAdapter::<C>::convert_request(
+                req,
+                max_body_size,
+                reservation.as_ref(),
+                budget.as_ref(),
+            )
+            .await
+            {
+                Ok(mut req) => {
+                    req.extensions_mut()
+                        .insert(RemoteAddr(connect_info.remote_addr));
+                    req.extensions_mut().insert(connect_info);
+                    req
+                }
                 Err(error) => {
-                    return Ok(HyperAdapter::<C>::error_to_response(error));
+                    let res = app.response_formatter().format_error(&error);
+                    return Ok(HyperAdapter::<C>::convert_response(res));
                 }
             };
 
             let core_res = app.handle(core_req).await;
+            drop(reservation);
             Ok(HyperAdapter::<C>::convert_response(core_res))
         })
     }
@@ -153,6 +246,73 @@ impl<C: Send + Sync + Clone + 'static> Clone for HyperService<C> {
         Self {
             app: self.app.clone(),
             max_body_size: self.max_body_size,
+            memory_budget: self.memory_budget.clone(),
+            connect_info: self.connect_info.clone(),
+        }
+    }
+}
+
+/// Streams the data frames of a hyper body, failing once more than
+/// `remaining` bytes have arrived.
+struct IncomingStream {
+    body: Incoming,
+    remaining: usize,
+}
+
+impl Stream for IncomingStream {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let frame = match Pin::new(&mut self.body).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                Poll::Ready(Some(Err(_))) => {
+                    return Poll::Ready(Some(Err(Error::bad_request(
+                        "Failed to read request body",
+                    ))))
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            // Trailers are dropped.
+            let Ok(data) = frame.into_data() else {
+                continue;
+            };
+            if data.len() > self.remaining {
+                return Poll::Ready(Some(Err(Error::payload_too_large())));
+            }
+            self.remaining -= data.len();
+            return Poll::Ready(Some(Ok(data)));
+        }
+    }
+}
+
+/// Sends a core body to hyper as-is: buffered bodies in one frame with an
+/// exact length, streams chunk by chunk.
+struct ResponseBody(Body);
+
+impl HttpBody for ResponseBody {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        Pin::new(&mut self.0)
+            .poll_next(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map(Frame::data)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        matches!(&self.0, Body::Full(bytes) if bytes.is_empty())
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match &self.0 {
+            Body::Full(bytes) => SizeHint::with_exact(bytes.len() as u64),
+            Body::Stream(_) => SizeHint::default(),
         }
     }
 }
@@ -0,0 +1,214 @@
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use xeno_core::logging;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Certificate and protocol settings for [`HyperAdapter::serve_tls`].
+///
+/// [`HyperAdapter::serve_tls`]: crate::HyperAdapter::serve_tls
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    alpn_protocols: Vec<Vec<u8>>,
+    reload_interval: Option<Duration>,
+    handshake_timeout: Duration,
+}
+
+impl TlsConfig {
+    /// Uses a PEM certificate chain (leaf first) and a PEM private key.
+    /// ALPN offers `h2` and `http/1.1` by default, and handshakes must
+    /// finish within 10 seconds.
+    pub fn from_pem_files(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            alpn_protocols: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            reload_interval: None,
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub fn alpn_protocols<I, P>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<Vec<u8>>,
+    {
+        self.alpn_protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Checks the PEM files every `interval` and switches new connections to
+    /// the new certificate when they change. A failed reload keeps the
+    /// current certificate.
+    pub fn reload_every(mut self, interval: Duration) -> Self {
+        self.reload_interval = Some(interval);
+        self
+    }
+
+    /// Drops connections that have not completed the TLS handshake within
+    /// `timeout`, so idle or slow clients cannot hold sockets open.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    fn load(&self) -> Result<Arc<ServerConfig>, BoxError> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Failed to read {}: {}", self.cert_path.display(), e))?;
+        if certs.is_empty() {
+            return Err(format!("No certificates in {}", self.cert_path.display()).into());
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| format!("Failed to read {}: {}", self.key_path.display(), e))?;
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        config.alpn_protocols = self.alpn_protocols.clone();
+        Ok(Arc::new(config))
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+}
+
+/// Hands out an acceptor for the current certificate, reloading it in the
+/// background when configured to.
+#[derive(Clone)]
+pub(crate) struct Acceptor {
+    current: Arc<RwLock<TlsAcceptor>>,
+    handshake_timeout: Duration,
+}
+
+impl Acceptor {
+    pub(crate) fn new(config: TlsConfig) -> Result<Self, BoxError> {
+        let acceptor = Self {
+            current: Arc::new(RwLock::new(TlsAcceptor::from(config.load()?))),
+            handshake_timeout: config.handshake_timeout,
+        };
+        if let Some(interval) = config.reload_interval {
+            tokio::spawn(acceptor.clone().watch(config, interval));
+        }
+        Ok(acceptor)
+    }
+
+    /// Runs the TLS handshake on `stream` with the current certificate.
+    pub(crate) async fn accept(&self, stream: TcpStream) -> Result<TlsStream<TcpStream>, BoxError> {
+        let acceptor = self.current.read().unwrap().clone();
+        match tokio::time::timeout(self.handshake_timeout, acceptor.accept(stream)).await {
+            Ok(stream) => Ok(stream?),
+            Err(_) => {
+                Err(format!("TLS handshake timed out after {:?}", self.handshake_timeout).into())
+            }
+        }
+    }
+
+    async fn watch(self, config: TlsConfig, interval: Duration) {
+        let mut last = config.modified();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let modified = config.modified();
+            if modified.is_none() || modified == last {
+                continue;
+            }
+            match config.load() {
+                Ok(server_config) => {
+                    *self.current.write().unwrap() = TlsAcceptor::from(server_config);
+                    last = modified;
+                    logging::global().warn(format!(
+                        "Reloaded TLS certificate {}",
+                        config.cert_path.display()
+                    ));
+                }
+                Err(e) => {
+                    logging::global().error(format!("Failed to reload TLS certificate: {}", e))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls_pki_types::ServerName;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    /// A self-signed certificate for `localhost`, written to a temporary
+    /// directory, and the client config trusting it.
+    fn self_signed(name: &str) -> (TlsConfig, Arc<ClientConfig>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("xeno-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let mut client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client.alpn_protocols = vec![b"h2".to_vec()];
+        let config = TlsConfig::from_pem_files(dir.join("cert.pem"), dir.join("key.pem"));
+        (config, Arc::new(client))
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (config, client) = self_signed("handshake");
+        let acceptor = Acceptor::new(config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut stream = TlsConnector::from(client)
+                .connect(name, stream)
+                .await
+                .unwrap();
+            let mut byte = [0; 1];
+            let _ = stream.read(&mut byte).await;
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = acceptor.accept(stream).await.unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        drop(stream);
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let (config, _) = self_signed("timeout");
+        let acceptor = Acceptor::new(config.handshake_timeout(Duration::from_millis(50))).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A client that connects and never says hello.
+        let _silent = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let error = acceptor.accept(stream).await.unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+    }
+}
@@ -1,22 +1,53 @@
 use bytes::Bytes;
+use http::StatusCode;
 use std::collections::HashMap;
-use xeno_core::{context::Kv, App};
+use std::net::{IpAddr, SocketAddr};
+use xeno_core::extract::RemoteAddr;
+use xeno_core::{context::Kv, App, Body, CoreResponse, Error};
 
 // Placeholder implementation - will be properly implemented when worker crate is available
 pub struct WorkersAdapter<C> {
-    #[allow(dead_code)]
     app: App<C>,
 }
 
 impl<C: Send + Sync + Clone + 'static> WorkersAdapter<C> {
     pub fn new(app: App<C>) -> Self {
-        Self { app }
+        Self { app: app.freeze() }
     }
 
     // This will be the main entry point for Cloudflare Workers
-    pub async fn handle_fetch(&self, _request: WorkerRequest) -> WorkerResponse {
-        // Placeholder implementation
-        WorkerResponse::new("Hello from Xeno on Cloudflare Workers!")
+    pub async fn handle_fetch(&self, request: WorkerRequest) -> WorkerResponse {
+        // A Worker has no startup hook, so the first request runs the
+        // warm-up; requests arriving meanwhile see it in progress.
+        self.app.run_warmup().await;
+        let mut builder = http::Request::builder()
+            .method(request.method.as_str())
+            .uri(&request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        let body = request.body.map_or_else(Body::empty, Body::from);
+        match builder.body(body) {
+            Ok(mut req) => {
+                // Cloudflare sets this at its edge, so a Worker can treat it
+                // as the peer address.
+                let peer = req
+                    .headers()
+                    .get("cf-connecting-ip")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<IpAddr>().ok());
+                if let Some(ip) = peer {
+                    req.extensions_mut()
+                        .insert(RemoteAddr(SocketAddr::new(ip, 0)));
+                }
+                WorkerResponse::from_response(self.app.handle(req).await).await
+            }
+            Err(_) => {
+                let error = Error::bad_request("Malformed request");
+                let res = self.app.response_formatter().format_error(&error);
+                WorkerResponse::from_response(res).await
+            }
+        }
     }
 }
 
@@ -28,18 +59,57 @@ pub struct WorkerRequest {
     pub body: Option<Bytes>,
 }
 
+/// Bodies stay raw bytes so binary responses (Protobuf, MessagePack,
+/// images) reach the client unchanged.
 pub struct WorkerResponse {
-    pub body: String,
+    pub body: WorkerBody,
     pub status: u16,
-    pub headers: HashMap<String, String>,
+    /// One entry per header value, in order, each passed to
+    /// `Headers::append` so repeated headers such as `Set-Cookie` stay
+    /// separate.
+    pub headers: Vec<(String, String)>,
+}
+
+/// A response body: buffered, or a chunk stream (e.g. [`Sse`] events) to be
+/// piped into the Worker's `ReadableStream` as chunks arrive.
+///
+/// [`Sse`]: xeno_core::response::Sse
+pub enum WorkerBody {
+    Bytes(Bytes),
+    Stream(Body),
 }
 
 impl WorkerResponse {
-    pub fn new(body: &str) -> Self {
+    pub fn new(body: impl Into<Bytes>) -> Self {
         Self {
-            body: body.to_string(),
+            body: WorkerBody::Bytes(body.into()),
             status: 200,
-            headers: HashMap::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status.as_u16();
+        self
+    }
+
+    /// Converts `res`, passing streamed bodies on unbuffered. Header values
+    /// that are not visible ASCII are dropped.
+    pub async fn from_response(res: CoreResponse) -> Self {
+        let (parts, body) = res.into_parts();
+        let headers = parts
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let body = match body {
+            Body::Full(bytes) => WorkerBody::Bytes(bytes),
+            stream => WorkerBody::Stream(stream),
+        };
+        Self {
+            body,
+            status: parts.status.as_u16(),
+            headers,
         }
     }
 }
@@ -0,0 +1,365 @@
+use crate::{
+    client_ip::ClientIp,
+    extract::MatchedPath,
+    middleware::{Middleware, Next},
+    Body, CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use http::header::CONTENT_LENGTH;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// One handled request, as passed to access log formats. The JSON format
+/// deserializes back into entries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessLogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub method: String,
+    /// Path and query as requested.
+    pub path: String,
+    /// The matched route pattern, if any route matched.
+    pub route: Option<String>,
+    pub status: u16,
+    #[serde(
+        rename = "latency_ms",
+        serialize_with = "as_millis",
+        deserialize_with = "from_millis"
+    )]
+    pub latency: Duration,
+    /// Body size in bytes, when known up front.
+    pub size: Option<u64>,
+    pub remote_addr: Option<String>,
+}
+
+fn as_millis<S: serde::Serializer>(latency: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(latency.as_secs_f64() * 1000.0)
+}
+
+fn from_millis<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    let millis = f64::deserialize(d)?;
+    Duration::try_from_secs_f64(millis / 1000.0).map_err(serde::de::Error::custom)
+}
+
+/// How entries are turned into log lines.
+#[derive(Clone)]
+pub enum AccessLogFormat {
+    /// The Common Log Format used by Apache and nginx.
+    Common,
+    /// One JSON object per line.
+    Json,
+    Custom(Arc<dyn Fn(&AccessLogEntry) -> String + Send + Sync>),
+}
+
+impl AccessLogFormat {
+    pub fn format(&self, entry: &AccessLogEntry) -> String {
+        match self {
+            AccessLogFormat::Common => format!(
+                "{} - - [{}] \"{} {}\" {} {}",
+                entry.remote_addr.as_deref().unwrap_or("-"),
+                entry.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+                entry.method,
+                entry.path,
+                entry.status,
+                entry
+                    .size
+                    .map_or_else(|| "-".to_string(), |size| size.to_string()),
+            ),
+            AccessLogFormat::Json => serde_json::to_string(entry).unwrap_or_default(),
+            AccessLogFormat::Custom(format) => format(entry),
+        }
+    }
+}
+
+/// How client addresses are recorded.
+#[derive(Clone, Default)]
+pub enum IpAnonymization {
+    #[default]
+    Keep,
+    /// Zeroes the host part: IPv4 addresses keep their /24, IPv6 addresses
+    /// their /48.
+    Truncate,
+    /// Replaces the address with the first 16 hex digits of its HMAC-SHA256
+    /// under `key`, so requests from one client can still be correlated.
+    /// Rotate the key to unlink old logs.
+    Hash([u8; 32]),
+    Drop,
+}
+
+/// Removes personal data from access log entries before they are written,
+/// in every format and writer.
+///
+/// ```ignore
+/// AccessLogMiddleware::json().anonymize(
+///     Anonymizer::new()
+///         .truncate_ips()
+///         .strip_query("/auth/magic")
+///         .redact("email")
+///         .redact("phone"),
+/// )
+/// ```
+#[derive(Clone, Default)]
+pub struct Anonymizer {
+    ip: IpAnonymization,
+    strip_query: Vec<String>,
+    redact: Vec<String>,
+}
+
+impl Anonymizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn truncate_ips(mut self) -> Self {
+        self.ip = IpAnonymization::Truncate;
+        self
+    }
+
+    pub fn hash_ips(mut self, key: [u8; 32]) -> Self {
+        self.ip = IpAnonymization::Hash(key);
+        self
+    }
+
+    pub fn drop_ips(mut self) -> Self {
+        self.ip = IpAnonymization::Drop;
+        self
+    }
+
+    /// Logs requests to the route pattern `route`, e.g. `/auth/magic`,
+    /// without their query string. `*` strips it from every request.
+    pub fn strip_query(mut self, route: impl Into<String>) -> Self {
+        self.strip_query.push(route.into());
+        self
+    }
+
+    /// Replaces the value of query parameter or path parameter `name`, e.g.
+    /// `email` in `/users/:email`, with `REDACTED`. Names are matched
+    /// case-insensitively.
+    pub fn redact(mut self, name: impl Into<String>) -> Self {
+        self.redact.push(name.into().to_ascii_lowercase());
+        self
+    }
+
+    pub fn apply(&self, entry: &mut AccessLogEntry) {
+        entry.remote_addr = entry.remote_addr.take().and_then(|addr| self.ip(&addr));
+
+        let (path, query) = match entry.path.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (entry.path.clone(), None),
+        };
+        let path = match &entry.route {
+            Some(route) if !self.redact.is_empty() => self.redact_path(&path, route),
+            _ => path,
+        };
+        let strip = self
+            .strip_query
+            .iter()
+            .any(|r| r == "*" || Some(r) == entry.route.as_ref());
+        entry.path = match query {
+            Some(query) if !strip => format!("{}?{}", path, self.redact_query(&query)),
+            _ => path,
+        };
+    }
+
+    fn ip(&self, addr: &str) -> Option<String> {
+        match &self.ip {
+            IpAnonymization::Keep => Some(addr.to_string()),
+            IpAnonymization::Drop => None,
+            IpAnonymization::Truncate => Some(match addr.parse::<IpAddr>().ok()? {
+                IpAddr::V4(ip) => {
+                    let [a, b, c, _] = ip.octets();
+                    IpAddr::from([a, b, c, 0]).to_string()
+                }
+                IpAddr::V6(ip) => {
+                    let mut octets = ip.octets();
+                    octets[6..].fill(0);
+                    IpAddr::from(octets).to_string()
+                }
+            }),
+            IpAnonymization::Hash(key) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).ok()?;
+                mac.update(addr.as_bytes());
+                let mut hash = String::with_capacity(16);
+                for byte in &mac.finalize().into_bytes()[..8] {
+                    let _ = write!(hash, "{:02x}", byte);
+                }
+                Some(hash)
+            }
+        }
+    }
+
+    fn redacted(&self, name: &str) -> bool {
+        self.redact.iter().any(|r| r.eq_ignore_ascii_case(name))
+    }
+
+    /// Redacts the segments of `path` that fill a redacted parameter of the
+    /// route pattern `route`.
+    fn redact_path(&self, path: &str, route: &str) -> String {
+        let mut segments: Vec<&str> = path.split('/').collect();
+        for (i, pattern) in route.split('/').enumerate() {
+            if i >= segments.len() {
+                break;
+            }
+            if let Some(name) = pattern.strip_prefix(':') {
+                if self.redacted(name) {
+                    segments[i] = "REDACTED";
+                }
+            } else if let Some(name) = pattern.strip_prefix('*') {
+                if self.redacted(name) {
+                    segments.truncate(i);
+                    segments.push("REDACTED");
+                }
+                break;
+            }
+        }
+        segments.join("/")
+    }
+
+    fn redact_query(&self, query: &str) -> String {
+        if self.redact.is_empty() {
+            return query.to_string();
+        }
+        query
+            .split('&')
+            .map(|pair| {
+                let name = pair.split_once('=').map_or(pair, |(name, _)| name);
+                let decoded: String = form_urlencoded::parse(name.as_bytes())
+                    .map(|(name, _)| name.into_owned())
+                    .next()
+                    .unwrap_or_default();
+                if self.redacted(&decoded) {
+                    format!("{}=REDACTED", name)
+                } else {
+                    pair.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+type Writer = Arc<dyn Fn(&AccessLogEntry, &AccessLogFormat) + Send + Sync>;
+
+/// Middleware logging every request after it has been handled.
+///
+/// Lines go to stdout unless another writer is set. The remote address is
+/// the request's [`ClientIp`]: the adapter's peer address, or the forwarded
+/// client when the peer is a trusted proxy.
+#[derive(Clone)]
+pub struct AccessLogMiddleware {
+    format: AccessLogFormat,
+    writer: Writer,
+    anonymizer: Option<Anonymizer>,
+}
+
+impl AccessLogMiddleware {
+    pub fn new(format: AccessLogFormat) -> Self {
+        Self {
+            format,
+            writer: Arc::new(|entry, format| println!("{}", format.format(entry))),
+            anonymizer: None,
+        }
+    }
+
+    pub fn common() -> Self {
+        Self::new(AccessLogFormat::Common)
+    }
+
+    pub fn json() -> Self {
+        Self::new(AccessLogFormat::Json)
+    }
+
+    /// Sends formatted lines somewhere other than stdout.
+    pub fn writer(mut self, write: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.writer = Arc::new(move |entry, format| write(&format.format(entry)));
+        self
+    }
+
+    /// Strips personal data from entries before they are written.
+    pub fn anonymize(mut self, anonymizer: Anonymizer) -> Self {
+        self.anonymizer = Some(anonymizer);
+        self
+    }
+
+    /// Emits entries as `tracing` events on the `xeno::access` target, with
+    /// the entry's fields as event fields.
+    #[cfg(feature = "tracing")]
+    pub fn tracing() -> Self {
+        Self {
+            format: AccessLogFormat::Json,
+            writer: Arc::new(|entry, _| {
+                tracing::info!(
+                    target: "xeno::access",
+                    method = %entry.method,
+                    path = %entry.path,
+                    route = entry.route.as_deref(),
+                    status = entry.status,
+                    latency_ms = entry.latency.as_secs_f64() * 1000.0,
+                    size = entry.size,
+                    remote_addr = entry.remote_addr.as_deref(),
+                );
+            }),
+            anonymizer: None,
+        }
+    }
+}
+
+fn response_size(res: &CoreResponse) -> Option<u64> {
+    match res.body() {
+        Body::Full(bytes) => Some(bytes.len() as u64),
+        Body::Stream(_) => res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for AccessLogMiddleware {
+    async fn handle(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let start = Instant::now();
+        let timestamp = Utc::now();
+        let method = req.method().to_string();
+        let path = req
+            .uri()
+            .path_and_query()
+            .map_or_else(|| req.uri().path().to_string(), |pq| pq.to_string());
+        let remote_addr = ClientIp::resolve(&req).map(|ClientIp(ip)| ip.to_string());
+
+        let result = next.run(ctx, req).await;
+        let (status, route, size) = match &result {
+            Ok(res) => (
+                res.status().as_u16(),
+                res.extensions().get::<MatchedPath>().map(|m| m.0.clone()),
+                response_size(res),
+            ),
+            Err(e) => (e.status_code().as_u16(), None, None),
+        };
+        let mut entry = AccessLogEntry {
+            timestamp,
+            method,
+            path,
+            route,
+            status,
+            latency: start.elapsed(),
+            size,
+            remote_addr,
+        };
+        if let Some(anonymizer) = &self.anonymizer {
+            anonymizer.apply(&mut entry);
+        }
+        (self.writer)(&entry, &self.format);
+        result
+    }
+}
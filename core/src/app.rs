@@ -1,76 +1,507 @@
-use crate::{middleware::MiddlewareStack, router::Router, CoreRequest, CoreResponse, Ctx, Handler};
-use http::Method;
-use std::sync::Arc;
+use crate::{
+    client_ip::TrustedProxies,
+    compose::NestedApp,
+    describe::{AppDescription, ConfigRequirement, ScopeDescription},
+    explain::Explain,
+    extract::BodyLimit,
+    formatter::{ErrorHandler, ErrorRequest, JsonFormatter, ResponseFormatter},
+    logging,
+    middleware::{Middleware, MiddlewareStack, MiddlewareSwitch},
+    plugin::{Plugin, PluginError},
+    route_table::RouteTable,
+    router::{FrozenRouter, PathPolicy, RouteError, RouteGroup, RouterBuilder},
+    spa::SpaFallback,
+    warmup::{self, WarmupProgress, WarmupTask},
+    Body, CoreRequest, CoreResponse, Ctx, Error, IntoHandler,
+};
+use http::header::{HeaderValue, CONTENT_LENGTH};
+use http::{Method, StatusCode};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
 
+type Scope = (String, Arc<dyn Fn() -> AppDescription + Send + Sync>);
+
+/// An application: routes, middleware and the context handlers receive.
+///
+/// Building one is cheap: each route is appended to an owned
+/// [`RouterBuilder`], and the route table is compiled once, on the first
+/// request or by [`App::build`]. Adding a route to a built or cloned app
+/// compiles a new table for that app and leaves the original's alone.
+///
+/// Clones are not independent apps, though: they share the context, and so
+/// its state and stores, as well as warm-up progress and the enabled flags
+/// behind [`MiddlewareSwitch`]. Only routes and middleware added to a clone
+/// are its own.
+///
+/// [`App::with_lazy_state`] defers expensive state until first use. Where
+/// every isolate pays for initialization, as on Workers, keep the app in a
+/// static so it is built once per isolate:
+///
+/// ```ignore
+/// static APP: LazyLock<App> = LazyLock::new(|| App::with_default_context().get("/", index));
+///
+/// async fn fetch(req: CoreRequest) -> CoreResponse {
+///     APP.handle(req).await
+/// }
+/// ```
+// There is no separate `AppBuilder`: registering a route only appends to
+// `routes` and drops this app's handle on the compiled table, so `App`
+// already is a cheap builder, and `build` hands back the same type.
 pub struct App<C = Ctx> {
-    router: Arc<Router<C>>,
-    middleware: Arc<MiddlewareStack<C>>,
+    routes: RouterBuilder<C>,
+    router: Arc<OnceLock<FrozenRouter<C>>>,
+    middleware: MiddlewareStack<C>,
+    formatter: Arc<dyn ResponseFormatter>,
+    explain: Option<Arc<Explain>>,
+    warmup: Vec<(String, WarmupTask<C>)>,
+    warmup_progress: WarmupProgress,
+    plugins: Vec<String>,
+    scopes: Vec<Scope>,
+    config: Vec<ConfigRequirement>,
+    trusted_proxies: Option<Arc<TrustedProxies>>,
+    body_limit: Option<BodyLimit>,
+    path_policy: PathPolicy,
     context: C,
 }
 
 impl<C: Send + Sync + Clone + 'static> App<C> {
     pub fn new(context: C) -> Self {
         Self {
-            router: Arc::new(Router::new()),
-            middleware: Arc::new(MiddlewareStack::new()),
+            routes: RouterBuilder::new(),
+            router: Arc::new(OnceLock::new()),
+            middleware: MiddlewareStack::new(),
+            formatter: Arc::new(JsonFormatter),
+            explain: None,
+            warmup: Vec::new(),
+            warmup_progress: WarmupProgress::default(),
+            plugins: Vec::new(),
+            scopes: Vec::new(),
+            config: Vec::new(),
+            trusted_proxies: None,
+            body_limit: None,
+            path_policy: PathPolicy::default(),
             context,
         }
     }
 
-    pub fn get(self, path: &str, handler: impl Handler<C> + 'static) -> Self {
-        let mut router = Arc::try_unwrap(self.router).unwrap_or_else(|arc| (*arc).clone());
-        router.add_route(Method::GET, path, Box::new(handler));
+    pub fn get<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::GET, path, handler)
+    }
 
-        Self {
-            router: Arc::new(router),
-            middleware: self.middleware,
-            context: self.context,
-        }
+    pub fn post<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::POST, path, handler)
+    }
+
+    pub fn put<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::PUT, path, handler)
     }
 
-    pub fn post(self, path: &str, handler: impl Handler<C> + 'static) -> Self {
-        let mut router = Arc::try_unwrap(self.router).unwrap_or_else(|arc| (*arc).clone());
-        router.add_route(Method::POST, path, Box::new(handler));
+    pub fn delete<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::DELETE, path, handler)
+    }
 
-        Self {
-            router: Arc::new(router),
-            middleware: self.middleware,
-            context: self.context,
+    /// Mounts a group of routes under a common path prefix.
+    pub fn nest(mut self, prefix: &str, router: RouterBuilder<C>) -> Self {
+        self.routes.nest(prefix, router);
+        self.router = Arc::new(OnceLock::new());
+        self
+    }
+
+    /// Registers the routes `group` adds under `prefix`, wrapped in the
+    /// middleware it adds:
+    ///
+    /// ```ignore
+    /// app.scope("/admin", |admin| {
+    ///     admin
+    ///         .middleware(RequireAdmin)
+    ///         .get("/users", list_users)
+    ///         .post("/users", create_user)
+    /// })
+    /// ```
+    pub fn scope(
+        mut self,
+        prefix: &str,
+        group: impl FnOnce(RouteGroup<C>) -> RouteGroup<C>,
+    ) -> Self {
+        self.routes
+            .nest(prefix, group(RouteGroup::new()).into_routes());
+        self.router = Arc::new(OnceLock::new());
+        self
+    }
+
+    /// Registers global middleware that wraps every request, including ones
+    /// that end in a 404 or 405.
+    pub fn middleware(mut self, middleware: impl Middleware<C> + 'static) -> Self {
+        self.middleware.add(Box::new(middleware));
+        self
+    }
+
+    /// Like [`App::middleware`], but registered under `name` for toggling
+    /// through [`App::middleware_switch`].
+    pub fn middleware_named(
+        mut self,
+        name: impl Into<String>,
+        middleware: impl Middleware<C> + 'static,
+    ) -> Self {
+        self.middleware.add_named(name, Box::new(middleware));
+        self
+    }
+
+    /// A handle for enabling and disabling global middleware at runtime.
+    /// Route-scoped middleware is not affected.
+    pub fn middleware_switch(&self) -> MiddlewareSwitch {
+        self.middleware.switch()
+    }
+
+    /// Replaces the serializer used for framework-generated error responses.
+    pub fn formatter(mut self, formatter: impl ResponseFormatter + 'static) -> Self {
+        self.formatter = Arc::new(formatter);
+        self.router = Arc::new(OnceLock::new());
+        self
+    }
+
+    /// Renders framework errors, including unmatched routes, with `handler`,
+    /// e.g. for branded HTML pages or RFC 7807 problem documents. Replaces
+    /// any [`App::formatter`].
+    pub fn error_handler(
+        self,
+        handler: impl Fn(&Error, &ErrorRequest) -> CoreResponse + Send + Sync + 'static,
+    ) -> Self {
+        self.formatter(ErrorHandler(handler))
+    }
+
+    /// Turns on explain mode for requests sent with `X-Xeno-Explain`.
+    pub fn explain(mut self, explain: Explain) -> Self {
+        self.explain = Some(Arc::new(explain));
+        self
+    }
+
+    /// Believes forwarding headers from `proxies` when resolving a
+    /// [`ClientIp`](crate::client_ip::ClientIp).
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = Some(Arc::new(proxies));
+        self
+    }
+
+    /// The most request body bytes extractors such as [`Json`](crate::Json)
+    /// read, 2 MiB by default; see [`BodyLimit`].
+    pub fn body_limit(mut self, max_bytes: usize) -> Self {
+        self.body_limit = Some(BodyLimit(max_bytes));
+        self
+    }
+
+    /// Sets how request paths are normalized before routing, e.g. whether
+    /// `/users/` reaches a `/users` route:
+    ///
+    /// ```ignore
+    /// app.path_policy(
+    ///     PathPolicy::new()
+    ///         .trailing_slash(TrailingSlash::Redirect)
+    ///         .merge_slashes(true),
+    /// )
+    /// ```
+    pub fn path_policy(mut self, policy: PathPolicy) -> Self {
+        self.path_policy = policy;
+        self.router = Arc::new(OnceLock::new());
+        self
+    }
+
+    pub fn response_formatter(&self) -> &dyn ResponseFormatter {
+        self.formatter.as_ref()
+    }
+
+    /// Registers `handler` for every method, including non-standard ones.
+    pub fn any<M>(mut self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.routes.add_any_route(path, handler.into_handler());
+        self.router = Arc::new(OnceLock::new());
+        self
+    }
+
+    /// Registers `handler` for an arbitrary method, e.g. WebDAV's `REPORT`.
+    pub fn route<M>(mut self, method: Method, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.routes.add_route(method, path, handler.into_handler());
+        // Any previously compiled table is stale once the route set changes.
+        self.router = Arc::new(OnceLock::new());
+        self
+    }
+
+    /// Handles requests that match no route, e.g. with a [`SpaFallback`].
+    pub fn fallback<M>(mut self, handler: impl IntoHandler<C, M>) -> Self {
+        self.routes.set_fallback(handler.into_handler());
+        self.router = Arc::new(OnceLock::new());
+        self
+    }
+
+    /// Serves the single-page application entry point `index` for unmatched
+    /// `GET` requests outside `/api/` whose path has no file extension, so
+    /// client-side routes survive a reload. The file is read once, here.
+    pub fn spa_fallback(self, index: impl Into<PathBuf>) -> Self {
+        self.fallback(SpaFallback::new(index))
+    }
+
+    /// Mounts a whole app with its own context type under `prefix`, e.g. a
+    /// library's auth or admin module, deriving its context from this app's
+    /// for every request.
+    ///
+    /// The sub-app keeps its middleware, formatter and fallback, and sees
+    /// paths with the prefix stripped. Its warm-up tasks run as part of this
+    /// app's.
+    ///
+    /// ```ignore
+    /// app.nest_with_context("/auth", auth::routes(), |ctx: Ctx| AuthCtx::new(ctx.kv))
+    /// ```
+    pub fn nest_with_context<D, F>(mut self, prefix: &str, app: App<D>, map: F) -> Self
+    where
+        D: Send + Sync + Clone + 'static,
+        F: Fn(C) -> D + Send + Sync + 'static,
+    {
+        let nested = NestedApp::new(prefix, app.freeze(), map);
+        let base = nested.prefix().to_string();
+        self.routes.add_any_route(&base, Box::new(nested.clone()));
+        self.routes.add_any_route(
+            &format!("{}/*path", base.trim_end_matches('/')),
+            Box::new(nested.clone()),
+        );
+        self.router = Arc::new(OnceLock::new());
+        let scope = nested.clone();
+        self.scopes
+            .push((base.clone(), Arc::new(move || scope.describe())));
+        self.warmup(&format!("nested {}", base), move |_| {
+            let nested = nested.clone();
+            async move {
+                nested.run_warmup().await;
+                Ok(())
+            }
+        })
+    }
+
+    /// Mounts a [`Plugin`]'s routes, middleware and warm-up tasks.
+    ///
+    /// Fails if a plugin of the same name is already mounted, a plugin it
+    /// [requires](Plugin::requires) is not, its [check](Plugin::check) of the
+    /// context fails, or it registers a route or fallback that conflicts
+    /// with the app's, as [`App::build`] would report it.
+    pub fn plugin(self, plugin: impl Plugin<C>) -> Result<Self, PluginError> {
+        let name = plugin.name().to_string();
+        if self.plugins.contains(&name) {
+            return Err(PluginError::AlreadyMounted(name));
+        }
+        if let Some(missing) = plugin
+            .requires()
+            .iter()
+            .find(|required| !self.plugins.iter().any(|p| p == *required))
+        {
+            return Err(PluginError::Missing {
+                plugin: name,
+                requires: missing.to_string(),
+            });
+        }
+        if let Err(source) = plugin.check(&self.context) {
+            return Err(PluginError::Check {
+                plugin: name,
+                source,
+            });
+        }
+
+        let start = self.routes.len();
+        let fallback = self.routes.fallback().cloned();
+        let mut app = plugin.register(self);
+        let errors = app.routes.errors_since(start);
+        if !errors.is_empty() {
+            return Err(PluginError::Routes {
+                plugin: name,
+                errors,
+            });
+        }
+        let replaced = match (&fallback, app.routes.fallback()) {
+            (Some(before), Some(now)) => !Arc::ptr_eq(before, now),
+            _ => false,
+        };
+        if replaced {
+            return Err(PluginError::Fallback(name));
         }
+        app.plugins.push(name);
+        Ok(app)
     }
 
-    pub fn put(self, path: &str, handler: impl Handler<C> + 'static) -> Self {
-        let mut router = Arc::try_unwrap(self.router).unwrap_or_else(|arc| (*arc).clone());
-        router.add_route(Method::PUT, path, Box::new(handler));
+    /// Names of the plugins mounted so far, in order.
+    pub fn plugins(&self) -> &[String] {
+        &self.plugins
+    }
 
-        Self {
-            router: Arc::new(router),
-            middleware: self.middleware,
-            context: self.context,
+    /// Declares a setting the app expects from its environment, e.g. an API
+    /// key or database URL, so it shows up in [`App::describe`].
+    pub fn requires_config(mut self, name: &str, description: &str) -> Self {
+        self.config.push(ConfigRequirement {
+            name: name.to_string(),
+            description: description.to_string(),
+        });
+        self
+    }
+
+    /// The app's routes, global middleware, nested apps, plugins and declared
+    /// configuration, for printing at startup or diffing in CI.
+    ///
+    /// ```ignore
+    /// println!("{}", app.describe());
+    /// std::fs::write("routes.json", app.describe().to_json())?;
+    /// ```
+    pub fn describe(&self) -> AppDescription {
+        AppDescription {
+            routes: self.routes.describe(),
+            middleware: self.middleware.status(),
+            scopes: self
+                .scopes
+                .iter()
+                .map(|(prefix, describe)| ScopeDescription {
+                    prefix: prefix.clone(),
+                    app: describe(),
+                })
+                .collect(),
+            plugins: self.plugins.clone(),
+            config: self.config.clone(),
+            fallback: self.routes.fallback().is_some(),
         }
     }
 
-    pub fn delete(self, path: &str, handler: impl Handler<C> + 'static) -> Self {
-        let mut router = Arc::try_unwrap(self.router).unwrap_or_else(|arc| (*arc).clone());
-        router.add_route(Method::DELETE, path, Box::new(handler));
+    /// Adds a task to run before the app is reported ready, e.g. priming a
+    /// cache, prefetching JWKS or filling a connection pool.
+    ///
+    /// The hyper adapter runs the warm-up before binding its listener, and
+    /// the Workers adapter on the first request. Until every task has run
+    /// the [`Readiness`](crate::health::Readiness) probe answers `503` with
+    /// the progress so far, and stays unavailable if a task failed.
+    pub fn warmup<F, Fut>(mut self, name: &str, task: F) -> Self
+    where
+        F: Fn(C) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.warmup.push((name.to_string(), warmup::task(task)));
+        self.warmup_progress.set_total(self.warmup.len());
+        self
+    }
 
-        Self {
-            router: Arc::new(router),
-            middleware: self.middleware,
-            context: self.context,
+    pub fn warmup_progress(&self) -> &WarmupProgress {
+        &self.warmup_progress
+    }
+
+    /// Runs the warm-up tasks one after another. Only the first call does
+    /// anything; a failed task is logged and the rest still run.
+    pub async fn run_warmup(&self) {
+        if !self.warmup_progress.start() {
+            return;
         }
+        for (name, task) in &self.warmup {
+            self.warmup_progress.begin(name);
+            let result = task(self.context.clone()).await;
+            if let Err(e) = &result {
+                logging::global().error(format!(
+                    "Warm-up task {} failed: {}",
+                    name,
+                    e.debug_message()
+                ));
+            }
+            self.warmup_progress.finish(name, &result);
+        }
+        self.warmup_progress.end();
+    }
+
+    /// Compiles the route table now instead of on the first request.
+    pub fn freeze(self) -> Self {
+        self.router();
+        self
+    }
+
+    /// Compiles the route table, failing with every duplicate, conflicting
+    /// or invalid route rather than logging and skipping them as
+    /// [`App::freeze`] and the first request do.
+    ///
+    /// Nested apps are compiled when they are mounted; build them before
+    /// passing them to [`App::nest_with_context`] to check their routes.
+    pub fn build(self) -> Result<Self, Vec<RouteError>> {
+        let router = self.routes.try_freeze()?;
+        let _ = self.router.set(
+            router
+                .with_formatter(Arc::clone(&self.formatter))
+                .with_path_policy(self.path_policy),
+        );
+        Ok(self)
+    }
+
+    fn router(&self) -> &FrozenRouter<C> {
+        self.router.get_or_init(|| {
+            self.routes
+                .freeze()
+                .with_formatter(Arc::clone(&self.formatter))
+                .with_path_policy(self.path_policy)
+        })
     }
 
     pub async fn handle(&self, req: CoreRequest) -> CoreResponse {
-        self.router.handle(self.context.clone(), req).await
+        self.handle_with_context(self.context.clone(), req).await
+    }
+
+    /// Handles `req` with another context than the app's own, as when the
+    /// app is nested with [`App::nest_with_context`].
+    pub async fn handle_with_context(&self, ctx: C, mut req: CoreRequest) -> CoreResponse {
+        let head = req.method() == Method::HEAD;
+        let trace = self.explain.as_ref().and_then(|e| e.start(&mut req));
+        req.extensions_mut().insert(self.warmup_progress.clone());
+        if let Some(trusted) = &self.trusted_proxies {
+            req.extensions_mut().insert(Arc::clone(trusted));
+        }
+        if let Some(limit) = self.body_limit {
+            req.extensions_mut().insert(limit);
+        }
+        let mut res = self
+            .middleware
+            .execute(ctx, req, self.router(), self.formatter.as_ref())
+            .await;
+        if let Some(trace) = trace {
+            trace.annotate(&mut res);
+        }
+        if head {
+            strip_body(&mut res);
+        }
+        res
+    }
+}
+
+/// Drops the body of a response to `HEAD`, recording the length it would
+/// have had, `0` included. A streamed body's length is only known from its
+/// header, and responses that never have a body (`1xx`, `204`, `304`) get
+/// none.
+fn strip_body(res: &mut CoreResponse) {
+    let body = std::mem::replace(res.body_mut(), Body::empty());
+    let status = res.status();
+    let bodiless = status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED;
+    if let Body::Full(bytes) = body {
+        if !bodiless && !res.headers().contains_key(CONTENT_LENGTH) {
+            res.headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+        }
     }
 }
 
 impl<C: Clone> Clone for App<C> {
     fn clone(&self) -> Self {
         Self {
+            routes: self.routes.clone(),
             router: Arc::clone(&self.router),
-            middleware: Arc::clone(&self.middleware),
+            middleware: self.middleware.clone(),
+            formatter: Arc::clone(&self.formatter),
+            explain: self.explain.clone(),
+            warmup: self.warmup.clone(),
+            warmup_progress: self.warmup_progress.clone(),
+            plugins: self.plugins.clone(),
+            scopes: self.scopes.clone(),
+            config: self.config.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+            body_limit: self.body_limit,
+            path_policy: self.path_policy,
             context: self.context.clone(),
         }
     }
@@ -80,4 +511,48 @@ impl App<Ctx> {
     pub fn with_default_context() -> Self {
         Self::new(Ctx::default())
     }
+
+    /// Adds the routes of a configured [`RouteTable`]. Routes already
+    /// registered in code win: a conflicting entry is skipped with a warning,
+    /// so call this after registering code routes.
+    pub fn route_table(mut self, table: &RouteTable) -> Self {
+        for entry in &table.routes {
+            // Validated when the table was loaded.
+            let Ok(method) = entry.method() else {
+                continue;
+            };
+            if self.routes.has_route(method.as_ref(), &entry.path) {
+                logging::global().warn(format!(
+                    "Skipping configured route {} {}: already registered",
+                    method.as_ref().map_or("*", Method::as_str),
+                    entry.path
+                ));
+                continue;
+            }
+            match method {
+                Some(method) => self.routes.add_route(method, &entry.path, entry.handler()),
+                None => self.routes.add_any_route(&entry.path, entry.handler()),
+            }
+        }
+        self.router = Arc::new(OnceLock::new());
+        self
+    }
+
+    /// Registers shared state for handlers to pull out with
+    /// [`State<T>`](crate::extract::State). One value is kept per type.
+    pub fn with_state<T: Send + Sync + 'static>(mut self, state: T) -> Self {
+        self.context.insert_state(state);
+        self
+    }
+
+    /// Like [`App::with_state`], but builds the state on first use instead
+    /// of at startup.
+    pub fn with_lazy_state<T, F>(mut self, init: F) -> Self
+    where
+        T: Send + Sync + 'static,
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        self.context.insert_lazy_state(init);
+        self
+    }
 }
@@ -0,0 +1,233 @@
+use crate::{context::Kv, Body, CoreRequest, CoreResponse, Ctx, Error, Handler};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_core::Stream;
+use http::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// One line of an NDJSON Kv export. Values are base64-encoded so binary
+/// entries survive the round trip.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KvRecord {
+    pub key: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// What an import does with keys that already exist in the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    Skip,
+    Overwrite,
+    /// Abort with `409 Conflict` before writing anything.
+    Fail,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+/// Serializes every entry under `prefix` as NDJSON.
+pub async fn export(kv: &dyn Kv, prefix: &str) -> Result<Bytes, Error> {
+    let mut out = BytesMut::new();
+    for key in list(kv, prefix).await? {
+        if let Some(line) = record_line(kv, key).await? {
+            out.put_slice(&line);
+        }
+    }
+    Ok(out.freeze())
+}
+
+/// Like [`export`], but streams the NDJSON one record at a time, so only
+/// the key list is held in memory.
+pub async fn export_stream(kv: Arc<dyn Kv>, prefix: &str) -> Result<Body, Error> {
+    let keys = list(kv.as_ref(), prefix).await?;
+    Ok(Body::from_stream(Records {
+        kv,
+        keys: keys.into(),
+        read: None,
+    }))
+}
+
+async fn list(kv: &dyn Kv, prefix: &str) -> Result<Vec<String>, Error> {
+    kv.list(prefix)
+        .await
+        .map_err(|e| Error::internal(format!("Failed to list keys: {}", e)))
+}
+
+/// The NDJSON line for `key`, or `None` if the entry has expired since the
+/// keys were listed.
+async fn record_line(kv: &dyn Kv, key: String) -> Result<Option<Bytes>, Error> {
+    let Some(value) = kv.get(&key).await else {
+        return Ok(None);
+    };
+    let record = KvRecord {
+        key,
+        value: STANDARD.encode(&value),
+        metadata: None,
+    };
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+    Ok(Some(line.into()))
+}
+
+type RecordRead = Pin<Box<dyn Future<Output = Result<Option<Bytes>, Error>> + Send>>;
+
+/// The records of `keys`, read one at a time.
+struct Records {
+    kv: Arc<dyn Kv>,
+    keys: VecDeque<String>,
+    read: Option<RecordRead>,
+}
+
+impl Stream for Records {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.read.is_none() {
+                let Some(key) = self.keys.pop_front() else {
+                    return Poll::Ready(None);
+                };
+                let kv = Arc::clone(&self.kv);
+                self.read = Some(Box::pin(async move { record_line(kv.as_ref(), key).await }));
+            }
+            let Some(read) = self.read.as_mut() else {
+                return Poll::Ready(None);
+            };
+            let Poll::Ready(result) = read.as_mut().poll(cx) else {
+                return Poll::Pending;
+            };
+            self.read = None;
+            match result {
+                Ok(Some(line)) => return Poll::Ready(Some(Ok(line))),
+                Ok(None) => continue,
+                Err(e) => {
+                    // Ends the stream after the error.
+                    self.keys.clear();
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
+
+/// Loads NDJSON produced by [`export`] into `kv`.
+pub async fn import(
+    kv: &dyn Kv,
+    ndjson: &[u8],
+    policy: ConflictPolicy,
+) -> Result<ImportSummary, Error> {
+    let mut records = Vec::new();
+    for (line_no, line) in ndjson.split(|b| *b == b'\n').enumerate() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let record: KvRecord = serde_json::from_slice(line).map_err(|e| {
+            Error::bad_request(format!("Invalid record on line {}: {}", line_no + 1, e))
+        })?;
+        let value = STANDARD.decode(&record.value).map_err(|e| {
+            Error::bad_request(format!("Invalid value on line {}: {}", line_no + 1, e))
+        })?;
+        records.push((record.key, value));
+    }
+
+    let mut summary = ImportSummary::default();
+    let mut pending = Vec::with_capacity(records.len());
+    for (key, value) in records {
+        if policy != ConflictPolicy::Overwrite && kv.get(&key).await.is_some() {
+            if policy == ConflictPolicy::Fail {
+                return Err(Error::conflict(format!("Key already exists: {}", key)));
+            }
+            summary.skipped += 1;
+            continue;
+        }
+        pending.push((key, value));
+    }
+
+    for (key, value) in pending {
+        kv.put(&key, Bytes::from(value))
+            .await
+            .map_err(|e| Error::internal(format!("Failed to import {}: {}", key, e)))?;
+        summary.imported += 1;
+    }
+    Ok(summary)
+}
+
+#[derive(Deserialize)]
+struct BackupQuery {
+    prefix: Option<String>,
+    conflict: Option<ConflictPolicy>,
+}
+
+fn backup_query(req: &CoreRequest) -> Result<BackupQuery, Error> {
+    crate::Query::<BackupQuery>::extract(req).map(|q| q.0)
+}
+
+fn context_kv(ctx: &Ctx) -> Result<&Arc<dyn Kv>, Error> {
+    ctx.kv
+        .as_ref()
+        .ok_or_else(|| Error::internal("Backup requires a Kv store"))
+}
+
+/// Admin handler streaming the context's Kv namespace as NDJSON, optionally
+/// limited by `?prefix=`.
+///
+/// This exposes every stored value; mount it behind authentication.
+pub struct KvExport;
+
+#[async_trait]
+impl Handler<Ctx> for KvExport {
+    async fn call(&self, ctx: Ctx, req: CoreRequest) -> Result<CoreResponse, Error> {
+        let query = backup_query(&req)?;
+        let body = export_stream(
+            Arc::clone(context_kv(&ctx)?),
+            query.prefix.as_deref().unwrap_or(""),
+        )
+        .await?;
+        Ok(http::Response::builder()
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(body)?)
+    }
+}
+
+/// Admin handler importing an NDJSON body into the context's Kv namespace.
+///
+/// `?conflict=skip|overwrite|fail` overrides the configured policy.
+pub struct KvImport {
+    policy: ConflictPolicy,
+}
+
+impl KvImport {
+    pub fn new(policy: ConflictPolicy) -> Self {
+        Self { policy }
+    }
+}
+
+impl Default for KvImport {
+    fn default() -> Self {
+        Self::new(ConflictPolicy::Skip)
+    }
+}
+
+#[async_trait]
+impl Handler<Ctx> for KvImport {
+    async fn call(&self, ctx: Ctx, req: CoreRequest) -> Result<CoreResponse, Error> {
+        let policy = backup_query(&req)?.conflict.unwrap_or(self.policy);
+        let body = req.into_body().collect().await?;
+        let summary = import(context_kv(&ctx)?.as_ref(), &body, policy).await?;
+        Ok(http::Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&summary)?.into())?)
+    }
+}
@@ -0,0 +1,315 @@
+use crate::{CoreRequest, Error};
+use bytes::{Bytes, BytesMut};
+use futures_core::Stream;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Mutex, PoisonError};
+use std::task::{Context, Poll};
+
+pub type BoxStream<T> = Pin<Box<dyn Stream<Item = T> + Send>>;
+
+/// Body of a [`CoreRequest`](crate::CoreRequest) or
+/// [`CoreResponse`](crate::CoreResponse): either fully buffered or a stream
+/// of chunks.
+///
+/// The built-in body extractors read a stream up to the request's
+/// [`BodyLimit`](crate::extract::BodyLimit) when they run; raw
+/// [`Handler`](crate::Handler) implementations and
+/// [`BodyStream`](crate::extract::BodyStream) receive it as-is and can
+/// process large uploads incrementally.
+pub enum Body {
+    Full(Bytes),
+    Stream(StreamBody),
+}
+
+/// A chunk stream usable as a body. Requests are shared by reference across
+/// tasks, so the stream is kept behind a lock that only polling touches.
+pub struct StreamBody {
+    stream: Mutex<BoxStream<Result<Bytes, Error>>>,
+    /// The whole stream once [`Body::read`] has drained it, for later reads.
+    read: Mutex<Option<Bytes>>,
+}
+
+impl StreamBody {
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<Bytes, Error>> + Send + 'static,
+    {
+        Self {
+            stream: Mutex::new(Box::pin(stream)),
+            read: Mutex::new(None),
+        }
+    }
+
+    fn read_bytes(&self) -> Option<Bytes> {
+        self.read
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl Stream for StreamBody {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut()
+            .stream
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+            .poll_next(cx)
+    }
+}
+
+/// What is left of a stream another reader took.
+struct Taken;
+
+impl Stream for Taken {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(None)
+    }
+}
+
+impl Body {
+    pub fn empty() -> Self {
+        Body::Full(Bytes::new())
+    }
+
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<Bytes, Error>> + Send + 'static,
+    {
+        Body::Stream(StreamBody::new(stream))
+    }
+
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Body::Stream(_))
+    }
+
+    /// The body's bytes, if it is buffered.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Full(bytes) => Some(bytes),
+            Body::Stream(_) => None,
+        }
+    }
+
+    /// Like [`Body::as_bytes`], but also returns a stream that
+    /// [`Body::read`] has drained, and fails for other unbuffered bodies.
+    pub fn bytes(&self) -> Result<Bytes, Error> {
+        match self {
+            Body::Full(bytes) => Ok(bytes.clone()),
+            Body::Stream(stream) => stream
+                .read_bytes()
+                .ok_or_else(|| Error::internal("Body has not been buffered")),
+        }
+    }
+
+    /// Reads the whole body through a shared reference, as extractors do,
+    /// failing with `413` once it exceeds `max_bytes`. A stream is drained
+    /// on the first call and its bytes kept for later ones.
+    pub async fn read(&self, max_bytes: usize) -> Result<Bytes, Error> {
+        let stream = match self {
+            Body::Full(bytes) if bytes.len() > max_bytes => return Err(Error::payload_too_large()),
+            Body::Full(bytes) => return Ok(bytes.clone()),
+            Body::Stream(stream) => stream,
+        };
+        if let Some(bytes) = stream.read_bytes() {
+            return match bytes.len() > max_bytes {
+                true => Err(Error::payload_too_large()),
+                false => Ok(bytes),
+            };
+        }
+
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = std::future::poll_fn(|cx| {
+            stream
+                .stream
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .as_mut()
+                .poll_next(cx)
+        })
+        .await
+        {
+            let chunk = chunk?;
+            if buf.len() + chunk.len() > max_bytes {
+                return Err(Error::payload_too_large());
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        let bytes = buf.freeze();
+        *stream.read.lock().unwrap_or_else(PoisonError::into_inner) = Some(bytes.clone());
+        Ok(bytes)
+    }
+
+    /// Moves the body out from behind a shared reference: a buffered body is
+    /// copied, a stream is handed over and leaves an empty one behind.
+    pub fn take(&self) -> Body {
+        match self {
+            Body::Full(bytes) => Body::Full(bytes.clone()),
+            Body::Stream(stream) => match stream.read_bytes() {
+                Some(bytes) => Body::Full(bytes),
+                None => {
+                    let mut taken = stream.stream.lock().unwrap_or_else(PoisonError::into_inner);
+                    Body::Stream(StreamBody {
+                        stream: Mutex::new(std::mem::replace(&mut *taken, Box::pin(Taken))),
+                        read: Mutex::new(None),
+                    })
+                }
+            },
+        }
+    }
+
+    /// Reads the whole body into memory.
+    pub async fn collect(self) -> Result<Bytes, Error> {
+        self.collect_limited(usize::MAX).await
+    }
+
+    /// Reads the whole body, failing with `413` once it exceeds `max_bytes`.
+    pub async fn collect_limited(self, max_bytes: usize) -> Result<Bytes, Error> {
+        let mut stream = match self {
+            Body::Full(bytes) if bytes.len() > max_bytes => return Err(Error::payload_too_large()),
+            Body::Full(bytes) => return Ok(bytes),
+            Body::Stream(stream) => stream,
+        };
+
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await
+        {
+            let chunk = chunk?;
+            if buf.len() + chunk.len() > max_bytes {
+                return Err(Error::payload_too_large());
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(buf.freeze())
+    }
+
+    /// Buffers a streaming body in place and returns the bytes.
+    pub async fn buffer(&mut self) -> Result<Bytes, Error> {
+        if self.is_stream() {
+            let bytes = std::mem::take(self).collect().await?;
+            *self = Body::Full(bytes);
+        }
+        self.bytes()
+    }
+
+    /// Clones a buffered body, or a stream [`Body::read`] has drained;
+    /// other streams cannot be cloned.
+    pub fn try_clone(&self) -> Option<Body> {
+        match self {
+            Body::Full(bytes) => Some(Body::Full(bytes.clone())),
+            Body::Stream(stream) => stream.read_bytes().map(Body::Full),
+        }
+    }
+}
+
+impl Stream for Body {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            Body::Stream(stream) => Pin::new(stream).poll_next(cx),
+            Body::Full(bytes) if bytes.is_empty() => Poll::Ready(None),
+            Body::Full(bytes) => Poll::Ready(Some(Ok(std::mem::take(bytes)))),
+        }
+    }
+}
+
+/// Copies a request for inspection after it has been handed on. A streaming
+/// body cannot be copied and is left empty in the copy.
+pub(crate) fn clone_request(req: &CoreRequest) -> CoreRequest {
+    let mut copy = http::Request::new(req.body().try_clone().unwrap_or_default());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    *copy.extensions_mut() = req.extensions().clone();
+    copy
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Full(bytes) => f.debug_tuple("Full").field(bytes).finish(),
+            Body::Stream(_) => f.write_str("Stream(..)"),
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::empty()
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(bytes: Bytes) -> Self {
+        Body::Full(bytes)
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Full(bytes.into())
+    }
+}
+
+impl From<String> for Body {
+    fn from(s: String) -> Self {
+        Body::Full(s.into())
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(s: &'static str) -> Self {
+        Body::Full(Bytes::from_static(s.as_bytes()))
+    }
+}
+
+impl From<&'static [u8]> for Body {
+    fn from(bytes: &'static [u8]) -> Self {
+        Body::Full(Bytes::from_static(bytes))
+    }
+}
+
+impl PartialEq<[u8]> for Body {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.as_bytes() == Some(other)
+    }
+}
+
+impl PartialEq<str> for Body {
+    fn eq(&self, other: &str) -> bool {
+        self == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for Body {
+    fn eq(&self, other: &&str) -> bool {
+        self == other.as_bytes()
+    }
+}
+
+impl PartialEq<Bytes> for Body {
+    fn eq(&self, other: &Bytes) -> bool {
+        self == other.as_ref()
+    }
+}
+
+/// A stream yielding `chunks` one by one, for tests of streamed bodies.
+#[cfg(test)]
+pub(crate) struct Chunks(pub std::collections::VecDeque<&'static str>);
+
+#[cfg(test)]
+impl Stream for Chunks {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.0.pop_front().map(|c| Ok(c.into())))
+    }
+}
@@ -0,0 +1,250 @@
+use crate::{
+    client_ip::ClientIp,
+    clock::{Clock, SystemClock},
+    middleware::{Middleware, Next},
+    CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use http::header::USER_AGENT;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Bot-management score supplied by the platform (Cloudflare's `cf.botManagement`),
+/// where 1 means almost certainly automated and 99 almost certainly human.
+///
+/// Adapters insert this into the request extensions when it is available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlatformBotScore {
+    pub score: u8,
+    pub verified_bot: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BotAction {
+    Allow,
+    Challenge,
+    Block,
+}
+
+/// The outcome of [`BotPolicy`], available to handlers via the request
+/// extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotDecision {
+    /// Combined likelihood that the client is automated, from 0 to 100.
+    pub score: u8,
+    pub action: BotAction,
+    pub signals: Vec<&'static str>,
+}
+
+/// Serves challenges (CAPTCHA pages, JS proofs of work) to suspicious clients.
+#[async_trait]
+pub trait ChallengeHandler<C: Send + Sync + Clone + 'static>: Send + Sync {
+    /// Whether the request carries proof of a solved challenge.
+    async fn is_verified(&self, ctx: &C, req: &CoreRequest) -> bool;
+
+    async fn challenge(&self, ctx: &C, req: &CoreRequest) -> Result<CoreResponse, Error>;
+}
+
+/// Default challenge handler: a `429` asking the client to slow down,
+/// rendered by the app's formatter.
+pub struct RejectChallenge;
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> ChallengeHandler<C> for RejectChallenge {
+    async fn is_verified(&self, _ctx: &C, _req: &CoreRequest) -> bool {
+        false
+    }
+
+    async fn challenge(&self, _ctx: &C, _req: &CoreRequest) -> Result<CoreResponse, Error> {
+        Err(Error::too_many_requests())
+    }
+}
+
+const AUTOMATION_USER_AGENTS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "curl",
+    "wget",
+    "python-requests",
+    "headless",
+    "scrapy",
+];
+
+/// Middleware that combines bot signals into an allow/challenge/block action.
+pub struct BotPolicy<C> {
+    challenge_at: u8,
+    block_at: u8,
+    rate_limit: Option<(u32, Duration)>,
+    max_clients: usize,
+    windows: Mutex<HashMap<IpAddr, (SystemTime, u32)>>,
+    clock: Arc<dyn Clock>,
+    challenge: Arc<dyn ChallengeHandler<C>>,
+}
+
+impl<C: Send + Sync + Clone + 'static> BotPolicy<C> {
+    pub fn new() -> Self {
+        Self {
+            challenge_at: 50,
+            block_at: 90,
+            rate_limit: None,
+            max_clients: 100_000,
+            windows: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+            challenge: Arc::new(RejectChallenge),
+        }
+    }
+
+    /// Scores at or above `challenge_at` are challenged, at or above
+    /// `block_at` blocked outright.
+    pub fn thresholds(mut self, challenge_at: u8, block_at: u8) -> Self {
+        self.challenge_at = challenge_at;
+        self.block_at = block_at;
+        self
+    }
+
+    /// Treats more than `max_requests` per `window` from one client as a bot
+    /// signal. Clients are told apart by their [`ClientIp`], so forwarding
+    /// headers only count when they come from a trusted proxy.
+    pub fn rate_signal(mut self, max_requests: u32, window: Duration) -> Self {
+        self.rate_limit = Some((max_requests, window));
+        self
+    }
+
+    /// The most clients whose request rate is tracked at once, 100 000 by
+    /// default. Past it, finished windows are pruned and then the oldest
+    /// client is forgotten.
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = max_clients.max(1);
+        self
+    }
+
+    /// The clock used for the request rate signal, e.g. a
+    /// [`ManualClock`](crate::clock::ManualClock) in tests.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn challenge_handler(mut self, handler: impl ChallengeHandler<C> + 'static) -> Self {
+        self.challenge = Arc::new(handler);
+        self
+    }
+
+    pub fn evaluate(&self, req: &CoreRequest) -> BotDecision {
+        let mut score: u32 = 0;
+        let mut signals = Vec::new();
+
+        if let Some(platform) = req.extensions().get::<PlatformBotScore>() {
+            if platform.verified_bot {
+                return BotDecision {
+                    score: 0,
+                    action: BotAction::Allow,
+                    signals: vec!["verified-bot"],
+                };
+            }
+            score += 100 - u32::from(platform.score.min(100));
+            signals.push("platform-score");
+        }
+
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        if user_agent.is_empty() {
+            score += 40;
+            signals.push("missing-user-agent");
+        } else if AUTOMATION_USER_AGENTS
+            .iter()
+            .any(|ua| user_agent.contains(ua))
+        {
+            score += 60;
+            signals.push("automation-user-agent");
+        }
+
+        if self.exceeds_rate(req) {
+            score += 50;
+            signals.push("request-rate");
+        }
+
+        let score = score.min(100) as u8;
+        let action = if score >= self.block_at {
+            BotAction::Block
+        } else if score >= self.challenge_at {
+            BotAction::Challenge
+        } else {
+            BotAction::Allow
+        };
+
+        BotDecision {
+            score,
+            action,
+            signals,
+        }
+    }
+
+    fn exceeds_rate(&self, req: &CoreRequest) -> bool {
+        let Some((max_requests, window)) = self.rate_limit else {
+            return false;
+        };
+
+        let Some(ClientIp(client)) = ClientIp::resolve(req) else {
+            return false;
+        };
+
+        let now = self.clock.now();
+        let expired = |start: SystemTime| now.duration_since(start).unwrap_or_default() > window;
+        let mut windows = self.windows.lock().unwrap();
+        if !windows.contains_key(&client) && windows.len() >= self.max_clients {
+            windows.retain(|_, (start, _)| !expired(*start));
+            if windows.len() >= self.max_clients {
+                let oldest = windows
+                    .iter()
+                    .min_by_key(|(_, (start, _))| *start)
+                    .map(|(ip, _)| *ip);
+                if let Some(oldest) = oldest {
+                    windows.remove(&oldest);
+                }
+            }
+        }
+        let entry = windows.entry(client).or_insert((now, 0));
+        if expired(entry.0) {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        entry.1 > max_requests
+    }
+}
+
+impl<C: Send + Sync + Clone + 'static> Default for BotPolicy<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for BotPolicy<C> {
+    async fn handle(
+        &self,
+        ctx: C,
+        mut req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let decision = self.evaluate(&req);
+
+        match decision.action {
+            BotAction::Block => return Err(Error::forbidden()),
+            BotAction::Challenge if !self.challenge.is_verified(&ctx, &req).await => {
+                return self.challenge.challenge(&ctx, &req).await;
+            }
+            _ => {}
+        }
+
+        req.extensions_mut().insert(decision);
+        next.run(ctx, req).await
+    }
+}
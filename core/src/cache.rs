@@ -0,0 +1,226 @@
+use crate::{explain, CoreRequest, CoreResponse, Error, Handler, RequestExt};
+use async_trait::async_trait;
+use http::header::{CACHE_CONTROL, SET_COOKIE};
+use http::Method;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_ENTRIES: usize = 1024;
+const DEFAULT_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Wraps a handler and memoizes its successful `GET` responses.
+///
+/// This is an application-level cache keyed by extracted request values, not
+/// an HTTP cache: freshness is its own TTL. It does honour the response
+/// saying it must not be shared, though: responses setting cookies or marked
+/// `Cache-Control: private` or `no-store` are never stored. The least
+/// recently used entries are evicted past the entry and byte limits.
+pub struct Cached<H> {
+    inner: H,
+    ttl: Duration,
+    keys: Vec<String>,
+    max_entries: usize,
+    max_bytes: usize,
+    store: Arc<Mutex<Store>>,
+}
+
+struct CacheEntry {
+    expires_at: Instant,
+    response: CoreResponse,
+    size: usize,
+    /// Position in [`Store::recency`].
+    used: u64,
+}
+
+/// Entries plus their use order, least recent first.
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, CacheEntry>,
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl Store {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+            self.bytes -= entry.size;
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.used);
+            entry.used = tick;
+            self.recency.insert(tick, key.to_string());
+        }
+    }
+
+    fn insert(&mut self, key: String, mut entry: CacheEntry, max_entries: usize, max_bytes: usize) {
+        self.remove(&key);
+        while self.entries.len() >= max_entries || self.bytes + entry.size > max_bytes {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.bytes -= evicted.size;
+            }
+        }
+        self.tick += 1;
+        entry.used = self.tick;
+        self.bytes += entry.size;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, entry);
+    }
+}
+
+pub fn cached<H>(handler: H) -> Cached<H> {
+    Cached {
+        inner: handler,
+        ttl: DEFAULT_TTL,
+        keys: Vec::new(),
+        max_entries: DEFAULT_MAX_ENTRIES,
+        max_bytes: DEFAULT_MAX_BYTES,
+        store: Arc::default(),
+    }
+}
+
+impl<H> Cached<H> {
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Adds a path or query parameter to the cache key.
+    ///
+    /// Without any keys the full request path and query string is used.
+    pub fn key(mut self, name: impl Into<String>) -> Self {
+        self.keys.push(name.into());
+        self
+    }
+
+    /// The most responses kept, 1024 by default.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    /// The most body bytes kept across all responses, 16 MiB by default.
+    /// Larger responses are not stored.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn clear(&self) {
+        *self.store.lock().unwrap() = Store::default();
+    }
+
+    fn cache_key(&self, req: &CoreRequest) -> String {
+        if self.keys.is_empty() {
+            return req
+                .uri()
+                .path_and_query()
+                .map(|pq| pq.as_str().to_string())
+                .unwrap_or_default();
+        }
+
+        let query: HashMap<String, String> =
+            form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+                .into_owned()
+                .collect();
+
+        let mut key = req.uri().path().to_string();
+        for name in &self.keys {
+            let value = req
+                .param(name)
+                .ok()
+                .or_else(|| query.get(name).map(String::as_str))
+                .unwrap_or("");
+            key.push('\0');
+            key.push_str(name);
+            key.push('=');
+            key.push_str(value);
+        }
+        key
+    }
+}
+
+#[async_trait]
+impl<C, H> Handler<C> for Cached<H>
+where
+    C: Send + Sync + Clone + 'static,
+    H: Handler<C>,
+{
+    async fn call(&self, ctx: C, req: CoreRequest) -> Result<CoreResponse, Error> {
+        if req.method() != Method::GET {
+            return self.inner.call(ctx, req).await;
+        }
+
+        let key = self.cache_key(&req);
+        let now = Instant::now();
+
+        {
+            let mut store = self.store.lock().unwrap();
+            match store.entries.get(&key) {
+                Some(entry) if entry.expires_at > now => {
+                    explain::note(&req, "cache", "hit");
+                    let response = clone_response(&entry.response);
+                    store.touch(&key);
+                    return Ok(response);
+                }
+                Some(_) => store.remove(&key),
+                None => {}
+            }
+        }
+        explain::note(&req, "cache", "miss");
+
+        let response = self.inner.call(ctx, req).await?;
+
+        let size = response.body().as_bytes().map_or(usize::MAX, <[u8]>::len);
+        if response.status().is_success() && size <= self.max_bytes && is_shareable(&response) {
+            self.store.lock().unwrap().insert(
+                key,
+                CacheEntry {
+                    expires_at: now + self.ttl,
+                    response: clone_response(&response),
+                    size,
+                    used: 0,
+                },
+                self.max_entries,
+                self.max_bytes,
+            );
+        }
+
+        Ok(response)
+    }
+}
+
+/// Whether the response may be served to other clients: no cookies, and no
+/// `private` or `no-store` directive.
+fn is_shareable(response: &CoreResponse) -> bool {
+    if response.headers().contains_key(SET_COOKIE) {
+        return false;
+    }
+    !response
+        .headers()
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|directive| directive.split('=').next().unwrap_or("").trim())
+        .any(|name| name.eq_ignore_ascii_case("private") || name.eq_ignore_ascii_case("no-store"))
+}
+
+fn clone_response(response: &CoreResponse) -> CoreResponse {
+    let mut cloned = http::Response::new(response.body().try_clone().unwrap_or_default());
+    *cloned.status_mut() = response.status();
+    *cloned.version_mut() = response.version();
+    *cloned.headers_mut() = response.headers().clone();
+    cloned
+}
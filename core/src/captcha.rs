@@ -0,0 +1,171 @@
+use crate::{
+    client_ip::ClientIp,
+    context::HttpClient,
+    extract::FromRequest,
+    middleware::{Middleware, Next},
+    CoreRequest, CoreResponse, Ctx, Error,
+};
+use async_trait::async_trait;
+use http::header::CONTENT_TYPE;
+use http::Method;
+use serde::Deserialize;
+
+const TOKEN_HEADER: &str = "x-captcha-token";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptchaProvider {
+    Turnstile,
+    HCaptcha,
+    ReCaptcha,
+}
+
+impl CaptchaProvider {
+    pub fn verify_url(&self) -> &'static str {
+        match self {
+            CaptchaProvider::Turnstile => {
+                "https://challenges.cloudflare.com/turnstile/v0/siteverify"
+            }
+            CaptchaProvider::HCaptcha => "https://api.hcaptcha.com/siteverify",
+            CaptchaProvider::ReCaptcha => "https://www.google.com/recaptcha/api/siteverify",
+        }
+    }
+
+    /// Form field the provider's widget submits the token in.
+    pub fn form_field(&self) -> &'static str {
+        match self {
+            CaptchaProvider::Turnstile => "cf-turnstile-response",
+            CaptchaProvider::HCaptcha => "h-captcha-response",
+            CaptchaProvider::ReCaptcha => "g-recaptcha-response",
+        }
+    }
+}
+
+/// A challenge token submitted by the client, taken from the
+/// `x-captcha-token` header or any provider's form field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptchaToken(pub String);
+
+impl CaptchaToken {
+    pub fn extract(req: &CoreRequest) -> Result<Self, Error> {
+        if let Some(token) = req
+            .headers()
+            .get(TOKEN_HEADER)
+            .and_then(|v| v.to_str().ok())
+        {
+            return Ok(CaptchaToken(token.to_string()));
+        }
+
+        let fields = [
+            CaptchaProvider::Turnstile.form_field(),
+            CaptchaProvider::HCaptcha.form_field(),
+            CaptchaProvider::ReCaptcha.form_field(),
+        ];
+        form_urlencoded::parse(&req.body().bytes()?)
+            .find(|(key, value)| fields.contains(&key.as_ref()) && !value.is_empty())
+            .map(|(_, value)| CaptchaToken(value.into_owned()))
+            .ok_or_else(|| Error::bad_request("Missing captcha token"))
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for CaptchaToken {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        if !req.headers().contains_key(TOKEN_HEADER) {
+            crate::extract::read_body(req).await?;
+        }
+        Self::extract(req)
+    }
+}
+
+#[derive(Deserialize)]
+struct SiteverifyResponse {
+    success: bool,
+}
+
+/// Server-side verification against a provider's `siteverify` endpoint.
+#[derive(Debug, Clone)]
+pub struct CaptchaVerifier {
+    provider: CaptchaProvider,
+    secret: String,
+}
+
+impl CaptchaVerifier {
+    pub fn new(provider: CaptchaProvider, secret: impl Into<String>) -> Self {
+        Self {
+            provider,
+            secret: secret.into(),
+        }
+    }
+
+    pub async fn verify(
+        &self,
+        http: &dyn HttpClient,
+        token: &str,
+        remote_ip: Option<&str>,
+    ) -> Result<bool, Error> {
+        let form = {
+            let mut form = form_urlencoded::Serializer::new(String::new());
+            form.append_pair("secret", &self.secret);
+            form.append_pair("response", token);
+            if let Some(ip) = remote_ip {
+                form.append_pair("remoteip", ip);
+            }
+            form.finish()
+        };
+
+        let req = http::Request::builder()
+            .method(Method::POST)
+            .uri(self.provider.verify_url())
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(form.into())?;
+
+        let res = http.send(req).await?;
+        if !res.status().is_success() {
+            return Err(Error::internal(format!(
+                "Captcha verification failed with status {}",
+                res.status()
+            )));
+        }
+
+        let body = res.into_body().collect().await?;
+        let body: SiteverifyResponse = serde_json::from_slice(&body)
+            .map_err(|e| Error::internal(format!("Invalid siteverify response: {}", e)))?;
+        Ok(body.success)
+    }
+}
+
+/// Middleware rejecting requests without a valid challenge token, using the
+/// context's HTTP client for verification. The provider is told the
+/// request's [`ClientIp`].
+pub struct RequireCaptcha {
+    verifier: CaptchaVerifier,
+}
+
+impl RequireCaptcha {
+    pub fn new(verifier: CaptchaVerifier) -> Self {
+        Self { verifier }
+    }
+}
+
+#[async_trait]
+impl Middleware<Ctx> for RequireCaptcha {
+    async fn handle(
+        &self,
+        ctx: Ctx,
+        req: CoreRequest,
+        next: Next<'_, Ctx>,
+    ) -> Result<CoreResponse, Error> {
+        let CaptchaToken(token) = CaptchaToken::from_request(&ctx, &req).await?;
+        let remote_ip = ClientIp::resolve(&req).map(|ClientIp(ip)| ip.to_string());
+
+        if !self
+            .verifier
+            .verify(ctx.http()?, &token, remote_ip.as_deref())
+            .await?
+        {
+            return Err(Error::forbidden());
+        }
+
+        next.run(ctx, req).await
+    }
+}
@@ -0,0 +1,204 @@
+use crate::extract::{FromRequest, RemoteAddr};
+use crate::{CoreRequest, Error};
+use async_trait::async_trait;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// The proxies whose forwarding headers an app believes, configured with
+/// [`App::trusted_proxies`](crate::App::trusted_proxies).
+///
+/// Headers are only read when the connection itself comes from a trusted
+/// proxy, so a client talking to the app directly cannot claim another
+/// address.
+///
+/// ```ignore
+/// let app = App::with_default_context()
+///     .trusted_proxies(TrustedProxies::parse(&["10.0.0.0/8", "127.0.0.1"])?);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+    cf_connecting_ip: bool,
+}
+
+impl TrustedProxies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts each address or CIDR range in `proxies`, e.g. `10.0.0.0/8`,
+    /// `192.0.2.7` or `fd00::/8`.
+    pub fn parse(proxies: &[&str]) -> Result<Self, Error> {
+        proxies
+            .iter()
+            .try_fold(Self::new(), |trusted, proxy| trusted.allow(proxy))
+    }
+
+    /// Loopback, RFC 1918 and IPv6 unique local addresses: a reverse proxy
+    /// on the same host or private network.
+    pub fn private() -> Self {
+        Self::parse(&[
+            "127.0.0.0/8",
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "::1/128",
+            "fc00::/7",
+        ])
+        .expect("valid private ranges")
+    }
+
+    pub fn allow(mut self, proxy: &str) -> Result<Self, Error> {
+        self.nets.push(
+            IpNet::parse(proxy)
+                .ok_or_else(|| Error::internal(format!("Invalid trusted proxy {:?}", proxy)))?,
+        );
+        Ok(self)
+    }
+
+    /// Also believes `CF-Connecting-IP` from trusted proxies, for origins
+    /// that only Cloudflare can reach. Off by default, as other proxies
+    /// pass the header through from the client.
+    pub fn cf_connecting_ip(mut self) -> Self {
+        self.cf_connecting_ip = true;
+        self
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    /// The client behind `peer`: the rightmost forwarded hop that is not a
+    /// trusted proxy, preferring `Forwarded` over `X-Forwarded-For`.
+    fn resolve(&self, peer: IpAddr, req: &CoreRequest) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+        if self.cf_connecting_ip {
+            if let Some(ip) = header(req, "cf-connecting-ip").and_then(|v| parse_hop(&v)) {
+                return ip;
+            }
+        }
+        let hops: Vec<String> = match header(req, "forwarded") {
+            Some(forwarded) => forwarded
+                .split(',')
+                .filter_map(|element| {
+                    element.split(';').find_map(|pair| {
+                        let (name, value) = pair.split_once('=')?;
+                        name.trim()
+                            .eq_ignore_ascii_case("for")
+                            .then(|| value.to_string())
+                    })
+                })
+                .collect(),
+            None => header(req, "x-forwarded-for")
+                .map(|v| v.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+        };
+
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            // An obfuscated or garbled hop ends the chain we can follow.
+            let Some(ip) = parse_hop(hop) else { break };
+            client = ip;
+            if !self.contains(ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Every value of header `name`, joined with commas.
+fn header(req: &CoreRequest, name: &str) -> Option<String> {
+    let values: Vec<&str> = req
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(","))
+}
+
+/// An address from a forwarding header: `192.0.2.1`, `192.0.2.1:4711`,
+/// `2001:db8::1` or `"[2001:db8::1]:4711"`.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    let ip = match hop.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?.parse().ok(),
+        None => hop
+            .parse::<IpAddr>()
+            .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+            .ok(),
+    };
+    ip.map(|ip: IpAddr| ip.to_canonical())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IpNet {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpNet {
+    fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The address of the client that made the request.
+///
+/// This is the connection's [`RemoteAddr`] unless that is one of the app's
+/// [`TrustedProxies`], in which case the forwarding headers are followed
+/// back past every trusted hop. Apps without trusted proxies always get the
+/// peer address.
+///
+/// ```ignore
+/// async fn login(ClientIp(ip): ClientIp, Json(form): Json<Login>) -> Result<Json<Session>, Error> {
+///     limiter.check(ip)?;
+///     ...
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// Resolves the client address for middleware that has the request at
+    /// hand; `None` when the adapter supplied no [`RemoteAddr`].
+    pub fn resolve(req: &CoreRequest) -> Option<Self> {
+        let peer = req.extensions().get::<RemoteAddr>()?.0.ip().to_canonical();
+        let client = match req.extensions().get::<Arc<TrustedProxies>>() {
+            Some(trusted) => trusted.resolve(peer, req),
+            None => peer,
+        };
+        Some(ClientIp(client))
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for ClientIp {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Self::resolve(req).ok_or_else(|| Error::internal("Remote address is not available"))
+    }
+}
@@ -0,0 +1,220 @@
+use crate::Ctx;
+use std::any::Any;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time, replaceable in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    fn unix_secs(&self) -> u64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// The system wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    /// Starts at `secs` seconds after the Unix epoch.
+    pub fn at_unix(secs: u64) -> Self {
+        Self::new(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Source of randomness for identifiers, replaceable in tests. Not meant
+/// for key material, which always comes from the OS.
+pub trait Rng: Send + Sync {
+    fn fill_bytes(&self, dest: &mut [u8]);
+
+    fn next_u64(&self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// A version 4 UUID built from this source.
+    #[cfg(feature = "uuid")]
+    fn uuid(&self) -> uuid::Uuid {
+        uuid::Uuid::from_bytes(self.uuid_bytes())
+    }
+
+    /// A hyphenated version 4 UUID built from this source, available without
+    /// the `uuid` feature.
+    fn uuid_string(&self) -> String {
+        let mut out = String::with_capacity(36);
+        for (i, byte) in self.uuid_bytes().iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                out.push('-');
+            }
+            let _ = write!(out, "{:02x}", byte);
+        }
+        out
+    }
+
+    /// Random bytes with the version 4 and RFC 4122 variant bits set.
+    fn uuid_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        self.fill_bytes(&mut bytes);
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        bytes
+    }
+}
+
+/// The operating system's random number generator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRng;
+
+impl Rng for SystemRng {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        use aes_gcm::aead::rand_core::RngCore;
+        aes_gcm::aead::OsRng.fill_bytes(dest);
+    }
+}
+
+/// A deterministic generator (SplitMix64): the same seed always yields the
+/// same sequence. Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: Arc<Mutex<u64>>,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(seed)),
+        }
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        let mut state = self.state.lock().unwrap();
+        for chunk in dest.chunks_mut(8) {
+            *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = *state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+/// A point in time broken down into UTC calendar fields, so timestamps can
+/// be formatted without a date library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UtcTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    /// Days since Sunday.
+    pub weekday: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub nanos: u32,
+}
+
+impl UtcTime {
+    /// Times before the Unix epoch are clamped to it.
+    pub fn from_system(time: SystemTime) -> Self {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since.as_secs();
+        let days = (secs / 86_400) as i64;
+        let rem = (secs % 86_400) as u32;
+
+        // Howard Hinnant's civil_from_days.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        Self {
+            year,
+            month,
+            day,
+            // 1970-01-01 was a Thursday.
+            weekday: ((days + 4) % 7) as u32,
+            hour: rem / 3600,
+            minute: rem / 60 % 60,
+            second: rem % 60,
+            nanos: since.subsec_nanos(),
+        }
+    }
+}
+
+/// Formats a time as RFC 3339 in UTC, e.g. `2023-11-14T22:13:20+00:00`,
+/// with as many fractional digits (0, 3, 6 or 9) as the time needs.
+pub fn rfc3339(time: SystemTime) -> String {
+    let t = UtcTime::from_system(time);
+    let fraction = match t.nanos {
+        0 => String::new(),
+        n if n % 1_000_000 == 0 => format!(".{:03}", n / 1_000_000),
+        n if n % 1_000 == 0 => format!(".{:06}", n / 1_000),
+        n => format!(".{:09}", n),
+    };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}+00:00",
+        t.year, t.month, t.day, t.hour, t.minute, t.second, fraction
+    )
+}
+
+/// The clock of a [`Ctx`], or the system clock for other context types.
+pub fn clock_of<C: 'static>(ctx: &C) -> Arc<dyn Clock> {
+    match (ctx as &dyn Any).downcast_ref::<Ctx>() {
+        Some(ctx) => Arc::clone(&ctx.clock),
+        None => Arc::new(SystemClock),
+    }
+}
+
+/// The random source of a [`Ctx`], or the OS for other context types.
+pub fn rng_of<C: 'static>(ctx: &C) -> Arc<dyn Rng> {
+    match (ctx as &dyn Any).downcast_ref::<Ctx>() {
+        Some(ctx) => Arc::clone(&ctx.rng),
+        None => Arc::new(SystemRng),
+    }
+}
@@ -0,0 +1,240 @@
+use crate::{describe::AppDescription, App, CoreRequest, CoreResponse, Error, Handler};
+use async_trait::async_trait;
+use http::header::HOST;
+use http::uri::{PathAndQuery, Uri};
+use std::sync::Arc;
+
+/// An app with its context type erased.
+#[async_trait]
+trait Mounted: Send + Sync {
+    async fn handle(&self, req: CoreRequest) -> CoreResponse;
+
+    async fn run_warmup(&self);
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Mounted for App<C> {
+    async fn handle(&self, req: CoreRequest) -> CoreResponse {
+        App::handle(self, req).await
+    }
+
+    async fn run_warmup(&self) {
+        App::run_warmup(self).await
+    }
+}
+
+#[derive(Clone)]
+struct Mount {
+    host: Option<String>,
+    prefix: String,
+    app: Arc<dyn Mounted>,
+}
+
+impl Mount {
+    /// The path left after the prefix, when the mount applies.
+    fn strip<'a>(&self, host: Option<&str>, path: &'a str) -> Option<&'a str> {
+        if self.host.is_some() && self.host.as_deref() != host {
+            return None;
+        }
+        if self.prefix.is_empty() {
+            return Some(path);
+        }
+        let rest = path.strip_prefix(self.prefix.as_str())?;
+        (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+    }
+}
+
+/// Several independent apps, each with its own context type, middleware
+/// and formatter, served on one listener.
+///
+/// Requests go to the app mounted for their `Host` with the longest
+/// matching path prefix, falling back to apps mounted for any host. The
+/// prefix is stripped, so an app mounted at `/billing` keeps declaring
+/// `/invoices` rather than `/billing/invoices`. Requests no app takes are
+/// answered `404 Not Found`.
+///
+/// ```ignore
+/// let apps = Apps::new()
+///     .mount("/billing", billing_app)
+///     .mount("/", web_app)
+///     .host("admin.example.com", admin_app);
+/// HyperAdapter::new(apps.into_app()).serve("0.0.0.0:8080").await?;
+/// ```
+#[derive(Clone, Default)]
+pub struct Apps {
+    mounts: Vec<Mount>,
+}
+
+impl Apps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `app` under `prefix` for every host.
+    pub fn mount<C: Send + Sync + Clone + 'static>(self, prefix: &str, app: App<C>) -> Self {
+        self.add(None, prefix, app)
+    }
+
+    /// Serves every path of `host` from `app`.
+    pub fn host<C: Send + Sync + Clone + 'static>(self, host: &str, app: App<C>) -> Self {
+        self.add(Some(host), "/", app)
+    }
+
+    /// Serves `app` under `prefix` for `host` only.
+    pub fn host_mount<C: Send + Sync + Clone + 'static>(
+        self,
+        host: &str,
+        prefix: &str,
+        app: App<C>,
+    ) -> Self {
+        self.add(Some(host), prefix, app)
+    }
+
+    fn add<C: Send + Sync + Clone + 'static>(
+        mut self,
+        host: Option<&str>,
+        prefix: &str,
+        app: App<C>,
+    ) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        self.mounts.push(Mount {
+            host: host.map(str::to_ascii_lowercase),
+            prefix: if prefix.is_empty() || prefix.starts_with('/') {
+                prefix.to_string()
+            } else {
+                format!("/{}", prefix)
+            },
+            app: Arc::new(app.freeze()),
+        });
+        self
+    }
+
+    /// An app for adapters that serves all mounted apps and runs their
+    /// warm-up tasks as its own.
+    pub fn into_app(self) -> App<()> {
+        let apps = self.clone();
+        App::new(()).fallback(self).warmup("apps", move |_| {
+            let apps = apps.clone();
+            async move {
+                for mount in &apps.mounts {
+                    mount.app.run_warmup().await;
+                }
+                Ok(())
+            }
+        })
+    }
+
+    fn route(&self, host: Option<&str>, path: &str) -> Option<(&Mount, String)> {
+        self.mounts
+            .iter()
+            .filter_map(|mount| Some((mount, mount.strip(host, path)?)))
+            .max_by_key(|(mount, _)| (mount.host.is_some(), mount.prefix.len()))
+            .map(|(mount, rest)| (mount, rest.to_string()))
+    }
+}
+
+fn request_host(req: &CoreRequest) -> Option<String> {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().host())?;
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or(ipv6),
+        None => host.split(':').next().unwrap_or(host),
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Handler<C> for Apps {
+    async fn call(&self, _ctx: C, mut req: CoreRequest) -> Result<CoreResponse, Error> {
+        let host = request_host(&req);
+        let Some((mount, rest)) = self.route(host.as_deref(), req.uri().path()) else {
+            return Err(Error::not_found());
+        };
+        if !mount.prefix.is_empty() {
+            set_path(&mut req, &rest)?;
+        }
+        Ok(mount.app.handle(req).await)
+    }
+}
+
+/// Replaces the request path, keeping the query; an empty path becomes `/`.
+pub(crate) fn set_path(req: &mut CoreRequest, path: &str) -> Result<(), Error> {
+    let path = if path.is_empty() { "/" } else { path };
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(
+        PathAndQuery::try_from(path_and_query)
+            .map_err(|_| Error::bad_request("Invalid request path"))?,
+    );
+    *req.uri_mut() = Uri::from_parts(parts).map_err(|e| Error::internal(e.to_string()))?;
+    Ok(())
+}
+
+/// An app mounted inside another one with a different context type; see
+/// [`App::nest_with_context`].
+pub(crate) struct NestedApp<C, D> {
+    app: App<D>,
+    prefix: String,
+    map: Arc<dyn Fn(C) -> D + Send + Sync>,
+}
+
+impl<C, D: Send + Sync + Clone + 'static> NestedApp<C, D> {
+    pub(crate) fn new(
+        prefix: &str,
+        app: App<D>,
+        map: impl Fn(C) -> D + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            app,
+            prefix: format!("/{}", prefix.trim_matches('/')),
+            map: Arc::new(map),
+        }
+    }
+
+    pub(crate) fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub(crate) async fn run_warmup(&self) {
+        self.app.run_warmup().await
+    }
+
+    pub(crate) fn describe(&self) -> AppDescription {
+        self.app.describe()
+    }
+}
+
+impl<C, D: Clone> Clone for NestedApp<C, D> {
+    fn clone(&self) -> Self {
+        Self {
+            app: self.app.clone(),
+            prefix: self.prefix.clone(),
+            map: Arc::clone(&self.map),
+        }
+    }
+}
+
+#[async_trait]
+impl<C, D> Handler<C> for NestedApp<C, D>
+where
+    C: Send + Sync + Clone + 'static,
+    D: Send + Sync + Clone + 'static,
+{
+    async fn call(&self, ctx: C, mut req: CoreRequest) -> Result<CoreResponse, Error> {
+        let prefix = self.prefix.trim_end_matches('/');
+        let rest = req
+            .uri()
+            .path()
+            .strip_prefix(prefix)
+            .unwrap_or_default()
+            .to_string();
+        set_path(&mut req, &rest)?;
+        Ok(self.app.handle_with_context((self.map)(ctx), req).await)
+    }
+}
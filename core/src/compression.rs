@@ -0,0 +1,279 @@
+use crate::{
+    middleware::{Middleware, Next},
+    Body, CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use http::header::{
+    HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    VARY,
+};
+use http::{Method, StatusCode};
+#[cfg(feature = "gzip")]
+use std::io::Write;
+
+/// A content coding the compression middleware can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    #[cfg(feature = "br")]
+    Brotli,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl Encoding {
+    fn token(self) -> &'static str {
+        match self {
+            #[cfg(feature = "br")]
+            Encoding::Brotli => "br",
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => "zstd",
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "br", feature = "gzip", feature = "zstd")),
+        allow(unused_variables)
+    )]
+    fn compress(self, input: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "br")]
+            Encoding::Brotli => {
+                let mut output = Vec::new();
+                let params = brotli::enc::BrotliEncoderParams {
+                    quality: 5,
+                    ..Default::default()
+                };
+                brotli::BrotliCompress(&mut &input[..], &mut output, &params)?;
+                Ok(output)
+            }
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => zstd::encode_all(input, 3),
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(input)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compresses buffered response bodies according to `Accept-Encoding`.
+///
+/// Each coding has its own feature: `br` and `gzip`, on by default, and
+/// `zstd`. Without any, the middleware only adds `Vary: Accept-Encoding`.
+/// Bodies below the size threshold, streamed bodies, partial (`206`, `416`
+/// or `Content-Range`) responses, responses that already carry a
+/// `Content-Encoding`, and content types that are compressed by nature
+/// (images, audio, video, archives) are left alone.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: usize,
+    encodings: Vec<Encoding>,
+}
+
+impl Compression {
+    /// Compresses bodies of 1 KiB and more, preferring brotli, then zstd,
+    /// then gzip when the client accepts them equally.
+    pub fn new() -> Self {
+        Self {
+            min_size: 1024,
+            encodings: vec![
+                #[cfg(feature = "br")]
+                Encoding::Brotli,
+                #[cfg(feature = "zstd")]
+                Encoding::Zstd,
+                #[cfg(feature = "gzip")]
+                Encoding::Gzip,
+            ],
+        }
+    }
+
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Restricts and orders the encodings offered, most preferred first.
+    pub fn encodings(mut self, encodings: impl IntoIterator<Item = Encoding>) -> Self {
+        self.encodings = encodings.into_iter().collect();
+        self
+    }
+
+    /// The best encoding the client accepts, by q-value and then by our
+    /// preference.
+    fn negotiate(&self, accept: &str) -> Option<Encoding> {
+        let mut wildcard = None;
+        let mut weights = Vec::new();
+        for item in accept.split(',') {
+            let mut parts = item.split(';');
+            let token = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if token == "*" {
+                wildcard = Some(q);
+            } else {
+                weights.push((token, q));
+            }
+        }
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in &self.encodings {
+            let q = weights
+                .iter()
+                .find(|(token, _)| token == encoding.token())
+                .map(|(_, q)| *q)
+                .or(wildcard)
+                .unwrap_or(0.0);
+            if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+                best = Some((*encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_compressible(res: &CoreResponse) -> bool {
+    let Some(content_type) = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return true;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    if mime == "image/svg+xml" {
+        return true;
+    }
+    let precompressed = [
+        "application/zip",
+        "application/gzip",
+        "application/x-gzip",
+        "application/zstd",
+        "application/x-7z-compressed",
+        "application/x-rar-compressed",
+        "application/pdf",
+        "font/woff",
+        "font/woff2",
+    ];
+    !(mime.starts_with("image/")
+        || mime.starts_with("audio/")
+        || mime.starts_with("video/")
+        || precompressed.contains(&mime.as_str()))
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for Compression {
+    async fn handle(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let encoding = req
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|accept| self.negotiate(accept));
+        let is_head = req.method() == Method::HEAD;
+
+        let mut res = next.run(ctx, req).await?;
+        // A range of the compressed body is not the compressed range, so
+        // partial responses are sent as they are.
+        if matches!(
+            res.status(),
+            StatusCode::NO_CONTENT
+                | StatusCode::NOT_MODIFIED
+                | StatusCode::PARTIAL_CONTENT
+                | StatusCode::RANGE_NOT_SATISFIABLE
+        ) || res.status().is_informational()
+            || res.headers().contains_key(CONTENT_RANGE)
+        {
+            return Ok(res);
+        }
+        if is_compressible(&res) {
+            res.headers_mut()
+                .append(VARY, HeaderValue::from_static("accept-encoding"));
+        }
+
+        let Some(encoding) = encoding else {
+            return Ok(res);
+        };
+        let Body::Full(bytes) = res.body() else {
+            return Ok(res);
+        };
+        if is_head
+            || bytes.len() < self.min_size
+            || res.headers().contains_key(CONTENT_ENCODING)
+            || !is_compressible(&res)
+        {
+            return Ok(res);
+        }
+
+        let compressed = encoding.compress(bytes).map_err(|e| {
+            Error::internal(format!("{} compression failed: {}", encoding.token(), e))
+        })?;
+        let headers = res.headers_mut();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.token()));
+        headers.insert(CONTENT_LENGTH, compressed.len().into());
+        *res.body_mut() = Body::from(compressed);
+        Ok(res)
+    }
+}
+
+#[cfg(all(test, feature = "gzip"))]
+mod tests {
+    use super::*;
+    use crate::{range, App, Ctx};
+
+    #[tokio::test]
+    async fn test_ranges_are_not_compressed() {
+        let text = "abcdefghij".repeat(300);
+        let body = text.clone();
+        let app = App::new(Ctx::new()).middleware(Compression::new()).get(
+            "/text",
+            move |req: CoreRequest| {
+                let res = http::Response::new(Body::from(body.clone()));
+                async move { range::ranged(&req, res) }
+            },
+        );
+        let get = |range: &str| {
+            http::Request::get("/text")
+                .header(ACCEPT_ENCODING, "gzip, br")
+                .header(http::header::RANGE, range)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app.handle(get("bytes=10-1999")).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(res.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(res.body(), &text[10..2000]);
+
+        let res = app.handle(get("bytes=5000-")).await;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert!(res.headers().get(CONTENT_ENCODING).is_none());
+
+        let res = app.handle(get("lines=1-2")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().contains_key(CONTENT_ENCODING));
+    }
+}
@@ -0,0 +1,404 @@
+use crate::{
+    logging::{self, Level},
+    middleware::{Middleware, Next},
+    CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use http::StatusCode;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+struct State {
+    limit: f64,
+    in_flight: usize,
+}
+
+/// Middleware limiting concurrent requests to a limit it finds by itself,
+/// using additive increase / multiplicative decrease (AIMD).
+///
+/// Every request answered within `latency_target` raises the limit by about
+/// one per limit's worth of requests; a slower one, or one ending in `408`,
+/// `503` or `504`, cuts it by the backoff ratio. Requests over the limit are
+/// shed at once with `503 Service Unavailable`, so a struggling database
+/// sees fewer concurrent queries instead of a growing queue.
+///
+/// Clones share the same limit, e.g. for reporting it.
+#[derive(Clone)]
+pub struct AdaptiveConcurrency {
+    state: Arc<Mutex<State>>,
+    latency_target: Duration,
+    min_limit: usize,
+    max_limit: usize,
+    backoff: f64,
+}
+
+impl AdaptiveConcurrency {
+    pub fn new(latency_target: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                limit: 20.0,
+                in_flight: 0,
+            })),
+            latency_target,
+            min_limit: 1,
+            max_limit: 1000,
+            backoff: 0.9,
+        }
+    }
+
+    /// The limit to start from, 20 by default.
+    pub fn initial_limit(self, limit: usize) -> Self {
+        self.state.lock().unwrap().limit = limit.clamp(self.min_limit, self.max_limit) as f64;
+        self
+    }
+
+    /// Bounds for the limit, 1 to 1000 by default.
+    pub fn limits(mut self, min: usize, max: usize) -> Self {
+        self.min_limit = min.max(1);
+        self.max_limit = max.max(self.min_limit);
+        let mut state = self.state.lock().unwrap();
+        state.limit = state
+            .limit
+            .clamp(self.min_limit as f64, self.max_limit as f64);
+        drop(state);
+        self
+    }
+
+    /// The factor applied to the limit on overload, 0.9 by default.
+    pub fn backoff(mut self, ratio: f64) -> Self {
+        if ratio > 0.0 && ratio < 1.0 {
+            self.backoff = ratio;
+        }
+        self
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit as usize
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    fn acquire(&self) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight >= state.limit as usize {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(Permit {
+            limiter: self,
+            start: Instant::now(),
+            overloaded: true,
+        })
+    }
+}
+
+/// A request's slot; adjusts the limit when released. Requests that are
+/// dropped before finishing, e.g. by a timeout, count as overloaded.
+struct Permit<'a> {
+    limiter: &'a AdaptiveConcurrency,
+    start: Instant,
+    overloaded: bool,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let limiter = self.limiter;
+        let overloaded = self.overloaded || self.start.elapsed() > limiter.latency_target;
+        let mut state = limiter.state.lock().unwrap();
+        state.in_flight -= 1;
+        state.limit = if overloaded {
+            state.limit * limiter.backoff
+        } else {
+            state.limit + 1.0 / state.limit
+        }
+        .clamp(limiter.min_limit as f64, limiter.max_limit as f64);
+    }
+}
+
+fn is_overload(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for AdaptiveConcurrency {
+    async fn handle(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let Some(mut permit) = self.acquire() else {
+            logging::global().log(
+                Level::Warn,
+                "concurrency shedding",
+                format!(
+                    "Shedding {} {}: concurrency limit {} reached",
+                    req.method(),
+                    req.uri().path(),
+                    self.limit()
+                ),
+            );
+            return Err(Error::service_unavailable());
+        };
+        let result = next.run(ctx, req).await;
+        permit.overloaded = match &result {
+            Ok(res) => is_overload(res.status()),
+            Err(error) => is_overload(error.status_code()),
+        };
+        result
+    }
+}
+
+struct BulkheadState {
+    in_flight: usize,
+    /// Waiting callers in arrival order, by ticket.
+    queue: VecDeque<(u64, Waker)>,
+    next_ticket: u64,
+    rejected: u64,
+}
+
+struct BulkheadShared {
+    name: String,
+    max_concurrent: usize,
+    max_queued: usize,
+    state: Mutex<BulkheadState>,
+}
+
+/// A pool of concurrency reserved for one dependency, e.g. the database or
+/// a slow upstream API, so it cannot tie up every request when it stalls.
+///
+/// At most `max_concurrent` calls run at once; up to the
+/// [queue](Bulkhead::queue) length more wait in arrival order, and the rest
+/// fail at once with `503 Service Unavailable`. A waiting call that is
+/// dropped, e.g. by a [`TimeoutMiddleware`](crate::timeout::TimeoutMiddleware),
+/// leaves the queue.
+///
+/// Register pools as [`Bulkheads`] state and use them from handlers:
+///
+/// ```ignore
+/// let app = App::with_default_context().with_state(
+///     Bulkheads::new()
+///         .pool(Bulkhead::new("db", 10).queue(50))
+///         .pool(Bulkhead::new("geo-api", 4)),
+/// );
+///
+/// async fn show(ctx: Ctx, Path(id): Path<u64>) -> Result<Json<User>, Error> {
+///     let user = ctx.bulkhead("db")?.run(load_user(&ctx, id)).await??;
+///     Ok(Json(user))
+/// }
+/// ```
+///
+/// Clones share the same pool.
+#[derive(Clone)]
+pub struct Bulkhead {
+    shared: Arc<BulkheadShared>,
+}
+
+/// A bulkhead's usage at one point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkheadStats {
+    pub in_flight: usize,
+    pub queued: usize,
+    /// Calls turned away since the pool was created.
+    pub rejected: u64,
+}
+
+impl Bulkhead {
+    /// A pool running up to `max_concurrent` calls, without a queue.
+    pub fn new(name: impl Into<String>, max_concurrent: usize) -> Self {
+        Self::with_limits(name.into(), max_concurrent.max(1), 0)
+    }
+
+    /// Lets up to `max_queued` calls wait for a free slot.
+    pub fn queue(self, max_queued: usize) -> Self {
+        Self::with_limits(
+            self.shared.name.clone(),
+            self.shared.max_concurrent,
+            max_queued,
+        )
+    }
+
+    fn with_limits(name: String, max_concurrent: usize, max_queued: usize) -> Self {
+        Self {
+            shared: Arc::new(BulkheadShared {
+                name,
+                max_concurrent,
+                max_queued,
+                state: Mutex::new(BulkheadState {
+                    in_flight: 0,
+                    queue: VecDeque::new(),
+                    next_ticket: 0,
+                    rejected: 0,
+                }),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.shared.name
+    }
+
+    pub fn stats(&self) -> BulkheadStats {
+        let state = self.shared.state.lock().unwrap();
+        BulkheadStats {
+            in_flight: state.in_flight,
+            queued: state.queue.len(),
+            rejected: state.rejected,
+        }
+    }
+
+    /// Waits for a slot, failing with `503` if the queue is full. The slot
+    /// is freed when the permit is dropped.
+    pub async fn acquire(&self) -> Result<BulkheadPermit, Error> {
+        Acquire {
+            shared: &self.shared,
+            ticket: None,
+        }
+        .await
+    }
+
+    /// Runs `call` in a slot of the pool.
+    pub async fn run<F: Future>(&self, call: F) -> Result<F::Output, Error> {
+        let _permit = self.acquire().await?;
+        Ok(call.await)
+    }
+}
+
+/// A slot in a [`Bulkhead`], held while the call runs.
+pub struct BulkheadPermit {
+    shared: Arc<BulkheadShared>,
+}
+
+impl Drop for BulkheadPermit {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.in_flight -= 1;
+        if let Some((_, waker)) = state.queue.front() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+struct Acquire<'a> {
+    shared: &'a Arc<BulkheadShared>,
+    ticket: Option<u64>,
+}
+
+impl Future for Acquire<'_> {
+    type Output = Result<BulkheadPermit, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let shared = self.shared;
+        let mut state = shared.state.lock().unwrap();
+        let free = state.in_flight < shared.max_concurrent;
+
+        let admitted = match self.ticket {
+            None if free && state.queue.is_empty() => true,
+            None if state.queue.len() < shared.max_queued => {
+                let ticket = state.next_ticket;
+                state.next_ticket += 1;
+                state.queue.push_back((ticket, cx.waker().clone()));
+                self.ticket = Some(ticket);
+                false
+            }
+            None => {
+                state.rejected += 1;
+                drop(state);
+                logging::global().log(
+                    Level::Warn,
+                    "bulkhead rejected",
+                    format!("Bulkhead {} is full", shared.name),
+                );
+                return Poll::Ready(Err(Error::service_unavailable()));
+            }
+            Some(ticket) => match state.queue.front() {
+                Some((first, _)) if *first == ticket && free => {
+                    state.queue.pop_front();
+                    true
+                }
+                _ => {
+                    if let Some(entry) = state.queue.iter_mut().find(|(t, _)| *t == ticket) {
+                        entry.1 = cx.waker().clone();
+                    }
+                    false
+                }
+            },
+        };
+        if !admitted {
+            return Poll::Pending;
+        }
+        state.in_flight += 1;
+        // More slots may be free, e.g. after several permits were dropped.
+        if state.in_flight < shared.max_concurrent {
+            if let Some((_, waker)) = state.queue.front() {
+                waker.wake_by_ref();
+            }
+        }
+        self.ticket = None;
+        Poll::Ready(Ok(BulkheadPermit {
+            shared: Arc::clone(shared),
+        }))
+    }
+}
+
+impl Drop for Acquire<'_> {
+    /// Gives up a place in the queue, passing the turn on if it was first.
+    fn drop(&mut self) {
+        let Some(ticket) = self.ticket else {
+            return;
+        };
+        let mut state = self.shared.state.lock().unwrap();
+        let Some(index) = state.queue.iter().position(|(t, _)| *t == ticket) else {
+            return;
+        };
+        state.queue.remove(index);
+        if index == 0 && state.in_flight < self.shared.max_concurrent {
+            if let Some((_, waker)) = state.queue.front() {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// Named [`Bulkhead`]s, registered as context state and looked up with
+/// [`Ctx::bulkhead`](crate::Ctx::bulkhead).
+#[derive(Clone, Default)]
+pub struct Bulkheads {
+    pools: HashMap<String, Bulkhead>,
+}
+
+impl Bulkheads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `bulkhead`, replacing any pool of the same name.
+    pub fn pool(mut self, bulkhead: Bulkhead) -> Self {
+        self.pools.insert(bulkhead.name().to_string(), bulkhead);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Bulkhead> {
+        self.pools.get(name)
+    }
+
+    /// Every pool's name and usage, sorted by name, e.g. for a status page.
+    pub fn stats(&self) -> Vec<(String, BulkheadStats)> {
+        let mut stats: Vec<_> = self
+            .pools
+            .values()
+            .map(|pool| (pool.name().to_string(), pool.stats()))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+}
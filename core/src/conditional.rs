@@ -0,0 +1,200 @@
+use crate::header::{http_date, parse_http_date, EntityTag, Header, IfNoneMatch};
+use crate::response::IntoResponse;
+use crate::{Body, CoreRequest, CoreResponse, Error};
+use http::header::{
+    HeaderName, HeaderValue, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_UNMODIFIED_SINCE,
+    LAST_MODIFIED,
+};
+use http::{Method, StatusCode};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What the request's conditional headers decide for the current
+/// representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    /// Serve or apply the request as usual.
+    Proceed,
+    /// `304 Not Modified`: the client's cached copy of a `GET` or `HEAD` is
+    /// current.
+    NotModified,
+    /// `412 Precondition Failed`: the resource changed since the client
+    /// last saw it, e.g. a lost update on `PUT`.
+    Failed,
+}
+
+/// The validators of a resource's current representation, checked against
+/// `If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+/// `If-Modified-Since` in the order RFC 9110 prescribes.
+///
+/// ```ignore
+/// async fn show(ctx: Ctx, req: CoreRequest) -> Result<CoreResponse, Error> {
+///     let item = load(&ctx, req.param("id")?).await?;
+///     Conditional::new()
+///         .etag(item.version.to_string())
+///         .last_modified(item.updated_at)
+///         .respond(&req, || Json(item))
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Conditional {
+    etag: Option<EntityTag>,
+    last_modified: Option<SystemTime>,
+    exists: bool,
+}
+
+impl Default for Conditional {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Conditional {
+    /// An existing resource without validators yet.
+    pub fn new() -> Self {
+        Self {
+            etag: None,
+            last_modified: None,
+            exists: true,
+        }
+    }
+
+    /// A resource that does not exist yet, so `If-None-Match: *` passes
+    /// (create only if absent) and any `If-Match` fails.
+    pub fn absent() -> Self {
+        Self {
+            exists: false,
+            ..Self::new()
+        }
+    }
+
+    /// A strong entity tag, given without quotes, e.g. a version number or
+    /// content hash.
+    pub fn etag(mut self, tag: impl Into<String>) -> Self {
+        self.etag = Some(EntityTag {
+            weak: false,
+            tag: tag.into(),
+        });
+        self
+    }
+
+    /// A weak entity tag, for representations that are equivalent but not
+    /// byte-identical. Never satisfies `If-Match`.
+    pub fn weak_etag(mut self, tag: impl Into<String>) -> Self {
+        self.etag = Some(EntityTag {
+            weak: true,
+            tag: tag.into(),
+        });
+        self
+    }
+
+    /// Truncated to whole seconds, the resolution of HTTP dates.
+    pub fn last_modified(mut self, time: SystemTime) -> Self {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.last_modified = Some(UNIX_EPOCH + Duration::from_secs(secs));
+        self
+    }
+
+    pub fn evaluate(&self, req: &CoreRequest) -> Precondition {
+        let safe = matches!(*req.method(), Method::GET | Method::HEAD);
+
+        if let Some(value) = header(req, IF_MATCH) {
+            // Only strong comparison can tell a write lost nothing.
+            let matched = match IfNoneMatch::decode(&value) {
+                Ok(IfNoneMatch::Any) => self.exists,
+                Ok(IfNoneMatch::Tags(tags)) => self.etag.as_ref().is_some_and(|current| {
+                    !current.weak && tags.iter().any(|t| !t.weak && t.tag == current.tag)
+                }),
+                Err(_) => false,
+            };
+            if !matched {
+                return Precondition::Failed;
+            }
+        } else if let Some(since) =
+            header(req, IF_UNMODIFIED_SINCE).and_then(|v| parse_http_date(&v))
+        {
+            if self.last_modified.is_some_and(|modified| modified > since) {
+                return Precondition::Failed;
+            }
+        }
+
+        if let Some(value) = header(req, IF_NONE_MATCH) {
+            let matched = match IfNoneMatch::decode(&value) {
+                Ok(IfNoneMatch::Any) => self.exists,
+                Ok(IfNoneMatch::Tags(tags)) => self
+                    .etag
+                    .as_ref()
+                    .is_some_and(|current| tags.iter().any(|t| t.tag == current.tag)),
+                Err(_) => false,
+            };
+            if matched {
+                return if safe {
+                    Precondition::NotModified
+                } else {
+                    Precondition::Failed
+                };
+            }
+        } else if safe {
+            let since = header(req, IF_MODIFIED_SINCE).and_then(|v| parse_http_date(&v));
+            if let (Some(since), Some(modified)) = (since, self.last_modified) {
+                if modified <= since {
+                    return Precondition::NotModified;
+                }
+            }
+        }
+
+        Precondition::Proceed
+    }
+
+    /// Sets the `ETag` and `Last-Modified` headers on `response`.
+    pub fn apply(&self, response: &mut CoreResponse) {
+        if let Some(etag) = &self.etag {
+            let value = if etag.weak {
+                format!("W/\"{}\"", etag.tag)
+            } else {
+                format!("\"{}\"", etag.tag)
+            };
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                response.headers_mut().insert(ETAG, value);
+            }
+        }
+        if let Some(modified) = self.last_modified {
+            response
+                .headers_mut()
+                .insert(LAST_MODIFIED, http_date(modified));
+        }
+    }
+
+    /// Answers with `body` when the preconditions pass, a bodiless `304`
+    /// when the client's copy is current, or [`Error::PreconditionFailed`].
+    /// `body` is only built when it is sent.
+    pub fn respond<T: IntoResponse>(
+        &self,
+        req: &CoreRequest,
+        body: impl FnOnce() -> T,
+    ) -> Result<CoreResponse, Error> {
+        let mut response = match self.evaluate(req) {
+            Precondition::Proceed => body().into_result()?,
+            Precondition::NotModified => http::Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())?,
+            Precondition::Failed => return Err(Error::PreconditionFailed),
+        };
+        self.apply(&mut response);
+        Ok(response)
+    }
+}
+
+/// Every value of header `name`, joined with `", "`.
+fn header(req: &CoreRequest, name: HeaderName) -> Option<String> {
+    let values: Vec<&str> = req
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(str::trim)
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
@@ -0,0 +1,308 @@
+use crate::{
+    cookie::{Cookies, SameSite, SetCookie},
+    extract::FromRequest,
+    middleware::{Middleware, Next},
+    CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// What a visitor can consent to. Strictly necessary cookies need no
+/// consent and have no purpose here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Purpose {
+    Preferences,
+    Analytics,
+    Marketing,
+}
+
+impl Purpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Purpose::Preferences => "preferences",
+            Purpose::Analytics => "analytics",
+            Purpose::Marketing => "marketing",
+        }
+    }
+}
+
+/// A visitor's consent choices, as stored in the consent cookie.
+///
+/// The default, used when the visitor has not decided yet, grants nothing.
+/// Extract it in handlers to decide whether to render tracking snippets or
+/// the banner:
+///
+/// ```ignore
+/// async fn page(consent: Consent) -> Html<String> {
+///     render_page(consent.allows(Purpose::Analytics), !consent.is_decided())
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Consent {
+    pub preferences: bool,
+    pub analytics: bool,
+    pub marketing: bool,
+    /// When the visitor chose, in Unix seconds; `None` until they do.
+    pub decided_at: Option<u64>,
+}
+
+impl Consent {
+    /// Every purpose granted, as by an "Accept all" button.
+    pub fn all(decided_at: u64) -> Self {
+        Self {
+            preferences: true,
+            analytics: true,
+            marketing: true,
+            decided_at: Some(decided_at),
+        }
+    }
+
+    /// Only strictly necessary cookies, as by a "Reject all" button.
+    pub fn necessary_only(decided_at: u64) -> Self {
+        Self {
+            decided_at: Some(decided_at),
+            ..Self::default()
+        }
+    }
+
+    /// Granting exactly `purposes`.
+    pub fn only(purposes: &[Purpose], decided_at: u64) -> Self {
+        let mut consent = Self::necessary_only(decided_at);
+        for purpose in purposes {
+            *consent.flag(*purpose) = true;
+        }
+        consent
+    }
+
+    pub fn allows(&self, purpose: Purpose) -> bool {
+        match purpose {
+            Purpose::Preferences => self.preferences,
+            Purpose::Analytics => self.analytics,
+            Purpose::Marketing => self.marketing,
+        }
+    }
+
+    /// Whether the visitor made a choice, i.e. the banner can stay hidden.
+    pub fn is_decided(&self) -> bool {
+        self.decided_at.is_some()
+    }
+
+    fn flag(&mut self, purpose: Purpose) -> &mut bool {
+        match purpose {
+            Purpose::Preferences => &mut self.preferences,
+            Purpose::Analytics => &mut self.analytics,
+            Purpose::Marketing => &mut self.marketing,
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for Consent {
+    /// The consent read by [`ConsentCookie`] middleware, or else from the
+    /// default cookie.
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        if let Some(consent) = req.extensions().get::<Consent>() {
+            return Ok(consent.clone());
+        }
+        Ok(ConsentCookie::new().read(req).unwrap_or_default())
+    }
+}
+
+/// Reads and writes the consent cookie, e.g.
+/// `v=1&preferences=1&analytics=0&marketing=0&at=1700000000`.
+///
+/// The cookie is not `HttpOnly` so a banner script can read it too. Bumping
+/// the [version](ConsentCookie::version) after the purposes or vendors
+/// change makes earlier choices count as undecided, so visitors are asked
+/// again.
+///
+/// As middleware it makes the request's [`Consent`] available to extractors
+/// and to middleware gated with [`ConsentGate`]:
+///
+/// ```ignore
+/// let consent = ConsentCookie::new().version(2);
+/// let app = App::new(ctx)
+///     .middleware(consent.clone())
+///     .middleware(ConsentGate::new(Purpose::Analytics, PageViews::new()))
+///     .post("/consent", move |ctx: Ctx, Json(choice): Json<Vec<Purpose>>| {
+///         let cookie = consent.write(&Consent::only(&choice, ctx.clock.unix_secs()));
+///         async move { (CookieJar::new().set(cookie), StatusCode::NO_CONTENT) }
+///     });
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConsentCookie {
+    name: String,
+    version: u32,
+    max_age: Duration,
+    domain: Option<String>,
+    secure: bool,
+}
+
+impl Default for ConsentCookie {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConsentCookie {
+    /// A `consent` cookie at version 1, kept for 180 days.
+    pub fn new() -> Self {
+        Self {
+            name: "consent".to_string(),
+            version: 1,
+            max_age: Duration::from_secs(180 * 24 * 60 * 60),
+            domain: None,
+            secure: true,
+        }
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Shares the choice across subdomains, e.g. `example.com`.
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Whether the cookie is marked `Secure`. On by default; turn off for
+    /// plain-HTTP development servers.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// The visitor's consent, or `None` when the cookie is missing,
+    /// malformed or from another version.
+    pub fn read(&self, req: &CoreRequest) -> Option<Consent> {
+        let cookies = Cookies::extract(req);
+        let value = cookies.get(&self.name)?;
+        let mut version = None;
+        let mut consent = Consent::default();
+        for (name, value) in form_urlencoded::parse(value.as_bytes()) {
+            let flag = match name.as_ref() {
+                "v" => {
+                    version = value.parse::<u32>().ok();
+                    continue;
+                }
+                "at" => {
+                    consent.decided_at = value.parse().ok();
+                    continue;
+                }
+                "preferences" => &mut consent.preferences,
+                "analytics" => &mut consent.analytics,
+                "marketing" => &mut consent.marketing,
+                _ => continue,
+            };
+            *flag = match value.as_ref() {
+                "1" => true,
+                "0" => false,
+                _ => return None,
+            };
+        }
+        (version == Some(self.version) && consent.is_decided()).then_some(consent)
+    }
+
+    /// The `Set-Cookie` recording `consent`.
+    pub fn write(&self, consent: &Consent) -> SetCookie {
+        let mut value = format!("v={}", self.version);
+        for purpose in [Purpose::Preferences, Purpose::Analytics, Purpose::Marketing] {
+            let granted = if consent.allows(purpose) { 1 } else { 0 };
+            value.push_str(&format!("&{}={}", purpose.as_str(), granted));
+        }
+        value.push_str(&format!("&at={}", consent.decided_at.unwrap_or(0)));
+        self.cookie(value).max_age(self.max_age)
+    }
+
+    /// The `Set-Cookie` deleting the choice, for a "withdraw consent" link.
+    pub fn withdraw(&self) -> SetCookie {
+        self.cookie(String::new()).max_age(Duration::ZERO)
+    }
+
+    fn cookie(&self, value: String) -> SetCookie {
+        let cookie = SetCookie::new(self.name.clone(), value)
+            .path("/")
+            .secure(self.secure)
+            .same_site(SameSite::Lax);
+        match &self.domain {
+            Some(domain) => cookie.domain(domain.clone()),
+            None => cookie,
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for ConsentCookie {
+    async fn handle(
+        &self,
+        ctx: C,
+        mut req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let consent = self.read(&req).unwrap_or_default();
+        req.extensions_mut().insert(consent);
+        next.run(ctx, req).await
+    }
+}
+
+/// Runs `middleware`, e.g. analytics or an ad-attribution tracker, only for
+/// visitors who consented to `purpose`; everyone else skips it. Install
+/// [`ConsentCookie`] before it to use a non-default cookie.
+pub struct ConsentGate<M> {
+    purpose: Purpose,
+    middleware: M,
+}
+
+impl<M> ConsentGate<M> {
+    pub fn new(purpose: Purpose, middleware: M) -> Self {
+        Self {
+            purpose,
+            middleware,
+        }
+    }
+}
+
+#[async_trait]
+impl<C, M> Middleware<C> for ConsentGate<M>
+where
+    C: Send + Sync + Clone + 'static,
+    M: Middleware<C>,
+{
+    async fn handle(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let allowed = match req.extensions().get::<Consent>() {
+            Some(consent) => consent.allows(self.purpose),
+            None => ConsentCookie::new()
+                .read(&req)
+                .is_some_and(|consent| consent.allows(self.purpose)),
+        };
+        if allowed {
+            self.middleware.handle(ctx, req, next).await
+        } else {
+            next.run(ctx, req).await
+        }
+    }
+
+    /// The gated middleware's name, so it can still be toggled by it.
+    fn name(&self) -> &str {
+        self.middleware.name()
+    }
+}
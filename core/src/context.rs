@@ -1,6 +1,17 @@
+use crate::{
+    clock::{Clock, Rng, SystemClock, SystemRng},
+    concurrency::{Bulkhead, Bulkheads},
+    sql::Sql,
+    CoreRequest, CoreResponse, Error,
+};
 use async_trait::async_trait;
 use bytes::Bytes;
-use std::sync::Arc;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
 #[async_trait]
 pub trait Kv: Send + Sync {
@@ -10,21 +21,193 @@ pub trait Kv: Send + Sync {
         key: &str,
         value: Bytes,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Stores a value that the backend may drop after `ttl`.
+    ///
+    /// Backends without native expiry keep the value indefinitely, so callers
+    /// relying on expiry should also check it on read.
+    async fn put_with_ttl(
+        &self,
+        key: &str,
+        value: Bytes,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let _ = ttl;
+        self.put(key, value).await
+    }
+
+    /// Returns every key starting with `prefix`. Backends that cannot
+    /// enumerate keys return an error.
+    async fn list(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let _ = prefix;
+        Err("Listing is not supported by this Kv backend".into())
+    }
+}
+
+/// Outbound HTTP, provided by the platform (hyper client, Workers `fetch`).
+#[async_trait]
+pub trait HttpClient: Send + Sync {
+    async fn send(&self, req: CoreRequest) -> Result<CoreResponse, Error>;
+}
+
+type SpawnFn =
+    Arc<dyn Fn(Box<dyn FnOnce() + Send>) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Runs blocking work, such as file reads, on a thread pool provided by the
+/// platform so it does not stall the executor. Register it as state:
+///
+/// ```ignore
+/// ctx.insert_state(BlockingPool::new(tokio::task::spawn_blocking));
+/// ```
+///
+/// Without one, [`Ctx::blocking`] runs the work in place.
+#[derive(Clone)]
+pub struct BlockingPool {
+    spawn: SpawnFn,
+}
+
+impl BlockingPool {
+    /// Uses `spawn` to start the work, awaiting what it returns.
+    pub fn new<F, Fut>(spawn: F) -> Self
+    where
+        F: Fn(Box<dyn FnOnce() + Send>) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+    {
+        Self {
+            spawn: Arc::new(move |work| {
+                let done = spawn(work);
+                Box::pin(async move {
+                    done.await;
+                })
+            }),
+        }
+    }
+
+    /// Runs `work` on the pool, failing if it panicked.
+    pub async fn run<T, F>(&self, work: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(None));
+        let result = Arc::clone(&slot);
+        (self.spawn)(Box::new(move || {
+            let value = work();
+            *result.lock().unwrap() = Some(value);
+        }))
+        .await;
+        let value = slot.lock().unwrap().take();
+        value.ok_or_else(|| Error::internal("Blocking task did not complete"))
+    }
 }
 
 #[derive(Clone)]
 pub struct Ctx {
     pub kv: Option<Arc<dyn Kv>>,
+    pub http: Option<Arc<dyn HttpClient>>,
+    pub sql: Option<Arc<dyn Sql>>,
+    /// Time source for timestamps and expiry; swap in a
+    /// [`ManualClock`](crate::clock::ManualClock) to make tests deterministic.
+    pub clock: Arc<dyn Clock>,
+    /// Random source for request and session IDs; see
+    /// [`SeededRng`](crate::clock::SeededRng).
+    pub rng: Arc<dyn Rng>,
+    state: Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
 }
 
 impl Ctx {
     pub fn new() -> Self {
-        Self { kv: None }
+        Self {
+            kv: None,
+            http: None,
+            sql: None,
+            clock: Arc::new(SystemClock),
+            rng: Arc::new(SystemRng),
+            state: Arc::new(HashMap::new()),
+        }
     }
 
     pub fn with_kv(kv: Arc<dyn Kv>) -> Self {
-        Self { kv: Some(kv) }
+        Self {
+            kv: Some(kv),
+            ..Self::new()
+        }
+    }
+
+    pub fn with_http(http: Arc<dyn HttpClient>) -> Self {
+        Self {
+            http: Some(http),
+            ..Self::new()
+        }
+    }
+
+    pub fn http(&self) -> Result<&dyn HttpClient, Error> {
+        self.http
+            .as_deref()
+            .ok_or_else(|| Error::internal("No HTTP client configured"))
     }
+
+    pub fn sql(&self) -> Result<&Arc<dyn Sql>, Error> {
+        self.sql
+            .as_ref()
+            .ok_or_else(|| Error::internal("No SQL backend configured"))
+    }
+
+    /// The [`Bulkhead`] registered as `name` in the [`Bulkheads`] state.
+    pub fn bulkhead(&self, name: &str) -> Result<&Bulkhead, Error> {
+        self.state::<Bulkheads>()
+            .and_then(|pools| pools.get(name))
+            .ok_or_else(|| Error::internal(format!("No bulkhead named {}", name)))
+    }
+
+    /// Runs blocking work on the registered [`BlockingPool`], or in place
+    /// when there is none.
+    pub async fn blocking<T, F>(&self, work: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        match self.state::<BlockingPool>() {
+            Some(pool) => pool.run(work).await,
+            None => Ok(work()),
+        }
+    }
+
+    /// Registers shared state, replacing any earlier value of the same type.
+    pub fn insert_state<T: Send + Sync + 'static>(&mut self, state: T) {
+        Arc::make_mut(&mut self.state).insert(TypeId::of::<T>(), Arc::new(state));
+    }
+
+    /// Registers state built by `init` the first time it is looked up, so
+    /// expensive setup (parsing config, compiling templates) stays out of a
+    /// cold start. Clones of the context share the built value.
+    pub fn insert_lazy_state<T, F>(&mut self, init: F)
+    where
+        T: Send + Sync + 'static,
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        let lazy = LazyState {
+            value: OnceLock::new(),
+            init: Box::new(init),
+        };
+        Arc::make_mut(&mut self.state).insert(TypeId::of::<T>(), Arc::new(lazy));
+    }
+
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        let state = self.state.get(&TypeId::of::<T>())?;
+        if let Some(lazy) = state.downcast_ref::<LazyState<T>>() {
+            return Some(lazy.value.get_or_init(|| (lazy.init)()));
+        }
+        state.downcast_ref()
+    }
+}
+
+struct LazyState<T> {
+    value: OnceLock<T>,
+    init: Box<dyn Fn() -> T + Send + Sync>,
 }
 
 impl Default for Ctx {
@@ -0,0 +1,283 @@
+use crate::{
+    context::HttpClient,
+    schema_drift::{compare, json_body, json_schema, operation, resolve},
+    App, Body, CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use http::Method;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// One request of a contract suite and what its response must look like.
+///
+/// ```json
+/// {"name": "fetch user", "path": "/users/1", "expect": {"status": 200}}
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct Fixture {
+    pub name: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Path and query, e.g. `/users/1?fields=name`.
+    pub path: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Sent as JSON when present.
+    #[serde(default)]
+    pub body: Option<Value>,
+    #[serde(default)]
+    pub expect: Expectation,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Expectation {
+    /// When absent, any documented `2xx` status passes.
+    pub status: Option<u16>,
+    /// Response headers that must be present with exactly these values.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Where a contract suite sends its requests.
+#[async_trait]
+pub trait ContractTarget: Send + Sync {
+    async fn send(&self, req: CoreRequest) -> Result<CoreResponse, Error>;
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> ContractTarget for App<C> {
+    async fn send(&self, req: CoreRequest) -> Result<CoreResponse, Error> {
+        Ok(self.handle(req).await)
+    }
+}
+
+/// A running deployment, reached through an [`HttpClient`].
+pub struct LiveTarget {
+    http: Arc<dyn HttpClient>,
+    base_url: String,
+}
+
+impl LiveTarget {
+    pub fn new(http: Arc<dyn HttpClient>, base_url: impl Into<String>) -> Self {
+        Self {
+            http,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl ContractTarget for LiveTarget {
+    async fn send(&self, mut req: CoreRequest) -> Result<CoreResponse, Error> {
+        let path = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+        *req.uri_mut() = format!("{}{}", self.base_url, path)
+            .parse()
+            .map_err(|_| Error::internal(format!("Invalid base URL: {}", self.base_url)))?;
+        self.http.send(req).await
+    }
+}
+
+/// The outcome of one fixture; it passed if there are no failures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixtureResult {
+    pub name: String,
+    pub failures: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ContractReport {
+    pub results: Vec<FixtureResult>,
+}
+
+impl ContractReport {
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|r| r.failures.is_empty())
+    }
+}
+
+impl fmt::Display for ContractReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut failed = 0;
+        for result in &self.results {
+            if result.failures.is_empty() {
+                writeln!(f, "ok   {}", result.name)?;
+                continue;
+            }
+            failed += 1;
+            writeln!(f, "FAIL {}", result.name)?;
+            for failure in &result.failures {
+                writeln!(f, "     {}", failure)?;
+            }
+        }
+        write!(
+            f,
+            "{} passed, {} failed",
+            self.results.len() - failed,
+            failed
+        )
+    }
+}
+
+/// Conformance checks of a deployment or an in-process [`App`] against an
+/// OpenAPI 3 document, driven by example fixtures.
+///
+/// Every fixture must hit a documented operation, get the expected (or a
+/// documented `2xx`) status and headers, and return a JSON body matching the
+/// documented response schema. Fixture request bodies are checked against
+/// the request schema too, so stale fixtures show up.
+pub struct ContractSuite {
+    spec: Value,
+    fixtures: Vec<Fixture>,
+}
+
+impl ContractSuite {
+    pub fn new(spec: Value, fixtures: Vec<Fixture>) -> Self {
+        Self { spec, fixtures }
+    }
+
+    pub fn from_json(spec: &str, fixtures: &str) -> Result<Self, Error> {
+        let spec = serde_json::from_str(spec)
+            .map_err(|e| Error::internal(format!("Invalid OpenAPI document: {}", e)))?;
+        let fixtures = serde_json::from_str(fixtures)
+            .map_err(|e| Error::internal(format!("Invalid fixtures: {}", e)))?;
+        Ok(Self::new(spec, fixtures))
+    }
+
+    pub async fn run(&self, target: &dyn ContractTarget) -> ContractReport {
+        let mut report = ContractReport::default();
+        for fixture in &self.fixtures {
+            let failures = match self.check(target, fixture).await {
+                Ok(failures) => failures,
+                Err(e) => vec![e.to_string()],
+            };
+            report.results.push(FixtureResult {
+                name: fixture.name.clone(),
+                failures,
+            });
+        }
+        report
+    }
+
+    /// The documented path template matching `path`, preferring literal
+    /// segments over parameters.
+    fn template(&self, method: &str, path: &str) -> Option<&str> {
+        let segments: Vec<&str> = path.split('/').collect();
+        self.spec
+            .get("paths")?
+            .as_object()?
+            .keys()
+            .filter(|template| operation(&self.spec, method, template).is_some())
+            .filter_map(|template| {
+                let parts: Vec<&str> = template.split('/').collect();
+                if parts.len() != segments.len() {
+                    return None;
+                }
+                let mut params = 0;
+                for (part, segment) in parts.iter().zip(&segments) {
+                    if part.starts_with('{') && part.ends_with('}') {
+                        params += 1;
+                    } else if part != segment {
+                        return None;
+                    }
+                }
+                Some((params, template.as_str()))
+            })
+            .min()
+            .map(|(_, template)| template)
+    }
+
+    async fn check(
+        &self,
+        target: &dyn ContractTarget,
+        fixture: &Fixture,
+    ) -> Result<Vec<String>, Error> {
+        let method = Method::from_bytes(fixture.method.to_ascii_uppercase().as_bytes())
+            .map_err(|_| Error::bad_request(format!("Invalid method {}", fixture.method)))?;
+        let path = fixture.path.split('?').next().unwrap_or("/");
+        let Some(template) = self.template(method.as_str(), path) else {
+            return Ok(vec![format!("{} {} is not documented", method, path)]);
+        };
+        let operation = operation(&self.spec, method.as_str(), template).unwrap_or(&Value::Null);
+        let mut failures = Vec::new();
+
+        let mut req = http::Request::builder()
+            .method(method.clone())
+            .uri(fixture.path.as_str());
+        for (name, value) in &fixture.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let body = match &fixture.body {
+            Some(body) => {
+                let request_body = operation.get("requestBody").map(|b| resolve(&self.spec, b));
+                if let Some(schema) = json_schema(request_body) {
+                    let mut issues = Vec::new();
+                    compare(&self.spec, schema, body, String::new(), &mut issues);
+                    failures.extend(issues.into_iter().map(|(pointer, issue)| {
+                        format!("fixture request body at '{}': {}", pointer, issue)
+                    }));
+                }
+                req = req.header(CONTENT_TYPE, "application/json");
+                Body::from(serde_json::to_vec(body)?)
+            }
+            None => Body::empty(),
+        };
+
+        let mut res = target.send(req.body(body)?).await?;
+        res.body_mut().buffer().await?;
+        let status = res.status();
+        match fixture.expect.status {
+            Some(expected) if expected != status.as_u16() => {
+                failures.push(format!("expected status {}, got {}", expected, status));
+            }
+            None if !status.is_success() => {
+                failures.push(format!("expected a 2xx status, got {}", status));
+            }
+            _ => {}
+        }
+        for (name, expected) in &fixture.expect.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Error::bad_request(format!("Invalid header name {}", name)))?;
+            let actual = res.headers().get(&name).map(HeaderValue::as_bytes);
+            if actual != Some(expected.as_bytes()) {
+                failures.push(format!(
+                    "expected header {}: {}, got {:?}",
+                    name,
+                    expected,
+                    actual.map(String::from_utf8_lossy)
+                ));
+            }
+        }
+
+        let responses = operation.get("responses");
+        let Some(documented) = responses
+            .and_then(|r| r.get(status.as_str()).or_else(|| r.get("default")))
+            .map(|r| resolve(&self.spec, r))
+        else {
+            failures.push(format!("status {} is not documented", status.as_u16()));
+            return Ok(failures);
+        };
+        if let Some(schema) = json_schema(Some(documented)) {
+            match json_body(res.headers(), res.body()) {
+                Some(body) => {
+                    let mut issues = Vec::new();
+                    compare(&self.spec, schema, &body, String::new(), &mut issues);
+                    failures.extend(
+                        issues
+                            .into_iter()
+                            .map(|(pointer, issue)| format!("body at '{}': {}", pointer, issue)),
+                    );
+                }
+                None => failures.push("expected a JSON body".to_string()),
+            }
+        }
+        Ok(failures)
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    extract::FromRequest,
+    formatter::{JsonFormatter, ResponseFormatter},
+    response::IntoResponse,
+    CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use http::header::{HeaderValue, COOKIE, SET_COOKIE};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Cookies sent by the client in the `Cookie` header(s).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cookies {
+    values: HashMap<String, String>,
+}
+
+impl Cookies {
+    pub fn extract(req: &CoreRequest) -> Self {
+        let mut values = HashMap::new();
+        let pairs = req
+            .headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'));
+        for pair in pairs {
+            let Some((name, value)) = pair.trim().split_once('=') else {
+                continue;
+            };
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            // Browsers send the most specific path first; keep that one.
+            values
+                .entry(name.trim().to_string())
+                .or_insert_with(|| value.to_string());
+        }
+        Self { values }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for Cookies {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Ok(Self::extract(req))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept this together with `Secure`, which is therefore
+    /// always added.
+    None,
+}
+
+/// A `Set-Cookie` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that makes the client delete `name`. Path and domain must
+    /// match the ones the cookie was set with.
+    pub fn removal(name: impl Into<String>) -> Self {
+        Self::new(name, "").max_age(Duration::ZERO)
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn to_header_value(&self) -> Result<HeaderValue, Error> {
+        if self.name.is_empty() || !self.name.bytes().all(is_token_byte) {
+            return Err(Error::internal(format!(
+                "Invalid cookie name: {:?}",
+                self.name
+            )));
+        }
+        if !self.value.bytes().all(is_cookie_octet) {
+            return Err(Error::internal(format!(
+                "Invalid value for cookie {}",
+                self.name
+            )));
+        }
+
+        let mut header = format!("{}={}", self.name, self.value);
+        for (attr, value) in [("Path", &self.path), ("Domain", &self.domain)] {
+            if let Some(value) = value {
+                if value.bytes().any(|b| b == b';' || b.is_ascii_control()) {
+                    return Err(Error::internal(format!(
+                        "Invalid {} for cookie {}",
+                        attr, self.name
+                    )));
+                }
+                header.push_str(&format!("; {}={}", attr, value));
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+        }
+        if self.secure || self.same_site == Some(SameSite::None) {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => header.push_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => header.push_str("; SameSite=Lax"),
+            Some(SameSite::None) => header.push_str("; SameSite=None"),
+            None => {}
+        }
+
+        HeaderValue::from_str(&header)
+            .map_err(|_| Error::internal(format!("Invalid cookie {}", self.name)))
+    }
+}
+
+/// Cookies to set on a response. Return `(jar, body)` from a handler, or call
+/// [`CookieJar::apply`] on a built response.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CookieJar {
+    cookies: Vec<SetCookie>,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(mut self, cookie: SetCookie) -> Self {
+        self.cookies.push(cookie);
+        self
+    }
+
+    pub fn remove(self, name: impl Into<String>) -> Self {
+        self.set(SetCookie::removal(name))
+    }
+
+    pub fn apply(&self, res: &mut CoreResponse) -> Result<(), Error> {
+        for cookie in &self.cookies {
+            res.headers_mut()
+                .append(SET_COOKIE, cookie.to_header_value()?);
+        }
+        Ok(())
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (CookieJar, T) {
+    fn into_response(self) -> CoreResponse {
+        let mut res = self.1.into_response();
+        match self.0.apply(&mut res) {
+            Ok(()) => res,
+            Err(error) => JsonFormatter.format_error(&error),
+        }
+    }
+}
+
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E)
+}
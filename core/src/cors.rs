@@ -0,0 +1,325 @@
+use crate::{
+    logging::{self, Level},
+    middleware::{Middleware, Next},
+    Body, CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use http::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use http::{Method, StatusCode};
+use regex::Regex;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+enum AllowedOrigin {
+    Exact(String),
+    /// A single `*`, as in `https://*.example.com`.
+    Wildcard(String, String),
+    Regex(Regex),
+}
+
+impl AllowedOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            AllowedOrigin::Wildcard(prefix, suffix) => {
+                origin.len() > prefix.len() + suffix.len()
+                    && origin.starts_with(prefix.as_str())
+                    && origin.ends_with(suffix.as_str())
+            }
+            AllowedOrigin::Regex(regex) => regex.is_match(origin),
+        }
+    }
+}
+
+/// Cross-origin resource sharing for browser clients.
+///
+/// Register it with [`App::middleware`](crate::App::middleware) so it sees
+/// preflight `OPTIONS` requests before routing and answers them itself.
+/// Requests from origins that are not allowed pass through without CORS
+/// headers, which makes the browser block them; rejected preflights get a
+/// `403`. Errors from further down the chain are rendered with the app's
+/// formatter first, so the browser lets scripts read them too, and every
+/// response carries `Vary: Origin` so caches keep origins apart.
+#[derive(Debug, Clone)]
+pub struct CorsMiddleware {
+    any_origin: bool,
+    origins: Vec<AllowedOrigin>,
+    methods: Vec<Method>,
+    /// `None` mirrors whatever the preflight asks for.
+    headers: Option<Vec<HeaderName>>,
+    expose_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl CorsMiddleware {
+    /// Allows no origins until some are added. `GET`, `HEAD` and `POST` are
+    /// allowed, and preflights may ask for any request header.
+    pub fn new() -> Self {
+        Self {
+            any_origin: false,
+            origins: Vec::new(),
+            methods: vec![Method::GET, Method::HEAD, Method::POST],
+            headers: None,
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allows every origin. With credentials the request's origin is echoed
+    /// back, since browsers reject `*` for credentialed requests.
+    pub fn allow_any_origin(mut self) -> Self {
+        self.any_origin = true;
+        self
+    }
+
+    /// Allows an exact origin such as `https://app.example.com`, or a
+    /// pattern with one `*` such as `https://*.example.com`. `"*"` alone
+    /// allows any origin.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        let origin = origin.trim_end_matches('/');
+        if origin == "*" {
+            return self.allow_any_origin();
+        }
+        let allowed = match origin.split_once('*') {
+            Some((prefix, suffix)) => AllowedOrigin::Wildcard(prefix.into(), suffix.into()),
+            None => AllowedOrigin::Exact(origin.into()),
+        };
+        self.origins.push(allowed);
+        self
+    }
+
+    /// Allows origins matching `pattern`, which must match the whole
+    /// `Origin` value; it is anchored as `^(?:pattern)$`.
+    pub fn allow_origin_regex(mut self, pattern: &str) -> Result<Self, Error> {
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| Error::internal(format!("Invalid CORS origin pattern: {}", e)))?;
+        self.origins.push(AllowedOrigin::Regex(regex));
+        Ok(self)
+    }
+
+    pub fn allow_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: impl IntoIterator<Item = HeaderName>) -> Self {
+        self.headers = Some(headers.into_iter().collect());
+        self
+    }
+
+    pub fn expose_headers(mut self, headers: impl IntoIterator<Item = HeaderName>) -> Self {
+        self.expose_headers = headers.into_iter().collect();
+        self
+    }
+
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long browsers may cache a preflight result.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn is_allowed(&self, origin: &str) -> bool {
+        self.any_origin || self.origins.iter().any(|allowed| allowed.matches(origin))
+    }
+
+    fn set_origin(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        if self.any_origin && !self.credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        }
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    fn preflight(&self, req: &CoreRequest, origin: &HeaderValue) -> Result<CoreResponse, Error> {
+        let method = req
+            .headers()
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| Method::from_bytes(v.as_bytes()).ok());
+        let requested_headers: Vec<&str> = req
+            .headers()
+            .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+
+        let method_allowed = method.is_some_and(|m| self.methods.contains(&m));
+        let headers_allowed = self.headers.as_ref().map_or(true, |allowed| {
+            requested_headers.iter().all(|name| {
+                allowed
+                    .iter()
+                    .any(|a| a.as_str().eq_ignore_ascii_case(name))
+            })
+        });
+        if !method_allowed || !headers_allowed {
+            logging::global().log(
+                Level::Warn,
+                "cors preflight rejected",
+                format!(
+                    "Rejected CORS preflight from {:?} for {:?}",
+                    origin,
+                    req.headers().get(ACCESS_CONTROL_REQUEST_METHOD)
+                ),
+            );
+            return Err(Error::forbidden());
+        }
+
+        let mut res = http::Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?;
+        let headers = res.headers_mut();
+        self.set_origin(headers, origin);
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, join(&self.methods)?);
+        let allow_headers = match &self.headers {
+            Some(allowed) => join(allowed)?,
+            None => join(&requested_headers)?,
+        };
+        if !allow_headers.is_empty() {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+        headers.append(
+            VARY,
+            HeaderValue::from_static(
+                "access-control-request-method, access-control-request-headers",
+            ),
+        );
+        Ok(res)
+    }
+}
+
+impl Default for CorsMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn join<T: AsRef<str>>(items: &[T]) -> Result<HeaderValue, Error> {
+    let joined = items
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join(", ");
+    HeaderValue::from_str(&joined).map_err(|_| Error::bad_request("Invalid CORS header list"))
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for CorsMiddleware {
+    async fn handle(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let Some(origin) = req.headers().get(ORIGIN).cloned() else {
+            let mut res = next.run_formatted(ctx, req).await;
+            res.headers_mut()
+                .append(VARY, HeaderValue::from_static("origin"));
+            return Ok(res);
+        };
+        let allowed = origin.to_str().is_ok_and(|o| self.is_allowed(o));
+
+        let is_preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+        let mut res = if is_preflight {
+            if !allowed {
+                logging::global().log(
+                    Level::Warn,
+                    "cors preflight rejected",
+                    format!("Rejected CORS preflight from origin {:?}", origin),
+                );
+                return Err(Error::forbidden());
+            }
+            self.preflight(&req, &origin)?
+        } else {
+            next.run_formatted(ctx, req).await
+        };
+
+        let headers = res.headers_mut();
+        headers.append(VARY, HeaderValue::from_static("origin"));
+        if allowed && !is_preflight {
+            self.set_origin(headers, &origin);
+            if !self.expose_headers.is_empty() {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, join(&self.expose_headers)?);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{App, Ctx};
+
+    struct Deny;
+
+    #[async_trait]
+    impl Middleware<Ctx> for Deny {
+        async fn before(&self, _ctx: &Ctx, _req: &mut CoreRequest) -> Result<(), Error> {
+            Err(Error::unauthorized())
+        }
+    }
+
+    fn request(origin: &str) -> CoreRequest {
+        http::Request::get("/items")
+            .header(ORIGIN, origin)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_errors_get_cors_headers() {
+        let app = App::new(Ctx::new())
+            .middleware(CorsMiddleware::new().allow_origin("https://app.example.com"))
+            .middleware(Deny)
+            .get("/items", || async { "items" });
+
+        let res = app.handle(request("https://app.example.com")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(res.headers()[VARY], "origin");
+
+        let res = app.handle(request("https://evil.example")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert_eq!(res.headers()[VARY], "origin");
+    }
+
+    #[test]
+    fn test_origin_regex_is_anchored() {
+        let cors = CorsMiddleware::new()
+            .allow_origin_regex(r"https://[a-z]+\.example\.com|http://localhost:\d+")
+            .unwrap();
+        assert!(cors.is_allowed("https://app.example.com"));
+        assert!(cors.is_allowed("http://localhost:3000"));
+        assert!(!cors.is_allowed("https://app.example.com.evil.example"));
+        assert!(!cors.is_allowed("https://evil.example/?https://app.example.com"));
+        assert!(!cors.is_allowed("http://localhost:3000.evil.example"));
+    }
+}
@@ -0,0 +1,220 @@
+use crate::{
+    context::Kv,
+    logging::{self, Level},
+    Error,
+};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const FORMAT_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+// 32-byte data key plus the 16-byte GCM tag.
+const WRAPPED_KEY_LEN: usize = 48;
+
+/// Source of key-encryption keys, addressed by ID so old values stay readable
+/// after rotation.
+pub trait KeyProvider: Send + Sync {
+    /// Key ID used for new encryptions.
+    fn current_key_id(&self) -> &str;
+
+    fn key(&self, key_id: &str) -> Option<[u8; 32]>;
+}
+
+/// A fixed in-memory key set, typically loaded from environment secrets.
+#[derive(Clone, Default)]
+pub struct StaticKeys {
+    current: String,
+    keys: HashMap<String, [u8; 32]>,
+}
+
+impl StaticKeys {
+    pub fn new(current_id: impl Into<String>, key: [u8; 32]) -> Self {
+        let current = current_id.into();
+        let mut keys = HashMap::new();
+        keys.insert(current.clone(), key);
+        Self { current, keys }
+    }
+
+    /// Adds a retired key that can still decrypt existing values.
+    pub fn with_key(mut self, id: impl Into<String>, key: [u8; 32]) -> Self {
+        self.keys.insert(id.into(), key);
+        self
+    }
+}
+
+impl KeyProvider for StaticKeys {
+    fn current_key_id(&self) -> &str {
+        &self.current
+    }
+
+    fn key(&self, key_id: &str) -> Option<[u8; 32]> {
+        self.keys.get(key_id).copied()
+    }
+}
+
+/// Envelope-encrypts `plaintext`: a fresh data key encrypts the payload and is
+/// itself wrapped with the provider's current key. `aad` (e.g. the storage
+/// key) is authenticated but not stored.
+pub fn seal(keys: &dyn KeyProvider, aad: &[u8], plaintext: &[u8]) -> Result<Bytes, Error> {
+    let key_id = keys.current_key_id();
+    if key_id.len() > u8::MAX as usize {
+        return Err(Error::internal("Key ID too long"));
+    }
+    let kek = keys
+        .key(key_id)
+        .ok_or_else(|| Error::internal("Current encryption key is missing"))?;
+
+    let data_key = Aes256Gcm::generate_key(OsRng);
+    let wrap_nonce = Aes256Gcm::generate_nonce(OsRng);
+    let wrapped_key = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&kek))
+        .encrypt(&wrap_nonce, data_key.as_slice())
+        .map_err(|_| Error::internal("Failed to wrap data key"))?;
+
+    let nonce = Aes256Gcm::generate_nonce(OsRng);
+    let ciphertext = Aes256Gcm::new(&data_key)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| Error::internal("Failed to encrypt value"))?;
+
+    let mut out = BytesMut::with_capacity(
+        2 + key_id.len() + 2 * NONCE_LEN + WRAPPED_KEY_LEN + ciphertext.len(),
+    );
+    out.put_u8(FORMAT_VERSION);
+    out.put_u8(key_id.len() as u8);
+    out.put_slice(key_id.as_bytes());
+    out.put_slice(&wrap_nonce);
+    out.put_slice(&wrapped_key);
+    out.put_slice(&nonce);
+    out.put_slice(&ciphertext);
+    Ok(out.freeze())
+}
+
+/// Reverses [`seal`].
+pub fn open(keys: &dyn KeyProvider, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::internal("Malformed encrypted value");
+
+    let (&version, rest) = sealed.split_first().ok_or_else(invalid)?;
+    if version != FORMAT_VERSION {
+        return Err(Error::internal("Unsupported encrypted value version"));
+    }
+    let (&id_len, rest) = rest.split_first().ok_or_else(invalid)?;
+    let id_len = id_len as usize;
+    if rest.len() < id_len + 2 * NONCE_LEN + WRAPPED_KEY_LEN {
+        return Err(invalid());
+    }
+    let (key_id, rest) = rest.split_at(id_len);
+    let (wrap_nonce, rest) = rest.split_at(NONCE_LEN);
+    let (wrapped_key, rest) = rest.split_at(WRAPPED_KEY_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let key_id = std::str::from_utf8(key_id).map_err(|_| invalid())?;
+    let kek = keys
+        .key(key_id)
+        .ok_or_else(|| Error::internal(format!("Unknown encryption key: {}", key_id)))?;
+
+    let data_key = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&kek))
+        .decrypt(Nonce::from_slice(wrap_nonce), wrapped_key)
+        .map_err(|_| Error::internal("Failed to unwrap data key"))?;
+
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key))
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| Error::internal("Failed to decrypt value"))
+}
+
+/// A [`Kv`] wrapper that encrypts every value at the application layer.
+///
+/// Values are bound to their key, so a ciphertext copied to another key will
+/// not decrypt. Undecryptable values read as missing.
+pub struct EncryptedKv {
+    inner: Arc<dyn Kv>,
+    keys: Arc<dyn KeyProvider>,
+}
+
+impl EncryptedKv {
+    pub fn new(inner: Arc<dyn Kv>, keys: Arc<dyn KeyProvider>) -> Self {
+        Self { inner, keys }
+    }
+
+    pub async fn put_encrypted<T: Serialize + ?Sized>(
+        &self,
+        key: &str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let json = serde_json::to_vec(value)?;
+        let sealed = seal(self.keys.as_ref(), key.as_bytes(), &json)?;
+        self.inner
+            .put(key, sealed)
+            .await
+            .map_err(|e| Error::internal(format!("Failed to store encrypted value: {}", e)))
+    }
+
+    pub async fn get_encrypted<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Error> {
+        let Some(sealed) = self.inner.get(key).await else {
+            return Ok(None);
+        };
+        let json = open(self.keys.as_ref(), key.as_bytes(), &sealed)?;
+        Ok(Some(serde_json::from_slice(&json)?))
+    }
+}
+
+#[async_trait]
+impl Kv for EncryptedKv {
+    async fn get(&self, key: &str) -> Option<Bytes> {
+        let sealed = self.inner.get(key).await?;
+        match open(self.keys.as_ref(), key.as_bytes(), &sealed) {
+            Ok(plaintext) => Some(Bytes::from(plaintext)),
+            Err(e) => {
+                // Keys can carry user identifiers, so they stay out of logs.
+                logging::global().log(
+                    Level::Error,
+                    "kv decrypt failed",
+                    format!("Failed to decrypt a Kv value: {}", e),
+                );
+                None
+            }
+        }
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        value: Bytes,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let sealed = seal(self.keys.as_ref(), key.as_bytes(), &value)?;
+        self.inner.put(key, sealed).await
+    }
+
+    async fn put_with_ttl(
+        &self,
+        key: &str,
+        value: Bytes,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let sealed = seal(self.keys.as_ref(), key.as_bytes(), &value)?;
+        self.inner.put_with_ttl(key, sealed, ttl).await
+    }
+
+    async fn list(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.list(prefix).await
+    }
+}
@@ -0,0 +1,115 @@
+use crate::middleware::MiddlewareStatus;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A machine-readable model of how an app is wired, returned by
+/// [`App::describe`](crate::App::describe).
+///
+/// Routes are sorted by path and method, so the JSON form can be committed
+/// and diffed across versions to review routing changes in CI. The
+/// [`Display`](fmt::Display) form is a table for startup logs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppDescription {
+    pub routes: Vec<RouteDescription>,
+    /// Global middleware in the order it runs.
+    pub middleware: Vec<MiddlewareStatus>,
+    /// Apps mounted with [`App::nest_with_context`](crate::App::nest_with_context).
+    pub scopes: Vec<ScopeDescription>,
+    pub plugins: Vec<String>,
+    pub config: Vec<ConfigRequirement>,
+    pub fallback: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteDescription {
+    /// `*` for routes registered for every method.
+    pub method: String,
+    pub path: String,
+    /// Route-scoped middleware, outermost first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub middleware: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScopeDescription {
+    pub prefix: String,
+    pub app: AppDescription,
+}
+
+/// A setting the app needs from its environment, declared with
+/// [`App::requires_config`](crate::App::requires_config).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigRequirement {
+    pub name: String,
+    pub description: String,
+}
+
+impl AppDescription {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    fn write_table(&self, f: &mut fmt::Formatter<'_>, prefix: &str) -> fmt::Result {
+        let width = self
+            .routes
+            .iter()
+            .map(|route| prefix.len() + route.path.len())
+            .max()
+            .unwrap_or(0);
+        for route in &self.routes {
+            let path = format!("{}{}", prefix, route.path);
+            write!(f, "{:<7} {:<width$}", route.method, path, width = width)?;
+            if !route.middleware.is_empty() {
+                write!(f, "  [{}]", route.middleware.join(", "))?;
+            }
+            writeln!(f)?;
+        }
+        if self.fallback {
+            writeln!(f, "{:<7} {}/ (fallback)", "*", prefix)?;
+        }
+        if !self.middleware.is_empty() {
+            let names: Vec<String> = self
+                .middleware
+                .iter()
+                .map(|m| {
+                    if m.enabled {
+                        m.name.clone()
+                    } else {
+                        format!("{} (disabled)", m.name)
+                    }
+                })
+                .collect();
+            writeln!(f, "middleware {}: {}", or_root(prefix), names.join(", "))?;
+        }
+        if !self.plugins.is_empty() {
+            writeln!(
+                f,
+                "plugins {}: {}",
+                or_root(prefix),
+                self.plugins.join(", ")
+            )?;
+        }
+        for config in &self.config {
+            writeln!(f, "config {}: {}", config.name, config.description)?;
+        }
+        for scope in &self.scopes {
+            let prefix = format!("{}{}", prefix, scope.prefix.trim_end_matches('/'));
+            scope.app.write_table(f, &prefix)?;
+        }
+        Ok(())
+    }
+}
+
+fn or_root(prefix: &str) -> &str {
+    if prefix.is_empty() {
+        "/"
+    } else {
+        prefix
+    }
+}
+
+impl fmt::Display for AppDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_table(f, "")
+    }
+}
@@ -0,0 +1,282 @@
+use crate::{
+    clock::{Clock, SystemClock},
+    logging::{self, Level},
+    middleware::{Middleware, Next},
+    Body, CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_core::Stream;
+use http::header::HeaderName;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+type KeyFn = Arc<dyn Fn(&CoreRequest) -> Option<String> + Send + Sync>;
+
+/// The authenticated caller a request is made for, such as a tenant or
+/// verified API key. Authentication middleware inserts it into the request
+/// extensions; [`EgressLimits`] counts usage against it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity(pub String);
+
+/// Bytes sent per key in the current period, for at most `max_keys` keys.
+struct Ledger {
+    period: Duration,
+    clock: Arc<dyn Clock>,
+    max_keys: usize,
+    usage: Mutex<HashMap<String, (SystemTime, u64)>>,
+}
+
+impl Ledger {
+    fn used(&self, key: &str) -> u64 {
+        let now = self.clock.now();
+        match self.usage.lock().unwrap().get(key) {
+            Some((start, bytes)) if !self.expired(*start, now) => *bytes,
+            _ => 0,
+        }
+    }
+
+    fn record(&self, key: &str, bytes: u64) {
+        let now = self.clock.now();
+        let mut usage = self.usage.lock().unwrap();
+        if !usage.contains_key(key) && usage.len() >= self.max_keys {
+            usage.retain(|_, (start, _)| !self.expired(*start, now));
+            if usage.len() >= self.max_keys {
+                // The oldest window is the closest to being reset anyway.
+                let oldest = usage
+                    .iter()
+                    .min_by_key(|(_, (start, _))| *start)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    logging::global().log(
+                        Level::Warn,
+                        "egress ledger full",
+                        format!(
+                            "Egress ledger holds {} keys; dropping usage of {}",
+                            self.max_keys, oldest
+                        ),
+                    );
+                    usage.remove(&oldest);
+                }
+            }
+        }
+        let entry = usage.entry(key.to_string()).or_insert((now, 0));
+        if self.expired(entry.0, now) {
+            *entry = (now, 0);
+        }
+        entry.1 += bytes;
+    }
+
+    fn expired(&self, start: SystemTime, now: SystemTime) -> bool {
+        now.duration_since(start).unwrap_or_default() >= self.period
+    }
+}
+
+/// Middleware counting response body bytes per tenant or API key and
+/// refusing requests once a key has used up its egress ceiling for the
+/// current period.
+///
+/// Keys are the request's [`Identity`], so only authenticated callers are
+/// counted and nobody gets a fresh ceiling by sending a new key; requests
+/// without one pass uncounted. Expired periods are pruned, and the ledger
+/// keeps at most [`max_keys`](EgressLimits::max_keys) keys. Streamed bodies are counted as
+/// their chunks are sent, so a response already underway finishes even if
+/// it crosses the ceiling; the next request is refused with `429 Too Many
+/// Requests`, or `402 Payment Required` for paid plans.
+///
+/// Add it before [`Compression`](crate::compression::Compression) so the
+/// compressed size is what counts. Clones share the same usage.
+///
+/// ```ignore
+/// app.middleware(
+///     EgressLimits::new(Duration::from_secs(86_400))
+///         .limit(1 << 30)
+///         .tenant_limit("acme", 10 << 30),
+/// )
+/// .middleware(Compression::new())
+/// ```
+#[derive(Clone)]
+pub struct EgressLimits {
+    ledger: Arc<Ledger>,
+    key: KeyFn,
+    default_limit: Option<u64>,
+    limits: HashMap<String, u64>,
+    payment_required: bool,
+}
+
+impl EgressLimits {
+    /// Usage is reset for each key `period` after its first counted response.
+    pub fn new(period: Duration) -> Self {
+        Self {
+            ledger: Arc::new(Ledger {
+                period,
+                clock: Arc::new(SystemClock),
+                max_keys: 100_000,
+                usage: Mutex::default(),
+            }),
+            key: Arc::new(|req| {
+                req.extensions()
+                    .get::<Identity>()
+                    .map(|identity| identity.0.clone())
+            }),
+            default_limit: None,
+            limits: HashMap::new(),
+            payment_required: false,
+        }
+    }
+
+    /// Takes the key from a request header, e.g. `x-api-key`, counting only
+    /// values `is_known` accepts, such as keys found in the key store.
+    pub fn key_header(
+        mut self,
+        header: HeaderName,
+        is_known: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.key = Arc::new(move |req| header_key(req, &header).filter(|key| is_known(key)));
+        self
+    }
+
+    /// Derives the key from the request. It must come from something the
+    /// caller cannot choose freely, such as a verified token.
+    pub fn key_fn(
+        mut self,
+        key: impl Fn(&CoreRequest) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.key = Arc::new(key);
+        self
+    }
+
+    /// The ceiling for keys without their own; unlimited by default.
+    pub fn limit(mut self, bytes: u64) -> Self {
+        self.default_limit = Some(bytes);
+        self
+    }
+
+    pub fn tenant_limit(mut self, key: impl Into<String>, bytes: u64) -> Self {
+        self.limits.insert(key.into(), bytes);
+        self
+    }
+
+    /// Refuses keys over their ceiling with `402 Payment Required`.
+    pub fn payment_required(mut self) -> Self {
+        self.payment_required = true;
+        self
+    }
+
+    /// Replaces the clock; call it before the limits are shared.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.ledger = Arc::new(Ledger {
+            period: self.ledger.period,
+            clock,
+            max_keys: self.ledger.max_keys,
+            usage: Mutex::default(),
+        });
+        self
+    }
+
+    /// The most keys tracked at once, 100 000 by default. Past it, expired
+    /// periods are pruned and then the oldest key's usage is dropped; call
+    /// it before the limits are shared.
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.ledger = Arc::new(Ledger {
+            period: self.ledger.period,
+            clock: Arc::clone(&self.ledger.clock),
+            max_keys: max_keys.max(1),
+            usage: Mutex::default(),
+        });
+        self
+    }
+
+    /// Bytes `key` has been sent in its current period.
+    pub fn usage(&self, key: &str) -> u64 {
+        self.ledger.used(key)
+    }
+
+    pub fn reset(&self, key: &str) {
+        self.ledger.usage.lock().unwrap().remove(key);
+    }
+
+    fn limit_for(&self, key: &str) -> Option<u64> {
+        self.limits.get(key).copied().or(self.default_limit)
+    }
+}
+
+fn header_key(req: &CoreRequest, header: &HeaderName) -> Option<String> {
+    req.headers()
+        .get(header)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+struct Counted {
+    body: Body,
+    ledger: Arc<Ledger>,
+    key: String,
+}
+
+impl Stream for Counted {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.ledger.record(&self.key, chunk.len() as u64);
+        }
+        poll
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for EgressLimits {
+    async fn handle(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let Some(key) = (self.key)(&req) else {
+            return next.run(ctx, req).await;
+        };
+        if let Some(limit) = self.limit_for(&key) {
+            let used = self.ledger.used(&key);
+            if used >= limit {
+                logging::global().log(
+                    Level::Warn,
+                    &format!("egress limit {}", key),
+                    format!(
+                        "Refusing {} {} for {}: {} of {} egress bytes used",
+                        req.method(),
+                        req.uri().path(),
+                        key,
+                        used,
+                        limit
+                    ),
+                );
+                return Err(if self.payment_required {
+                    Error::payment_required()
+                } else {
+                    Error::too_many_requests()
+                });
+            }
+        }
+
+        let response = next.run(ctx, req).await?;
+        let (parts, body) = response.into_parts();
+        let body = match body {
+            Body::Full(bytes) => {
+                self.ledger.record(&key, bytes.len() as u64);
+                Body::Full(bytes)
+            }
+            body => Body::from_stream(Counted {
+                body,
+                ledger: Arc::clone(&self.ledger),
+                key,
+            }),
+        };
+        Ok(CoreResponse::from_parts(parts, body))
+    }
+}
@@ -1,3 +1,4 @@
+use crate::validate::ValidationErrors;
 use http::StatusCode;
 
 #[derive(thiserror::Error, Debug)]
@@ -17,20 +18,53 @@ pub enum Error {
     #[error("HTTP error")]
     Http(#[from] http::Error),
 
+    #[error("Method not allowed")]
+    MethodNotAllowed,
+
     #[error("Unauthorized")]
     Unauthorized,
 
+    #[error("Payment required")]
+    PaymentRequired,
+
     #[error("Forbidden")]
     Forbidden,
 
+    #[error("Not acceptable")]
+    NotAcceptable,
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
     #[error("Request entity too large")]
     PayloadTooLarge,
 
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+
+    #[error("Precondition failed")]
+    PreconditionFailed,
+
     #[error("Request timeout")]
     RequestTimeout,
 
+    #[error("Too many requests")]
+    TooManyRequests,
+
     #[error("Unprocessable entity: {0}")]
     UnprocessableEntity(String),
+
+    #[error("Validation failed: {0}")]
+    Validation(ValidationErrors),
+
+    #[error("Unavailable for legal reasons")]
+    UnavailableForLegalReasons,
+
+    #[error("Bad gateway: {0}")]
+    BadGateway(String),
+
+    #[error("Service unavailable")]
+    ServiceUnavailable,
 }
 
 impl Error {
@@ -41,11 +75,22 @@ impl Error {
             Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::Json(_) => StatusCode::BAD_REQUEST,
             Error::Http(_) => StatusCode::BAD_REQUEST,
+            Error::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
             Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::PaymentRequired => StatusCode::PAYMENT_REQUIRED,
             Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            Error::Conflict(_) => StatusCode::CONFLICT,
             Error::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
             Error::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            Error::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
             Error::UnprocessableEntity(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::UnavailableForLegalReasons => StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+            Error::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            Error::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
@@ -56,11 +101,22 @@ impl Error {
             Error::Internal(_) => "Internal Server Error",
             Error::Json(_) => "Invalid JSON",
             Error::Http(_) => "HTTP Error",
+            Error::MethodNotAllowed => "Method Not Allowed",
             Error::Unauthorized => "Unauthorized",
+            Error::PaymentRequired => "Payment Required",
             Error::Forbidden => "Forbidden",
+            Error::NotAcceptable => "Not Acceptable",
+            Error::Conflict(_) => "Conflict",
             Error::PayloadTooLarge => "Request Entity Too Large",
+            Error::UnsupportedMediaType(_) => "Unsupported Media Type",
+            Error::PreconditionFailed => "Precondition Failed",
             Error::RequestTimeout => "Request Timeout",
+            Error::TooManyRequests => "Too Many Requests",
             Error::UnprocessableEntity(_) => "Unprocessable Entity",
+            Error::Validation(_) => "Validation Failed",
+            Error::UnavailableForLegalReasons => "Unavailable For Legal Reasons",
+            Error::BadGateway(_) => "Bad Gateway",
+            Error::ServiceUnavailable => "Service Unavailable",
         }
     }
 
@@ -80,23 +136,63 @@ impl Error {
         Self::NotFound
     }
 
+    pub fn method_not_allowed() -> Self {
+        Self::MethodNotAllowed
+    }
+
     pub fn unauthorized() -> Self {
         Self::Unauthorized
     }
 
+    pub fn payment_required() -> Self {
+        Self::PaymentRequired
+    }
+
     pub fn forbidden() -> Self {
         Self::Forbidden
     }
 
+    pub fn not_acceptable() -> Self {
+        Self::NotAcceptable
+    }
+
+    pub fn conflict<T: Into<String>>(message: T) -> Self {
+        Self::Conflict(message.into())
+    }
+
     pub fn payload_too_large() -> Self {
         Self::PayloadTooLarge
     }
 
+    pub fn unsupported_media_type<T: Into<String>>(message: T) -> Self {
+        Self::UnsupportedMediaType(message.into())
+    }
+
+    pub fn precondition_failed() -> Self {
+        Self::PreconditionFailed
+    }
+
     pub fn request_timeout() -> Self {
         Self::RequestTimeout
     }
 
+    pub fn too_many_requests() -> Self {
+        Self::TooManyRequests
+    }
+
     pub fn unprocessable_entity<T: Into<String>>(message: T) -> Self {
         Self::UnprocessableEntity(message.into())
     }
+
+    pub fn unavailable_for_legal_reasons() -> Self {
+        Self::UnavailableForLegalReasons
+    }
+
+    pub fn bad_gateway<T: Into<String>>(message: T) -> Self {
+        Self::BadGateway(message.into())
+    }
+
+    pub fn service_unavailable() -> Self {
+        Self::ServiceUnavailable
+    }
 }
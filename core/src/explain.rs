@@ -0,0 +1,146 @@
+use crate::{CoreRequest, CoreResponse, Error};
+use http::header::{HeaderName, HeaderValue};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Request header that turns on explain mode, and the response header the
+/// trace is written to.
+pub const EXPLAIN_HEADER: HeaderName = HeaderName::from_static("x-xeno-explain");
+
+/// Explain mode for debugging middleware stacks.
+///
+/// Requests carrying `X-Xeno-Explain` that pass the gate get one
+/// `X-Xeno-Explain` response header per traced step: each middleware with
+/// whether it passed the request on, short-circuited or failed, the matched
+/// route, the handler, and notes such as cache hits, with timings. Enable it
+/// with [`App::explain`](crate::App::explain).
+///
+/// By default only debug builds honour the header; use
+/// [`Explain::authorize`] to gate it on something like an admin token.
+pub struct Explain {
+    authorize: Box<dyn Fn(&CoreRequest) -> bool + Send + Sync>,
+}
+
+impl Explain {
+    pub fn new() -> Self {
+        Self {
+            authorize: Box::new(|_| cfg!(debug_assertions)),
+        }
+    }
+
+    pub fn authorize(
+        mut self,
+        check: impl Fn(&CoreRequest) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.authorize = Box::new(check);
+        self
+    }
+
+    /// Attaches a trace to `req` if it asks for one and is allowed to.
+    pub(crate) fn start(&self, req: &mut CoreRequest) -> Option<Trace> {
+        if !req.headers().contains_key(EXPLAIN_HEADER) || !(self.authorize)(req) {
+            return None;
+        }
+        let trace = Trace::default();
+        req.extensions_mut().insert(trace.clone());
+        Some(trace)
+    }
+}
+
+impl Default for Explain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A traced step of request handling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// `middleware`, `route`, `handler` or the name passed to [`note`].
+    pub stage: String,
+    pub name: String,
+    pub outcome: String,
+    pub elapsed: Option<Duration>,
+}
+
+/// The steps recorded for one request. Present in the request extensions
+/// while explain mode is on.
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    events: Arc<Mutex<Vec<TraceEvent>>>,
+}
+
+impl Trace {
+    pub fn events(&self) -> Vec<TraceEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub(crate) fn begin(&self, stage: &str, name: &str) -> usize {
+        let mut events = self.events.lock().unwrap();
+        events.push(TraceEvent {
+            stage: stage.to_string(),
+            name: name.to_string(),
+            outcome: "running".to_string(),
+            elapsed: None,
+        });
+        events.len() - 1
+    }
+
+    pub(crate) fn set_outcome(&self, index: usize, outcome: &str) {
+        if let Some(event) = self.events.lock().unwrap().get_mut(index) {
+            event.outcome = outcome.to_string();
+        }
+    }
+
+    /// Completes a step. Middleware that never handed the request on is
+    /// recorded as having short-circuited.
+    pub(crate) fn end(
+        &self,
+        index: usize,
+        elapsed: Duration,
+        result: &Result<CoreResponse, Error>,
+    ) {
+        let mut events = self.events.lock().unwrap();
+        let Some(event) = events.get_mut(index) else {
+            return;
+        };
+        event.elapsed = Some(elapsed);
+        event.outcome = match result {
+            Err(e) => format!("error {}", e.status_code().as_u16()),
+            Ok(res) if event.outcome == "running" => {
+                format!("short-circuited {}", res.status().as_u16())
+            }
+            Ok(res) => format!("{} {}", event.outcome, res.status().as_u16()),
+        };
+    }
+
+    pub(crate) fn annotate(&self, res: &mut CoreResponse) {
+        for event in self.events() {
+            let mut line = format!("{} {}", event.stage, event.name);
+            if !event.outcome.is_empty() {
+                line.push_str(&format!(" {}", event.outcome));
+            }
+            if let Some(elapsed) = event.elapsed {
+                line.push_str(&format!(" {}us", elapsed.as_micros()));
+            }
+            if let Ok(value) = HeaderValue::from_str(&line) {
+                res.headers_mut().append(EXPLAIN_HEADER, value);
+            }
+        }
+    }
+}
+
+/// Records a note in the request's explain trace, if it has one, e.g.
+/// `note(&req, "cache", "hit")`.
+pub fn note(req: &CoreRequest, stage: &str, detail: &str) {
+    if let Some(trace) = req.extensions().get::<Trace>() {
+        let index = trace.begin(stage, detail);
+        trace.set_outcome(index, "");
+    }
+}
+
+/// `xeno_core::cors::CorsMiddleware` becomes `CorsMiddleware`.
+pub(crate) fn short_type_name(name: &str) -> &str {
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
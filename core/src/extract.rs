@@ -1,7 +1,151 @@
-use crate::{CoreRequest, Error};
+use crate::{params, Body, CoreRequest, Ctx, Error};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::uri::Scheme;
+use http::{HeaderMap, Method, Uri};
 use serde::de::DeserializeOwned;
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
 
+/// Types that can be built from an incoming request, used as arguments of
+/// function handlers.
+///
+/// Implement it for your own types (auth tokens, tenant IDs, feature-flag
+/// contexts) to use them next to the built-in extractors. A failed extraction
+/// short-circuits the handler and the error is rendered as the response.
+#[async_trait]
+pub trait FromRequest<C: Send + Sync + Clone + 'static>: Sized + Send {
+    async fn from_request(ctx: &C, req: &CoreRequest) -> Result<Self, Error>;
+}
+
+/// The most body bytes the extractors read, 2 MiB unless the app sets
+/// another limit with [`App::body_limit`](crate::App::body_limit). Larger
+/// bodies are rejected with `413`. Middleware can insert its own limit for
+/// the routes it wraps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyLimit(pub usize);
+
+impl Default for BodyLimit {
+    fn default() -> Self {
+        BodyLimit(2 * 1024 * 1024)
+    }
+}
+
+/// Reads the body of `req` within its [`BodyLimit`], for extractors of the
+/// body. Later calls return the same bytes.
+pub async fn read_body(req: &CoreRequest) -> Result<Bytes, Error> {
+    let BodyLimit(limit) = req
+        .extensions()
+        .get::<BodyLimit>()
+        .copied()
+        .unwrap_or_default();
+    req.body().read(limit).await
+}
+
+/// The request body unread, streaming if the adapter streams it, for
+/// handlers that process large uploads as they arrive. It takes the body,
+/// so list it after any other extractor that reads the body.
+///
+/// ```ignore
+/// async fn upload(BodyStream(body): BodyStream) -> Result<String, Error> {
+///     let mut size = 0;
+///     // poll `body` as a `Stream` of chunks
+///     ...
+/// }
+/// ```
+pub struct BodyStream(pub Body);
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for BodyStream {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Ok(BodyStream(req.body().take()))
+    }
+}
+
+/// The request itself, with its body unread like a [`BodyStream`]; list it
+/// after any other extractor that reads the body.
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for CoreRequest {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        let mut copy = crate::body::clone_request(req);
+        *copy.body_mut() = req.body().take();
+        Ok(copy)
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for Method {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Ok(req.method().clone())
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for Uri {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Ok(req.uri().clone())
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for HeaderMap {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Ok(req.headers().clone())
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for Bytes {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        read_body(req).await
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for String {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        String::from_utf8(read_body(req).await?.to_vec())
+            .map_err(|_| Error::bad_request("Request body is not valid UTF-8"))
+    }
+}
+
+/// Makes an extractor optional: a failed extraction, such as a missing or
+/// malformed query string, gives `None` instead of rejecting the request.
+///
+/// ```ignore
+/// async fn list(page: Option<Query<Page>>) -> Json<Vec<Item>> {
+///     let Page { offset, limit } = page.map_or_else(Page::default, |Query(p)| p);
+///     ...
+/// }
+/// ```
+#[async_trait]
+impl<C, T> FromRequest<C> for Option<T>
+where
+    C: Send + Sync + Clone + 'static,
+    T: FromRequest<C>,
+{
+    async fn from_request(ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Ok(T::from_request(ctx, req).await.ok())
+    }
+}
+
+/// Hands the extraction error to the handler instead of rendering it, e.g.
+/// to answer malformed JSON with a custom body.
+#[async_trait]
+impl<C, T> FromRequest<C> for Result<T, Error>
+where
+    C: Send + Sync + Clone + 'static,
+    T: FromRequest<C>,
+{
+    async fn from_request(ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Ok(T::from_request(ctx, req).await)
+    }
+}
+
+/// Route parameters, e.g. `Path<u32>` for `/users/:id`,
+/// `Path<(u32, String)>` for `/users/:id/posts/:slug` (in route order), or a
+/// struct with fields named after the parameters.
 pub struct Path<T>(pub T);
 
 impl<T> Path<T>
@@ -9,18 +153,76 @@ where
     T: DeserializeOwned,
 {
     pub fn extract(req: &CoreRequest) -> Result<Self, Error> {
-        let params = req
-            .extensions()
-            .get::<HashMap<String, String>>()
-            .ok_or_else(|| Error::BadRequest("No path parameters found".to_string()))?;
+        let params = match req.extensions().get::<PathParams>() {
+            Some(params) => params.0.clone(),
+            None => req
+                .extensions()
+                .get::<HashMap<String, String>>()
+                .ok_or_else(|| Error::BadRequest("No path parameters found".to_string()))?
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        };
+        Ok(Path(params::from_path_params(&params)?))
+    }
+}
+
+/// The route parameters in the order they appear in the route pattern.
+/// The router also inserts them as a `HashMap<String, String>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathParams(pub Vec<(String, String)>);
+
+/// Typed access to what the router, middleware and adapters attach to a
+/// request, for handlers that take the raw [`CoreRequest`].
+///
+/// ```ignore
+/// let id: u32 = req.param_as("id")?;
+/// let user = req.extension::<CurrentUser>()?;
+/// ```
+pub trait RequestExt {
+    /// The route parameter `name` as matched; `400` when the route has no
+    /// such parameter.
+    fn param(&self, name: &str) -> Result<&str, Error>;
 
-        let json_value = serde_json::to_value(params)
-            .map_err(|e| Error::BadRequest(format!("Failed to convert path params: {}", e)))?;
+    /// The route parameter `name` parsed into `T`; `400` when it is missing
+    /// or does not parse.
+    fn param_as<T: FromStr>(&self, name: &str) -> Result<T, Error>;
+
+    /// A value inserted into the request's extensions. Its absence means a
+    /// middleware or adapter that should provide it is not installed, so it
+    /// is an internal error.
+    fn extension<T: Send + Sync + 'static>(&self) -> Result<&T, Error>;
+}
+
+impl<B> RequestExt for http::Request<B> {
+    fn param(&self, name: &str) -> Result<&str, Error> {
+        let value = match self.extensions().get::<PathParams>() {
+            Some(PathParams(params)) => params
+                .iter()
+                .find(|(param, _)| param == name)
+                .map(|(_, value)| value.as_str()),
+            None => self
+                .extensions()
+                .get::<HashMap<String, String>>()
+                .and_then(|params| params.get(name))
+                .map(String::as_str),
+        };
+        value.ok_or_else(|| Error::bad_request(format!("Missing path parameter {}", name)))
+    }
 
-        let extracted = T::deserialize(json_value)
-            .map_err(|e| Error::BadRequest(format!("Failed to deserialize path params: {}", e)))?;
+    fn param_as<T: FromStr>(&self, name: &str) -> Result<T, Error> {
+        self.param(name)?
+            .parse()
+            .map_err(|_| Error::bad_request(format!("Invalid path parameter {}", name)))
+    }
 
-        Ok(Path(extracted))
+    fn extension<T: Send + Sync + 'static>(&self) -> Result<&T, Error> {
+        self.extensions().get::<T>().ok_or_else(|| {
+            Error::internal(format!(
+                "Request extension {} is not set",
+                std::any::type_name::<T>()
+            ))
+        })
     }
 }
 
@@ -32,30 +234,270 @@ where
 {
     pub fn extract(req: &CoreRequest) -> Result<Self, Error> {
         let query_str = req.uri().query().unwrap_or("");
+        Ok(Query(params::from_query_str(query_str)?))
+    }
+}
 
-        let params: HashMap<String, String> = url::form_urlencoded::parse(query_str.as_bytes())
-            .into_owned()
-            .collect();
+pub struct Json<T>(pub T);
 
-        let json_value = serde_json::to_value(params)
-            .map_err(|e| Error::BadRequest(format!("Failed to convert query params: {}", e)))?;
+impl<T> Json<T>
+where
+    T: DeserializeOwned,
+{
+    pub fn extract(req: &CoreRequest) -> Result<Self, Error> {
+        let parsed = serde_json::from_slice(&req.body().bytes()?)?;
+        Ok(Json(parsed))
+    }
+}
 
-        let extracted = T::deserialize(json_value)
-            .map_err(|e| Error::BadRequest(format!("Failed to deserialize query params: {}", e)))?;
+/// A MessagePack request body, for service-to-service calls. The request
+/// must declare `application/msgpack` (or `application/x-msgpack`) as its
+/// content type; anything else is rejected with `415`.
+#[cfg(feature = "msgpack")]
+pub struct MsgPack<T>(pub T);
 
-        Ok(Query(extracted))
+#[cfg(feature = "msgpack")]
+impl<T> MsgPack<T>
+where
+    T: DeserializeOwned,
+{
+    pub fn extract(req: &CoreRequest) -> Result<Self, Error> {
+        require_content_type(req, "application/msgpack", |mime| {
+            mime == "application/msgpack" || mime == "application/x-msgpack"
+        })?;
+        let parsed = rmp_serde::from_slice(&req.body().bytes()?)
+            .map_err(|e| Error::bad_request(format!("Invalid MessagePack body: {}", e)))?;
+        Ok(MsgPack(parsed))
     }
 }
 
-pub struct Json<T>(pub T);
+/// An XML request body, e.g. from a SOAP service or an RSS/Atom feed. The
+/// request must declare `application/xml`, `text/xml` or a `+xml` type
+/// such as `application/atom+xml`; anything else is rejected with `415`.
+#[cfg(feature = "xml")]
+pub struct Xml<T>(pub T);
 
-impl<T> Json<T>
+#[cfg(feature = "xml")]
+impl<T> Xml<T>
 where
     T: DeserializeOwned,
 {
     pub fn extract(req: &CoreRequest) -> Result<Self, Error> {
-        let body = req.body();
-        let parsed = serde_json::from_slice(body)?;
-        Ok(Json(parsed))
+        require_content_type(req, "application/xml", |mime| {
+            mime == "application/xml" || mime == "text/xml" || mime.ends_with("+xml")
+        })?;
+        let bytes = req.body().bytes()?;
+        let text = std::str::from_utf8(&bytes)
+            .map_err(|_| Error::bad_request("XML body is not valid UTF-8"))?;
+        let parsed = quick_xml::de::from_str(text)
+            .map_err(|e| Error::bad_request(format!("Invalid XML body: {}", e)))?;
+        Ok(Xml(parsed))
+    }
+}
+
+/// A Protocol Buffers request body decoded with
+/// [prost](https://docs.rs/prost). The request must declare
+/// `application/x-protobuf` (or `application/protobuf`) as its content
+/// type; anything else is rejected with `415`.
+#[cfg(feature = "protobuf")]
+pub struct Proto<T>(pub T);
+
+#[cfg(feature = "protobuf")]
+impl<T> Proto<T>
+where
+    T: prost::Message + Default,
+{
+    pub fn extract(req: &CoreRequest) -> Result<Self, Error> {
+        require_content_type(req, "application/x-protobuf", |mime| {
+            mime == "application/x-protobuf" || mime == "application/protobuf"
+        })?;
+        let parsed = T::decode(req.body().bytes()?)
+            .map_err(|e| Error::bad_request(format!("Invalid Protobuf body: {}", e)))?;
+        Ok(Proto(parsed))
+    }
+}
+
+/// Rejects `req` with `415` unless its media type, lowercased and without
+/// parameters, passes `accepts`.
+#[cfg(any(feature = "msgpack", feature = "xml", feature = "protobuf"))]
+fn require_content_type(
+    req: &CoreRequest,
+    expected: &str,
+    accepts: impl Fn(&str) -> bool,
+) -> Result<(), Error> {
+    let content_type = req
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    if accepts(&mime) {
+        Ok(())
+    } else {
+        Err(Error::unsupported_media_type(format!(
+            "Expected {}, got {:?}",
+            expected, content_type
+        )))
+    }
+}
+
+#[async_trait]
+impl<C, T> FromRequest<C> for Path<T>
+where
+    C: Send + Sync + Clone + 'static,
+    T: DeserializeOwned + Send,
+{
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Self::extract(req)
+    }
+}
+
+#[async_trait]
+impl<C, T> FromRequest<C> for Query<T>
+where
+    C: Send + Sync + Clone + 'static,
+    T: DeserializeOwned + Send,
+{
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Self::extract(req)
+    }
+}
+
+#[async_trait]
+impl<C, T> FromRequest<C> for Json<T>
+where
+    C: Send + Sync + Clone + 'static,
+    T: DeserializeOwned + Send,
+{
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        read_body(req).await?;
+        Self::extract(req)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+#[async_trait]
+impl<C, T> FromRequest<C> for MsgPack<T>
+where
+    C: Send + Sync + Clone + 'static,
+    T: DeserializeOwned + Send,
+{
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        read_body(req).await?;
+        Self::extract(req)
+    }
+}
+
+#[cfg(feature = "xml")]
+#[async_trait]
+impl<C, T> FromRequest<C> for Xml<T>
+where
+    C: Send + Sync + Clone + 'static,
+    T: DeserializeOwned + Send,
+{
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        read_body(req).await?;
+        Self::extract(req)
+    }
+}
+
+#[cfg(feature = "protobuf")]
+#[async_trait]
+impl<C, T> FromRequest<C> for Proto<T>
+where
+    C: Send + Sync + Clone + 'static,
+    T: prost::Message + Default,
+{
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        read_body(req).await?;
+        Self::extract(req)
+    }
+}
+
+/// Shared state registered with [`App::with_state`](crate::App::with_state),
+/// looked up by type.
+pub struct State<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest<Ctx> for State<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    async fn from_request(ctx: &Ctx, _req: &CoreRequest) -> Result<Self, Error> {
+        ctx.state::<T>().cloned().map(State).ok_or_else(|| {
+            Error::internal(format!(
+                "State {} is not registered",
+                std::any::type_name::<T>()
+            ))
+        })
+    }
+}
+
+/// The peer address of the connection, inserted into the request
+/// extensions by adapters that know it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteAddr(pub SocketAddr);
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for RemoteAddr {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        req.extensions()
+            .get::<RemoteAddr>()
+            .copied()
+            .ok_or_else(|| Error::internal("Remote address is not available"))
+    }
+}
+
+/// Details of the connection a request arrived on, inserted into the
+/// request extensions by adapters that accept connections themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectInfo {
+    pub remote_addr: SocketAddr,
+    /// The address the connection was accepted on, when known.
+    pub local_addr: Option<SocketAddr>,
+    pub scheme: Scheme,
+    /// Set for connections served over TLS.
+    pub tls: Option<TlsInfo>,
+}
+
+/// What the TLS handshake established.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The server name the client asked for (SNI).
+    pub server_name: Option<String>,
+    /// The negotiated ALPN protocol, e.g. `h2`.
+    pub alpn_protocol: Option<String>,
+    /// The DER-encoded leaf certificate the client presented, if the server
+    /// asked for one.
+    pub client_cert: Option<Bytes>,
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for ConnectInfo {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        req.extensions()
+            .get::<ConnectInfo>()
+            .cloned()
+            .ok_or_else(|| Error::internal("Connection info is not available"))
+    }
+}
+
+/// The route pattern a request matched, e.g. `/users/:id`. The router puts
+/// it into the extensions of both the request and the response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedPath(pub String);
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for MatchedPath {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        req.extensions()
+            .get::<MatchedPath>()
+            .cloned()
+            .ok_or_else(|| Error::internal("Request was not routed"))
     }
 }
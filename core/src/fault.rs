@@ -0,0 +1,325 @@
+use crate::{
+    clock,
+    handler::Handler,
+    logging::{self, Level},
+    middleware::{Middleware, Next},
+    timeout::SleepFn,
+    Body, CoreRequest, CoreResponse, Error,
+};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_core::Stream;
+use http::header::{HeaderValue, CONTENT_TYPE};
+use http::{Method, StatusCode};
+use matchit::Router as MatchItRouter;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Marks responses affected by an injected fault, so they can be told apart
+/// from real failures in logs and alerts.
+pub const FAULT_HEADER: &str = "x-xeno-fault";
+
+/// What happens to a request selected by a [`FaultRule`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Fault {
+    /// Delays the request before handling it normally.
+    Latency { ms: u64 },
+    /// Answers with this status instead of calling the handler.
+    Error { status: u16 },
+    /// Fails the response body after the headers, which servers turn into a
+    /// dropped connection.
+    Abort,
+}
+
+/// Injects `fault` into `percent` of the requests matching all of its
+/// criteria. Also the JSON shape accepted by [`FaultAdmin`]:
+///
+/// ```json
+/// {"route": "/users/*rest", "header": ["x-chaos", "on"], "percent": 10,
+///  "fault": {"type": "error", "status": 503}}
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaultRule {
+    /// A route pattern such as `/users/:id`; all paths when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// A header name and the value it must have.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<(String, String)>,
+    pub percent: f64,
+    pub fault: Fault,
+}
+
+impl FaultRule {
+    pub fn new(percent: f64, fault: Fault) -> Self {
+        Self {
+            route: None,
+            header: None,
+            percent,
+            fault,
+        }
+    }
+
+    pub fn latency(percent: f64, delay: Duration) -> Self {
+        Self::new(
+            percent,
+            Fault::Latency {
+                ms: delay.as_millis() as u64,
+            },
+        )
+    }
+
+    pub fn error(percent: f64, status: StatusCode) -> Self {
+        Self::new(
+            percent,
+            Fault::Error {
+                status: status.as_u16(),
+            },
+        )
+    }
+
+    pub fn abort(percent: f64) -> Self {
+        Self::new(percent, Fault::Abort)
+    }
+
+    pub fn route(mut self, pattern: impl Into<String>) -> Self {
+        self.route = Some(pattern.into());
+        self
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.header = Some((name.into(), value.into()));
+        self
+    }
+}
+
+struct CompiledRule {
+    rule: FaultRule,
+    route: Option<MatchItRouter<()>>,
+}
+
+impl CompiledRule {
+    fn new(rule: FaultRule) -> Result<Self, Error> {
+        if !(0.0..=100.0).contains(&rule.percent) {
+            return Err(Error::bad_request(format!(
+                "Fault percent must be between 0 and 100, got {}",
+                rule.percent
+            )));
+        }
+        if let Fault::Error { status } = rule.fault {
+            StatusCode::from_u16(status)
+                .map_err(|_| Error::bad_request(format!("Invalid fault status {}", status)))?;
+        }
+        let route = match &rule.route {
+            Some(pattern) => {
+                let mut router = MatchItRouter::new();
+                router.insert(pattern.as_str(), ()).map_err(|e| {
+                    Error::bad_request(format!("Invalid fault route {}: {}", pattern, e))
+                })?;
+                Some(router)
+            }
+            None => None,
+        };
+        Ok(Self { rule, route })
+    }
+
+    fn matches(&self, req: &CoreRequest) -> bool {
+        if let Some(route) = &self.route {
+            if route.at(req.uri().path()).is_err() {
+                return false;
+            }
+        }
+        match &self.rule.header {
+            Some((name, value)) => req
+                .headers()
+                .get(name.as_str())
+                .is_some_and(|v| v.as_bytes() == value.as_bytes()),
+            None => true,
+        }
+    }
+}
+
+/// The rule set of a [`FaultInjection`], replaceable while it runs. Clones
+/// share the same rules.
+#[derive(Clone)]
+pub struct FaultRules {
+    rules: Arc<ArcSwap<Vec<CompiledRule>>>,
+}
+
+impl FaultRules {
+    /// Replaces all rules; an empty list turns fault injection off.
+    pub fn set(&self, rules: Vec<FaultRule>) -> Result<(), Error> {
+        let compiled = rules
+            .into_iter()
+            .map(CompiledRule::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.rules.store(Arc::new(compiled));
+        Ok(())
+    }
+
+    pub fn clear(&self) {
+        self.rules.store(Arc::new(Vec::new()));
+    }
+
+    pub fn list(&self) -> Vec<FaultRule> {
+        self.rules.load().iter().map(|c| c.rule.clone()).collect()
+    }
+}
+
+/// Middleware injecting latency, error responses or aborted connections
+/// into a share of requests, for checking client retries and alerting.
+///
+/// The first matching rule whose dice roll succeeds applies; the roll uses
+/// the context's [`Rng`](crate::clock::Rng), so seeded tests are
+/// repeatable. As with [`TimeoutMiddleware`](crate::timeout::TimeoutMiddleware)
+/// the platform's sleep is passed in. Starts without rules unless given
+/// some; change them at runtime through [`FaultInjection::rules`].
+pub struct FaultInjection {
+    rules: FaultRules,
+    sleep: SleepFn,
+}
+
+impl FaultInjection {
+    pub fn new<S, F>(sleep: S) -> Self
+    where
+        S: Fn(Duration) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            rules: FaultRules {
+                rules: Arc::new(ArcSwap::from_pointee(Vec::new())),
+            },
+            sleep: Arc::new(move |duration| Box::pin(sleep(duration))),
+        }
+    }
+
+    /// Adds a rule, logging and skipping invalid ones.
+    pub fn rule(self, rule: FaultRule) -> Self {
+        let mut rules = self.rules.list();
+        rules.push(rule);
+        if let Err(e) = self.rules.set(rules) {
+            logging::global().error(format!("Failed to add fault rule: {}", e));
+        }
+        self
+    }
+
+    pub fn rules(&self) -> FaultRules {
+        self.rules.clone()
+    }
+
+    fn pick<C: 'static>(&self, ctx: &C, req: &CoreRequest) -> Option<Fault> {
+        let rules = self.rules.rules.load();
+        let mut matching = rules.iter().filter(|r| r.matches(req)).peekable();
+        matching.peek()?;
+        let rng = clock::rng_of(ctx);
+        matching.find_map(|compiled| {
+            // Hundredths of a percent.
+            let roll = rng.next_u64() % 10_000;
+            ((roll as f64) < compiled.rule.percent * 100.0).then(|| compiled.rule.fault.clone())
+        })
+    }
+}
+
+fn fault_response(status: StatusCode) -> Result<CoreResponse, Error> {
+    let body = serde_json::json!({
+        "error": "Injected fault",
+        "status": status.as_u16(),
+    });
+    Ok(http::Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header(FAULT_HEADER, "error")
+        .body(body.to_string().into())?)
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for FaultInjection {
+    async fn handle(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let Some(fault) = self.pick(&ctx, &req) else {
+            return next.run(ctx, req).await;
+        };
+        logging::global().log(
+            Level::Warn,
+            &format!("fault {:?}", fault),
+            format!(
+                "Injecting {:?} into {} {}",
+                fault,
+                req.method(),
+                req.uri().path()
+            ),
+        );
+        match fault {
+            Fault::Latency { ms } => {
+                (self.sleep)(Duration::from_millis(ms)).await;
+                let mut res = next.run(ctx, req).await?;
+                res.headers_mut()
+                    .insert(FAULT_HEADER, HeaderValue::from_static("latency"));
+                Ok(res)
+            }
+            Fault::Error { status } => {
+                fault_response(StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY))
+            }
+            Fault::Abort => {
+                let body = Body::from_stream(Aborted(false));
+                Ok(http::Response::builder()
+                    .header(FAULT_HEADER, "abort")
+                    .body(body)?)
+            }
+        }
+    }
+}
+
+/// A body stream failing on its first poll.
+struct Aborted(bool);
+
+impl Stream for Aborted {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.0 {
+            return Poll::Ready(None);
+        }
+        self.0 = true;
+        Poll::Ready(Some(Err(Error::internal("Injected connection abort"))))
+    }
+}
+
+/// Admin handler listing fault rules on `GET` and replacing them on `POST`
+/// with a JSON array of [`FaultRule`]s; `[]` stops all injection.
+///
+/// Mount behind authentication.
+pub struct FaultAdmin {
+    rules: FaultRules,
+}
+
+impl FaultAdmin {
+    pub fn new(rules: FaultRules) -> Self {
+        Self { rules }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Handler<C> for FaultAdmin {
+    async fn call(&self, _ctx: C, req: CoreRequest) -> Result<CoreResponse, Error> {
+        if req.method() == Method::POST {
+            let body = req.into_body().collect().await?;
+            let rules: Vec<FaultRule> = serde_json::from_slice(&body)
+                .map_err(|e| Error::bad_request(format!("Invalid fault rules: {}", e)))?;
+            self.rules.set(rules)?;
+        }
+        Ok(http::Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&self.rules.list())?.into())?)
+    }
+}
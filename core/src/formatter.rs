@@ -0,0 +1,380 @@
+use crate::{
+    clock::{self, Clock, Rng, SystemClock, SystemRng},
+    cookie::Cookies,
+    Body, CoreRequest, CoreResponse, Error,
+};
+use http::header::{HeaderValue, ACCEPT, CONTENT_TYPE, COOKIE, LOCATION};
+use http::{StatusCode, Uri};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The parts of a request that error rendering may depend on, captured
+/// before the request is handed to middleware and handlers, along with the
+/// context's clock and random source.
+#[derive(Clone)]
+pub struct ErrorRequest {
+    pub uri: Uri,
+    pub accept: Option<HeaderValue>,
+    pub cookies: Vec<HeaderValue>,
+    pub clock: Arc<dyn Clock>,
+    pub rng: Arc<dyn Rng>,
+}
+
+impl Default for ErrorRequest {
+    fn default() -> Self {
+        Self {
+            uri: Uri::default(),
+            accept: None,
+            cookies: Vec::new(),
+            clock: Arc::new(SystemClock),
+            rng: Arc::new(SystemRng),
+        }
+    }
+}
+
+impl ErrorRequest {
+    pub fn from_request(req: &CoreRequest) -> Self {
+        Self {
+            uri: req.uri().clone(),
+            accept: req.headers().get(ACCEPT).cloned(),
+            cookies: req.headers().get_all(COOKIE).iter().cloned().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Uses the clock and random source of `ctx`.
+    pub(crate) fn with_context<C: 'static>(mut self, ctx: &C) -> Self {
+        self.clock = clock::clock_of(ctx);
+        self.rng = clock::rng_of(ctx);
+        self
+    }
+
+    /// Whether the client ranks HTML above JSON, as browsers navigating to
+    /// a page do. `*/*` alone counts as JSON.
+    pub fn prefers_html(&self) -> bool {
+        let Some(accept) = self.accept.as_ref().and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        let (mut html, mut json, mut any) = (0.0f32, 0.0f32, 0.0f32);
+        for item in accept.split(',') {
+            let mut parts = item.split(';');
+            let mime = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match mime.as_str() {
+                "text/html" | "application/xhtml+xml" => html = html.max(q),
+                "application/json" | "application/problem+json" => json = json.max(q),
+                "*/*" => any = any.max(q),
+                _ => {}
+            }
+        }
+        if json == 0.0 {
+            json = any;
+        }
+        html > json
+    }
+
+    pub fn has_cookie(&self, name: &str) -> bool {
+        let mut req = CoreRequest::new(Body::empty());
+        for cookie in &self.cookies {
+            req.headers_mut().append(COOKIE, cookie.clone());
+        }
+        Cookies::extract(&req).get(name).is_some()
+    }
+}
+
+/// Renders the responses the framework produces on its own: handler and
+/// middleware errors, unmatched routes and unsupported methods.
+///
+/// Install a custom implementation with [`App::formatter`](crate::App::formatter)
+/// to emit something other than JSON.
+pub trait ResponseFormatter: Send + Sync {
+    fn format_error(&self, error: &Error) -> CoreResponse;
+
+    /// Renders an error for a particular request, for formatters that
+    /// negotiate. Defaults to [`ResponseFormatter::format_error`].
+    fn format_error_for(&self, error: &Error, req: &ErrorRequest) -> CoreResponse {
+        let _ = req;
+        self.format_error(error)
+    }
+
+    fn not_found(&self) -> CoreResponse {
+        self.format_error(&Error::NotFound)
+    }
+
+    fn method_not_allowed(&self) -> CoreResponse {
+        self.format_error(&Error::MethodNotAllowed)
+    }
+
+    /// The response for a request no route matches. Defaults to
+    /// [`ResponseFormatter::not_found`].
+    fn not_found_for(&self, req: &ErrorRequest) -> CoreResponse {
+        let _ = req;
+        self.not_found()
+    }
+
+    /// Defaults to [`ResponseFormatter::method_not_allowed`]; the router adds
+    /// the `Allow` header afterwards.
+    fn method_not_allowed_for(&self, req: &ErrorRequest) -> CoreResponse {
+        let _ = req;
+        self.method_not_allowed()
+    }
+}
+
+/// A formatter rendering every framework error, including 404 and 405, with
+/// one function; see [`App::error_handler`](crate::App::error_handler).
+pub struct ErrorHandler<F>(pub F);
+
+impl<F> ResponseFormatter for ErrorHandler<F>
+where
+    F: Fn(&Error, &ErrorRequest) -> CoreResponse + Send + Sync,
+{
+    fn format_error(&self, error: &Error) -> CoreResponse {
+        (self.0)(error, &ErrorRequest::default())
+    }
+
+    fn format_error_for(&self, error: &Error, req: &ErrorRequest) -> CoreResponse {
+        (self.0)(error, req)
+    }
+
+    fn not_found_for(&self, req: &ErrorRequest) -> CoreResponse {
+        (self.0)(&Error::NotFound, req)
+    }
+
+    fn method_not_allowed_for(&self, req: &ErrorRequest) -> CoreResponse {
+        (self.0)(&Error::MethodNotAllowed, req)
+    }
+}
+
+/// The default formatter, producing `application/json` bodies.
+///
+/// The body is written by hand rather than through `serde_json`, keeping the
+/// error path small in size-sensitive builds such as Workers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormatter;
+
+impl JsonFormatter {
+    fn render(&self, error: &Error, now: SystemTime, request_id: String) -> CoreResponse {
+        let status = error.status_code();
+
+        #[cfg(debug_assertions)]
+        let message = error.debug_message();
+
+        #[cfg(not(debug_assertions))]
+        let message = error.safe_message().to_string();
+
+        // Field errors describe the client's own input, so they are sent in
+        // release builds too.
+        let details = match error {
+            Error::Validation(errors) => {
+                let fields: Vec<String> = errors
+                    .fields
+                    .iter()
+                    .map(|e| {
+                        format!(
+                            r#"{{"field":{},"message":{}}}"#,
+                            json_string(&e.field),
+                            json_string(&e.message)
+                        )
+                    })
+                    .collect();
+                format!(r#","errors":[{}]"#, fields.join(","))
+            }
+            _ => String::new(),
+        };
+
+        let body = format!(
+            r#"{{"error":{}{},"status":{},"timestamp":"{}"}}"#,
+            json_string(&message),
+            details,
+            status.as_u16(),
+            clock::rfc3339(now)
+        );
+
+        http::Response::builder()
+            .status(status)
+            .header("content-type", "application/json; charset=utf-8")
+            .header("x-request-id", request_id)
+            .body(body.into())
+            .unwrap()
+    }
+}
+
+/// Quotes and escapes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl ResponseFormatter for JsonFormatter {
+    fn format_error(&self, error: &Error) -> CoreResponse {
+        self.render(error, SystemTime::now(), SystemRng.uuid_string())
+    }
+
+    fn format_error_for(&self, error: &Error, req: &ErrorRequest) -> CoreResponse {
+        self.render(error, req.clock.now(), req.rng.uuid_string())
+    }
+
+    fn not_found(&self) -> CoreResponse {
+        http::Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("content-type", "application/json; charset=utf-8")
+            .body(r#"{"error":"Not Found"}"#.into())
+            .unwrap()
+    }
+
+    fn method_not_allowed(&self) -> CoreResponse {
+        http::Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header("content-type", "application/json; charset=utf-8")
+            .body(r#"{"error":"Method Not Allowed"}"#.into())
+            .unwrap()
+    }
+}
+
+type HtmlTemplate = Arc<dyn Fn(&Error) -> String + Send + Sync>;
+
+/// A formatter choosing the error representation per client: JSON for API
+/// clients, an HTML page for browsers, and optionally a redirect to a login
+/// page for browsers without a session that hit a `401`.
+///
+/// Errors raised outside a request, and unmatched routes, are rendered as
+/// JSON.
+#[derive(Clone)]
+pub struct NegotiatedFormatter {
+    json: Arc<dyn ResponseFormatter>,
+    html: HtmlTemplate,
+    login: Option<(String, String)>,
+}
+
+impl NegotiatedFormatter {
+    pub fn new() -> Self {
+        Self {
+            json: Arc::new(JsonFormatter),
+            html: Arc::new(default_html),
+            login: None,
+        }
+    }
+
+    /// Replaces the formatter used for API clients.
+    pub fn json(mut self, formatter: impl ResponseFormatter + 'static) -> Self {
+        self.json = Arc::new(formatter);
+        self
+    }
+
+    /// Renders the HTML error page. The output is sent as is, so escape
+    /// anything taken from the error.
+    pub fn html(mut self, template: impl Fn(&Error) -> String + Send + Sync + 'static) -> Self {
+        self.html = Arc::new(template);
+        self
+    }
+
+    /// Redirects browsers without the `session_cookie` cookie to
+    /// `login_url` on `401`, passing the original path and query as `next`.
+    pub fn login_redirect(
+        mut self,
+        login_url: impl Into<String>,
+        session_cookie: impl Into<String>,
+    ) -> Self {
+        self.login = Some((login_url.into(), session_cookie.into()));
+        self
+    }
+
+    fn login_response(&self, error: &Error, req: &ErrorRequest) -> Option<CoreResponse> {
+        let (login_url, session_cookie) = self.login.as_ref()?;
+        if !matches!(error, Error::Unauthorized) || req.has_cookie(session_cookie) {
+            return None;
+        }
+        let next = req.uri.path_and_query().map_or("/", |pq| pq.as_str());
+        let separator = if login_url.contains('?') { '&' } else { '?' };
+        let location = format!(
+            "{}{}{}",
+            login_url,
+            separator,
+            form_urlencoded::Serializer::new(String::new())
+                .append_pair("next", next)
+                .finish()
+        );
+        http::Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION, location)
+            .body(Body::empty())
+            .ok()
+    }
+}
+
+impl Default for NegotiatedFormatter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn default_html(error: &Error) -> String {
+    let status = error.status_code();
+    format!(
+        "<!DOCTYPE html>\n<html><head><title>{code} {reason}</title></head>\
+         <body><h1>{code} {reason}</h1></body></html>\n",
+        code = status.as_u16(),
+        reason = error.safe_message(),
+    )
+}
+
+impl ResponseFormatter for NegotiatedFormatter {
+    fn format_error(&self, error: &Error) -> CoreResponse {
+        self.json.format_error(error)
+    }
+
+    fn format_error_for(&self, error: &Error, req: &ErrorRequest) -> CoreResponse {
+        if !req.prefers_html() {
+            return self.json.format_error_for(error, req);
+        }
+        if let Some(res) = self.login_response(error, req) {
+            return res;
+        }
+        http::Response::builder()
+            .status(error.status_code())
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body((self.html)(error).into())
+            .unwrap()
+    }
+
+    fn not_found(&self) -> CoreResponse {
+        self.json.not_found()
+    }
+
+    fn method_not_allowed(&self) -> CoreResponse {
+        self.json.method_not_allowed()
+    }
+
+    fn not_found_for(&self, req: &ErrorRequest) -> CoreResponse {
+        match req.prefers_html() {
+            true => self.format_error_for(&Error::NotFound, req),
+            false => self.json.not_found_for(req),
+        }
+    }
+
+    fn method_not_allowed_for(&self, req: &ErrorRequest) -> CoreResponse {
+        match req.prefers_html() {
+            true => self.format_error_for(&Error::MethodNotAllowed, req),
+            false => self.json.method_not_allowed_for(req),
+        }
+    }
+}
@@ -1,7 +1,113 @@
-use crate::{CoreRequest, CoreResponse, Error};
+use crate::{extract::FromRequest, CoreRequest, CoreResponse, Error, IntoResponse};
 use async_trait::async_trait;
+use std::future::Future;
+use std::marker::PhantomData;
 
 #[async_trait]
 pub trait Handler<C: Send + Sync + Clone + 'static>: Send + Sync {
     async fn call(&self, ctx: C, req: CoreRequest) -> Result<CoreResponse, Error>;
+
+    /// Route-scoped middleware wrapping this handler, outermost first, as
+    /// listed by [`App::describe`](crate::App::describe).
+    fn middleware_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Lets plain `async fn(ctx, req)` items and closures be used as handlers.
+#[async_trait]
+impl<C, F, Fut> Handler<C> for F
+where
+    C: Send + Sync + Clone + 'static,
+    F: Fn(C, CoreRequest) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<CoreResponse, Error>> + Send,
+{
+    async fn call(&self, ctx: C, req: CoreRequest) -> Result<CoreResponse, Error> {
+        (self)(ctx, req).await
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Handler<C> for Box<dyn Handler<C>> {
+    async fn call(&self, ctx: C, req: CoreRequest) -> Result<CoreResponse, Error> {
+        self.as_ref().call(ctx, req).await
+    }
+
+    fn middleware_names(&self) -> Vec<String> {
+        self.as_ref().middleware_names()
+    }
+}
+
+/// Conversion into a boxed [`Handler`], accepted by the route registration
+/// methods.
+///
+/// Implemented for every `Handler` and for async functions whose arguments
+/// all implement [`FromRequest`], e.g.
+/// `async fn get_user(Path(id): Path<u32>) -> Result<Json<User>, Error>`.
+/// Body extractors such as [`Json`](crate::Json) read the body when they
+/// run, up to the request's [`BodyLimit`](crate::extract::BodyLimit), and
+/// [`BodyStream`](crate::extract::BodyStream) takes it unread. `M` only
+/// exists to keep those implementations apart.
+pub trait IntoHandler<C: Send + Sync + Clone + 'static, M>: Send + Sync + 'static {
+    fn into_handler(self) -> Box<dyn Handler<C>>;
+}
+
+impl<C, H> IntoHandler<C, ()> for H
+where
+    C: Send + Sync + Clone + 'static,
+    H: Handler<C> + 'static,
+{
+    fn into_handler(self) -> Box<dyn Handler<C>> {
+        Box::new(self)
+    }
+}
+
+/// Adapts an extractor-argument function into a [`Handler`].
+pub struct FnHandler<F, M> {
+    f: F,
+    _marker: PhantomData<fn() -> M>,
 }
+
+macro_rules! impl_fn_handler {
+    ($($ty:ident),*) => {
+        #[async_trait]
+        #[allow(non_snake_case, unused_variables)]
+        impl<C, F, Fut, R, $($ty,)*> Handler<C> for FnHandler<F, fn($($ty,)*)>
+        where
+            C: Send + Sync + Clone + 'static,
+            F: Fn($($ty,)*) -> Fut + Send + Sync,
+            Fut: Future<Output = R> + Send,
+            R: IntoResponse,
+            $($ty: FromRequest<C>,)*
+        {
+            async fn call(&self, ctx: C, req: CoreRequest) -> Result<CoreResponse, Error> {
+                $(let $ty = $ty::from_request(&ctx, &req).await?;)*
+                (self.f)($($ty,)*).await.into_result()
+            }
+        }
+
+        impl<C, F, Fut, R, $($ty,)*> IntoHandler<C, fn($($ty,)*)> for F
+        where
+            C: Send + Sync + Clone + 'static,
+            F: Fn($($ty,)*) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = R> + Send,
+            R: IntoResponse,
+            $($ty: FromRequest<C> + 'static,)*
+        {
+            fn into_handler(self) -> Box<dyn Handler<C>> {
+                Box::new(FnHandler {
+                    f: self,
+                    _marker: PhantomData,
+                })
+            }
+        }
+    };
+}
+
+impl_fn_handler!();
+impl_fn_handler!(T1);
+impl_fn_handler!(T1, T2);
+impl_fn_handler!(T1, T2, T3);
+impl_fn_handler!(T1, T2, T3, T4);
+impl_fn_handler!(T1, T2, T3, T4, T5);
+impl_fn_handler!(T1, T2, T3, T4, T5, T6);
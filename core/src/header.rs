@@ -0,0 +1,430 @@
+use crate::{clock::UtcTime, extract::FromRequest, CoreRequest, Error};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::header::{
+    HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE, IF_NONE_MATCH, RANGE, USER_AGENT,
+};
+use std::fmt::Write;
+use std::time::SystemTime;
+
+/// Builds a header value from untrusted input.
+///
+/// CR, LF and other control characters are dropped so the input cannot start
+/// a new header line; bytes outside printable ASCII are percent-encoded.
+pub fn sanitize_header_value(input: &str) -> HeaderValue {
+    let mut out = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            '\t' | ' '..='~' if ch != '%' => out.push(ch),
+            c if c.is_control() => {}
+            c => percent_encode_char(&mut out, c),
+        }
+    }
+
+    // Every remaining byte is visible ASCII, space or tab.
+    HeaderValue::from_str(&out).expect("sanitized header value is always valid")
+}
+
+/// Formats a time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> HeaderValue {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let t = UtcTime::from_system(time);
+    let date = format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        DAYS[t.weekday as usize],
+        t.day,
+        MONTHS[t.month as usize - 1],
+        t.year,
+        t.hour,
+        t.minute,
+        t.second
+    );
+    HeaderValue::from_str(&date).expect("HTTP dates are always valid header values")
+}
+
+/// Parses an HTTP date in the IMF-fixdate form [`http_date`] produces.
+/// The obsolete RFC 850 and asctime forms are not accepted.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (_, rest) = value.trim().split_once(", ")?;
+    let mut parts = rest.split(' ');
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut clock = parts.next()?.split(':').map(|n| n.parse::<u64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if parts.next()? != "GMT" || parts.next().is_some() || clock.next().is_some() {
+        return None;
+    }
+    if !(1..=31).contains(&day) || year < 1970 || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Howard Hinnant's days_from_civil.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = (era * 146_097 + doe).checked_sub(719_468)?;
+
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+}
+
+/// Encodes a value per RFC 5987 `ext-value` syntax, e.g. `UTF-8''na%C3%AFve.txt`.
+pub fn encode_rfc5987(value: &str) -> String {
+    let mut out = String::from("UTF-8''");
+    for ch in value.chars() {
+        if is_attr_char(ch) {
+            out.push(ch);
+        } else {
+            percent_encode_char(&mut out, ch);
+        }
+    }
+    out
+}
+
+/// Builds a `Content-Disposition` value carrying a user-supplied filename.
+///
+/// Emits an ASCII-only `filename` fallback alongside an RFC 5987 `filename*`
+/// parameter for clients that understand it.
+pub fn content_disposition(disposition: &str, filename: &str) -> HeaderValue {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' | '/' => '_',
+            c if c.is_ascii_graphic() || c == ' ' => c,
+            _ => '_',
+        })
+        .collect();
+
+    let disposition: String = disposition.chars().filter(char::is_ascii_graphic).collect();
+
+    let value = format!(
+        "{}; filename=\"{}\"; filename*={}",
+        disposition,
+        fallback,
+        encode_rfc5987(filename)
+    );
+    HeaderValue::from_str(&value).expect("content disposition is always visible ASCII")
+}
+
+fn is_attr_char(c: char) -> bool {
+    c.is_ascii_alphanumeric()
+        || matches!(
+            c,
+            '!' | '#' | '$' | '&' | '+' | '-' | '.' | '^' | '_' | '`' | '|' | '~'
+        )
+}
+
+pub(crate) fn percent_encode_char(out: &mut String, c: char) {
+    let mut buf = [0u8; 4];
+    for byte in c.encode_utf8(&mut buf).bytes() {
+        let _ = write!(out, "%{:02X}", byte);
+    }
+}
+
+/// A header that can be parsed into a typed value, for use with
+/// [`TypedHeader`].
+pub trait Header: Sized {
+    fn name() -> HeaderName;
+
+    /// Parses the header's value; repeated headers are joined with `", "`.
+    /// The error message is shown to the client.
+    fn decode(value: &str) -> Result<Self, String>;
+}
+
+/// Extracts and validates a typed header, answering `400 Bad Request` with a
+/// description when it is missing or malformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypedHeader<T>(pub T);
+
+impl<T: Header> TypedHeader<T> {
+    pub fn extract(req: &CoreRequest) -> Result<Self, Error> {
+        let name = T::name();
+        let mut values = Vec::new();
+        for value in req.headers().get_all(&name) {
+            let value = value
+                .to_str()
+                .map_err(|_| Error::bad_request(format!("Invalid {} header: not ASCII", name)))?;
+            values.push(value.trim());
+        }
+        if values.is_empty() {
+            return Err(Error::bad_request(format!("Missing {} header", name)));
+        }
+
+        T::decode(&values.join(", "))
+            .map(TypedHeader)
+            .map_err(|reason| Error::bad_request(format!("Invalid {} header: {}", name, reason)))
+    }
+}
+
+#[async_trait]
+impl<C, T> FromRequest<C> for TypedHeader<T>
+where
+    C: Send + Sync + Clone + 'static,
+    T: Header + Send,
+{
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Self::extract(req)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authorization {
+    Bearer(String),
+    Basic {
+        username: String,
+        password: String,
+    },
+    /// Any other scheme, with its credentials left unparsed.
+    Other {
+        scheme: String,
+        credentials: String,
+    },
+}
+
+impl Header for Authorization {
+    fn name() -> HeaderName {
+        AUTHORIZATION
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        let (scheme, credentials) = value
+            .split_once(' ')
+            .ok_or("expected `<scheme> <credentials>`")?;
+        let credentials = credentials.trim();
+        if credentials.is_empty() {
+            return Err("missing credentials".to_string());
+        }
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            Ok(Authorization::Bearer(credentials.to_string()))
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = STANDARD
+                .decode(credentials)
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or("Basic credentials are not valid base64 UTF-8")?;
+            let (username, password) = decoded
+                .split_once(':')
+                .ok_or("Basic credentials must be `user:password`")?;
+            Ok(Authorization::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            })
+        } else {
+            Ok(Authorization::Other {
+                scheme: scheme.to_string(),
+                credentials: credentials.to_string(),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    /// Lowercased `type/subtype`, without parameters.
+    pub mime: String,
+    /// Parameters with lowercased names and unquoted values.
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+
+    /// `application/json` or any `+json` suffix type.
+    pub fn is_json(&self) -> bool {
+        self.mime == "application/json" || self.mime.ends_with("+json")
+    }
+}
+
+impl Header for ContentType {
+    fn name() -> HeaderName {
+        CONTENT_TYPE
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        let mut parts = value.split(';');
+        let mime = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let valid = mime
+            .split_once('/')
+            .is_some_and(|(t, s)| !t.is_empty() && !s.is_empty() && !s.contains('/'));
+        if !valid {
+            return Err(format!("`{}` is not a media type", mime));
+        }
+
+        let mut params = Vec::new();
+        for param in parts.map(str::trim).filter(|p| !p.is_empty()) {
+            let (name, value) = param
+                .split_once('=')
+                .ok_or_else(|| format!("malformed parameter `{}`", param))?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            params.push((name.trim().to_ascii_lowercase(), value.to_string()));
+        }
+        Ok(ContentType { mime, params })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAgent(pub String);
+
+impl Header for UserAgent {
+    fn name() -> HeaderName {
+        USER_AGENT
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        Ok(UserAgent(value.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityTag {
+    pub weak: bool,
+    /// The opaque tag, without quotes.
+    pub tag: String,
+}
+
+impl EntityTag {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (weak, quoted) = match value.strip_prefix("W/") {
+            Some(rest) => (true, rest),
+            None => (false, value),
+        };
+        let tag = quoted.strip_prefix('"')?.strip_suffix('"')?;
+        if tag.contains('"') {
+            return None;
+        }
+        Some(EntityTag {
+            weak,
+            tag: tag.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IfNoneMatch {
+    Any,
+    Tags(Vec<EntityTag>),
+}
+
+impl IfNoneMatch {
+    /// Weak comparison against the current representation's `ETag` value
+    /// (quoted, optionally `W/`-prefixed).
+    pub fn matches(&self, etag: &str) -> bool {
+        match self {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Tags(tags) => EntityTag::parse(etag)
+                .is_some_and(|current| tags.iter().any(|t| t.tag == current.tag)),
+        }
+    }
+}
+
+impl Header for IfNoneMatch {
+    fn name() -> HeaderName {
+        IF_NONE_MATCH
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        if value.trim() == "*" {
+            return Ok(IfNoneMatch::Any);
+        }
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(|t| EntityTag::parse(t).ok_or_else(|| format!("`{}` is not an entity tag", t)))
+            .collect::<Result<Vec<_>, _>>()
+            .map(IfNoneMatch::Tags)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `first-last`, inclusive.
+    FromTo(u64, u64),
+    /// `first-`, to the end.
+    From(u64),
+    /// `-n`, the last `n` bytes.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// The inclusive first and last byte positions within a body of `len`
+    /// bytes, or `None` if the range is unsatisfiable.
+    pub fn resolve(self, len: u64) -> Option<(u64, u64)> {
+        let (first, last) = match self {
+            ByteRange::FromTo(first, last) => (first, last.min(len.checked_sub(1)?)),
+            ByteRange::From(first) => (first, len.checked_sub(1)?),
+            ByteRange::Suffix(0) => return None,
+            ByteRange::Suffix(n) => (len.saturating_sub(n), len.checked_sub(1)?),
+        };
+        (first <= last).then_some((first, last))
+    }
+}
+
+/// A `Range: bytes=...` request header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Range {
+    pub ranges: Vec<ByteRange>,
+}
+
+impl Header for Range {
+    fn name() -> HeaderName {
+        RANGE
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        let spec = value
+            .trim()
+            .strip_prefix("bytes=")
+            .ok_or("only `bytes` ranges are supported")?;
+
+        let mut ranges = Vec::new();
+        for part in spec.split(',').map(str::trim) {
+            let (first, last) = part
+                .split_once('-')
+                .ok_or_else(|| format!("malformed range `{}`", part))?;
+            let number = |n: &str| {
+                n.parse::<u64>()
+                    .map_err(|_| format!("malformed range `{}`", part))
+            };
+            let range = match (first, last) {
+                ("", "") => return Err(format!("malformed range `{}`", part)),
+                ("", n) => ByteRange::Suffix(number(n)?),
+                (f, "") => ByteRange::From(number(f)?),
+                (f, l) => {
+                    let (f, l) = (number(f)?, number(l)?);
+                    if l < f {
+                        return Err(format!("range `{}` ends before it starts", part));
+                    }
+                    ByteRange::FromTo(f, l)
+                }
+            };
+            ranges.push(range);
+        }
+        Ok(Range { ranges })
+    }
+}
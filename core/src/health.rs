@@ -0,0 +1,66 @@
+use crate::{
+    logging::{self, Level},
+    warmup::WarmupProgress,
+    CoreRequest, CoreResponse, Ctx, Error, Handler,
+};
+use async_trait::async_trait;
+use http::header::CONTENT_TYPE;
+use http::StatusCode;
+use serde_json::{json, Map, Value};
+
+/// Readiness probe for the context's backends.
+///
+/// When a Sql backend is configured it is pinged with `SELECT 1` and its pool
+/// statistics are included, and when the app has warm-up tasks their
+/// progress is; any failed check turns the response into a
+/// `503 Service Unavailable`.
+pub struct Readiness;
+
+#[async_trait]
+impl Handler<Ctx> for Readiness {
+    async fn call(&self, ctx: Ctx, req: CoreRequest) -> Result<CoreResponse, Error> {
+        let mut checks = Map::new();
+        let mut ready = true;
+
+        if let Some(warmup) = req.extensions().get::<WarmupProgress>() {
+            if warmup.total() > 0 {
+                ready &= warmup.is_ready();
+                checks.insert("warmup".to_string(), warmup.to_json());
+            }
+        }
+
+        if let Some(sql) = &ctx.sql {
+            let ping = sql.query("SELECT 1", &[]).await;
+            if let Err(e) = &ping {
+                logging::global().log(
+                    Level::Warn,
+                    "readiness sql",
+                    format!("Readiness check failed for sql: {}", e),
+                );
+            }
+            ready &= ping.is_ok();
+            checks.insert(
+                "sql".to_string(),
+                json!({
+                    "ok": ping.is_ok(),
+                    "error": ping.err().map(|e| e.safe_message()),
+                    "pool": sql.pool_stats(),
+                }),
+            );
+        }
+
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let body = json!({
+            "status": if ready { "ready" } else { "unavailable" },
+            "checks": Value::Object(checks),
+        });
+        Ok(http::Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&body)?.into())?)
+    }
+}
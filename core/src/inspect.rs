@@ -0,0 +1,130 @@
+use crate::{middleware::Middleware, CoreRequest, Error};
+use async_trait::async_trait;
+use std::time::{Duration, Instant};
+
+/// A filter that needs to look at the raw request body (WAF rules, virus-scan
+/// hooks and the like).
+///
+/// Run it through [`BodyInspection`], which enforces byte and time budgets and
+/// leaves the body in place for downstream extractors.
+#[async_trait]
+pub trait BodyInspector<C: Send + Sync + Clone + 'static>: Send + Sync {
+    async fn inspect(
+        &self,
+        ctx: &C,
+        req: &CoreRequest,
+        body: &InspectedBody<'_>,
+    ) -> Result<(), Error>;
+}
+
+/// The part of the body an inspector is allowed to see.
+pub struct InspectedBody<'a> {
+    bytes: &'a [u8],
+    truncated: bool,
+    deadline: Instant,
+}
+
+impl<'a> InspectedBody<'a> {
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Whether the body was longer than the byte budget and cut short.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn remaining_time(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Lets long-running inspectors bail out cooperatively once the time
+    /// budget is spent.
+    pub fn check_deadline(&self) -> Result<(), Error> {
+        if Instant::now() > self.deadline {
+            Err(Error::request_timeout())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// What to do with bodies larger than the byte budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Fail the request with `413 Payload Too Large`.
+    Reject,
+    /// Inspect only the first `max_bytes` bytes.
+    InspectPrefix,
+    /// Let the request through without inspection.
+    Skip,
+}
+
+/// Middleware that runs a [`BodyInspector`] under byte and time budgets.
+///
+/// Core has no timer, so the time budget is enforced cooperatively through
+/// [`InspectedBody::check_deadline`] and once more after the inspector
+/// returns; an inspector that overran fails the request with `408`.
+pub struct BodyInspection<I> {
+    inspector: I,
+    max_bytes: usize,
+    time_budget: Duration,
+    oversize: OversizePolicy,
+}
+
+impl<I> BodyInspection<I> {
+    pub fn new(inspector: I) -> Self {
+        Self {
+            inspector,
+            max_bytes: 64 * 1024,
+            time_budget: Duration::from_millis(50),
+            oversize: OversizePolicy::Reject,
+        }
+    }
+
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn time_budget(mut self, budget: Duration) -> Self {
+        self.time_budget = budget;
+        self
+    }
+
+    pub fn on_oversize(mut self, policy: OversizePolicy) -> Self {
+        self.oversize = policy;
+        self
+    }
+}
+
+#[async_trait]
+impl<C, I> Middleware<C> for BodyInspection<I>
+where
+    C: Send + Sync + Clone + 'static,
+    I: BodyInspector<C>,
+{
+    async fn before(&self, ctx: &C, req: &mut CoreRequest) -> Result<(), Error> {
+        // Buffering keeps the body on the request for extractors further
+        // down; `Bytes` is reference counted, so this does not copy it.
+        let body = req.body_mut().buffer().await?;
+        let truncated = body.len() > self.max_bytes;
+
+        if truncated {
+            match self.oversize {
+                OversizePolicy::Reject => return Err(Error::payload_too_large()),
+                OversizePolicy::Skip => return Ok(()),
+                OversizePolicy::InspectPrefix => {}
+            }
+        }
+
+        let view = InspectedBody {
+            bytes: &body[..body.len().min(self.max_bytes)],
+            truncated,
+            deadline: Instant::now() + self.time_budget,
+        };
+
+        self.inspector.inspect(ctx, req, &view).await?;
+        view.check_deadline()
+    }
+}
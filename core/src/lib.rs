@@ -1,21 +1,95 @@
+#[cfg(feature = "chrono")]
+pub mod access_log;
 pub mod app;
+pub mod backup;
+pub mod body;
+pub mod bot;
+pub mod cache;
+pub mod captcha;
+pub mod client_ip;
+pub mod clock;
+pub mod compose;
+pub mod compression;
+pub mod concurrency;
+pub mod conditional;
+pub mod consent;
 pub mod context;
+pub mod contract;
+pub mod cookie;
+pub mod cors;
+pub mod crypto;
+pub mod describe;
+pub mod egress;
 pub mod error;
+pub mod explain;
 pub mod extract;
+pub mod fault;
+pub mod formatter;
 pub mod handler;
+pub mod header;
+pub mod health;
+pub mod inspect;
+pub mod logging;
+pub mod magic_link;
+pub mod memory;
+pub mod metrics;
 pub mod middleware;
+pub mod negotiate;
+pub mod nonce;
+pub mod outbound;
+pub mod params;
+pub mod plugin;
+pub mod proxy;
+pub mod range;
+pub mod redirect;
+pub mod residency;
 pub mod response;
+pub mod rewrite;
+pub mod route_table;
 pub mod router;
+pub mod schema_drift;
+pub mod session;
+pub mod spa;
+pub mod sql;
+pub mod timeout;
+pub mod translate;
+pub mod validate;
+pub mod waf;
+pub mod warmup;
 
 pub use app::App;
+pub use body::Body;
+pub use cache::cached;
+pub use client_ip::{ClientIp, TrustedProxies};
+pub use conditional::{Conditional, Precondition};
 pub use context::Ctx;
 pub use error::Error;
-pub use extract::{Json, Path, Query};
-pub use handler::Handler;
-pub use response::IntoResponse;
+#[cfg(feature = "msgpack")]
+pub use extract::MsgPack;
+#[cfg(feature = "protobuf")]
+pub use extract::Proto;
+#[cfg(feature = "xml")]
+pub use extract::Xml;
+pub use extract::{FromRequest, Json, Path, Query, RequestExt, State};
+pub use formatter::{
+    ErrorHandler, ErrorRequest, JsonFormatter, NegotiatedFormatter, ResponseFormatter,
+};
+pub use handler::{Handler, IntoHandler};
+pub use header::TypedHeader;
+pub use middleware::{HandlerExt, Middleware, Next};
+pub use negotiate::{Accepts, Negotiate};
+pub use plugin::Plugin;
+pub use redirect::{Redirect, RedirectPolicy};
+pub use response::{
+    Accepted, AppendHeaders, Created, Html, IntoResponse, ResponseBuilder, Sse, SseEvent,
+};
+pub use router::{
+    PathPolicy, PercentDecoding, RouteError, RouteGroup, RouterBuilder, TrailingSlash,
+};
+pub use validate::{Validate, ValidatedJson, ValidationErrors};
 
-pub type CoreRequest = http::Request<bytes::Bytes>;
-pub type CoreResponse = http::Response<bytes::Bytes>;
+pub type CoreRequest = http::Request<Body>;
+pub type CoreResponse = http::Response<Body>;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -25,6 +99,8 @@ mod tests {
     use async_trait::async_trait;
     use http::{Method, StatusCode};
     use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     struct TestHandler {
         response: &'static str,
@@ -42,14 +118,7 @@ mod tests {
     #[async_trait]
     impl Handler<Ctx> for PathTestHandler {
         async fn call(&self, _ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
-            let params = req
-                .extensions()
-                .get::<HashMap<String, String>>()
-                .ok_or_else(|| Error::bad_request("Missing path parameters"))?;
-
-            let id = params
-                .get("id")
-                .ok_or_else(|| Error::bad_request("Missing id parameter"))?;
+            let id = req.param("id")?;
 
             let response_body = format!(r#"{{"id": "{}"}}"#, id);
             Ok(http::Response::builder()
@@ -82,13 +151,13 @@ mod tests {
         let req = http::Request::builder()
             .method(Method::GET)
             .uri("/hello")
-            .body(bytes::Bytes::new())
+            .body(Body::empty())
             .unwrap();
 
         let response = app.handle(req).await;
         assert_eq!(response.status(), StatusCode::OK);
 
-        let body = String::from_utf8_lossy(response.body());
+        let body = String::from_utf8_lossy(response.body().as_bytes().unwrap());
         assert_eq!(body, "Hello, World!");
     }
 
@@ -105,13 +174,13 @@ mod tests {
         let req = http::Request::builder()
             .method(Method::GET)
             .uri("/nonexistent")
-            .body(bytes::Bytes::new())
+            .body(Body::empty())
             .unwrap();
 
         let response = app.handle(req).await;
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
 
-        let body = String::from_utf8_lossy(response.body());
+        let body = String::from_utf8_lossy(response.body().as_bytes().unwrap());
         assert!(body.contains("Not Found"));
     }
 
@@ -123,13 +192,13 @@ mod tests {
         let req = http::Request::builder()
             .method(Method::GET)
             .uri("/error")
-            .body(bytes::Bytes::new())
+            .body(Body::empty())
             .unwrap();
 
         let response = app.handle(req).await;
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 
-        let body = String::from_utf8_lossy(response.body());
+        let body = String::from_utf8_lossy(response.body().as_bytes().unwrap());
         assert!(body.contains("error"));
     }
 
@@ -141,13 +210,13 @@ mod tests {
         let req = http::Request::builder()
             .method(Method::GET)
             .uri("/users/123")
-            .body(bytes::Bytes::new())
+            .body(Body::empty())
             .unwrap();
 
         let response = app.handle(req).await;
         assert_eq!(response.status(), StatusCode::OK);
 
-        let body = String::from_utf8_lossy(response.body());
+        let body = String::from_utf8_lossy(response.body().as_bytes().unwrap());
         assert!(body.contains(r#""id": "123""#));
     }
 
@@ -175,17 +244,5770 @@ mod tests {
             let req = http::Request::builder()
                 .method(method)
                 .uri(path)
-                .body(bytes::Bytes::new())
+                .body(Body::empty())
                 .unwrap();
 
             let response = app.handle(req).await;
             assert_eq!(response.status(), expected_status);
 
-            let body = String::from_utf8_lossy(response.body());
+            let body = String::from_utf8_lossy(response.body().as_bytes().unwrap());
             assert!(body.contains(expected_content));
         }
     }
 
+    #[tokio::test]
+    async fn test_routes_added_after_freeze() {
+        let app = App::new(Ctx::new())
+            .get("/hello", TestHandler { response: "Hello" })
+            .freeze()
+            .get("/world", TestHandler { response: "World" });
+
+        for (path, expected) in [("/hello", "Hello"), ("/world", "World")] {
+            let req = http::Request::builder()
+                .method(Method::GET)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                String::from_utf8_lossy(response.body().as_bytes().unwrap()),
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes_added_to_clone_of_built_app() {
+        let app = App::new(Ctx::new())
+            .get("/hello", TestHandler { response: "Hello" })
+            .build()
+            .unwrap();
+        let extended = app.clone().get("/world", TestHandler { response: "World" });
+
+        let get = |path: &str| {
+            http::Request::builder()
+                .uri(path)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(app.handle(get("/hello")).await.status(), StatusCode::OK);
+        assert_eq!(
+            app.handle(get("/world")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            extended.handle(get("/hello")).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            extended.handle(get("/world")).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    struct CountingHandler {
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Handler<Ctx> for CountingHandler {
+        async fn call(&self, _ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(format!("{} {}", req.uri().path(), n).into_response())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_handler() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler = cached(CountingHandler {
+            calls: calls.clone(),
+        })
+        .key("id");
+        let app = App::new(Ctx::new()).get("/users/:id", handler);
+
+        for (path, expected) in [
+            ("/users/1", "/users/1 0"),
+            ("/users/1?x=y", "/users/1 0"),
+            ("/users/2", "/users/2 1"),
+        ] {
+            let req = http::Request::builder()
+                .method(Method::GET)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(
+                String::from_utf8_lossy(response.body().as_bytes().unwrap()),
+                expected
+            );
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // The least recently used entry goes first.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler = cached(CountingHandler {
+            calls: calls.clone(),
+        })
+        .max_entries(2);
+        let app = App::new(Ctx::new()).get("/:id", handler);
+        let get = |path: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = http::Request::get(path).body(Body::empty()).unwrap();
+                let res = app.handle(req).await;
+                String::from_utf8(res.body().as_bytes().unwrap().to_vec()).unwrap()
+            }
+        };
+        assert_eq!(get("/a").await, "/a 0");
+        assert_eq!(get("/b").await, "/b 1");
+        assert_eq!(get("/a").await, "/a 0");
+        assert_eq!(get("/c").await, "/c 2");
+        assert_eq!(get("/a").await, "/a 0");
+        assert_eq!(get("/b").await, "/b 3");
+
+        // Responses meant for one client are never stored.
+        struct Personal(Arc<AtomicUsize>);
+
+        #[async_trait]
+        impl Handler<Ctx> for Personal {
+            async fn call(&self, _ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                let res = http::Response::builder();
+                let res = match req.uri().path() {
+                    "/cookie" => res.header("set-cookie", "sid=1"),
+                    "/private" => res.header("cache-control", "max-age=60, Private"),
+                    _ => res.header("cache-control", "no-store"),
+                };
+                Ok(res.body(Body::from("mine"))?)
+            }
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = App::new(Ctx::new()).get("/:kind", cached(Personal(calls.clone())));
+        for path in ["/cookie", "/private", "/no-store"] {
+            for _ in 0..2 {
+                app.handle(http::Request::get(path).body(Body::empty()).unwrap())
+                    .await;
+            }
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn test_nested_router() {
+        let users = RouterBuilder::new()
+            .get("/", TestHandler { response: "list" })
+            .get("/:id", PathTestHandler);
+        let app = App::new(Ctx::new())
+            .get("/", TestHandler { response: "root" })
+            .nest("/api/v1/users/", users);
+
+        for (path, expected_status, expected_content) in [
+            ("/", StatusCode::OK, "root"),
+            ("/api/v1/users", StatusCode::OK, "list"),
+            ("/api/v1/users/42", StatusCode::OK, r#""id": "42""#),
+            ("/users/42", StatusCode::NOT_FOUND, "Not Found"),
+        ] {
+            let req = http::Request::builder()
+                .method(Method::GET)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status);
+            assert!(String::from_utf8_lossy(response.body().as_bytes().unwrap())
+                .contains(expected_content));
+        }
+
+        // A child's fallback answers for its prefix only.
+        let mut admin = RouterBuilder::new().get("/users", TestHandler { response: "users" });
+        admin.set_fallback(Box::new(TestHandler {
+            response: "admin shell",
+        }));
+        let app = App::new(Ctx::new())
+            .get("/", TestHandler { response: "root" })
+            .nest("/admin", admin);
+        for (path, expected_status, expected_content) in [
+            ("/admin/users", StatusCode::OK, "users"),
+            ("/admin", StatusCode::OK, "admin shell"),
+            ("/admin/settings/profile", StatusCode::OK, "admin shell"),
+            ("/settings", StatusCode::NOT_FOUND, "Not Found"),
+        ] {
+            let req = http::Request::builder()
+                .method(Method::GET)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status);
+            assert!(String::from_utf8_lossy(response.body().as_bytes().unwrap())
+                .contains(expected_content));
+        }
+    }
+
+    struct PlainTextFormatter;
+
+    impl ResponseFormatter for PlainTextFormatter {
+        fn format_error(&self, error: &Error) -> CoreResponse {
+            (error.status_code(), error.safe_message()).into_response()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_formatter() {
+        let app = App::new(Ctx::new())
+            .get("/error", ErrorTestHandler)
+            .formatter(PlainTextFormatter);
+
+        for (path, expected_status, expected_body) in [
+            ("/error", StatusCode::BAD_REQUEST, "Bad Request"),
+            ("/missing", StatusCode::NOT_FOUND, "Not Found"),
+        ] {
+            let req = http::Request::builder()
+                .method(Method::GET)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status);
+            assert_eq!(
+                response.headers()["content-type"],
+                "text/plain; charset=utf-8"
+            );
+            assert_eq!(
+                String::from_utf8_lossy(response.body().as_bytes().unwrap()),
+                expected_body
+            );
+        }
+    }
+
+    #[derive(serde::Deserialize)]
+    struct StaticPath {
+        path: String,
+    }
+
+    struct StaticHandler;
+
+    #[async_trait]
+    impl Handler<Ctx> for StaticHandler {
+        async fn call(&self, _ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
+            let Path(params) = Path::<StaticPath>::extract(&req)?;
+            Ok(format!("[{}]", params.path).into_response())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_route() {
+        let app = App::new(Ctx::new()).get("/static/*path", StaticHandler);
+
+        for (path, expected_status, expected_content) in [
+            ("/static/css/site.css", StatusCode::OK, "[css/site.css]"),
+            ("/static/", StatusCode::OK, "[]"),
+            ("/assets/site.css", StatusCode::NOT_FOUND, "Not Found"),
+        ] {
+            let req = http::Request::builder()
+                .method(Method::GET)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status);
+            assert!(String::from_utf8_lossy(response.body().as_bytes().unwrap())
+                .contains(expected_content));
+        }
+    }
+
+    #[test]
+    fn test_response_builder_rejects_invalid_header() {
+        let response = ResponseBuilder::new()
+            .status(StatusCode::CREATED)
+            .header("x-name", "ok")
+            .body("created")
+            .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["x-name"], "ok");
+
+        let result = ResponseBuilder::new()
+            .header("x-name", "evil\r\nset-cookie: a=b")
+            .body("ignored");
+        assert!(matches!(result, Err(Error::Http(_))));
+        assert_eq!(result.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() {
+        let app = App::new(Ctx::new())
+            .get("/items", TestHandler { response: "list" })
+            .post("/items", TestHandler { response: "create" });
+
+        let req = http::Request::builder()
+            .method(Method::DELETE)
+            .uri("/items")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.handle(req).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()["allow"], "GET, POST, HEAD");
+
+        let req = http::Request::builder()
+            .method(Method::DELETE)
+            .uri("/other")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.handle(req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().get("allow").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_any_and_custom_methods() {
+        let report = Method::from_bytes(b"REPORT").unwrap();
+        let app = App::new(Ctx::new())
+            .any("/webhook", TestHandler { response: "any" })
+            .post("/webhook", TestHandler { response: "post" })
+            .route(
+                report.clone(),
+                "/calendar",
+                TestHandler { response: "report" },
+            );
+
+        for (method, path, expected_status, expected_content) in [
+            (Method::GET, "/webhook", StatusCode::OK, "any"),
+            (Method::POST, "/webhook", StatusCode::OK, "post"),
+            (report.clone(), "/webhook", StatusCode::OK, "any"),
+            (report, "/calendar", StatusCode::OK, "report"),
+            (
+                Method::GET,
+                "/calendar",
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method Not Allowed",
+            ),
+        ] {
+            let req = http::Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status);
+            assert!(String::from_utf8_lossy(response.body().as_bytes().unwrap())
+                .contains(expected_content));
+        }
+    }
+
+    #[test]
+    fn test_header_sanitization() {
+        use header::{content_disposition, sanitize_header_value};
+
+        assert_eq!(
+            sanitize_header_value("evil\r\nset-cookie: a=b"),
+            "evilset-cookie: a=b"
+        );
+        assert_eq!(sanitize_header_value("café 100%"), "caf%C3%A9 100%25");
+        assert_eq!(
+            content_disposition("attachment", "résumé \"final\".pdf"),
+            "attachment; filename=\"r_sum_ _final_.pdf\"; \
+             filename*=UTF-8''r%C3%A9sum%C3%A9%20%22final%22.pdf"
+        );
+    }
+
+    struct RequireHeader(&'static str);
+
+    #[async_trait]
+    impl<C: Send + Sync + Clone + 'static> Middleware<C> for RequireHeader {
+        async fn before(&self, _ctx: &C, req: &mut CoreRequest) -> Result<()> {
+            if req.headers().contains_key(self.0) {
+                Ok(())
+            } else {
+                Err(Error::unauthorized())
+            }
+        }
+
+        async fn after(&self, _ctx: &C, _req: &CoreRequest, res: &mut CoreResponse) -> Result<()> {
+            res.headers_mut()
+                .append("x-checked", http::HeaderValue::from_static(self.0));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_middleware() {
+        let app = App::new(Ctx::new())
+            .get("/public", TestHandler { response: "public" })
+            .get(
+                "/admin",
+                TestHandler { response: "admin" }
+                    .with_middleware(RequireHeader("authorization"))
+                    .with_middleware(RequireHeader("x-tenant")),
+            );
+
+        for (path, headers, expected_status) in [
+            ("/public", vec![], StatusCode::OK),
+            ("/admin", vec!["authorization"], StatusCode::UNAUTHORIZED),
+            ("/admin", vec!["authorization", "x-tenant"], StatusCode::OK),
+        ] {
+            let mut builder = http::Request::builder().method(Method::GET).uri(path);
+            for name in headers {
+                builder = builder.header(name, "1");
+            }
+            let req = builder.body(Body::empty()).unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status);
+            if path == "/admin" && expected_status == StatusCode::OK {
+                let checked: Vec<_> = response.headers().get_all("x-checked").iter().collect();
+                assert_eq!(checked, ["x-tenant", "authorization"]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_app_scope() {
+        let app = App::new(Ctx::new())
+            .get("/users", TestHandler { response: "public" })
+            .scope("/admin", |admin| {
+                admin
+                    .get("/users", TestHandler { response: "users" })
+                    .middleware(RequireHeader("authorization"))
+                    .post(
+                        "/users",
+                        TestHandler {
+                            response: "created",
+                        }
+                        .with_middleware(RequireHeader("x-tenant")),
+                    )
+            });
+
+        for (method, path, headers, expected_status) in [
+            (Method::GET, "/users", vec![], StatusCode::OK),
+            (
+                Method::GET,
+                "/admin/users",
+                vec![],
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                Method::GET,
+                "/admin/users",
+                vec!["authorization"],
+                StatusCode::OK,
+            ),
+            (
+                Method::POST,
+                "/admin/users",
+                vec!["x-tenant"],
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                Method::POST,
+                "/admin/users",
+                vec!["authorization", "x-tenant"],
+                StatusCode::OK,
+            ),
+            (Method::GET, "/admin/other", vec![], StatusCode::NOT_FOUND),
+        ] {
+            let mut builder = http::Request::builder().method(method).uri(path);
+            for name in headers {
+                builder = builder.header(name, "1");
+            }
+            let response = app.handle(builder.body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), expected_status, "{}", path);
+        }
+
+        let routes = app.describe().routes;
+        let post = routes.iter().find(|r| r.method == "POST").unwrap();
+        assert_eq!(post.path, "/admin/users");
+        assert_eq!(post.middleware, ["RequireHeader", "RequireHeader"]);
+    }
+
+    #[tokio::test]
+    async fn test_path_policy() {
+        let routes = |policy: PathPolicy| {
+            App::new(Ctx::new())
+                .path_policy(policy)
+                .get(
+                    "/users",
+                    |req: CoreRequest| async move { req.uri().to_string() },
+                )
+                .get("/files/", TestHandler { response: "files" })
+                .get(
+                    "/users/:name",
+                    |Path(name): Path<String>| async move { name },
+                )
+        };
+        let get = |app: &App, uri: &str| {
+            let req = http::Request::get(uri).body(Body::empty()).unwrap();
+            let app = app.clone();
+            async move { app.handle(req).await }
+        };
+
+        let strict = routes(PathPolicy::new());
+        assert_eq!(
+            get(&strict, "/users/").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(get(&strict, "/files").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(get(&strict, "/users/a%20b").await.body(), "a%20b");
+
+        let redirecting = routes(
+            PathPolicy::new()
+                .trailing_slash(TrailingSlash::Redirect)
+                .merge_slashes(true),
+        );
+        let res = get(&redirecting, "/users/?page=2").await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers()["location"], "/users?page=2");
+        let res = get(&redirecting, "/files").await;
+        assert_eq!(res.headers()["location"], "/files/");
+        let res = get(&redirecting, "//users//ann").await;
+        assert_eq!(res.headers()["location"], "/users/ann");
+        let res = get(&redirecting, "/users/%5Cevil.com/").await;
+        assert_eq!(res.headers()["location"], "/users/%5Cevil.com");
+        let hosts = routes(PathPolicy::new().trailing_slash(TrailingSlash::Redirect))
+            .get("/:host", TestHandler { response: "host" });
+        let res = get(&hosts, "/%5Cevil.com/").await;
+        assert_eq!(res.headers()["location"], "/%5Cevil.com");
+        for path in ["/\\evil.com/", "//evil.com/"] {
+            let res = get(&hosts, path).await;
+            assert_ne!(res.status(), StatusCode::PERMANENT_REDIRECT, "{}", path);
+        }
+        assert_eq!(get(&redirecting, "/users").await.status(), StatusCode::OK);
+        assert_eq!(
+            get(&redirecting, "/nowhere/").await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let lenient = routes(
+            PathPolicy::new()
+                .trailing_slash(TrailingSlash::Ignore)
+                .merge_slashes(true)
+                .percent_decoding(PercentDecoding::Decode),
+        );
+        assert_eq!(
+            get(&lenient, "/users/?page=2").await.body(),
+            "/users?page=2"
+        );
+        assert_eq!(get(&lenient, "/files").await.body(), "files");
+        assert_eq!(get(&lenient, "/users//a%20b%2Fc/").await.body(), "a b/c");
+        let res = get(&lenient, "/users/%FF").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_redirect_policy() {
+        let policy = RedirectPolicy::new()
+            .allow_host("app.example.com")
+            .allow_path_prefix("/dashboard");
+
+        assert!(policy.validate("/dashboard/settings").is_ok());
+        assert!(policy.validate("https://app.example.com/dashboard").is_ok());
+        assert!(policy.validate("HTTPS://App.Example.com/dashboard").is_ok());
+        for target in [
+            "/admin",
+            "//evil.example/dashboard",
+            "/\\evil.example",
+            "https://evil.example/dashboard",
+            "https://app.example.com@evil.example/dashboard",
+            "https://app.example.com/dashboard/../admin",
+            "https://app.example.com/dashboard/%2E%2E/admin",
+            "javascript:alert(1)",
+        ] {
+            assert!(policy.validate(target).is_err(), "{} was allowed", target);
+        }
+
+        let req = http::Request::builder()
+            .uri("/login?next=https%3A%2F%2Fevil.example%2F")
+            .body(Body::empty())
+            .unwrap();
+        let response = policy
+            .redirect_from_query(&req, "next", "/dashboard")
+            .into_response();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers()["location"], "/dashboard");
+    }
+
+    struct RejectWord(&'static str);
+
+    #[async_trait]
+    impl inspect::BodyInspector<Ctx> for RejectWord {
+        async fn inspect(
+            &self,
+            _ctx: &Ctx,
+            _req: &CoreRequest,
+            body: &inspect::InspectedBody<'_>,
+        ) -> Result<()> {
+            let text = String::from_utf8_lossy(body.bytes());
+            if text.contains(self.0) {
+                Err(Error::forbidden())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    struct EchoBody;
+
+    #[async_trait]
+    impl Handler<Ctx> for EchoBody {
+        async fn call(&self, _ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
+            Ok(req.into_body().into_response())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_inspection() {
+        use inspect::{BodyInspection, OversizePolicy};
+
+        let app = App::new(Ctx::new())
+            .post(
+                "/strict",
+                EchoBody.with_middleware(BodyInspection::new(RejectWord("DROP")).max_bytes(8)),
+            )
+            .post(
+                "/prefix",
+                EchoBody.with_middleware(
+                    BodyInspection::new(RejectWord("DROP"))
+                        .max_bytes(8)
+                        .on_oversize(OversizePolicy::InspectPrefix),
+                ),
+            );
+
+        for (path, body, expected_status) in [
+            ("/strict", "hello", StatusCode::OK),
+            ("/strict", "DROP", StatusCode::FORBIDDEN),
+            ("/strict", "hello world", StatusCode::PAYLOAD_TOO_LARGE),
+            ("/prefix", "hello world DROP", StatusCode::OK),
+            ("/prefix", "DROP hello world", StatusCode::FORBIDDEN),
+        ] {
+            let req = http::Request::builder()
+                .method(Method::POST)
+                .uri(path)
+                .body(Body::from(body))
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status);
+            if expected_status == StatusCode::OK {
+                assert_eq!(response.body(), body.as_bytes());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_global_middleware() {
+        let app = App::new(Ctx::new())
+            .get("/hello", TestHandler { response: "Hello" })
+            .middleware(RequireHeader("authorization"));
+
+        for (path, authorized, expected_status) in [
+            ("/hello", false, StatusCode::UNAUTHORIZED),
+            ("/hello", true, StatusCode::OK),
+            ("/missing", true, StatusCode::NOT_FOUND),
+        ] {
+            let mut builder = http::Request::builder().method(Method::GET).uri(path);
+            if authorized {
+                builder = builder.header("authorization", "1");
+            }
+            let req = builder.body(Body::empty()).unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status);
+            assert_eq!(response.headers().contains_key("x-checked"), authorized);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_waf_rules() {
+        use waf::{Waf, WafRuleSet};
+
+        let app = App::new(Ctx::new())
+            .get(
+                "/search",
+                TestHandler {
+                    response: "results",
+                },
+            )
+            .post("/search", EchoBody)
+            .middleware(Waf::new(WafRuleSet::recommended()));
+
+        for (method, uri, body, expected_status) in [
+            (Method::GET, "/search?q=rust", "", StatusCode::OK),
+            (
+                Method::GET,
+                "/search?q=1%27+OR+%271%27%3D%271",
+                "",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                Method::GET,
+                "/search/%2e%2e/%2e%2e/etc",
+                "",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                Method::POST,
+                "/search",
+                "<SCRIPT>alert(1)</script>",
+                StatusCode::FORBIDDEN,
+            ),
+            (Method::TRACE, "/search", "", StatusCode::FORBIDDEN),
+        ] {
+            let req = http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::from(body))
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status, "{}", uri);
+        }
+
+        let rules = WafRuleSet::from_json(
+            br#"{"rules":[{"id":"ua","targets":["headers"],"match":"contains_any",
+                "patterns":["sqlmap"],"action":{"score":4}}],"block_score":4}"#,
+        )
+        .unwrap();
+        let req = http::Request::builder()
+            .header("user-agent", "sqlmap/1.7")
+            .body(Body::empty())
+            .unwrap();
+        let verdict = rules.evaluate(&req);
+        assert!(verdict.blocked);
+        assert_eq!(verdict.matched, ["ua"]);
+    }
+
+    struct Maintenance;
+
+    #[async_trait]
+    impl Middleware<Ctx> for Maintenance {
+        async fn handle(
+            &self,
+            ctx: Ctx,
+            req: CoreRequest,
+            next: Next<'_, Ctx>,
+        ) -> Result<CoreResponse> {
+            if req.uri().path().starts_with("/admin") {
+                return Ok((StatusCode::SERVICE_UNAVAILABLE, "maintenance").into_response());
+            }
+            let mut res = next.run(ctx, req).await?;
+            res.headers_mut()
+                .insert("x-wrapped", http::HeaderValue::from_static("1"));
+            Ok(res)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_onion_middleware() {
+        let app = App::new(Ctx::new())
+            .get("/hello", TestHandler { response: "Hello" })
+            .get("/admin", TestHandler { response: "admin" })
+            .middleware(Maintenance)
+            .middleware(RequireHeader("authorization"));
+
+        let req = http::Request::builder()
+            .uri("/admin")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.handle(req).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            String::from_utf8_lossy(response.body().as_bytes().unwrap()),
+            "maintenance"
+        );
+
+        let req = http::Request::builder()
+            .uri("/hello")
+            .header("authorization", "1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.handle(req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-wrapped"], "1");
+        assert_eq!(response.headers()["x-checked"], "authorization");
+    }
+
+    struct DecisionHandler;
+
+    #[async_trait]
+    impl Handler<Ctx> for DecisionHandler {
+        async fn call(&self, _ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
+            let decision = req
+                .extensions()
+                .get::<bot::BotDecision>()
+                .ok_or_else(|| Error::internal("missing bot decision"))?;
+            Ok(format!("{:?}", decision.action).into_response())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bot_policy() {
+        use bot::{BotPolicy, PlatformBotScore};
+        use extract::RemoteAddr;
+        use std::time::Duration;
+
+        let app = App::new(Ctx::new())
+            .get("/", DecisionHandler)
+            .middleware(BotPolicy::new());
+
+        let cases = [
+            (Some("Mozilla/5.0"), None, StatusCode::OK),
+            (None, None, StatusCode::OK),
+            (Some("curl/8.0"), None, StatusCode::TOO_MANY_REQUESTS),
+            (Some("curl/8.0"), Some(5), StatusCode::FORBIDDEN),
+            (Some("Mozilla/5.0"), Some(95), StatusCode::OK),
+        ];
+        for (user_agent, platform_score, expected_status) in cases {
+            let mut builder = http::Request::builder().uri("/");
+            if let Some(ua) = user_agent {
+                builder = builder.header("user-agent", ua);
+            }
+            if let Some(score) = platform_score {
+                builder = builder.extension(PlatformBotScore {
+                    score,
+                    verified_bot: false,
+                });
+            }
+            let req = builder.body(Body::empty()).unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status);
+        }
+
+        // The rate signal follows the peer address, not client headers.
+        let clock = clock::ManualClock::at_unix(1_700_000_000);
+        let app = App::new(Ctx::new()).get("/", DecisionHandler).middleware(
+            BotPolicy::new()
+                .rate_signal(1, Duration::from_secs(60))
+                .max_clients(1)
+                .clock(Arc::new(clock.clone())),
+        );
+        let get = |peer: &str, forwarded_for: &str| {
+            let req = http::Request::builder()
+                .uri("/")
+                .header("user-agent", "Mozilla/5.0")
+                .header("x-forwarded-for", forwarded_for)
+                .extension(RemoteAddr(peer.parse().unwrap()))
+                .body(Body::empty())
+                .unwrap();
+            app.handle(req)
+        };
+        assert_eq!(get("192.0.2.1:1", "1.1.1.1").await.status(), StatusCode::OK);
+        assert_eq!(
+            get("192.0.2.1:2", "2.2.2.2").await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // One client at most: a new one evicts the last.
+        assert_eq!(get("192.0.2.2:1", "1.1.1.1").await.status(), StatusCode::OK);
+        assert_eq!(get("192.0.2.1:3", "1.1.1.1").await.status(), StatusCode::OK);
+    }
+
+    async fn greet(_ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
+        Ok(format!("greetings from {}", req.uri().path()).into_response())
+    }
+
+    #[tokio::test]
+    async fn test_function_handlers() {
+        let suffix = String::from("!");
+        let app = App::new(Ctx::new()).get("/fn", greet).get(
+            "/closure",
+            move |_ctx: Ctx, _req: CoreRequest| {
+                let body = format!("hi{}", suffix);
+                async move { Ok(body.into_response()) }
+            },
+        );
+
+        for (path, expected) in [("/fn", "greetings from /fn"), ("/closure", "hi!")] {
+            let req = http::Request::builder()
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                String::from_utf8_lossy(response.body().as_bytes().unwrap()),
+                expected
+            );
+        }
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Greeting {
+        greeting: String,
+    }
+
+    #[derive(serde::Serialize)]
+    struct User {
+        id: String,
+        greeting: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct UserId {
+        id: String,
+    }
+
+    async fn get_user(
+        Path(UserId { id }): Path<UserId>,
+        Query(q): Query<Greeting>,
+    ) -> Result<Json<User>> {
+        Ok(Json(User {
+            id,
+            greeting: q.greeting,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_extractor_handlers() {
+        let app = App::new(Ctx::new())
+            .get("/users/:id", get_user)
+            .get("/ping", || async { "pong" })
+            .post("/echo", |Json(value): Json<serde_json::Value>| async move {
+                Json(value)
+            });
+
+        for (method, uri, body, expected_status, expected_body) in [
+            (
+                Method::GET,
+                "/users/7?greeting=hi",
+                "",
+                StatusCode::OK,
+                r#"{"id":"7","greeting":"hi"}"#,
+            ),
+            (
+                Method::GET,
+                "/users/7",
+                "",
+                StatusCode::BAD_REQUEST,
+                "error",
+            ),
+            (Method::GET, "/ping", "", StatusCode::OK, "pong"),
+            (
+                Method::POST,
+                "/echo",
+                r#"{"a":1}"#,
+                StatusCode::OK,
+                r#"{"a":1}"#,
+            ),
+            (Method::POST, "/echo", "{", StatusCode::BAD_REQUEST, "error"),
+        ] {
+            let req = http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::from(body))
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status, "{}", uri);
+            assert!(String::from_utf8_lossy(response.body().as_bytes().unwrap())
+                .contains(expected_body));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_extractors_read_lazily() {
+        use body::Chunks;
+        use extract::BodyStream;
+
+        let app = App::new(Ctx::new())
+            .body_limit(8)
+            .post("/echo", |Json(value): Json<serde_json::Value>| async move {
+                Json(value)
+            })
+            .post("/twice", |a: bytes::Bytes, b: String| async move {
+                format!("{}|{}", a.len(), b)
+            })
+            .post("/ignore", || async { "ignored" })
+            .post("/upload", |BodyStream(body): BodyStream| async move {
+                let streamed = body.is_stream();
+                let len = body.collect().await?.len();
+                Ok::<_, Error>(format!("{} {}", streamed, len))
+            });
+        let send = |uri: &str, chunks: Vec<&'static str>| {
+            let req = http::Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .body(Body::from_stream(Chunks(chunks.into())))
+                .unwrap();
+            app.handle(req)
+        };
+
+        let res = send("/echo", vec![r#"{"a""#, ":1}"]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), r#"{"a":1}"#);
+        let res = send("/twice", vec!["ab", "c"]).await;
+        assert_eq!(res.body(), "3|abc");
+        let res = send("/echo", vec![r#"{"a":"#, r#""too long"}"#]).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Handlers without a body extractor, or taking the stream, are not
+        // held to the limit.
+        let res = send("/ignore", vec!["0123456789", "0123456789"]).await;
+        assert_eq!(res.body(), "ignored");
+        let res = send("/upload", vec!["0123456789", "0123456789"]).await;
+        assert_eq!(res.body(), "true 20");
+    }
+
+    struct FakeSiteverify;
+
+    #[async_trait]
+    impl context::HttpClient for FakeSiteverify {
+        async fn send(&self, req: CoreRequest) -> Result<CoreResponse> {
+            assert_eq!(req.uri(), captcha::CaptchaProvider::Turnstile.verify_url());
+            let form = String::from_utf8_lossy(req.body().as_bytes().unwrap()).into_owned();
+            let success = form.contains("response=good") && form.contains("secret=s3cret");
+            Ok(Json(serde_json::json!({ "success": success })).into_response())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_require_captcha() {
+        use captcha::{CaptchaProvider, CaptchaVerifier, RequireCaptcha};
+
+        let verifier = CaptchaVerifier::new(CaptchaProvider::Turnstile, "s3cret");
+        let app = App::new(Ctx::with_http(Arc::new(FakeSiteverify))).post(
+            "/signup",
+            TestHandler {
+                response: "welcome",
+            }
+            .with_middleware(RequireCaptcha::new(verifier)),
+        );
+
+        for (body, expected_status) in [
+            ("email=a%40b.c&cf-turnstile-response=good", StatusCode::OK),
+            (
+                "email=a%40b.c&cf-turnstile-response=bad",
+                StatusCode::FORBIDDEN,
+            ),
+            ("email=a%40b.c", StatusCode::BAD_REQUEST),
+        ] {
+            let req = http::Request::builder()
+                .method(Method::POST)
+                .uri("/signup")
+                .body(Body::from(body))
+                .unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status, "{}", body);
+        }
+    }
+
+    struct TenantId(String);
+
+    #[async_trait]
+    impl FromRequest<Ctx> for TenantId {
+        async fn from_request(ctx: &Ctx, req: &CoreRequest) -> Result<Self> {
+            if ctx.kv.is_some() {
+                return Err(Error::internal("unexpected kv"));
+            }
+            req.headers()
+                .get("x-tenant")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| TenantId(v.to_string()))
+                .ok_or_else(Error::unauthorized)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_extractor() {
+        let app = App::new(Ctx::new()).post(
+            "/notes",
+            |TenantId(tenant): TenantId, method: Method, body: String| async move {
+                format!("{} {} {}", tenant, method, body)
+            },
+        );
+
+        let req = http::Request::builder()
+            .method(Method::POST)
+            .uri("/notes")
+            .header("x-tenant", "acme")
+            .body(Body::from("hello"))
+            .unwrap();
+        let response = app.handle(req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            String::from_utf8_lossy(response.body().as_bytes().unwrap()),
+            "acme POST hello"
+        );
+
+        let req = http::Request::builder()
+            .method(Method::POST)
+            .uri("/notes")
+            .body(Body::from("hello"))
+            .unwrap();
+        let response = app.handle(req).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[derive(Default)]
+    struct MemoryKv {
+        entries: std::sync::Mutex<HashMap<String, bytes::Bytes>>,
+    }
+
+    #[async_trait]
+    impl context::Kv for MemoryKv {
+        async fn get(&self, key: &str) -> Option<bytes::Bytes> {
+            self.entries.lock().unwrap().get(key).cloned()
+        }
+
+        async fn put(
+            &self,
+            key: &str,
+            value: bytes::Bytes,
+        ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            self.entries.lock().unwrap().insert(key.to_string(), value);
+            Ok(())
+        }
+
+        async fn list(
+            &self,
+            prefix: &str,
+        ) -> std::result::Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+            let mut keys: Vec<String> = self
+                .entries
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|k| k.starts_with(prefix))
+                .cloned()
+                .collect();
+            keys.sort();
+            Ok(keys)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nonce_replay_protection() {
+        use nonce::{NonceGuard, NonceSource};
+
+        let app = App::new(Ctx::with_kv(Arc::new(MemoryKv::default())))
+            .post(
+                "/header",
+                TestHandler { response: "ok" }.with_middleware(NonceGuard::header("x-nonce")),
+            )
+            .post(
+                "/body",
+                TestHandler { response: "ok" }
+                    .with_middleware(NonceGuard::new(NonceSource::JsonField("nonce".to_string()))),
+            );
+
+        for (path, nonce, body, expected_status) in [
+            ("/header", Some("abc"), "", StatusCode::OK),
+            ("/header", Some("abc"), "", StatusCode::CONFLICT),
+            ("/header", Some("def"), "", StatusCode::OK),
+            ("/header", None, "", StatusCode::BAD_REQUEST),
+            ("/body", None, r#"{"nonce":"n1"}"#, StatusCode::OK),
+            ("/body", None, r#"{"nonce":"n1"}"#, StatusCode::CONFLICT),
+        ] {
+            let mut builder = http::Request::builder().method(Method::POST).uri(path);
+            if let Some(nonce) = nonce {
+                builder = builder.header("x-nonce", nonce);
+            }
+            let req = builder.body(Body::from(body)).unwrap();
+
+            let response = app.handle(req).await;
+            assert_eq!(response.status(), expected_status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_kv() {
+        use context::Kv;
+        use crypto::{EncryptedKv, StaticKeys};
+
+        let store = Arc::new(MemoryKv::default());
+        let old = EncryptedKv::new(store.clone(), Arc::new(StaticKeys::new("k1", [1; 32])));
+        old.put_encrypted("user:1:email", "alice@example.com")
+            .await
+            .unwrap();
+
+        let raw = store.get("user:1:email").await.unwrap();
+        assert!(!raw.windows(5).any(|w| w == b"alice"));
+
+        // Rotated keys still read values sealed with a retired key.
+        let rotated = EncryptedKv::new(
+            store.clone(),
+            Arc::new(StaticKeys::new("k2", [2; 32]).with_key("k1", [1; 32])),
+        );
+        let email: Option<String> = rotated.get_encrypted("user:1:email").await.unwrap();
+        assert_eq!(email.as_deref(), Some("alice@example.com"));
+
+        // Ciphertext is bound to its key.
+        store.put("user:2:email", raw).await.unwrap();
+        assert!(rotated.get("user:2:email").await.is_none());
+        assert!(rotated
+            .get_encrypted::<String>("user:2:email")
+            .await
+            .is_err());
+
+        rotated.put("plain", bytes::Bytes::from("v")).await.unwrap();
+        assert_eq!(rotated.get("plain").await.unwrap(), "v");
+        assert!(old.get("plain").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_state_extractor() {
+        use extract::State;
+
+        #[derive(Clone)]
+        struct Pool(&'static str);
+        #[derive(Clone)]
+        struct Config {
+            greeting: &'static str,
+        }
+
+        async fn hello(State(pool): State<Pool>, State(config): State<Config>) -> String {
+            format!("{} from {}", config.greeting, pool.0)
+        }
+
+        async fn missing(State(_): State<u64>) -> &'static str {
+            "unreachable"
+        }
+
+        let app = App::with_default_context()
+            .with_state(Pool("primary"))
+            .with_state(Config { greeting: "hello" })
+            .get("/", hello)
+            .get("/missing", missing);
+
+        let req = http::Request::builder()
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.handle(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "hello from primary");
+
+        let req = http::Request::builder()
+            .uri("/missing")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.handle(req).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_kv_export_import() {
+        use backup::{ConflictPolicy, KvExport, KvImport};
+        use context::Kv;
+
+        let source = Arc::new(MemoryKv::default());
+        source.put("user:1", "alice".into()).await.unwrap();
+        source
+            .put("user:2", vec![0u8, 159, 146].into())
+            .await
+            .unwrap();
+        source.put("session:1", "s".into()).await.unwrap();
+
+        let export = App::new(Ctx::with_kv(source.clone())).get("/export", KvExport);
+        let req = http::Request::builder()
+            .uri("/export?prefix=user:")
+            .body(Body::empty())
+            .unwrap();
+        let res = export.handle(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/x-ndjson");
+        assert!(res.body().is_stream());
+        let dump = res.into_body().collect().await.unwrap();
+        assert_eq!(
+            dump.split(|b| *b == b'\n')
+                .filter(|l| !l.is_empty())
+                .count(),
+            2
+        );
+
+        let target = Arc::new(MemoryKv::default());
+        target.put("user:1", "existing".into()).await.unwrap();
+        let import = App::new(Ctx::with_kv(target.clone()))
+            .post("/import", KvImport::new(ConflictPolicy::Fail));
+        let import_req = |uri: &str| {
+            http::Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .body(Body::from(dump.clone()))
+                .unwrap()
+        };
+
+        let res = import.handle(import_req("/import")).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert!(target.get("user:2").await.is_none());
+
+        let res = import.handle(import_req("/import?conflict=skip")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), r#"{"imported":1,"skipped":1}"#);
+        assert_eq!(target.get("user:1").await.unwrap(), "existing");
+        assert_eq!(target.get("user:2").await.unwrap(), vec![0u8, 159, 146]);
+
+        let res = import
+            .handle(import_req("/import?conflict=overwrite"))
+            .await;
+        assert_eq!(res.body(), r#"{"imported":2,"skipped":0}"#);
+        assert_eq!(target.get("user:1").await.unwrap(), "alice");
+    }
+
+    #[derive(Default)]
+    struct FakeSql {
+        statements: std::sync::Mutex<Vec<(String, Vec<serde_json::Value>)>>,
+        rows: Vec<sql::Row>,
+        affected: u64,
+        pool: Option<sql::AcquireWaits>,
+    }
+
+    #[async_trait]
+    impl sql::Sql for FakeSql {
+        async fn query(&self, sql: &str, params: &[serde_json::Value]) -> Result<Vec<sql::Row>> {
+            self.statements
+                .lock()
+                .unwrap()
+                .push((sql.to_string(), params.to_vec()));
+            Ok(self.rows.clone())
+        }
+
+        async fn execute(&self, sql: &str, params: &[serde_json::Value]) -> Result<u64> {
+            self.statements
+                .lock()
+                .unwrap()
+                .push((sql.to_string(), params.to_vec()));
+            Ok(self.affected)
+        }
+
+        fn pool_stats(&self) -> Option<sql::PoolStats> {
+            let waits = self.pool.as_ref()?;
+            Some(sql::PoolStats {
+                size: 4,
+                idle: 1,
+                waiting: 0,
+                max_size: 8,
+                acquires: waits.acquires(),
+                acquire_wait_micros: waits.wait_micros(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repository() {
+        use serde_json::json;
+        use sql::{Entity, Placeholder, Repository};
+
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Account {
+            id: u32,
+            email: String,
+            version: u32,
+        }
+
+        impl Entity for Account {
+            const TABLE: &'static str = "accounts";
+            const VERSION: Option<&'static str> = Some("version");
+        }
+
+        let account = Account {
+            id: 7,
+            email: "a@example.com".to_string(),
+            version: 3,
+        };
+
+        let db = Arc::new(FakeSql {
+            rows: vec![json!({"id": 7, "email": "a@example.com", "version": 3})
+                .as_object()
+                .unwrap()
+                .clone()],
+            affected: 1,
+            ..Default::default()
+        });
+        let repo = Repository::<Account>::new(db.clone()).placeholder(Placeholder::Numbered);
+
+        assert_eq!(repo.find(7).await.unwrap(), Some(account));
+        let account = repo.find(7).await.unwrap().unwrap();
+        repo.insert(&account).await.unwrap();
+        repo.update(&account).await.unwrap();
+        assert!(repo.delete(7).await.unwrap());
+
+        let statements = db.statements.lock().unwrap().clone();
+        assert_eq!(
+            statements[0],
+            (
+                "SELECT * FROM accounts WHERE id = $1".to_string(),
+                vec![json!(7)]
+            )
+        );
+        assert_eq!(
+            statements[2],
+            (
+                "INSERT INTO accounts (email, id, version) VALUES ($1, $2, $3)".to_string(),
+                vec![json!("a@example.com"), json!(7), json!(3)]
+            )
+        );
+        assert_eq!(
+            statements[3],
+            (
+                "UPDATE accounts SET email = $1, version = version + 1 WHERE id = $2 AND version = $3"
+                    .to_string(),
+                vec![json!("a@example.com"), json!(7), json!(3)]
+            )
+        );
+        assert_eq!(statements[4].0, "DELETE FROM accounts WHERE id = $1");
+
+        // A stale version updates nothing.
+        let stale = Repository::<Account>::new(Arc::new(FakeSql::default()));
+        let err = stale.update(&account).await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_includes_sql() {
+        let req = || {
+            http::Request::builder()
+                .uri("/ready")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let app = App::with_default_context().get("/ready", health::Readiness);
+        let res = app.handle(req()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), r#"{"checks":{},"status":"ready"}"#);
+
+        let mut ctx = Ctx::new();
+        ctx.sql = Some(Arc::new(FakeSql::default()));
+        let app = App::new(ctx).get("/ready", health::Readiness);
+        let res = app.handle(req()).await;
+        let body: serde_json::Value =
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body["checks"]["sql"]["ok"], true);
+        assert!(body["checks"]["sql"]["pool"].is_null());
+
+        let waits = sql::AcquireWaits::new();
+        waits.record(std::time::Duration::from_millis(3));
+        waits.record(std::time::Duration::from_millis(2));
+        let mut ctx = Ctx::new();
+        ctx.sql = Some(Arc::new(FakeSql {
+            pool: Some(waits),
+            ..Default::default()
+        }));
+        let app = App::new(ctx).get("/ready", health::Readiness);
+        let res = app.handle(req()).await;
+        let body: serde_json::Value =
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(body["checks"]["sql"]["pool"]["acquires"], 2);
+        assert_eq!(body["checks"]["sql"]["pool"]["acquire_wait_micros"], 5000);
+    }
+
+    #[tokio::test]
+    async fn test_cookies() {
+        use cookie::{CookieJar, Cookies, SameSite, SetCookie};
+
+        async fn login(cookies: Cookies) -> (CookieJar, String) {
+            let theme = cookies.get("theme").unwrap_or("none").to_string();
+            let jar = CookieJar::new()
+                .set(
+                    SetCookie::new("session", "abc123")
+                        .path("/")
+                        .http_only(true)
+                        .same_site(SameSite::None)
+                        .max_age(std::time::Duration::from_secs(3600)),
+                )
+                .remove("legacy");
+            (jar, theme)
+        }
+
+        let app = App::with_default_context().post("/login", login);
+        let req = http::Request::builder()
+            .method(Method::POST)
+            .uri("/login")
+            .header("cookie", "theme=\"dark\"; lang=en")
+            .header("cookie", "theme=light")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.handle(req).await;
+
+        assert_eq!(res.body(), "dark");
+        let set: Vec<_> = res.headers().get_all("set-cookie").iter().collect();
+        assert_eq!(
+            set,
+            [
+                "session=abc123; Path=/; Max-Age=3600; Secure; HttpOnly; SameSite=None",
+                "legacy=; Max-Age=0",
+            ]
+        );
+
+        let bad = (CookieJar::new().set(SetCookie::new("id", "a;b")), "ok").into_response();
+        assert_eq!(bad.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_read_write_split() {
+        use sql::{PinAfterWrite, ReadWriteSplit, Sql};
+
+        async fn save(ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
+            let db = ctx.sql()?;
+            db.query("SELECT * FROM notes", &[]).await?;
+            if req.method() == Method::POST {
+                db.execute("INSERT INTO notes (body) VALUES (?)", &[])
+                    .await?;
+                db.query("SELECT * FROM notes", &[]).await?;
+            }
+            Ok(http::Response::new(Body::empty()))
+        }
+
+        let primary = Arc::new(FakeSql::default());
+        let replica = Arc::new(FakeSql::default());
+        let split = ReadWriteSplit::new(primary.clone()).replica(replica.clone());
+        assert!(split.query("SELECT * FROM t FOR UPDATE", &[]).await.is_ok());
+
+        let app = App::with_default_context()
+            .middleware(PinAfterWrite::new(split))
+            .get("/notes", save)
+            .post("/notes", save);
+        for method in [Method::GET, Method::POST] {
+            let req = http::Request::builder()
+                .method(method)
+                .uri("/notes")
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(app.handle(req).await.status(), StatusCode::OK);
+        }
+
+        let count = |db: &FakeSql| db.statements.lock().unwrap().len();
+        // Both initial reads hit the replica; the write and the read after it
+        // stay on the primary.
+        assert_eq!(count(&replica), 2);
+        assert_eq!(count(&primary), 3);
+    }
+
+    #[tokio::test]
+    async fn test_typed_headers() {
+        use header::{Authorization, ByteRange, ContentType, IfNoneMatch, Range};
+
+        async fn inspect(
+            TypedHeader(auth): TypedHeader<Authorization>,
+            TypedHeader(content_type): TypedHeader<ContentType>,
+        ) -> String {
+            let user = match auth {
+                Authorization::Basic { username, .. } => username,
+                other => format!("{:?}", other),
+            };
+            format!(
+                "{} {} {:?}",
+                user,
+                content_type.mime,
+                content_type.charset()
+            )
+        }
+
+        let app = App::with_default_context().post("/", inspect);
+        let req = |auth: &str| {
+            http::Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header("authorization", auth)
+                .header("content-type", "Application/JSON; charset=\"utf-8\"")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app.handle(req("Basic YWxpY2U6czNjcmV0")).await;
+        assert_eq!(res.body(), "alice application/json Some(\"utf-8\")");
+
+        let res = app.handle(req("Basic !!!")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value =
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            body["error"],
+            "Bad request: Invalid authorization header: Basic credentials are not valid base64 UTF-8"
+        );
+
+        let missing = http::Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.handle(missing).await.status(), StatusCode::BAD_REQUEST);
+
+        let range = http::Request::builder()
+            .header("range", "bytes=0-99, 500-, -20")
+            .header("if-none-match", "W/\"a\", \"b\"")
+            .body(Body::empty())
+            .unwrap();
+        let TypedHeader(Range { ranges }) = TypedHeader::<Range>::extract(&range).unwrap();
+        assert_eq!(
+            ranges,
+            [
+                ByteRange::FromTo(0, 99),
+                ByteRange::From(500),
+                ByteRange::Suffix(20)
+            ]
+        );
+        let TypedHeader(tags) = TypedHeader::<IfNoneMatch>::extract(&range).unwrap();
+        assert!(tags.matches("\"a\""));
+        assert!(!tags.matches("\"c\""));
+    }
+
+    /// Fails with `503` until `failures` calls have been made, echoing the
+    /// request headers back as JSON.
+    struct FlakyUpstream {
+        failures: usize,
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl context::HttpClient for FlakyUpstream {
+        async fn send(&self, req: CoreRequest) -> Result<CoreResponse> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response());
+            }
+            let headers: HashMap<_, _> = req
+                .headers()
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
+                .collect();
+            Ok(Json(headers).into_response())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbound_interceptors() {
+        use outbound::{InterceptedClient, OutboundMetrics, PropagateHeaders, Retry, SetHeader};
+        use std::sync::Mutex;
+        use std::time::Duration;
+
+        async fn call_upstream(ctx: Ctx, _req: CoreRequest) -> Result<CoreResponse> {
+            let req = http::Request::builder()
+                .uri("https://upstream.example/")
+                .body(Body::empty())?;
+            ctx.http()?.send(req).await
+        }
+
+        let upstream = Arc::new(FlakyUpstream {
+            failures: 2,
+            calls: AtomicUsize::new(0),
+        });
+        let metrics = OutboundMetrics::new();
+        let delays = Arc::new(Mutex::new(Vec::new()));
+        let slept = delays.clone();
+        let retry = Retry::new(3, move |delay| {
+            slept.lock().unwrap().push(delay);
+            std::future::ready(())
+        })
+        .base_delay(Duration::from_millis(100))
+        .max_delay(Duration::from_millis(150))
+        .rng(Arc::new(clock::SeededRng::new(7)));
+        let client = InterceptedClient::new(upstream.clone())
+            .interceptor(metrics.clone())
+            .interceptor(SetHeader::bearer("t0ken").unwrap())
+            .interceptor(retry);
+
+        let app = App::new(Ctx::with_http(Arc::new(client)))
+            .middleware(PropagateHeaders::default())
+            .get("/", call_upstream);
+        let req = http::Request::builder()
+            .uri("/")
+            .header("traceparent", "00-abc-def-01")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.handle(req).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        let sent: HashMap<String, String> =
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(sent["authorization"], "Bearer t0ken");
+        assert_eq!(sent["traceparent"], "00-abc-def-01");
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 3);
+
+        // Retries happen inside the metrics interceptor.
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.requests, snapshot.failures), (1, 0));
+
+        // Jittered below 100ms, then below 200ms capped at 150ms.
+        let delays = delays.lock().unwrap();
+        assert_eq!(delays.len(), 2);
+        assert!(delays[0] <= Duration::from_millis(100));
+        assert!(delays[1] <= Duration::from_millis(150));
+        assert_ne!(delays[0], delays[1]);
+    }
+
+    #[tokio::test]
+    async fn test_propagate_context() {
+        use outbound::{PropagateContext, RequestContext};
+        use std::time::Duration;
+
+        async fn call_upstream(ctx: Ctx, _req: CoreRequest) -> Result<CoreResponse> {
+            let req = http::Request::builder()
+                .uri("https://orders.internal/")
+                .body(Body::empty())?;
+            ctx.http()?.send(req).await
+        }
+
+        let upstream = Arc::new(FlakyUpstream {
+            failures: 0,
+            calls: AtomicUsize::new(0),
+        });
+        let app = App::new(Ctx::with_http(upstream.clone()))
+            .middleware(
+                PropagateContext::new()
+                    .auth_from(|req| {
+                        Some(format!(
+                            "user:{}",
+                            req.headers().get("x-user")?.to_str().ok()?
+                        ))
+                    })
+                    .default_budget(Duration::from_secs(2)),
+            )
+            .get("/", call_upstream);
+        let send = |headers: &[(&str, &str)]| {
+            let mut builder = http::Request::get("/");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            app.handle(builder.body(Body::empty()).unwrap())
+        };
+        let sent = |res: CoreResponse| -> HashMap<String, String> {
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap()
+        };
+
+        let res = send(&[
+            ("x-request-id", "req-1"),
+            ("x-tenant-id", "acme"),
+            ("x-request-budget-ms", "5000"),
+            ("x-user", "7"),
+            ("x-auth-context", "user:root"),
+        ])
+        .await;
+        let headers = sent(res);
+        assert_eq!(headers["x-request-id"], "req-1");
+        assert_eq!(headers["x-tenant-id"], "acme");
+        assert_eq!(headers["x-auth-context"], "user:7");
+        let budget: u64 = headers["x-request-budget-ms"].parse().unwrap();
+        assert!(budget > 4000 && budget <= 5000, "{}", budget);
+
+        let headers = sent(send(&[]).await);
+        assert_eq!(headers["x-request-id"].len(), 36);
+        assert!(!headers.contains_key("x-tenant-id"));
+        assert!(!headers.contains_key("x-auth-context"));
+        let budget: u64 = headers["x-request-budget-ms"].parse().unwrap();
+        assert!(budget > 1000 && budget <= 2000, "{}", budget);
+
+        // A spent budget fails the call without sending it.
+        let calls = upstream.calls.load(Ordering::SeqCst);
+        let res = send(&[("x-request-budget-ms", "0")]).await;
+        assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), calls);
+
+        // The receiving service picks the context back up.
+        let receiver = App::new(Ctx::new()).get("/", |context: RequestContext| async move {
+            format!(
+                "{:?} {:?} {}",
+                context.request_id,
+                context.tenant,
+                context
+                    .remaining()
+                    .is_some_and(|r| r <= Duration::from_millis(300))
+            )
+        });
+        let req = http::Request::get("/")
+            .header("x-request-id", "req-1")
+            .header("x-tenant-id", "acme")
+            .header("x-request-budget-ms", "300")
+            .body(Body::empty())
+            .unwrap();
+        let res = receiver.handle(req).await;
+        assert_eq!(res.body(), "Some(\"req-1\") Some(\"acme\") true");
+    }
+
+    use crate::body::Chunks;
+
+    #[tokio::test]
+    async fn test_streaming_bodies() {
+        async fn count_bytes(_ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
+            assert!(req.body().is_stream());
+            let len = req.into_body().collect_limited(8).await?.len();
+            Ok(len.to_string().into_response())
+        }
+
+        async fn echo(body: String) -> String {
+            body
+        }
+
+        let app = App::with_default_context()
+            .post("/count", count_bytes)
+            .post("/echo", echo)
+            .get("/download", |_ctx: Ctx, _req: CoreRequest| async {
+                Ok(Body::from_stream(Chunks(["a", "b", "c"].into())).into_response())
+            });
+        let upload = |uri: &str, chunks: &[&'static str]| {
+            http::Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .body(Body::from_stream(Chunks(chunks.iter().copied().collect())))
+                .unwrap()
+        };
+
+        let res = app.handle(upload("/count", &["abc", "de"])).await;
+        assert_eq!(res.body(), "5");
+        let res = app.handle(upload("/count", &["abcde", "fghij"])).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res = app.handle(upload("/echo", &["hello ", "world"])).await;
+        assert_eq!(res.body(), "hello world");
+
+        let req = http::Request::builder()
+            .uri("/download")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.handle(req).await;
+        assert!(res.body().is_stream());
+        assert_eq!(res.into_body().collect().await.unwrap(), "abc");
+    }
+
+    /// Answers with the URI it was sent to, so tests can see which upstream
+    /// a proxied request reached.
+    struct EchoUpstream;
+
+    #[async_trait]
+    impl context::HttpClient for EchoUpstream {
+        async fn send(&self, req: CoreRequest) -> Result<CoreResponse> {
+            if req.uri().host() == Some("consul") {
+                let entries = serde_json::json!([
+                    {"Node": {"Address": "10.0.0.1"}, "Service": {"Address": "", "Port": 8080}},
+                    {"Node": {"Address": "10.0.0.1"}, "Service": {"Address": "10.0.0.2", "Port": 9090}}
+                ]);
+                return Ok(Json(entries).into_response());
+            }
+            let mut res = req.uri().to_string().into_response();
+            res.headers_mut()
+                .insert("connection", http::HeaderValue::from_static("close"));
+            Ok(res)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_resolution_and_balancing() {
+        use proxy::{Balance, ConsulResolver, Proxy, Resolver, StaticResolver};
+
+        let consul = ConsulResolver::new("http://consul:8500", Arc::new(EchoUpstream));
+        let upstreams = consul.resolve("users").await.unwrap();
+        assert_eq!(
+            upstreams
+                .iter()
+                .map(|u| u.base_url.as_str())
+                .collect::<Vec<_>>(),
+            ["http://10.0.0.1:8080", "http://10.0.0.2:9090"]
+        );
+
+        let app = App::new(Ctx::with_http(Arc::new(EchoUpstream)))
+            .any(
+                "/rr/*path",
+                Proxy::new("users", StaticResolver::new(["http://a", "http://b/"])),
+            )
+            .any(
+                "/hash/*path",
+                Proxy::new(
+                    "users",
+                    StaticResolver::new(["http://a", "http://b", "http://c"]),
+                )
+                .balance(Balance::HashHeader(http::HeaderName::from_static("x-user"))),
+            );
+        let send = |uri: &str, user: &str| {
+            http::Request::builder()
+                .uri(uri)
+                .header("x-user", user)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let first = app.handle(send("/rr/users?page=2", "")).await;
+        let second = app.handle(send("/rr/users?page=2", "")).await;
+        assert_eq!(first.body(), "http://a/rr/users?page=2");
+        assert_eq!(second.body(), "http://b/rr/users?page=2");
+        assert!(first.headers().get("connection").is_none());
+
+        for user in ["alice", "bob", "carol"] {
+            let a = app.handle(send("/hash/x", user)).await;
+            let b = app.handle(send("/hash/x", user)).await;
+            assert_eq!(a.body().as_bytes(), b.body().as_bytes());
+        }
+
+        let empty = App::new(Ctx::with_http(Arc::new(EchoUpstream))).get(
+            "/",
+            Proxy::new("none", StaticResolver::new(Vec::<String>::new())),
+        );
+        let res = empty.handle(send("/", "")).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    /// Upstream that is down for any URL containing "dead".
+    struct PartlyDown;
+
+    #[async_trait]
+    impl context::HttpClient for PartlyDown {
+        async fn send(&self, req: CoreRequest) -> Result<CoreResponse> {
+            if req.uri().to_string().contains("dead") {
+                return Err(Error::internal("connection refused"));
+            }
+            Ok(req.uri().to_string().into_response())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_upstream_health() {
+        use proxy::{Proxy, StaticResolver, Upstream, UpstreamHealth, UpstreamHealthStatus};
+
+        let health = UpstreamHealth::new().failure_threshold(2);
+        let app = App::new(Ctx::with_http(Arc::new(PartlyDown)))
+            .get(
+                "/api",
+                Proxy::new("api", StaticResolver::new(["http://dead", "http://alive"]))
+                    .health(health.clone()),
+            )
+            .get(
+                "/admin/upstreams",
+                UpstreamHealthStatus::new(health.clone()),
+            );
+        let get = |uri: &str| {
+            http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Round-robin hits the dead upstream every other request until it is
+        // ejected after two failures.
+        let mut statuses = Vec::new();
+        for _ in 0..6 {
+            statuses.push(app.handle(get("/api")).await.status());
+        }
+        assert_eq!(
+            statuses
+                .iter()
+                .filter(|s| **s == StatusCode::BAD_GATEWAY)
+                .count(),
+            2
+        );
+        assert!(statuses[4..].iter().all(|s| *s == StatusCode::OK));
+
+        let res = app.handle(get("/admin/upstreams")).await;
+        let status: serde_json::Value =
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(status["http://dead"]["available"], false);
+        assert_eq!(status["http://dead"]["consecutive_failures"], 2);
+        assert_eq!(status["http://alive"]["available"], true);
+
+        // Active probes take the alive upstream out as well once it fails.
+        let alive = Upstream::new("http://alive");
+        health.probe(&PartlyDown, &[alive.clone()]).await;
+        assert!(health.is_available(&alive));
+        let probe = UpstreamHealth::new().probe_path("/dead");
+        probe.probe(&PartlyDown, &[alive.clone()]).await;
+        assert!(!probe.is_available(&alive));
+        assert_eq!(
+            App::new(Ctx::with_http(Arc::new(PartlyDown)))
+                .get(
+                    "/api",
+                    Proxy::new("api", StaticResolver::new(["http://alive"])).health(probe)
+                )
+                .handle(get("/api"))
+                .await
+                .status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_sticky_sessions() {
+        use proxy::{Failover, Proxy, StaticResolver, Sticky, UpstreamHealth};
+
+        let health = UpstreamHealth::new().failure_threshold(1);
+        let strict_health = UpstreamHealth::new().failure_threshold(1);
+        let app = App::new(Ctx::with_http(Arc::new(PartlyDown)))
+            .get(
+                "/cookie",
+                Proxy::new("legacy", StaticResolver::new(["http://a", "http://b"]))
+                    .sticky(Sticky::cookie("route")),
+            )
+            .get(
+                "/header",
+                Proxy::new("legacy", StaticResolver::new(["http://dead", "http://b"]))
+                    .health(health.clone())
+                    .sticky(Sticky::header(http::HeaderName::from_static("x-session"))),
+            )
+            .get(
+                "/strict",
+                Proxy::new("legacy", StaticResolver::new(["http://a", "http://b"]))
+                    .health(strict_health.clone())
+                    .sticky(
+                        Sticky::header(http::HeaderName::from_static("x-session"))
+                            .failover(Failover::Reject),
+                    ),
+            );
+        let get = |uri: &str, header: Option<(&str, &str)>| {
+            let mut req = http::Request::builder().uri(uri);
+            if let Some((name, value)) = header {
+                req = req.header(name, value);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let first = app.handle(get("/cookie", None)).await;
+        let set_cookie = first.headers()["set-cookie"].to_str().unwrap();
+        assert!(set_cookie.contains("Max-Age=1800"));
+        assert!(set_cookie.contains("; Secure"));
+        assert!(set_cookie.contains("SameSite=Lax"));
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        for _ in 0..4 {
+            let res = app
+                .handle(get("/cookie", Some(("cookie", cookie.as_str()))))
+                .await;
+            assert_eq!(res.body().as_bytes(), first.body().as_bytes());
+            assert!(res.headers().get("set-cookie").is_none());
+        }
+
+        // The first session lands on the dead upstream, fails and is rebound.
+        let session = Some(("x-session", "s1"));
+        let failed = app.handle(get("/header", session)).await;
+        assert_eq!(failed.status(), StatusCode::BAD_GATEWAY);
+        let rebound = app.handle(get("/header", session)).await;
+        assert_eq!(rebound.body(), "http://b/header");
+
+        // A strict proxy refuses to move a session off its upstream.
+        let res = app.handle(get("/strict", Some(("x-session", "s2")))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let bound = String::from_utf8(res.body().as_bytes().unwrap().to_vec()).unwrap();
+        strict_health.record_failure(
+            &proxy::Upstream::new(bound.trim_end_matches("/strict")),
+            "down",
+        );
+        let res = app.handle(get("/strict", Some(("x-session", "s2")))).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = app.handle(get("/strict", Some(("x-session", "s3")))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    /// A legacy upstream answering with its own host in redirects and
+    /// nested JSON fields.
+    struct LegacyUpstream;
+
+    #[async_trait]
+    impl context::HttpClient for LegacyUpstream {
+        async fn send(&self, _req: CoreRequest) -> Result<CoreResponse> {
+            Ok(http::Response::builder()
+                .status(StatusCode::CREATED)
+                .header("server", "legacy/1.0")
+                .header("x-legacy-id", "42")
+                .header("location", "http://legacy:8080/users/42")
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"user":{"user_name":"ada"},"keep":true}"#))
+                .unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_rewrite_rules() {
+        use proxy::{Proxy, StaticResolver};
+        use rewrite::ResponseRewrite;
+
+        let rules: ResponseRewrite = serde_json::from_value(serde_json::json!([
+            {"op": "strip_header", "name": "server"},
+            {"op": "rename_header", "from": "x-legacy-id", "to": "x-user-id"},
+            {"op": "rewrite_location", "from": "http://legacy:8080", "to": "https://api.example.com/v1"},
+            {"op": "map_json", "from": "/user/user_name", "to": "/name"},
+        ]))
+        .unwrap();
+        let app = App::new(Ctx::with_http(Arc::new(LegacyUpstream)))
+            .post(
+                "/users",
+                Proxy::new("legacy", StaticResolver::new(["http://legacy:8080"]))
+                    .with_middleware(rules),
+            )
+            .post(
+                "/raw",
+                Proxy::new("legacy", StaticResolver::new(["http://legacy:8080"])),
+            );
+        let post = |uri: &str| http::Request::post(uri).body(Body::empty()).unwrap();
+
+        let res = app.handle(post("/users")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert!(res.headers().get("server").is_none());
+        assert!(res.headers().get("x-legacy-id").is_none());
+        assert_eq!(res.headers()["x-user-id"], "42");
+        assert_eq!(
+            res.headers()["location"],
+            "https://api.example.com/v1/users/42"
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"user": {}, "name": "ada", "keep": true})
+        );
+
+        let raw = app.handle(post("/raw")).await;
+        assert_eq!(raw.headers()["server"], "legacy/1.0");
+
+        let invalid: std::result::Result<ResponseRewrite, _> =
+            serde_json::from_value(serde_json::json!([{"op": "strip_header", "name": "bad name"}]));
+        assert!(invalid.is_err());
+        assert!(ResponseRewrite::new().map_json("user", "/name").is_err());
+
+        // Upstreams are asked for identity bodies; encoded ones pass through.
+        let rewrite = ResponseRewrite::new().map_json("/a", "/b").unwrap();
+        let app = App::new(Ctx::new()).middleware(rewrite.clone()).get(
+            "/",
+            |req: CoreRequest| async move {
+                let encoding = req.headers().get("accept-encoding").cloned();
+                let mut res = http::Response::builder().header("content-type", "application/json");
+                if let Some(encoding) = encoding {
+                    res = res.header("content-encoding", encoding);
+                }
+                res.body(Body::from(r#"{"a":1}"#)).unwrap()
+            },
+        );
+        let get = |encoding: &str| {
+            http::Request::get("/")
+                .header("accept-encoding", encoding)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(app.handle(get("gzip")).await.body(), r#"{"b":1}"#);
+        let mut encoded = http::Response::builder()
+            .header("content-type", "application/json")
+            .header("content-encoding", "gzip")
+            .body(Body::from(&b"\x1f\x8b"[..]))
+            .unwrap();
+        rewrite.apply(&mut encoded).await.unwrap();
+        assert_eq!(encoded.body(), &b"\x1f\x8b"[..]);
+
+        let mut large = http::Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"a":"0123456789"}"#))
+            .unwrap();
+        let error = rewrite
+            .max_body_bytes(8)
+            .apply(&mut large)
+            .await
+            .unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_cors_middleware() {
+        use cors::CorsMiddleware;
+
+        let cors = CorsMiddleware::new()
+            .allow_origin("https://app.example.com")
+            .allow_origin("https://*.preview.example.com")
+            .allow_origin_regex(r"^http://localhost:\d+$")
+            .unwrap()
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
+            .expose_headers([http::HeaderName::from_static("x-request-id")])
+            .allow_credentials(true)
+            .max_age(std::time::Duration::from_secs(600));
+        let app = App::new(Ctx::new())
+            .middleware(cors)
+            .get("/items", TestHandler { response: "items" });
+        let request = |method: Method, origin: &str, extra: &[(&str, &str)]| {
+            let mut req = http::Request::builder()
+                .method(method)
+                .uri("/items")
+                .header("origin", origin);
+            for (name, value) in extra {
+                req = req.header(*name, *value);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        for origin in [
+            "https://app.example.com",
+            "https://pr-7.preview.example.com",
+            "http://localhost:3000",
+        ] {
+            let res = app.handle(request(Method::GET, origin, &[])).await;
+            assert_eq!(res.body(), "items");
+            assert_eq!(res.headers()["access-control-allow-origin"], origin);
+            assert_eq!(res.headers()["access-control-allow-credentials"], "true");
+            assert_eq!(
+                res.headers()["access-control-expose-headers"],
+                "x-request-id"
+            );
+        }
+
+        let res = app
+            .handle(request(Method::GET, "https://evil.example", &[]))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("access-control-allow-origin").is_none());
+
+        // Preflights are answered without a registered OPTIONS route.
+        let res = app
+            .handle(request(
+                Method::OPTIONS,
+                "https://app.example.com",
+                &[
+                    ("access-control-request-method", "DELETE"),
+                    (
+                        "access-control-request-headers",
+                        "Content-Type, Authorization",
+                    ),
+                ],
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()["access-control-allow-methods"],
+            "GET, POST, DELETE"
+        );
+        assert_eq!(res.headers()["access-control-max-age"], "600");
+
+        for extra in [
+            [("access-control-request-method", "PUT"), ("x", "y")],
+            [
+                ("access-control-request-method", "GET"),
+                ("access-control-request-headers", "x-secret"),
+            ],
+        ] {
+            let res = app
+                .handle(request(Method::OPTIONS, "https://app.example.com", &extra))
+                .await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        }
+
+        let public = App::new(Ctx::new())
+            .middleware(CorsMiddleware::new().allow_origin("*"))
+            .get("/items", TestHandler { response: "items" });
+        let res = public
+            .handle(request(Method::GET, "https://anyone.example", &[]))
+            .await;
+        assert_eq!(res.headers()["access-control-allow-origin"], "*");
+        assert_eq!(res.headers()["vary"], "origin");
+    }
+
+    #[tokio::test]
+    async fn test_body_translation() {
+        use translate::Translate;
+
+        // A toy SOAP-ish service that uppercases the <name> element.
+        struct Soap;
+
+        #[async_trait]
+        impl Handler<Ctx> for Soap {
+            async fn call(&self, _ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
+                assert_eq!(req.headers()["content-type"], "text/xml");
+                assert_eq!(req.headers()["accept"], "text/xml");
+                assert!(req.headers().get("accept-encoding").is_none());
+                let xml = String::from_utf8(req.body().bytes()?.to_vec()).unwrap();
+                Ok(http::Response::builder()
+                    .header("content-type", "text/xml; charset=utf-8")
+                    .body(Body::from(xml.to_uppercase().replace("NAME>", "name>")))?)
+            }
+        }
+
+        let translate = Translate::new()
+            .request("application/json", "text/xml", |body| {
+                let value: serde_json::Value = serde_json::from_slice(&body)
+                    .map_err(|_| Error::bad_request("Expected a JSON body"))?;
+                let name = value["name"].as_str().unwrap_or_default();
+                Ok(format!("<name>{}</name>", name).into())
+            })
+            .response("text/xml", "application/json", |body| {
+                let xml = String::from_utf8_lossy(&body);
+                let name = xml.trim_start_matches("<name>").trim_end_matches("</name>");
+                Ok(serde_json::to_vec(&serde_json::json!({ "name": name }))?.into())
+            });
+        let app = App::new(Ctx::new())
+            .post("/users", Soap.with_middleware(translate.clone()))
+            .post("/small", Soap.with_middleware(translate.max_body_bytes(8)));
+        let post = |content_type: &str, body: &'static str| {
+            http::Request::post("/users")
+                .header("content-type", content_type)
+                .header("accept-encoding", "gzip")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let res = app
+            .handle(post("application/json", r#"{"name":"ada"}"#))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        assert_eq!(res.body(), r#"{"name":"ADA"}"#);
+
+        let res = app.handle(post("application/json", "not json")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // XML sent directly skips the request conversion.
+        let res = app.handle(post("text/xml", "<name>bob</name>")).await;
+        assert_eq!(res.body(), r#"{"name":"BOB"}"#);
+
+        let mut req = post("application/json", r#"{"name":"ada"}"#);
+        *req.uri_mut() = "/small".parse().unwrap();
+        let res = app.handle(req).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let mut req = post("text/xml", "<name>bob</name>");
+        *req.uri_mut() = "/small".parse().unwrap();
+        let res = app.handle(req).await;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_explain_mode() {
+        use explain::Explain;
+
+        struct Deny;
+
+        #[async_trait]
+        impl Middleware<Ctx> for Deny {
+            async fn handle(
+                &self,
+                ctx: Ctx,
+                req: CoreRequest,
+                next: Next<'_, Ctx>,
+            ) -> Result<CoreResponse> {
+                if req.uri().path() == "/denied" {
+                    return Ok(StatusCode::FORBIDDEN.into_response());
+                }
+                next.run(ctx, req).await
+            }
+
+            fn name(&self) -> &str {
+                "deny"
+            }
+        }
+
+        let app = App::new(Ctx::new())
+            .explain(Explain::new().authorize(|req| req.headers().contains_key("x-admin")))
+            .middleware(Deny)
+            .get(
+                "/users/:id",
+                cached(TestHandler { response: "user" }).with_middleware(RequireHeader("x-admin")),
+            )
+            .get("/denied", TestHandler { response: "never" });
+        let get = |uri: &str, admin: bool| {
+            let mut req = http::Request::builder()
+                .uri(uri)
+                .header("x-xeno-explain", "1");
+            if admin {
+                req = req.header("x-admin", "1");
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let lines = |res: &CoreResponse| -> Vec<String> {
+            res.headers()
+                .get_all("x-xeno-explain")
+                .iter()
+                .map(|v| {
+                    // Drop the timing, which varies between runs.
+                    let line = v.to_str().unwrap();
+                    line.strip_suffix("us")
+                        .and_then(|l| l.rsplit_once(' '))
+                        .map_or(line, |(l, _)| l)
+                        .to_string()
+                })
+                .collect()
+        };
+
+        let _ = app.handle(get("/users/1", true)).await;
+        let res = app.handle(get("/users/1", true)).await;
+        assert_eq!(
+            lines(&res),
+            [
+                "middleware deny passed 200",
+                "handler GET /users/:id responded 200",
+                "middleware RequireHeader passed 200",
+                "cache hit",
+            ]
+        );
+
+        let res = app.handle(get("/denied", true)).await;
+        assert_eq!(lines(&res), ["middleware deny short-circuited 403"]);
+
+        let res = app.handle(get("/nowhere", true)).await;
+        assert_eq!(
+            lines(&res),
+            ["middleware deny passed 404", "route none 404"]
+        );
+
+        // Without passing the gate there is no trace.
+        let res = app.handle(get("/users/1", false)).await;
+        assert!(res.headers().get("x-xeno-explain").is_none());
+    }
+
+    #[cfg(feature = "chrono")]
+    #[tokio::test]
+    async fn test_access_log() {
+        use access_log::AccessLogMiddleware;
+        use extract::RemoteAddr;
+
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = |lines: &Arc<std::sync::Mutex<Vec<String>>>| {
+            let lines = Arc::clone(lines);
+            move |line: &str| lines.lock().unwrap().push(line.to_string())
+        };
+        let app = App::new(Ctx::new())
+            .middleware(AccessLogMiddleware::json().writer(sink(&lines)))
+            .middleware(AccessLogMiddleware::common().writer(sink(&lines)))
+            .get("/users/:id", TestHandler { response: "user" });
+
+        let mut req = http::Request::builder()
+            .uri("/users/7?full=1")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut()
+            .insert(RemoteAddr("192.0.2.1:5555".parse().unwrap()));
+        let _ = app.handle(req).await;
+        let _ = app
+            .handle(
+                http::Request::builder()
+                    .method("DELETE")
+                    .uri("/users/7")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+
+        let lines = lines.lock().unwrap();
+        // The inner (common) middleware finishes first.
+        assert!(lines[0].starts_with("192.0.2.1 - - ["));
+        assert!(lines[0].ends_with("] \"GET /users/7?full=1\" 200 4"));
+        let json: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(json["method"], "GET");
+        assert_eq!(json["route"], "/users/:id");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["size"], 4);
+        assert_eq!(json["remote_addr"], "192.0.2.1");
+        assert!(json["latency_ms"].is_f64());
+
+        assert!(lines[2].starts_with("- - - ["));
+        assert!(lines[2].contains("\"DELETE /users/7\" 405"));
+        let json: serde_json::Value = serde_json::from_str(&lines[3]).unwrap();
+        assert_eq!(json["route"], serde_json::Value::Null);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_access_log_anonymizer() {
+        use access_log::{AccessLogEntry, Anonymizer};
+
+        let entry = |path: &str, route: Option<&str>, addr: &str| AccessLogEntry {
+            timestamp: chrono::Utc::now(),
+            method: "GET".to_string(),
+            path: path.to_string(),
+            route: route.map(str::to_string),
+            status: 200,
+            latency: std::time::Duration::ZERO,
+            size: None,
+            remote_addr: Some(addr.to_string()),
+        };
+        let anonymize = |anonymizer: &Anonymizer, mut entry: AccessLogEntry| {
+            anonymizer.apply(&mut entry);
+            (entry.path, entry.remote_addr)
+        };
+
+        let anonymizer = Anonymizer::new()
+            .truncate_ips()
+            .strip_query("/auth/magic")
+            .redact("Email")
+            .redact("phone");
+        assert_eq!(
+            anonymize(
+                &anonymizer,
+                entry(
+                    "/users/a@b.example/files/x?email=a%40b&page=2&phone",
+                    Some("/users/:email/files/:name"),
+                    "192.0.2.77"
+                )
+            ),
+            (
+                "/users/REDACTED/files/x?email=REDACTED&page=2&phone=REDACTED".to_string(),
+                Some("192.0.2.0".to_string())
+            )
+        );
+        assert_eq!(
+            anonymize(
+                &anonymizer,
+                entry(
+                    "/auth/magic?token=abc",
+                    Some("/auth/magic"),
+                    "2001:db8:1:2:3::4"
+                )
+            ),
+            ("/auth/magic".to_string(), Some("2001:db8:1::".to_string()))
+        );
+        assert_eq!(
+            anonymize(&anonymizer, entry("/nowhere?E%6Dail=x", None, "not an ip")),
+            ("/nowhere?E%6Dail=REDACTED".to_string(), None)
+        );
+
+        let hashing = Anonymizer::new().hash_ips([7; 32]).strip_query("*");
+        let (path, first) = anonymize(&hashing, entry("/a?q=1", None, "192.0.2.1"));
+        let (_, again) = anonymize(&hashing, entry("/b", None, "192.0.2.1"));
+        let (_, other) = anonymize(&hashing, entry("/b", None, "192.0.2.2"));
+        assert_eq!(path, "/a");
+        assert_eq!(first.as_ref().map(String::len), Some(16));
+        assert_eq!(first, again);
+        assert_ne!(first, other);
+
+        let dropping = Anonymizer::new().drop_ips();
+        assert_eq!(anonymize(&dropping, entry("/", None, "192.0.2.1")).1, None);
+    }
+
+    #[cfg(all(feature = "gzip", feature = "br"))]
+    #[tokio::test]
+    async fn test_compression() {
+        use compression::{Compression, Encoding};
+        use std::io::Read;
+
+        let text = "xeno ".repeat(500);
+        struct Typed(&'static str, String);
+
+        #[async_trait]
+        impl Handler<Ctx> for Typed {
+            async fn call(&self, _ctx: Ctx, _req: CoreRequest) -> Result<CoreResponse> {
+                Ok(http::Response::builder()
+                    .header("content-type", self.0)
+                    .body(Body::from(self.1.clone()))?)
+            }
+        }
+
+        let app = App::new(Ctx::new())
+            .middleware(Compression::new())
+            .get("/text", Typed("text/plain", text.clone()))
+            .get("/small", Typed("text/plain", "tiny".into()))
+            .get("/image", Typed("image/png", text.clone()));
+        let get = |uri: &str, accept: &str| {
+            http::Request::builder()
+                .uri(uri)
+                .header("accept-encoding", accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app.handle(get("/text", "gzip, deflate")).await;
+        assert_eq!(res.headers()["content-encoding"], "gzip");
+        assert_eq!(res.headers()["vary"], "accept-encoding");
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(res.body().as_bytes().unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        let res = app.handle(get("/text", "gzip;q=0.5, br")).await;
+        assert_eq!(res.headers()["content-encoding"], "br");
+        let mut decoded = Vec::new();
+        brotli::BrotliDecompress(&mut res.body().as_bytes().unwrap(), &mut decoded).unwrap();
+        assert_eq!(decoded, text.as_bytes());
+
+        for (uri, accept) in [
+            ("/small", "gzip"),
+            ("/image", "gzip"),
+            ("/text", "identity"),
+            ("/text", "gzip;q=0"),
+        ] {
+            let res = app.handle(get(uri, accept)).await;
+            assert!(
+                res.headers().get("content-encoding").is_none(),
+                "{uri} {accept}"
+            );
+        }
+
+        let gzip_only = App::new(Ctx::new())
+            .middleware(Compression::new().encodings([Encoding::Gzip]).min_size(0))
+            .get("/small", Typed("text/plain", "tiny".into()));
+        let res = gzip_only.handle(get("/small", "*")).await;
+        assert_eq!(res.headers()["content-encoding"], "gzip");
+    }
+
+    #[tokio::test]
+    async fn test_runtime_middleware_toggling() {
+        use middleware::MiddlewareAdmin;
+
+        let app = App::new(Ctx::new())
+            .middleware_named("auth", RequireHeader("authorization"))
+            .middleware(RequireHeader("x-tenant"))
+            .get("/", TestHandler { response: "ok" });
+        let admin = App::new(Ctx::new()).any(
+            "/admin/middleware",
+            MiddlewareAdmin::new(app.middleware_switch()),
+        );
+        let plain = || {
+            http::Request::builder()
+                .uri("/")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let toggle = |name: &str, enabled: bool| {
+            http::Request::post("/admin/middleware")
+                .body(Body::from(
+                    serde_json::json!({ "name": name, "enabled": enabled }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        assert_eq!(app.handle(plain()).await.status(), StatusCode::UNAUTHORIZED);
+
+        let res = admin.handle(toggle("auth", false)).await;
+        let status: serde_json::Value =
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            status,
+            serde_json::json!([
+                {"name": "auth", "enabled": false},
+                {"name": "RequireHeader", "enabled": true},
+            ])
+        );
+        assert_eq!(app.handle(plain()).await.status(), StatusCode::UNAUTHORIZED);
+
+        let _ = admin.handle(toggle("RequireHeader", false)).await;
+        let res = app.handle(plain()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("x-checked").is_none());
+
+        assert_eq!(
+            admin.handle(toggle("missing", false)).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        app.middleware_switch().set_enabled("auth", true).unwrap();
+        assert_eq!(app.handle(plain()).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_route_table_config() {
+        use route_table::RouteTable;
+
+        let page =
+            std::env::temp_dir().join(format!("xeno-maintenance-{}.html", std::process::id()));
+        std::fs::write(&page, "<h1>Down for maintenance</h1>").unwrap();
+        let table = RouteTable::from_json(
+            &serde_json::json!([
+                {"path": "/old", "type": "redirect", "to": "/new", "status": 301},
+                {"path": "/status", "method": "get", "type": "static", "status": 503,
+                 "body": "maintenance", "headers": {"retry-after": "120"}},
+                {"path": "/page", "type": "file", "file": page},
+                {"path": "/legacy/*rest", "type": "proxy", "upstream": "http://legacy:8080"},
+                {"path": "/", "method": "GET", "type": "static", "body": "shadowed"},
+            ])
+            .to_string(),
+        )
+        .unwrap();
+        let app = App::new(Ctx::with_http(Arc::new(EchoUpstream)))
+            .get("/", TestHandler { response: "code" })
+            .route_table(&table);
+        let get = |uri: &str| {
+            http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app.handle(get("/old")).await;
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers()["location"], "/new");
+
+        let res = app.handle(get("/status")).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["retry-after"], "120");
+        assert_eq!(res.body(), "maintenance");
+
+        let res = app.handle(get("/page")).await;
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(res.headers()["content-length"], "29");
+        let body = res.into_body().collect().await.unwrap();
+        assert_eq!(body, "<h1>Down for maintenance</h1>");
+        std::fs::remove_file(&page).unwrap();
+
+        let res = app.handle(get("/legacy/a?b=1")).await;
+        assert_eq!(res.body(), "http://legacy:8080/legacy/a?b=1");
+
+        assert_eq!(app.handle(get("/")).await.body(), "code");
+
+        assert!(RouteTable::from_json(r#"[{"path": "nope", "type": "static"}]"#).is_err());
+        assert!(RouteTable::from_json(r#"[{"path": "/x", "type": "teleport"}]"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blue_green_upstream_sets() {
+        use proxy::{Proxy, UpstreamSets, UpstreamSetsAdmin};
+
+        let sets = UpstreamSets::new()
+            .set("blue", ["http://blue-1", "http://blue-2"])
+            .set("green", ["http://green-1"])
+            .kv_flag("deploy/active");
+        let kv = Arc::new(MemoryKv {
+            entries: Default::default(),
+        });
+        let mut ctx = Ctx::with_http(Arc::new(EchoUpstream));
+        ctx.kv = Some(kv.clone());
+        let app = App::new(ctx)
+            .get("/api/*rest", Proxy::blue_green("api", sets.clone()))
+            .post("/admin/upstreams", UpstreamSetsAdmin::new(sets.clone()));
+        let get = || {
+            http::Request::builder()
+                .uri("/api/users")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app.handle(get()).await;
+        assert_eq!(res.headers()["x-xeno-upstream-set"], "blue");
+        assert!(res.body().as_bytes().unwrap().starts_with(b"http://blue-"));
+
+        let switch = |set: &str| {
+            http::Request::builder()
+                .method(Method::POST)
+                .uri("/admin/upstreams")
+                .body(Body::from(format!(r#"{{"active": "{}"}}"#, set)))
+                .unwrap()
+        };
+        let res = app.handle(switch("green")).await;
+        let status: serde_json::Value =
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(status["active"], "green");
+        assert_eq!(status["sets"]["blue"][1], "http://blue-2");
+        let res = app.handle(get()).await;
+        assert_eq!(res.headers()["x-xeno-upstream-set"], "green");
+        assert_eq!(res.body(), "http://green-1/api/users");
+        assert_eq!(
+            app.handle(switch("red")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        context::Kv::put(kv.as_ref(), "deploy/active", "blue".into())
+            .await
+            .unwrap();
+        let res = app.handle(get()).await;
+        assert_eq!(res.headers()["x-xeno-upstream-set"], "blue");
+        assert_eq!(sets.active(), "blue");
+
+        context::Kv::put(kv.as_ref(), "deploy/active", "purple".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            app.handle(get()).await.headers()["x-xeno-upstream-set"],
+            "blue"
+        );
+    }
+
+    struct Sleepy(std::time::Duration);
+
+    #[async_trait]
+    impl Handler<Ctx> for Sleepy {
+        async fn call(&self, _ctx: Ctx, _req: CoreRequest) -> Result<CoreResponse> {
+            tokio::time::sleep(self.0).await;
+            Ok("done".into_response())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout_middleware() {
+        use std::time::Duration;
+        use timeout::TimeoutMiddleware;
+
+        let app = App::new(Ctx::new())
+            .middleware(
+                TimeoutMiddleware::new(Duration::from_millis(50), tokio::time::sleep)
+                    .route("/reports/*rest", Duration::from_millis(500)),
+            )
+            .get("/fast", Sleepy(Duration::ZERO))
+            .get("/slow", Sleepy(Duration::from_secs(5)))
+            .get("/reports/*rest", Sleepy(Duration::from_millis(100)))
+            .get(
+                "/tight",
+                Sleepy(Duration::from_millis(30)).with_middleware(TimeoutMiddleware::new(
+                    Duration::from_millis(10),
+                    tokio::time::sleep,
+                )),
+            );
+        let get = |uri: &str| {
+            http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(app.handle(get("/fast")).await.body(), "done");
+        let started = std::time::Instant::now();
+        let res = app.handle(get("/slow")).await;
+        assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            app.handle(get("/reports/q3")).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            app.handle(get("/tight")).await.status(),
+            StatusCode::REQUEST_TIMEOUT
+        );
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_error_formatting() {
+        let app = App::new(Ctx::new())
+            .formatter(NegotiatedFormatter::new().login_redirect("/login?src=app", "session"))
+            .middleware(RequireHeader("authorization"))
+            .get("/account", TestHandler { response: "ok" });
+        let request = |accept: &str, cookie: Option<&str>| {
+            let mut req = http::Request::builder()
+                .uri("/account?tab=billing")
+                .header("accept", accept);
+            if let Some(cookie) = cookie {
+                req = req.header("cookie", cookie);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let browser = "text/html,application/xhtml+xml,*/*;q=0.8";
+
+        let res = app.handle(request("application/json", None)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(res.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("application/json"));
+        assert!(
+            app.handle(request("*/*", None)).await.headers()["content-type"]
+                .to_str()
+                .unwrap()
+                .starts_with("application/json")
+        );
+
+        let res = app.handle(request(browser, None)).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            res.headers()["location"],
+            "/login?src=app&next=%2Faccount%3Ftab%3Dbilling"
+        );
+
+        let res = app
+            .handle(request(browser, Some("theme=dark; session=abc")))
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        assert!(std::str::from_utf8(res.body().as_bytes().unwrap())
+            .unwrap()
+            .contains("<h1>401 Unauthorized</h1>"));
+    }
+
+    #[tokio::test]
+    async fn test_schema_drift_recording() {
+        use schema_drift::SchemaRecorder;
+
+        let spec = serde_json::json!({
+            "openapi": "3.0.0",
+            "paths": {
+                "/users/{id}": {
+                    "get": {"responses": {"200": {"content": {"application/json": {
+                        "schema": {"$ref": "#/components/schemas/User"}
+                    }}}}}
+                },
+                "/users": {
+                    "post": {
+                        "requestBody": {"content": {"application/json": {"schema": {
+                            "type": "object",
+                            "required": ["name"],
+                            "properties": {"name": {"type": "string"}}
+                        }}}},
+                        "responses": {"201": {"description": "Created"}}
+                    }
+                }
+            },
+            "components": {"schemas": {"User": {
+                "type": "object",
+                "required": ["id", "name"],
+                "properties": {
+                    "id": {"type": "integer"},
+                    "name": {"type": "string"},
+                    "tags": {"type": "array", "items": {"type": "string"}}
+                }
+            }}}
+        });
+        let recorder = SchemaRecorder::new(spec).sample_every(1);
+        let app = App::new(Ctx::new())
+            .middleware(recorder.clone())
+            .get("/users/:id", |_req: CoreRequest| async {
+                Ok::<_, Error>(Json(
+                    serde_json::json!({"id": 1, "name": "ada", "tags": [7], "email": "a@b"}),
+                ))
+            })
+            .post("/users", |_req: CoreRequest| async {
+                Ok::<_, Error>(
+                    http::Response::builder()
+                        .status(StatusCode::CREATED)
+                        .body(Body::empty())?,
+                )
+            })
+            .get("/hidden", TestHandler { response: "shh" });
+        let send = |method: Method, uri: &str, body: &str| {
+            http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        app.handle(send(Method::GET, "/users/1", "")).await;
+        app.handle(send(Method::GET, "/users/2", "")).await;
+        app.handle(send(Method::POST, "/users", r#"{"nick": "ada"}"#))
+            .await;
+        app.handle(send(Method::GET, "/hidden", "")).await;
+
+        let drifts: Vec<(String, String, u64)> = recorder
+            .drifts()
+            .into_iter()
+            .map(|(d, n)| {
+                (
+                    format!("{} {} {}", d.operation, d.message, d.pointer),
+                    d.issue,
+                    n,
+                )
+            })
+            .collect();
+        let expected = [
+            ("GET /hidden  ", "undocumented operation", 1),
+            (
+                "GET /users/{id} response 200 /email",
+                "undocumented field",
+                2,
+            ),
+            (
+                "GET /users/{id} response 200 /tags/0",
+                "expected string, found integer",
+                2,
+            ),
+            ("POST /users request /name", "missing required field", 1),
+            ("POST /users request /nick", "undocumented field", 1),
+        ];
+        assert_eq!(drifts.len(), expected.len(), "{:?}", drifts);
+        for ((at, issue, n), (want_at, want_issue, want_n)) in drifts.iter().zip(expected) {
+            assert_eq!(
+                (at.as_str(), issue.as_str(), *n),
+                (want_at, want_issue, want_n)
+            );
+        }
+        let snapshot = recorder.snapshot();
+        assert_eq!((snapshot.sampled, snapshot.drifted), (4, 4));
+        assert_eq!(
+            recorder.shapes()["GET /users/{id}"]["response 200"],
+            serde_json::json!({"id": "integer", "name": "string", "tags": ["integer"], "email": "string"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_kv_sessions() {
+        use crypto::StaticKeys;
+        use session::{Session, SessionMiddleware};
+
+        async fn login(session: Session) -> Result<&'static str> {
+            session.renew();
+            session.insert("user", "ada")?;
+            session.insert("visits", 1u32)?;
+            Ok("welcome")
+        }
+
+        async fn me(session: Session) -> String {
+            let user = session.get::<String>("user").unwrap_or_default();
+            let visits = session.get::<u32>("visits").unwrap_or(0);
+            let _ = session.insert("visits", visits + 1);
+            format!("{} {}", user, visits)
+        }
+
+        async fn logout(session: Session) -> &'static str {
+            session.destroy();
+            "bye"
+        }
+
+        let kv = Arc::new(MemoryKv {
+            entries: Default::default(),
+        });
+        let keys = Arc::new(StaticKeys::new("k1", [7; 32]));
+        let app = App::new(Ctx::with_kv(kv.clone()))
+            .middleware(SessionMiddleware::new(keys).secure(false))
+            .post("/login", login)
+            .get("/me", me)
+            .post("/logout", logout);
+        let request = |method: Method, uri: &str, cookie: Option<&str>| {
+            let mut req = http::Request::builder().method(method).uri(uri);
+            if let Some(cookie) = cookie {
+                req = req.header("cookie", cookie);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let session_cookie = |res: &CoreResponse| {
+            let set = res.headers()["set-cookie"].to_str().unwrap().to_string();
+            assert!(set.contains("HttpOnly; SameSite=Lax"), "{}", set);
+            set.split(';').next().unwrap().to_string()
+        };
+
+        let res = app.handle(request(Method::GET, "/me", None)).await;
+        assert_eq!(res.body(), " 0");
+        let anonymous = session_cookie(&res);
+
+        let res = app
+            .handle(request(Method::POST, "/login", Some(&anonymous)))
+            .await;
+        let cookie = session_cookie(&res);
+        assert_ne!(cookie, anonymous);
+        assert_eq!(
+            app.handle(request(Method::GET, "/me", Some(&anonymous)))
+                .await
+                .body(),
+            " 0"
+        );
+
+        let res = app.handle(request(Method::GET, "/me", Some(&cookie))).await;
+        assert_eq!(res.body(), "ada 1");
+        let res = app.handle(request(Method::GET, "/me", Some(&cookie))).await;
+        assert_eq!(res.body(), "ada 2");
+        let forged = format!("{}x", cookie);
+        assert_eq!(
+            app.handle(request(Method::GET, "/me", Some(&forged)))
+                .await
+                .body(),
+            " 0"
+        );
+
+        let res = app
+            .handle(request(Method::POST, "/logout", Some(&cookie)))
+            .await;
+        assert!(res.headers()["set-cookie"]
+            .to_str()
+            .unwrap()
+            .starts_with("xeno_session=; Path=/; Max-Age=0"));
+        assert_eq!(
+            app.handle(request(Method::GET, "/me", Some(&cookie)))
+                .await
+                .body(),
+            " 0"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_contract_suite() {
+        use contract::ContractSuite;
+
+        let spec = serde_json::json!({
+            "openapi": "3.0.0",
+            "paths": {
+                "/users/{id}": {"get": {"responses": {
+                    "200": {"content": {"application/json": {"schema": {
+                        "type": "object",
+                        "required": ["id"],
+                        "properties": {"id": {"type": "integer"}, "name": {"type": "string"}}
+                    }}}},
+                    "404": {"description": "Unknown user"}
+                }}},
+                "/users/me": {"get": {"responses": {"200": {"content": {"text/plain": {}}}}}},
+                "/users": {"post": {
+                    "requestBody": {"content": {"application/json": {"schema": {
+                        "type": "object",
+                        "properties": {"name": {"type": "string"}}
+                    }}}},
+                    "responses": {"201": {"description": "Created"}}
+                }}
+            }
+        });
+        let fixtures = serde_json::json!([
+            {"name": "user", "path": "/users/1", "expect": {"headers": {"x-api": "v1"}}},
+            {"name": "me", "path": "/users/me"},
+            {"name": "missing", "path": "/users/0", "expect": {"status": 404}},
+            {"name": "create", "method": "post", "path": "/users", "body": {"name": 7},
+             "expect": {"status": 201}},
+            {"name": "undocumented", "path": "/admin"},
+        ]);
+        let suite = ContractSuite::from_json(&spec.to_string(), &fixtures.to_string()).unwrap();
+        let app = App::new(Ctx::new())
+            .get("/users/me", TestHandler { response: "me" })
+            .get(
+                "/users/:id",
+                |Path(params): Path<HashMap<String, String>>| async move {
+                    let id = &params["id"];
+                    if id == "0" {
+                        return Err(Error::not_found());
+                    }
+                    let mut res = Json(serde_json::json!({"id": id})).into_response();
+                    res.headers_mut()
+                        .insert("x-api", http::HeaderValue::from_static("v1"));
+                    Ok(res)
+                },
+            )
+            .post("/users", |_req: CoreRequest| async {
+                Ok::<_, Error>(
+                    http::Response::builder()
+                        .status(StatusCode::CREATED)
+                        .body(Body::empty())?,
+                )
+            });
+
+        let report = suite.run(&app).await;
+        assert!(!report.passed());
+        let failures: HashMap<_, _> = report
+            .results
+            .iter()
+            .map(|r| (r.name.as_str(), r.failures.clone()))
+            .collect();
+        assert_eq!(
+            failures["user"],
+            ["body at '/id': expected integer, found string"]
+        );
+        assert!(failures["me"].is_empty());
+        assert!(failures["missing"].is_empty());
+        assert_eq!(
+            failures["create"],
+            ["fixture request body at '/name': expected string, found integer"]
+        );
+        assert_eq!(failures["undocumented"], ["GET /admin is not documented"]);
+        assert!(report.to_string().ends_with("2 passed, 3 failed"));
+    }
+
+    struct Channel<T>(tokio::sync::mpsc::UnboundedReceiver<T>);
+
+    impl<T> futures_core::Stream for Channel<T> {
+        type Item = T;
+
+        fn poll_next(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<T>> {
+            self.0.poll_recv(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sse_response() {
+        use response::{Sse, SseEvent};
+        use std::time::Duration;
+
+        let event = SseEvent::data("line one\nline two")
+            .event("update\nforged: x")
+            .id("7")
+            .retry(Duration::from_secs(3));
+        assert_eq!(
+            event.encode(),
+            "event: updateforged: x\nid: 7\nretry: 3000\ndata: line one\ndata: line two\n\n"
+        );
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let res = Sse::new(Channel(rx))
+            .keep_alive(Duration::from_millis(30), tokio::time::sleep)
+            .into_response();
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+        assert_eq!(res.headers()["cache-control"], "no-cache");
+        assert!(res.body().is_stream());
+
+        tx.send(SseEvent::json(&serde_json::json!({"n": 1})))
+            .unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            tx.send(Ok(SseEvent::data("bye").event("close"))).unwrap();
+        });
+        let body = res.into_body().collect().await.unwrap();
+        assert_eq!(body, "data: {\"n\":1}\n\n:\n\nevent: close\ndata: bye\n\n");
+    }
+
+    #[test]
+    fn test_time_and_id_formatting() {
+        use clock::{Rng, SeededRng};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let at = |secs: u64, nanos: u32| UNIX_EPOCH + Duration::new(secs, nanos);
+        assert_eq!(clock::rfc3339(at(0, 0)), "1970-01-01T00:00:00+00:00");
+        assert_eq!(
+            clock::rfc3339(at(951_825_600, 500_000_000)),
+            "2000-02-29T12:00:00.500+00:00"
+        );
+        assert_eq!(
+            clock::rfc3339(at(1_700_000_000, 1)),
+            "2023-11-14T22:13:20.000000001+00:00"
+        );
+        assert_eq!(
+            header::http_date(at(784_111_777, 0)),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+
+        let id = SeededRng::new(7).uuid_string();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        #[cfg(feature = "uuid")]
+        assert_eq!(SeededRng::new(7).uuid().to_string(), id);
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_clock_and_rng() {
+        use clock::{ManualClock, SeededRng};
+        use crypto::StaticKeys;
+        use session::{Session, SessionMiddleware};
+        use std::time::Duration;
+
+        async fn visit(session: Session) -> Result<String> {
+            let visits = session.get::<u32>("visits").unwrap_or(0);
+            session.insert("visits", visits + 1)?;
+            Ok(visits.to_string())
+        }
+
+        let clock = ManualClock::at_unix(1_700_000_000);
+        let app = |seed: u64| {
+            let mut ctx = Ctx::with_kv(Arc::new(MemoryKv::default()));
+            ctx.clock = Arc::new(clock.clone());
+            ctx.rng = Arc::new(SeededRng::new(seed));
+            App::new(ctx)
+                .middleware(
+                    SessionMiddleware::new(Arc::new(StaticKeys::new("k1", [7; 32])))
+                        .ttl(Duration::from_secs(60))
+                        .secure(false),
+                )
+                .get(
+                    "/fail",
+                    TestHandler { response: "ok" }.with_middleware(RequireHeader("x-key")),
+                )
+                .get("/visit", visit)
+        };
+        let request = |uri: &str, cookie: Option<&str>| {
+            let mut req = http::Request::builder().uri(uri);
+            if let Some(cookie) = cookie {
+                req = req.header("cookie", cookie);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let (first, second) = (app(42), app(42));
+        let a = first.handle(request("/fail", None)).await;
+        let b = second.handle(request("/fail", None)).await;
+        assert_eq!(a.headers()["x-request-id"], b.headers()["x-request-id"]);
+        let body: serde_json::Value = serde_json::from_slice(a.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(body["timestamp"], "2023-11-14T22:13:20+00:00");
+        let other = app(7).handle(request("/fail", None)).await;
+        assert_ne!(a.headers()["x-request-id"], other.headers()["x-request-id"]);
+
+        let res = first.handle(request("/visit", None)).await;
+        let cookie = res.headers()["set-cookie"].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        let res = first.handle(request("/visit", Some(&cookie))).await;
+        assert_eq!(res.body(), "1");
+        clock.advance(Duration::from_secs(61));
+        let res = first.handle(request("/visit", Some(&cookie))).await;
+        assert_eq!(res.body(), "0");
+    }
+
+    #[tokio::test]
+    async fn test_fault_injection() {
+        use clock::SeededRng;
+        use fault::{FaultAdmin, FaultInjection, FaultRule, FAULT_HEADER};
+        use std::time::Duration;
+
+        let faults = FaultInjection::new(tokio::time::sleep)
+            .rule(
+                FaultRule::error(100.0, StatusCode::SERVICE_UNAVAILABLE).header("x-chaos", "error"),
+            )
+            .rule(FaultRule::abort(100.0).route("/download/:file"))
+            .rule(FaultRule::latency(100.0, Duration::from_millis(30)).route("/slow"));
+        let rules = faults.rules();
+        let mut ctx = Ctx::new();
+        ctx.rng = Arc::new(SeededRng::new(1));
+        let app = App::new(ctx)
+            .middleware(faults)
+            .get("/", TestHandler { response: "ok" })
+            .get("/slow", TestHandler { response: "ok" })
+            .get("/download/:file", TestHandler { response: "ok" });
+        let admin = App::new(Ctx::new()).any("/admin/faults", FaultAdmin::new(rules));
+        let get = |uri: &str, chaos: Option<&str>| {
+            let mut req = http::Request::builder().uri(uri);
+            if let Some(chaos) = chaos {
+                req = req.header("x-chaos", chaos);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let res = app.handle(get("/", None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(FAULT_HEADER).is_none());
+
+        let res = app.handle(get("/", Some("error"))).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[FAULT_HEADER], "error");
+
+        let res = app.handle(get("/download/a.zip", None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.into_body().collect().await.is_err());
+
+        let started = std::time::Instant::now();
+        let res = app.handle(get("/slow", None)).await;
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert_eq!(res.headers()[FAULT_HEADER], "latency");
+        assert_eq!(res.body(), "ok");
+
+        let res = admin
+            .handle(
+                http::Request::post("/admin/faults")
+                    .body(Body::from(
+                        r#"[{"percent": 50, "fault": {"type": "error", "status": 500}}]"#,
+                    ))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut failed = 0;
+        for _ in 0..200 {
+            if app.handle(get("/download/a.zip", None)).await.status()
+                == StatusCode::INTERNAL_SERVER_ERROR
+            {
+                failed += 1;
+            }
+        }
+        assert!((60..140).contains(&failed), "{}", failed);
+
+        let invalid = admin
+            .handle(
+                http::Request::post("/admin/faults")
+                    .body(Body::from(
+                        r#"[{"percent": 150, "fault": {"type": "abort"}}]"#,
+                    ))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+
+        let _ = admin
+            .handle(
+                http::Request::post("/admin/faults")
+                    .body(Body::from("[]"))
+                    .unwrap(),
+            )
+            .await;
+        let res = app.handle(get("/", Some("error"))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        use route_table::RouteTable;
+
+        let video = std::env::temp_dir().join(format!("xeno-range-{}.mp4", std::process::id()));
+        std::fs::write(&video, "0123456789").unwrap();
+        let table = RouteTable::from_json(
+            &serde_json::json!([{"path": "/video", "type": "file", "file": video}]).to_string(),
+        )
+        .unwrap();
+        let mut ctx = Ctx::new();
+        ctx.insert_state(context::BlockingPool::new(tokio::task::spawn_blocking));
+        let app = App::new(ctx).route_table(&table);
+        let body = |res: CoreResponse| async move { res.into_body().collect().await.unwrap() };
+        let get = |range: Option<&str>, if_range: Option<&str>| {
+            let mut req = http::Request::builder().uri("/video");
+            if let Some(range) = range {
+                req = req.header("range", range);
+            }
+            if let Some(if_range) = if_range {
+                req = req.header("if-range", if_range);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let res = app.handle(get(None, None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["accept-ranges"], "bytes");
+        let last_modified = res.headers()["last-modified"].to_str().unwrap().to_string();
+        assert_eq!(body(res).await, "0123456789");
+
+        for (range, status, content_range, expected) in [
+            (
+                "bytes=2-4",
+                StatusCode::PARTIAL_CONTENT,
+                "bytes 2-4/10",
+                "234",
+            ),
+            (
+                "bytes=7-",
+                StatusCode::PARTIAL_CONTENT,
+                "bytes 7-9/10",
+                "789",
+            ),
+            (
+                "bytes=-3",
+                StatusCode::PARTIAL_CONTENT,
+                "bytes 7-9/10",
+                "789",
+            ),
+            (
+                "bytes=8-100",
+                StatusCode::PARTIAL_CONTENT,
+                "bytes 8-9/10",
+                "89",
+            ),
+            (
+                "bytes=10-",
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "bytes */10",
+                "",
+            ),
+            (
+                "bytes=0-1, 4-5",
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "bytes */10",
+                "",
+            ),
+        ] {
+            let res = app.handle(get(Some(range), None)).await;
+            assert_eq!(res.status(), status, "{}", range);
+            assert_eq!(res.headers()["content-range"], content_range);
+            assert_eq!(res.headers()["content-length"], expected.len().to_string());
+            assert_eq!(body(res).await, expected);
+        }
+
+        let res = app.handle(get(Some("lines=1-2"), None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app
+            .handle(get(Some("bytes=0-1"), Some(&last_modified)))
+            .await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        let res = app
+            .handle(get(
+                Some("bytes=0-1"),
+                Some("Sun, 06 Nov 1994 08:49:37 GMT"),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await, "0123456789");
+        std::fs::remove_file(&video).unwrap();
+
+        let buffered = http::Response::builder()
+            .header("etag", "\"v1\"")
+            .body(Body::from("hello world"))
+            .unwrap();
+        let res = range::ranged(&get(Some("bytes=6-"), Some("\"v1\"")), buffered);
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.body(), "world");
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget() {
+        use memory::MemoryBudget;
+
+        let budget = MemoryBudget::new(10);
+        let stream = || Body::from_stream(Chunks(["abcd", "efgh"].into()));
+
+        let first = budget.reservation();
+        let body = first.track(stream()).collect().await.unwrap();
+        assert_eq!(body, "abcdefgh");
+        assert_eq!((budget.used(), first.held()), (8, 8));
+
+        let second = budget.reservation();
+        let shed = second.track(stream()).collect().await.unwrap_err();
+        assert_eq!(shed.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            budget.admit(4).unwrap_err().status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            budget.admit(11).unwrap_err().status_code(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        drop(first);
+        drop(second);
+        assert_eq!(budget.used(), 0);
+        budget.admit(10).unwrap();
+
+        let greedy = budget.reservation();
+        let too_large = greedy
+            .track(Body::from_stream(Chunks(["abcdef", "ghijkl"].into())))
+            .collect()
+            .await
+            .unwrap_err();
+        assert_eq!(too_large.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        drop(greedy);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_spa_fallback() {
+        use spa::SpaFallback;
+
+        let index = std::env::temp_dir().join(format!("xeno-spa-{}.html", std::process::id()));
+        std::fs::write(&index, "<div id=app></div>").unwrap();
+        let spa = SpaFallback::new(&index).api_prefix("graphql");
+        let app = App::new(Ctx::new())
+            .get("/api/users", TestHandler { response: "users" })
+            .post("/login", TestHandler { response: "ok" })
+            .fallback(spa.clone());
+        let request = |method: Method, uri: &str| {
+            http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for uri in ["/", "/settings/profile", "/users/42?tab=posts"] {
+            let res = app.handle(request(Method::GET, uri)).await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", uri);
+            assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+            assert_eq!(res.body(), "<div id=app></div>");
+        }
+        for uri in ["/app.js", "/api/missing", "/api", "/graphql/x"] {
+            let res = app.handle(request(Method::GET, uri)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+        assert_eq!(
+            app.handle(request(Method::GET, "/api/users")).await.body(),
+            "users"
+        );
+        assert_eq!(
+            app.handle(request(Method::GET, "/apiary")).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            app.handle(request(Method::POST, "/settings"))
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            app.handle(request(Method::GET, "/login")).await.status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+
+        // The page is read once; a deploy reloads it explicitly.
+        std::fs::write(&index, "<div id=app v2></div>").unwrap();
+        assert_eq!(
+            app.handle(request(Method::GET, "/")).await.body(),
+            "<div id=app></div>"
+        );
+        spa.reload().unwrap();
+        assert_eq!(
+            app.handle(request(Method::GET, "/")).await.body(),
+            "<div id=app v2></div>"
+        );
+        std::fs::remove_file(&index).unwrap();
+        assert!(spa.reload().is_err());
+        assert_eq!(
+            app.handle(request(Method::GET, "/")).await.body(),
+            "<div id=app v2></div>"
+        );
+
+        let app = App::new(Ctx::new()).fallback(SpaFallback::html("<main></main>"));
+        assert_eq!(
+            app.handle(request(Method::GET, "/home")).await.body(),
+            "<main></main>"
+        );
+
+        let app = App::new(Ctx::new()).spa_fallback("/nonexistent/index.html");
+        assert_eq!(
+            app.handle(request(Method::GET, "/home")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_concurrency() {
+        use concurrency::AdaptiveConcurrency;
+        use std::time::Duration;
+
+        let limiter = AdaptiveConcurrency::new(Duration::from_millis(20))
+            .initial_limit(2)
+            .limits(1, 10);
+        let app = App::new(Ctx::new())
+            .middleware(limiter.clone())
+            .get("/slow", Sleepy(Duration::from_millis(50)))
+            .get("/fast", TestHandler { response: "ok" });
+        let get = |uri: &str| {
+            http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (a, b, c) = tokio::join!(
+            app.handle(get("/slow")),
+            app.handle(get("/slow")),
+            app.handle(get("/slow"))
+        );
+        let mut statuses = [a.status(), b.status(), c.status()];
+        statuses.sort();
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::SERVICE_UNAVAILABLE
+            ]
+        );
+        assert_eq!((limiter.limit(), limiter.in_flight()), (1, 0));
+
+        for _ in 0..5 {
+            assert_eq!(app.handle(get("/fast")).await.status(), StatusCode::OK);
+        }
+        assert_eq!(limiter.limit(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_error_handler_and_fallback() {
+        let problem = |error: &Error, req: &ErrorRequest| {
+            let status = error.status_code();
+            http::Response::builder()
+                .status(status)
+                .header("content-type", "application/problem+json")
+                .body(Body::from(
+                    serde_json::json!({
+                        "type": "about:blank",
+                        "title": error.safe_message(),
+                        "status": status.as_u16(),
+                        "instance": req.uri.path(),
+                    })
+                    .to_string(),
+                ))
+                .unwrap()
+        };
+        let app = App::new(Ctx::new())
+            .get("/error", ErrorTestHandler)
+            .post(
+                "/items",
+                TestHandler {
+                    response: "created",
+                },
+            )
+            .error_handler(problem);
+        let request = |method: Method, uri: &str| {
+            http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let problem_of = |res: &CoreResponse| -> serde_json::Value {
+            assert_eq!(res.headers()["content-type"], "application/problem+json");
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap()
+        };
+
+        let res = app.handle(request(Method::GET, "/missing")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(problem_of(&res)["instance"], "/missing");
+
+        let res = app.handle(request(Method::GET, "/items")).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()["allow"], "POST");
+        assert_eq!(problem_of(&res)["status"], 405);
+
+        let res = app.handle(request(Method::GET, "/error")).await;
+        assert_eq!(problem_of(&res)["status"], 400);
+
+        async fn not_here(_ctx: Ctx, req: CoreRequest) -> Result<CoreResponse> {
+            if req.uri().path().starts_with("/old/") {
+                return Ok(Redirect::see_other("/new").into_response());
+            }
+            Err(Error::not_found())
+        }
+        let app = app.fallback(not_here);
+        let res = app.handle(request(Method::GET, "/old/page")).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        let res = app.handle(request(Method::DELETE, "/gone")).await;
+        assert_eq!(problem_of(&res)["instance"], "/gone");
+    }
+
+    #[tokio::test]
+    async fn test_error_into_response() {
+        enum ApiError {
+            Banned,
+        }
+
+        impl IntoResponse for ApiError {
+            fn into_response(self) -> CoreResponse {
+                match self {
+                    ApiError::Banned => (StatusCode::FORBIDDEN, "banned").into_response(),
+                }
+            }
+        }
+
+        async fn profile(
+            Path(params): Path<HashMap<String, String>>,
+        ) -> std::result::Result<Json<serde_json::Value>, ApiError> {
+            match params["name"].as_str() {
+                "mallory" => Err(ApiError::Banned),
+                name => Ok(Json(serde_json::json!({ "name": name }))),
+            }
+        }
+
+        async fn missing() -> Result<String> {
+            Err(Error::not_found())
+        }
+
+        let app = App::new(Ctx::new())
+            .get("/users/:name", profile)
+            .get("/missing", missing)
+            .error_handler(|error, _req| {
+                (error.status_code(), "custom error page").into_response()
+            });
+        let get = |uri: &str| {
+            http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app.handle(get("/users/ada")).await;
+        assert_eq!(res.body(), r#"{"name":"ada"}"#);
+        let res = app.handle(get("/users/mallory")).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(res.body(), "banned");
+
+        let res = app.handle(get("/missing")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.body(), "custom error page");
+
+        let res = Error::conflict("taken").into_response();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            res.headers()["content-type"],
+            "application/json; charset=utf-8"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_metrics_cardinality() {
+        use metrics::{MetricsHandler, RequestMetrics};
+
+        let metrics = RequestMetrics::new()
+            .max_label_values(3)
+            .label_from_header("client-version", http::HeaderName::from_static("x-client"));
+        let app = App::new(Ctx::new())
+            .middleware(metrics.clone())
+            .get("/users/:id", TestHandler { response: "user" })
+            .get("/metrics", MetricsHandler::new(metrics.clone()));
+        let request = |method: &str, uri: &str, client: &str| {
+            http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("x-client", client)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for id in 0..5 {
+            let _ = app
+                .handle(request("GET", &format!("/users/{}", id), "1.0"))
+                .await;
+        }
+        for probe in ["/wp-admin", "/.env", "/phpmyadmin", "/backup.zip"] {
+            let _ = app.handle(request("GET", probe, "1.0")).await;
+        }
+        let _ = app.handle(request("BREW", "/users/1", "1.0")).await;
+        for client in ["2.0", "3.0", "4.0", "evil\"\tclient"] {
+            let _ = app.handle(request("GET", "/users/1", client)).await;
+        }
+
+        let body = app.handle(request("GET", "/metrics", "1.0")).await;
+        assert_eq!(body.headers()["content-type"], "text/plain; version=0.0.4");
+        let text = String::from_utf8(body.body().as_bytes().unwrap().to_vec()).unwrap();
+        let count = |labels: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(&format!("xeno_requests_total{{{}}} ", labels)))
+                .unwrap_or_else(|| panic!("no series {} in\n{}", labels, text))
+                .to_string()
+        };
+        assert_eq!(
+            count(r#"method="GET",route="/users/:id",status="200",client_version="1.0""#),
+            "5"
+        );
+        assert_eq!(
+            count(r#"method="GET",route="other",status="404",client_version="1.0""#),
+            "4"
+        );
+        assert_eq!(
+            count(r#"method="other",route="other",status="405",client_version="1.0""#),
+            "1"
+        );
+        assert_eq!(
+            count(r#"method="GET",route="/users/:id",status="200",client_version="other""#),
+            "2"
+        );
+        assert!(!text.contains("wp-admin"));
+
+        let waits = sql::AcquireWaits::new();
+        waits.record(std::time::Duration::from_millis(1500));
+        let metrics = RequestMetrics::new().sql_pool(Arc::new(FakeSql {
+            pool: Some(waits),
+            ..Default::default()
+        }));
+        let text = metrics.render();
+        assert!(text.contains("xeno_sql_pool_connections{state=\"in_use\"} 3\n"));
+        assert!(text.contains("xeno_sql_pool_acquires_total 1\n"));
+        assert!(text.ends_with("xeno_sql_pool_acquire_wait_seconds_total 1.5\n"));
+    }
+
+    #[test]
+    fn test_log_sampling() {
+        use crate::clock::ManualClock;
+        use crate::logging::{Level, LogSampler};
+        use std::sync::Mutex;
+        use std::time::Duration;
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let clock = ManualClock::at_unix(1_700_000_000);
+        let sampler = LogSampler::new(Duration::from_secs(10))
+            .clock(Arc::new(clock.clone()))
+            .writer({
+                let lines = lines.clone();
+                move |level, line| lines.lock().unwrap().push((level, line.to_string()))
+            });
+
+        for i in 0..100 {
+            sampler.log(
+                Level::Warn,
+                "upstream timeout",
+                format!("Upstream timed out (request {})", i),
+            );
+        }
+        sampler.error("Database unreachable");
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec![
+                (Level::Warn, "Upstream timed out (request 0)".to_string()),
+                (Level::Error, "Database unreachable".to_string()),
+            ]
+        );
+
+        // The next call after the window summarizes the storm with its last
+        // occurrence, then starts a new window.
+        clock.advance(Duration::from_secs(10));
+        sampler.log(
+            Level::Warn,
+            "upstream timeout",
+            "Upstream timed out (request 100)",
+        );
+        assert_eq!(
+            lines.lock().unwrap()[2..],
+            [
+                (
+                    Level::Warn,
+                    "Upstream timed out (request 99) (repeated 99 more times in 10s)".to_string()
+                ),
+                (Level::Warn, "Upstream timed out (request 100)".to_string()),
+            ]
+        );
+
+        sampler.warn("Disk almost full");
+        sampler.warn("Disk almost full");
+        sampler.flush();
+        assert_eq!(
+            lines.lock().unwrap()[4..],
+            [
+                (Level::Warn, "Disk almost full".to_string()),
+                (
+                    Level::Warn,
+                    "Disk almost full (repeated 1 more times in 10s)".to_string()
+                ),
+            ]
+        );
+        sampler.flush();
+        assert_eq!(lines.lock().unwrap().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_html_response() {
+        let app = App::new(Ctx::new()).get("/", || async { Html("<h1>Hello</h1>") });
+        let req = http::Request::builder()
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.handle(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(res.into_body().as_bytes().unwrap(), b"<h1>Hello</h1>");
+    }
+
+    #[cfg(feature = "askama")]
+    #[tokio::test]
+    async fn test_html_template() {
+        use crate::response::HtmlTemplate;
+
+        #[derive(askama::Template)]
+        #[template(source = "<p>{{ name }}</p>", ext = "html")]
+        struct Greeting<'a> {
+            name: &'a str,
+        }
+
+        let res = HtmlTemplate(Greeting { name: "<b>Ann</b>" }).into_response();
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(
+            res.into_body().as_bytes().unwrap(),
+            b"<p>&lt;b&gt;Ann&lt;/b&gt;</p>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_egress_limits() {
+        use crate::clock::ManualClock;
+        use crate::egress::{EgressLimits, Identity};
+        use std::time::Duration;
+
+        // Stands in for authentication: trusts x-api-key unless it is "forged".
+        struct Authenticate;
+
+        #[async_trait]
+        impl Middleware<Ctx> for Authenticate {
+            async fn before(&self, _ctx: &Ctx, req: &mut CoreRequest) -> Result<()> {
+                let key = req.headers().get("x-api-key").cloned();
+                if let Some(key) = key.filter(|key| key != "forged") {
+                    let identity = Identity(key.to_str().unwrap().to_string());
+                    req.extensions_mut().insert(identity);
+                }
+                Ok(())
+            }
+        }
+
+        let clock = ManualClock::at_unix(1_700_000_000);
+        let limits = EgressLimits::new(Duration::from_secs(3600))
+            .clock(Arc::new(clock.clone()))
+            .max_keys(2)
+            .limit(20)
+            .tenant_limit("acme", 1000);
+        let app = App::new(Ctx::new())
+            .middleware(Authenticate)
+            .middleware(limits.clone())
+            .get(
+                "/data",
+                TestHandler {
+                    response: "twelve bytes",
+                },
+            )
+            .get("/stream", || async {
+                Body::from_stream(Chunks(vec!["0123456789", "0123456789"].into()))
+            });
+        let get = |path: &str, key: Option<&str>| {
+            let mut req = http::Request::builder().uri(path);
+            if let Some(key) = key {
+                req = req.header("x-api-key", key);
+            }
+            app.handle(req.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(get("/data", Some("free")).await.status(), StatusCode::OK);
+        assert_eq!(get("/data", Some("free")).await.status(), StatusCode::OK);
+        assert_eq!(limits.usage("free"), 24);
+        assert_eq!(
+            get("/data", Some("free")).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(get("/data", Some("acme")).await.status(), StatusCode::OK);
+        assert_eq!(get("/data", None).await.status(), StatusCode::OK);
+        assert_eq!(get("/data", Some("forged")).await.status(), StatusCode::OK);
+        assert_eq!(limits.usage("forged"), 0);
+
+        // Streamed bytes count once they have been sent.
+        let res = get("/stream", Some("acme")).await;
+        assert_eq!(limits.usage("acme"), 12);
+        res.into_body().collect().await.unwrap();
+        assert_eq!(limits.usage("acme"), 32);
+
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(limits.usage("free"), 0);
+        assert_eq!(get("/data", Some("free")).await.status(), StatusCode::OK);
+
+        // Past the key cap, expired periods go first, then the oldest.
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(get("/data", Some("beta")).await.status(), StatusCode::OK);
+        assert_eq!(limits.usage("free"), 12);
+        assert_eq!(limits.usage("acme"), 0);
+        assert_eq!(get("/data", Some("gamma")).await.status(), StatusCode::OK);
+        assert_eq!(limits.usage("free"), 0);
+        assert_eq!(limits.usage("beta"), 12);
+        assert_eq!(limits.usage("gamma"), 12);
+
+        let paid = App::new(Ctx::new())
+            .middleware(
+                EgressLimits::new(Duration::from_secs(60))
+                    .key_header(http::HeaderName::from_static("x-api-key"), |key| {
+                        key == "free"
+                    })
+                    .limit(0)
+                    .payment_required(),
+            )
+            .get(
+                "/data",
+                TestHandler {
+                    response: "twelve bytes",
+                },
+            );
+        let req = http::Request::builder()
+            .uri("/data")
+            .header("x-api-key", "free")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            paid.handle(req).await.status(),
+            StatusCode::PAYMENT_REQUIRED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redirect_helpers() {
+        let app = App::new(Ctx::new())
+            .get("/old", || async { Redirect::permanent("/new") })
+            .post("/upload", || async { Redirect::to("/v2/upload") })
+            .post("/login", || async { Redirect::see_other("/home") });
+
+        for (method, path, status, location) in [
+            (Method::GET, "/old", StatusCode::PERMANENT_REDIRECT, "/new"),
+            (
+                Method::POST,
+                "/upload",
+                StatusCode::TEMPORARY_REDIRECT,
+                "/v2/upload",
+            ),
+            (Method::POST, "/login", StatusCode::SEE_OTHER, "/home"),
+        ] {
+            let req = http::Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+            let res = app.handle(req).await;
+            assert_eq!(res.status(), status);
+            assert_eq!(res.headers()["location"], location);
+        }
+
+        let redirect = Redirect::to("/next\r\nset-cookie: a=b");
+        assert!(!redirect.location().as_bytes().contains(&b'\n'));
+    }
+
+    #[tokio::test]
+    async fn test_header_tuple_responses() {
+        use http::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION, SET_COOKIE};
+        use http::HeaderMap;
+
+        let app = App::new(Ctx::new())
+            .post("/users", || async {
+                let mut headers = HeaderMap::new();
+                headers.insert(LOCATION, "/users/7".parse().unwrap());
+                (StatusCode::CREATED, headers, "created")
+            })
+            .get("/feed", || async {
+                let mut headers = HeaderMap::new();
+                headers.insert(CONTENT_TYPE, "application/atom+xml".parse().unwrap());
+                (headers, "<feed/>")
+            })
+            .get("/login", || async {
+                (
+                    StatusCode::ACCEPTED,
+                    AppendHeaders([
+                        (SET_COOKIE, "a=1"),
+                        (SET_COOKIE, "b=2"),
+                        (CACHE_CONTROL, "no-store"),
+                    ]),
+                    "ok",
+                )
+            })
+            .get("/bad", || async {
+                (
+                    AppendHeaders([("x-note", "line\nbreak")]),
+                    Ok::<_, Error>("hi"),
+                )
+            });
+        let send = |method: Method, path: &str| {
+            app.handle(
+                http::Request::builder()
+                    .method(method)
+                    .uri(path)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let res = send(Method::POST, "/users").await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()[LOCATION], "/users/7");
+
+        let res = send(Method::GET, "/feed").await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/atom+xml");
+
+        let res = send(Method::GET, "/login").await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let cookies: Vec<_> = res.headers().get_all(SET_COOKIE).iter().collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+        assert_eq!(res.headers()[CACHE_CONTROL], "no-store");
+
+        let res = send(Method::GET, "/bad").await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_readiness() {
+        let primed = Arc::new(AtomicUsize::new(0));
+        let app = App::new(Ctx::new())
+            .get("/ready", health::Readiness)
+            .warmup("cache", {
+                let primed = primed.clone();
+                move |_ctx: Ctx| {
+                    let primed = primed.clone();
+                    async move {
+                        primed.fetch_add(1, Ordering::SeqCst);
+                        Ok(())
+                    }
+                }
+            })
+            .warmup("jwks", |_ctx| async { Ok(()) });
+        let ready = || async {
+            let req = http::Request::builder()
+                .uri("/ready")
+                .body(Body::empty())
+                .unwrap();
+            let res = app.handle(req).await;
+            let body: serde_json::Value =
+                serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+            (res.status(), body["checks"]["warmup"].clone())
+        };
+
+        let (status, warmup) = ready().await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(warmup["completed"], 0);
+        assert_eq!(warmup["total"], 2);
+
+        app.run_warmup().await;
+        app.run_warmup().await;
+        assert_eq!(primed.load(Ordering::SeqCst), 1);
+        let (status, warmup) = ready().await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(warmup["completed"], 2);
+
+        let failing = App::new(Ctx::new())
+            .get("/ready", health::Readiness)
+            .warmup("pool", |_ctx| async { Err(Error::internal("db down")) });
+        failing.run_warmup().await;
+        assert!(failing.warmup_progress().is_done());
+        assert_eq!(failing.warmup_progress().failed(), ["pool"]);
+        let req = http::Request::builder()
+            .uri("/ready")
+            .body(Body::empty())
+            .unwrap();
+        let res = failing.handle(req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!String::from_utf8_lossy(res.body().as_bytes().unwrap()).contains("db down"));
+    }
+
+    #[tokio::test]
+    async fn test_multi_app_composition() {
+        use crate::compose::Apps;
+
+        #[derive(Clone)]
+        struct Billing {
+            currency: &'static str,
+        }
+
+        let billing = App::new(Billing { currency: "EUR" })
+            .get(
+                "/invoices",
+                |billing: Billing, req: CoreRequest| async move {
+                    let query = req.uri().query().unwrap_or_default().to_string();
+                    Ok(format!("{} invoices, {}", billing.currency, query).into_response())
+                },
+            )
+            .warmup("rates", |_billing| async { Ok(()) });
+        let web = App::new(Ctx::new()).get(
+            "/invoices",
+            TestHandler {
+                response: "web invoices",
+            },
+        );
+        let admin = App::new(Ctx::new())
+            .get("/", TestHandler { response: "admin" })
+            .formatter(NegotiatedFormatter::default());
+        let billing_progress = billing.warmup_progress().clone();
+        let app = Apps::new()
+            .mount("/billing", billing)
+            .mount("/", web)
+            .host("admin.example.com", admin)
+            .into_app();
+
+        let send = |host: &str, path: &str| {
+            app.handle(
+                http::Request::builder()
+                    .uri(path)
+                    .header("host", host)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        let body =
+            |res: CoreResponse| String::from_utf8_lossy(res.body().as_bytes().unwrap()).to_string();
+
+        let res = send("example.com", "/billing/invoices?customer=acme").await;
+        assert_eq!(body(res), "EUR invoices, customer=acme");
+        assert_eq!(
+            body(send("example.com:8080", "/invoices").await),
+            "web invoices"
+        );
+        assert_eq!(body(send("ADMIN.example.com", "/").await), "admin");
+        assert_eq!(
+            send("example.com", "/billingx").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let res = send("admin.example.com", "/invoices").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        assert!(!billing_progress.is_done());
+        app.run_warmup().await;
+        assert!(billing_progress.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_query_sequences_and_nesting() {
+        use serde::Deserialize;
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Range {
+            lo: u32,
+            hi: Option<u32>,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        #[serde(rename_all = "lowercase")]
+        enum Sort {
+            Newest,
+            Oldest,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Search {
+            tag: Vec<String>,
+            page: u32,
+            draft: bool,
+            sort: Option<Sort>,
+            range: Option<Range>,
+            #[serde(default)]
+            ids: Vec<u64>,
+        }
+
+        let search = |query: &str| params::from_query_str::<Search>(query);
+
+        assert_eq!(
+            search("tag=a&tag=b&page=2&draft=true&sort=oldest&range[lo]=1&ids[1]=20&ids[0]=10")
+                .unwrap(),
+            Search {
+                tag: vec!["a".into(), "b".into()],
+                page: 2,
+                draft: true,
+                sort: Some(Sort::Oldest),
+                range: Some(Range { lo: 1, hi: None }),
+                ids: vec![10, 20],
+            }
+        );
+        let single =
+            search("tag=rust&page=1&draft=false&sort=&range%5Blo%5D=3&range%5Bhi%5D=").unwrap();
+        assert_eq!(single.tag, ["rust"]);
+        assert_eq!(single.sort, None);
+        assert_eq!(single.range, Some(Range { lo: 3, hi: None }));
+        assert!(search("tag[]=x&tag[]=y&page=1&draft=0").is_err());
+        assert_eq!(
+            search("tag[]=x&tag[]=y&page=1&draft=true").unwrap().tag,
+            ["x", "y"]
+        );
+        assert!(search("tag=a&page=two&draft=true").is_err());
+        assert!(search("tag=a&tag[x]=b&page=1&draft=true").is_err());
+        assert!(params::from_query_str::<HashMap<String, String>>("a[b][c][d][e][f]=1").is_err());
+
+        // A repeated key read as a single value keeps the last one.
+        let map: HashMap<String, String> = params::from_query_str("a=1&a=2&b=3").unwrap();
+        assert_eq!(map["a"], "2");
+
+        let app = App::new(Ctx::new()).get("/search", |Query(search): Query<Search>| async move {
+            format!("{} {:?} {}", search.page, search.tag, search.draft)
+        });
+        let req = http::Request::builder()
+            .uri("/search?tag=a&tag=b&page=3&draft=true")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.handle(req).await;
+        assert_eq!(res.body(), r#"3 ["a", "b"] true"#);
+    }
+
+    #[tokio::test]
+    async fn test_nest_with_context() {
+        #[derive(Clone)]
+        struct AuthCtx {
+            realm: String,
+        }
+
+        let warmed = Arc::new(AtomicUsize::new(0));
+        let auth = App::new(AuthCtx {
+            realm: "unused".to_string(),
+        })
+        .get("/", |auth: AuthCtx, _req: CoreRequest| async move {
+            Ok(format!("login to {}", auth.realm).into_response())
+        })
+        .get("/users/:id", |auth: AuthCtx, req: CoreRequest| async move {
+            let Path(params): Path<HashMap<String, String>> = Path::extract(&req)?;
+            Ok(format!("{} user {} ({})", auth.realm, params["id"], req.uri()).into_response())
+        })
+        .middleware(RequireHeader("authorization"))
+        .warmup("keys", {
+            let warmed = warmed.clone();
+            move |_| {
+                let warmed = warmed.clone();
+                async move {
+                    warmed.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            }
+        });
+
+        let mut ctx = Ctx::new();
+        ctx.insert_state("acme".to_string());
+        let app = App::new(ctx)
+            .get("/", TestHandler { response: "home" })
+            .nest_with_context("/auth/", auth, |ctx: Ctx| AuthCtx {
+                realm: ctx.state::<String>().cloned().unwrap_or_default(),
+            });
+        let send = |path: &str, authorized: bool| {
+            let mut req = http::Request::builder().uri(path);
+            if authorized {
+                req = req.header("authorization", "Bearer t");
+            }
+            app.handle(req.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(send("/", false).await.body(), "home");
+        assert_eq!(send("/auth", true).await.body(), "login to acme");
+        assert_eq!(send("/auth/", true).await.body(), "login to acme");
+        assert_eq!(
+            send("/auth/users/7?full=1", true).await.body(),
+            "acme user 7 (/users/7?full=1)"
+        );
+        // The sub-app's middleware and 404 apply under the prefix only.
+        assert_eq!(
+            send("/auth/users/7", false).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            send("/auth/nope", true).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(send("/authx", true).await.status(), StatusCode::NOT_FOUND);
+
+        app.run_warmup().await;
+        assert_eq!(warmed.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_typed_path_params() {
+        #[derive(serde::Deserialize)]
+        struct PostRef {
+            user: u32,
+            slug: String,
+            draft: Option<bool>,
+        }
+
+        let app =
+            App::new(Ctx::new())
+                .get("/users/:id", |Path(id): Path<u32>| async move {
+                    format!("user {}", id + 1)
+                })
+                .get(
+                    "/users/:user/posts/:slug",
+                    |Path((user, slug)): Path<(u32, String)>| async move {
+                        format!("{} by {}", slug, user)
+                    },
+                )
+                .get("/s/:slug/u/:user", |Path(post): Path<PostRef>| async move {
+                    format!("{} by {} {:?}", post.slug, post.user, post.draft)
+                })
+                .get(
+                    "/files/*path",
+                    |Path(path): Path<String>| async move { path },
+                );
+        let get = |uri: &str| {
+            app.handle(
+                http::Request::builder()
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        let body =
+            |res: CoreResponse| String::from_utf8_lossy(res.body().as_bytes().unwrap()).to_string();
+
+        assert_eq!(body(get("/users/41").await), "user 42");
+        assert_eq!(body(get("/users/7/posts/hello").await), "hello by 7");
+        assert_eq!(body(get("/s/hello/u/7").await), "hello by 7 None");
+        assert_eq!(body(get("/files/a/b.txt").await), "a/b.txt");
+        assert_eq!(body(get("/files/").await), "");
+
+        let res = get("/users/abc").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(body(res).contains("(parameter id)"));
+        let res = get("/users/x/posts/hello").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(body(res).contains("(parameter user)"));
+
+        // Extraction shapes that do not fit the route are rejected.
+        let params = [
+            ("a".to_string(), "1".to_string()),
+            ("b".to_string(), "2".to_string()),
+        ];
+        assert!(params::from_path_params::<u32>(&params).is_err());
+        assert!(params::from_path_params::<(u32, u32, u32)>(&params).is_err());
+        assert_eq!(
+            params::from_path_params::<(u8, String)>(&params).unwrap(),
+            (1, "2".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_content_negotiation() {
+        use crate::negotiate::Format;
+
+        #[derive(serde::Serialize)]
+        struct User {
+            id: u32,
+            name: String,
+        }
+
+        let app = App::new(Ctx::new())
+            .get("/user", |accepts: Accepts| async move {
+                accepts.negotiate(User {
+                    id: 7,
+                    name: "ada".to_string(),
+                })
+            })
+            .get("/lenient", |accepts: Accepts| async move {
+                accepts
+                    .negotiate(serde_json::json!({ "ok": true }))
+                    .formats(&[Format::Json])
+                    .or_default()
+            });
+        let get = |uri: &str, accept: Option<&str>| {
+            let mut req = http::Request::builder().uri(uri);
+            if let Some(accept) = accept {
+                req = req.header("accept", accept);
+            }
+            app.handle(req.body(Body::empty()).unwrap())
+        };
+        let content_type =
+            |res: &CoreResponse| res.headers()["content-type"].to_str().unwrap().to_string();
+
+        let res = get("/user", None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(content_type(&res), "application/json; charset=utf-8");
+        assert_eq!(res.headers()["vary"], "accept");
+        assert_eq!(res.body().as_bytes().unwrap(), br#"{"id":7,"name":"ada"}"#);
+
+        let res = get("/user", Some("text/html, application/*;q=0.5")).await;
+        assert_eq!(content_type(&res), "application/json; charset=utf-8");
+
+        let res = get("/user", Some("text/html")).await;
+        assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        let res = get("/user", Some("application/json;q=0, text/*")).await;
+        assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        #[cfg(feature = "xml")]
+        {
+            let res = get("/user", Some("application/xml")).await;
+            assert_eq!(content_type(&res), "application/xml; charset=utf-8");
+            assert_eq!(
+                res.body().as_bytes().unwrap(),
+                b"<User><id>7</id><name>ada</name></User>".as_slice()
+            );
+        }
+        #[cfg(feature = "msgpack")]
+        {
+            let res = get("/user", Some("application/msgpack, application/json;q=0.9")).await;
+            assert_eq!(content_type(&res), "application/msgpack");
+            assert_eq!(res.body().as_bytes().unwrap()[0], 0x82);
+        }
+        let res = get("/lenient", Some("text/html")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(content_type(&res), "application/json; charset=utf-8");
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            "accept",
+            "text/*;q=0.3, text/html;q=0.7, */*;q=0.1".parse().unwrap(),
+        );
+        let accepts = Accepts::from_headers(&headers);
+        assert_eq!(accepts.ranges().len(), 3);
+        assert_eq!(accepts.quality("text/html"), 0.7);
+        assert_eq!(accepts.quality("text/plain"), 0.3);
+        assert_eq!(accepts.quality("image/png"), 0.1);
+        assert_eq!(
+            accepts.preferred(&["image/png", "text/plain", "text/html"]),
+            Some("text/html")
+        );
+        assert!(Accepts::default().accepts("anything/at-all"));
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[tokio::test]
+    async fn test_msgpack_body() {
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        let app = App::new(Ctx::new()).post("/flip", |MsgPack(p): MsgPack<Point>| async move {
+            MsgPack(Point { x: p.y, y: p.x })
+        });
+        let post = |content_type: &str, body: Vec<u8>| {
+            app.handle(
+                http::Request::builder()
+                    .method(Method::POST)
+                    .uri("/flip")
+                    .header("content-type", content_type)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        let body = rmp_serde::to_vec_named(&Point { x: 1, y: 2 }).unwrap();
+        let res = post("application/msgpack", body.clone()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/msgpack");
+        let flipped: Point = rmp_serde::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(flipped, Point { x: 2, y: 1 });
+
+        // Positional encoding decodes too.
+        let res = post("application/x-msgpack", rmp_serde::to_vec(&(3, 4)).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = post("application/json", body).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let res = post("application/msgpack", vec![0xc1]).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = post("application/msgpack", rmp_serde::to_vec(&"text").unwrap()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[cfg(feature = "xml")]
+    #[tokio::test]
+    async fn test_xml_body() {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Entry {
+            title: String,
+        }
+
+        let app = App::new(Ctx::new()).post("/echo", |Xml(entry): Xml<Entry>| async move {
+            Xml(Entry {
+                title: entry.title.to_uppercase(),
+            })
+        });
+        let post = |content_type: &str, body: &'static str| {
+            app.handle(
+                http::Request::builder()
+                    .method(Method::POST)
+                    .uri("/echo")
+                    .header("content-type", content_type)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        for content_type in [
+            "application/xml",
+            "text/xml; charset=utf-8",
+            "application/atom+xml",
+        ] {
+            let res = post(content_type, "<entry><title>hi</title></entry>").await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers()["content-type"],
+                "application/xml; charset=utf-8"
+            );
+            assert_eq!(res.body(), "<Entry><title>HI</title></Entry>");
+        }
+
+        let res = post("application/json", r#"{"title":"hi"}"#).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let res = post("application/xml", "<entry><name>hi</name></entry>").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[tokio::test]
+    async fn test_protobuf_body() {
+        use prost::Message;
+
+        #[derive(Clone, PartialEq, prost::Message)]
+        struct Counter {
+            #[prost(string, tag = "1")]
+            name: String,
+            #[prost(uint64, tag = "2")]
+            value: u64,
+        }
+
+        let app = App::new(Ctx::new()).post("/inc", |Proto(c): Proto<Counter>| async move {
+            Proto(Counter {
+                value: c.value + 1,
+                ..c
+            })
+        });
+        let post = |content_type: &str, body: Vec<u8>| {
+            app.handle(
+                http::Request::builder()
+                    .method(Method::POST)
+                    .uri("/inc")
+                    .header("content-type", content_type)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        let counter = Counter {
+            name: "hits".to_string(),
+            value: 41,
+        };
+        let res = post("application/x-protobuf", counter.encode_to_vec()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/x-protobuf");
+        let decoded = Counter::decode(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.value, 42);
+        assert_eq!(decoded.name, "hits");
+
+        let res = post("application/octet-stream", counter.encode_to_vec()).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let res = post("application/protobuf", vec![0x0a, 0xff]).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_static_app_with_lazy_state() {
+        use std::sync::LazyLock;
+
+        static BUILDS: AtomicUsize = AtomicUsize::new(0);
+        static APP: LazyLock<App> = LazyLock::new(|| {
+            App::with_default_context()
+                .with_lazy_state(|| {
+                    BUILDS.fetch_add(1, Ordering::SeqCst);
+                    vec!["alpha".to_string(), "beta".to_string()]
+                })
+                .get("/", || async { "home" })
+                .get("/words", |State(words): State<Vec<String>>| async move {
+                    words.join(",")
+                })
+        });
+        let get = |uri: &'static str| {
+            APP.handle(
+                http::Request::builder()
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(get("/").await.body(), "home");
+        assert_eq!(BUILDS.load(Ordering::SeqCst), 0);
+        assert_eq!(get("/words").await.body(), "alpha,beta");
+        assert_eq!(get("/words").await.body(), "alpha,beta");
+        assert_eq!(BUILDS.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_validated_json() {
+        #[derive(serde::Deserialize)]
+        struct SignUp {
+            email: String,
+            age: u32,
+        }
+
+        impl Validate for SignUp {
+            fn validate(&self) -> std::result::Result<(), ValidationErrors> {
+                let mut errors = ValidationErrors::new();
+                if !self.email.contains('@') {
+                    errors.add("email", "must be an email address");
+                }
+                if self.age < 18 {
+                    errors.add("age", "must be at least 18");
+                }
+                errors.into_result()
+            }
+        }
+
+        let app = App::new(Ctx::new()).post(
+            "/signup",
+            |ValidatedJson(form): ValidatedJson<SignUp>| async move { form.email },
+        );
+        let post = |body: &'static str| {
+            app.handle(
+                http::Request::builder()
+                    .method(Method::POST)
+                    .uri("/signup")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        let res = post(r#"{"email":"a@example.com","age":30}"#).await;
+        assert_eq!(res.body(), "a@example.com");
+
+        let res = post(r#"{"email":"nope","age":12}"#).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value =
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            body["errors"],
+            serde_json::json!([
+                {"field": "email", "message": "must be an email address"},
+                {"field": "age", "message": "must be at least 18"}
+            ])
+        );
+        assert_eq!(body["status"], 422);
+
+        let res = post(r#"{"email":"a@example.com"}"#).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_request_ext() {
+        #[derive(Clone)]
+        struct Tenant(&'static str);
+
+        struct SetTenant;
+
+        #[async_trait]
+        impl Middleware<Ctx> for SetTenant {
+            async fn before(&self, _ctx: &Ctx, req: &mut CoreRequest) -> Result<()> {
+                req.extensions_mut().insert(Tenant("acme"));
+                Ok(())
+            }
+        }
+
+        let handler = |_ctx: Ctx, req: CoreRequest| async move {
+            let id: u32 = req.param_as("id")?;
+            let slug = req.param("slug")?;
+            let Tenant(tenant) = req.extension::<Tenant>()?;
+            Ok(format!("{} {} {}", tenant, id, slug).into_response())
+        };
+        let app = App::new(Ctx::new())
+            .get("/users/:id/posts/:slug", handler)
+            .get("/bare/:id/:slug", handler.with_middleware(SetTenant))
+            .get("/users/:id", |_ctx: Ctx, req: CoreRequest| async move {
+                req.param("slug")?;
+                Ok("unreachable".into_response())
+            })
+            .middleware_named("tenant", SetTenant);
+        let get = |uri: &'static str| {
+            app.handle(
+                http::Request::builder()
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(get("/users/7/posts/hi").await.body(), "acme 7 hi");
+        assert_eq!(
+            get("/users/x/posts/hi").await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(get("/users/7").await.status(), StatusCode::BAD_REQUEST);
+
+        app.middleware_switch()
+            .set_enabled("tenant", false)
+            .unwrap();
+        assert_eq!(
+            get("/users/7/posts/hi").await.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(get("/bare/7/hi").await.body(), "acme 7 hi");
+    }
+
+    #[tokio::test]
+    async fn test_optional_and_fallible_extractors() {
+        #[derive(serde::Deserialize)]
+        struct Page {
+            offset: u32,
+            limit: u32,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Item {
+            name: String,
+        }
+
+        let app = App::new(Ctx::new())
+            .get("/items", |page: Option<Query<Page>>| async move {
+                let (offset, limit) = page.map_or((0, 20), |Query(p)| (p.offset, p.limit));
+                format!("{}..{}", offset, offset + limit)
+            })
+            .post("/items", |item: Result<Json<Item>>| async move {
+                match item {
+                    Ok(Json(item)) => (StatusCode::CREATED, item.name),
+                    Err(e) => (StatusCode::BAD_REQUEST, format!("bad item: {}", e)),
+                }
+            });
+        let send = |method: Method, uri: &'static str, body: &'static str| {
+            app.handle(
+                http::Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(send(Method::GET, "/items", "").await.body(), "0..20");
+        assert_eq!(
+            send(Method::GET, "/items?offset=40&limit=10", "")
+                .await
+                .body(),
+            "40..50"
+        );
+        assert_eq!(
+            send(Method::GET, "/items?offset=x", "").await.body(),
+            "0..20"
+        );
+
+        let res = send(Method::POST, "/items", r#"{"name":"pen"}"#).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.body(), "pen");
+        let res = send(Method::POST, "/items", "{").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.body(), "bad item: JSON parse error");
+    }
+
+    #[test]
+    fn test_response_presets() {
+        let res = ResponseBuilder::ok().body("hi").unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "hi");
+
+        let res = ResponseBuilder::created("/users/7")
+            .json_body(&serde_json::json!({"id": 7}))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()["location"], "/users/7");
+        assert_eq!(
+            res.headers()["content-type"],
+            "application/json; charset=utf-8"
+        );
+        assert_eq!(res.body(), "{\"id\":7}");
+
+        let res = ResponseBuilder::no_content().finish().unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.body(), "");
+
+        let res = ResponseBuilder::json(&vec![1, 2]).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "[1,2]");
+
+        assert!(ResponseBuilder::created("bad\nlocation").finish().is_err());
+
+        let res = ResponseBuilder::problem(StatusCode::CONFLICT, "slug is taken");
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(res.headers()["content-type"], "application/problem+json");
+        let body: serde_json::Value =
+            serde_json::from_slice(res.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Conflict",
+                "status": 409,
+                "detail": "slug is taken",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_ip() {
+        use client_ip::{ClientIp, TrustedProxies};
+        use extract::RemoteAddr;
+
+        let ip = |s: &str| s.parse::<std::net::IpAddr>().unwrap();
+        let request = |peer: &str, headers: &[(&str, &str)]| {
+            let mut builder = http::Request::builder().uri("/ip");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            let mut req = builder.body(Body::empty()).unwrap();
+            req.extensions_mut()
+                .insert(RemoteAddr(peer.parse().unwrap()));
+            req
+        };
+        let app = App::new(())
+            .trusted_proxies(
+                TrustedProxies::parse(&["10.0.0.0/8", "2001:db8::/32"])
+                    .unwrap()
+                    .cf_connecting_ip(),
+            )
+            .get(
+                "/ip",
+                |ClientIp(ip): ClientIp| async move { ip.to_string() },
+            );
+        let client = |req: CoreRequest| {
+            let app = app.clone();
+            async move { app.handle(req).await.into_body() }
+        };
+
+        // Untrusted peers cannot claim another address.
+        let spoofed = request("203.0.113.9:443", &[("x-forwarded-for", "198.51.100.1")]);
+        assert_eq!(client(spoofed).await, "203.0.113.9");
+
+        // The rightmost untrusted hop wins, skipping trusted proxies.
+        let req = request(
+            "10.0.0.2:443",
+            &[("x-forwarded-for", "1.1.1.1, 198.51.100.1, 10.0.0.7")],
+        );
+        assert_eq!(client(req).await, "198.51.100.1");
+        let req = request("10.0.0.2:443", &[("x-forwarded-for", "10.0.0.8, 10.0.0.7")]);
+        assert_eq!(client(req).await, "10.0.0.8");
+
+        let req = request(
+            "[2001:db8::1]:443",
+            &[(
+                "forwarded",
+                "for=192.0.2.60;proto=https, for=\"[2001:db8:cafe::17]:4711\"",
+            )],
+        );
+        assert_eq!(client(req).await, "192.0.2.60");
+        let req = request(
+            "10.0.0.2:443",
+            &[("forwarded", "for=_hidden, for=10.0.0.5")],
+        );
+        assert_eq!(client(req).await, "10.0.0.5");
+
+        let req = request("10.0.0.2:443", &[("cf-connecting-ip", "198.51.100.4")]);
+        assert_eq!(client(req).await, "198.51.100.4");
+
+        // Without a trusted list the peer address is used as is.
+        let req = request(
+            "[::ffff:10.0.0.2]:443",
+            &[("x-forwarded-for", "198.51.100.1")],
+        );
+        assert_eq!(ClientIp::resolve(&req), Some(ClientIp(ip("10.0.0.2"))));
+
+        let res = app
+            .handle(http::Request::get("/ip").body(Body::empty()).unwrap())
+            .await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        assert!(TrustedProxies::parse(&["10.0.0.0/33"]).is_err());
+        assert!(TrustedProxies::parse(&["proxy.internal"]).is_err());
+        assert!(TrustedProxies::private().contains(ip("::ffff:192.168.1.1")));
+        assert!(!TrustedProxies::private().contains(ip("172.32.0.1")));
+    }
+
+    #[test]
+    fn test_created_and_accepted() {
+        let res = Created::new("/users/7", Json(serde_json::json!({"id": 7}))).into_response();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()["location"], "/users/7");
+        assert_eq!(
+            res.headers()["content-type"],
+            "application/json; charset=utf-8"
+        );
+        assert_eq!(res.body(), "{\"id\":7}");
+
+        let created = Created::at_route(
+            "/teams/:team/users/:id",
+            &[("id", "a b"), ("team", "x")],
+            (),
+        )
+        .unwrap();
+        let res = created.into_response();
+        assert_eq!(res.headers()["location"], "/teams/x/users/a%20b");
+        assert_eq!(res.body(), "");
+
+        assert_eq!(
+            response::route_path("/files/*path", &[("path", "docs/ä.txt")]).unwrap(),
+            "/files/docs/%C3%A4.txt"
+        );
+        assert!(Created::at_route("/users/:id", &[], ()).is_err());
+
+        let res = Accepted::new("queued").into_response();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert!(res.headers().get("location").is_none());
+        assert_eq!(res.body(), "queued");
+
+        let res = Accepted::new(())
+            .at_route("/jobs/:id", &[("id", "42")])
+            .unwrap()
+            .into_response();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(res.headers()["location"], "/jobs/42");
+
+        let res = Created::new("/bad\nlocation", ()).into_result();
+        assert_eq!(
+            res.unwrap_err().status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_info() {
+        use extract::{ConnectInfo, TlsInfo};
+
+        let app = App::new(()).get("/conn", |info: ConnectInfo| async move {
+            format!(
+                "{} {} {}",
+                info.remote_addr,
+                info.scheme,
+                info.tls.and_then(|tls| tls.server_name).unwrap_or_default()
+            )
+        });
+
+        let mut req = http::Request::get("/conn").body(Body::empty()).unwrap();
+        req.extensions_mut().insert(ConnectInfo {
+            remote_addr: "192.0.2.1:5555".parse().unwrap(),
+            local_addr: Some("127.0.0.1:443".parse().unwrap()),
+            scheme: http::uri::Scheme::HTTPS,
+            tls: Some(TlsInfo {
+                server_name: Some("api.example.com".to_string()),
+                ..TlsInfo::default()
+            }),
+        });
+        let res = app.handle(req).await;
+        assert_eq!(res.body(), "192.0.2.1:5555 https api.example.com");
+
+        let res = app
+            .handle(http::Request::get("/conn").body(Body::empty()).unwrap())
+            .await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_head_served_by_get() {
+        let app = App::new(Ctx::new())
+            .get("/page", || async {
+                http::Response::builder()
+                    .header("x-version", "3")
+                    .body(Body::from("hello"))
+                    .unwrap()
+            })
+            .get("/empty", || async { "" })
+            .get("/nothing", || async { StatusCode::NO_CONTENT })
+            .get("/report", || async { "expensive" })
+            .route(Method::HEAD, "/report", || async {
+                http::Response::builder()
+                    .header("content-length", "9")
+                    .body(Body::empty())
+                    .unwrap()
+            })
+            .post("/submit", || async { "ok" });
+        let send = |method: Method, uri: &str| {
+            let req = http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            app.handle(req)
+        };
+
+        let res = send(Method::HEAD, "/page").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-version"], "3");
+        assert_eq!(res.headers()["content-length"], "5");
+        assert_eq!(res.body(), "");
+        assert_eq!(send(Method::GET, "/page").await.body(), "hello");
+
+        let res = send(Method::HEAD, "/empty").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-length"], "0");
+        let res = send(Method::HEAD, "/nothing").await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(!res.headers().contains_key("content-length"));
+
+        // An explicit HEAD route is used instead of the GET one.
+        let res = send(Method::HEAD, "/report").await;
+        assert_eq!(res.headers()["content-length"], "9");
+        assert_eq!(res.body(), "");
+
+        let res = send(Method::HEAD, "/submit").await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()["allow"], "POST");
+    }
+
+    #[tokio::test]
+    async fn test_router_method_map() {
+        let report = Method::from_bytes(b"REPORT").unwrap();
+        let router = RouterBuilder::<()>::new()
+            .route(report.clone(), "/items/:id", || async { "report" })
+            .get("/items/:id", || async { "get" })
+            .post("/items/:id", || async { "post" })
+            .any("/files/*path", || async { "any" })
+            .get("/files/*path", || async { "get file" })
+            .freeze();
+
+        assert_eq!(
+            router.allowed_methods("/items/1"),
+            vec![Method::GET, Method::POST, Method::HEAD, report.clone()]
+        );
+        assert!(router.allowed_methods("/nothing").is_empty());
+
+        let send = |method: Method, uri: &str| {
+            let req = http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            router.handle((), req)
+        };
+        assert_eq!(send(report, "/items/1").await.body(), "report");
+        let res = send(Method::DELETE, "/items/1").await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()["allow"], "GET, POST, HEAD, REPORT");
+
+        // Method-specific routes win over the any-method route on a path.
+        assert_eq!(send(Method::GET, "/files/a/b").await.body(), "get file");
+        assert_eq!(send(Method::PUT, "/files/a/b").await.body(), "any");
+        assert_eq!(send(Method::GET, "/files/").await.body(), "get file");
+    }
+
+    #[tokio::test]
+    async fn test_router_param_names_per_method() {
+        use std::collections::BTreeMap;
+        async fn params(Path(params): Path<BTreeMap<String, String>>) -> String {
+            format!("{:?}", params)
+        }
+        let router = RouterBuilder::<()>::new()
+            .get("/users/:id", |Path(id): Path<String>| async move { id })
+            .delete("/users/:name", params)
+            .get("/files/:owner/*path", params)
+            .put("/files/:user/*rest", params)
+            .try_freeze()
+            .unwrap();
+
+        let send = |method: Method, uri: &str| {
+            let req = http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            router.handle((), req)
+        };
+        assert_eq!(send(Method::GET, "/users/7").await.body(), "7");
+        assert_eq!(
+            send(Method::DELETE, "/users/7").await.body(),
+            r#"{"name": "7"}"#
+        );
+        assert_eq!(
+            send(Method::GET, "/files/ann/a/b").await.body(),
+            r#"{"owner": "ann", "path": "a/b"}"#
+        );
+        assert_eq!(
+            send(Method::PUT, "/files/ann/").await.body(),
+            r#"{"rest": "", "user": "ann"}"#
+        );
+    }
+
+    #[test]
+    fn test_conditional_requests() {
+        use conditional::{Conditional, Precondition};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let modified = UNIX_EPOCH + Duration::new(784_111_777, 500);
+        assert_eq!(
+            header::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        assert_eq!(
+            header::parse_http_date(header::http_date(modified).to_str().unwrap()),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        assert_eq!(
+            header::parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            None
+        );
+
+        let request = |method: Method, headers: &[(&str, &str)]| {
+            let mut builder = http::Request::builder().method(method).uri("/items/1");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let current = Conditional::new().etag("v2").last_modified(modified);
+        let eval =
+            |method: Method, headers: &[(&str, &str)]| current.evaluate(&request(method, headers));
+
+        assert_eq!(eval(Method::GET, &[]), Precondition::Proceed);
+        assert_eq!(
+            eval(Method::GET, &[("if-none-match", "\"v1\", W/\"v2\"")]),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            eval(Method::PUT, &[("if-none-match", "*")]),
+            Precondition::Failed
+        );
+        assert_eq!(
+            eval(
+                Method::GET,
+                &[("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]
+            ),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            eval(
+                Method::GET,
+                &[("if-modified-since", "Sun, 06 Nov 1994 08:49:36 GMT")]
+            ),
+            Precondition::Proceed
+        );
+        // If-None-Match takes precedence over If-Modified-Since.
+        assert_eq!(
+            eval(
+                Method::GET,
+                &[
+                    ("if-none-match", "\"v1\""),
+                    ("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ]
+            ),
+            Precondition::Proceed
+        );
+
+        assert_eq!(
+            eval(Method::PUT, &[("if-match", "\"v2\"")]),
+            Precondition::Proceed
+        );
+        assert_eq!(
+            eval(Method::PUT, &[("if-match", "\"v1\"")]),
+            Precondition::Failed
+        );
+        assert_eq!(
+            eval(Method::PUT, &[("if-match", "W/\"v2\"")]),
+            Precondition::Failed
+        );
+        assert_eq!(
+            eval(
+                Method::PUT,
+                &[("if-unmodified-since", "Sat, 05 Nov 1994 08:49:37 GMT")]
+            ),
+            Precondition::Failed
+        );
+
+        let create = request(Method::PUT, &[("if-none-match", "*")]);
+        assert_eq!(
+            Conditional::absent().evaluate(&create),
+            Precondition::Proceed
+        );
+        let update = request(Method::PUT, &[("if-match", "*")]);
+        assert_eq!(
+            Conditional::absent().evaluate(&update),
+            Precondition::Failed
+        );
+
+        let res = current
+            .respond(&request(Method::GET, &[]), || "item")
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["etag"], "\"v2\"");
+        assert_eq!(
+            res.headers()["last-modified"],
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        assert_eq!(res.body(), "item");
+
+        let req = request(Method::GET, &[("if-none-match", "\"v2\"")]);
+        let res = current
+            .respond(&req, || -> &str { panic!("body built for a 304") })
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()["etag"], "\"v2\"");
+        assert_eq!(res.body(), "");
+
+        let req = request(Method::DELETE, &[("if-match", "\"v1\"")]);
+        let error = current.respond(&req, || "deleted").unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn test_build_reports_route_errors() {
+        let app = App::new(())
+            .get("/users/:id", || async { "user" })
+            .post("/users/:id", || async { "created" })
+            .build()
+            .unwrap();
+        let res = app
+            .handle(http::Request::get("/users/7").body(Body::empty()).unwrap())
+            .await;
+        assert_eq!(res.body(), "user");
+
+        let errors = App::new(())
+            .get("/users/:id", || async { "user" })
+            .get("/users/:id", || async { "again" })
+            .get("/users/:name", || async { "named" })
+            .get("/files/*path/raw", || async { "raw" })
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            errors[0],
+            RouteError::Duplicate {
+                method: "GET".to_string(),
+                path: "/users/:id".to_string(),
+            }
+        );
+        assert!(matches!(
+            &errors[1],
+            RouteError::Conflict { path, .. } if path == "/users/:name"
+        ));
+        assert!(matches!(
+            &errors[2],
+            RouteError::Invalid { path, .. } if path == "/files/*path/raw"
+        ));
+        assert_eq!(
+            errors[0].to_string(),
+            "GET /users/:id is registered more than once"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_one_time_tokens() {
+        use clock::ManualClock;
+        use magic_link::{confirm_page, OneTimeToken, OneTimeTokens};
+        use std::time::Duration;
+
+        let clock = ManualClock::at_unix(1_700_000_000);
+        let mut ctx = Ctx::with_kv(Arc::new(MemoryKv::default()));
+        ctx.clock = Arc::new(clock.clone());
+        let tokens = OneTimeTokens::new().ttl(Duration::from_secs(600));
+        let app = App::new(ctx.clone())
+            .with_state(tokens.clone())
+            .get("/auth/magic", |req: CoreRequest| async move {
+                confirm_page(&req)
+            })
+            .post(
+                "/auth/magic",
+                |OneTimeToken(subject): OneTimeToken| async move { subject },
+            )
+            .post(
+                "/verify",
+                |OneTimeToken(subject): OneTimeToken| async move { subject },
+            );
+        let redeem = |uri: &str, token: &str| {
+            let app = app.clone();
+            let req = http::Request::post(uri)
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(Body::from(format!("token={}", token)))
+                .unwrap();
+            async move { app.handle(req).await }
+        };
+
+        let token = tokens
+            .mint(&ctx, "/auth/magic", "ada@example.com")
+            .await
+            .unwrap();
+        assert_eq!(token.len(), 64);
+        // Stored under its hash, not the token itself.
+        assert!(ctx
+            .kv
+            .as_ref()
+            .unwrap()
+            .get(&format!("otk:{}", token))
+            .await
+            .is_none());
+
+        // Following the link only shows the confirmation form.
+        let res = app
+            .handle(
+                http::Request::get(format!("/auth/magic?token={}", token))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["cache-control"], "no-store");
+        let page = String::from_utf8(res.body().as_bytes().unwrap().to_vec()).unwrap();
+        assert!(page.contains(r#"method="post" action="/auth/magic""#));
+        assert!(page.contains(&format!(r#"value="{}""#, token)));
+        let res = app
+            .handle(
+                http::Request::get("/auth/magic?token=%22%3E%3Cscript%3E")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = redeem("/auth/magic", &token).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "ada@example.com");
+
+        // Burned on first use.
+        let res = redeem("/auth/magic", &token).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // Bound to the route it was minted for.
+        let token = tokens
+            .mint(&ctx, "/auth/magic", "ada@example.com")
+            .await
+            .unwrap();
+        let res = redeem("/verify", &token).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        clock.advance(Duration::from_secs(601));
+        let res = redeem("/auth/magic", &token).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = app
+            .handle(
+                http::Request::post("/auth/magic")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = redeem("/auth/magic", "guess").await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_region_policy() {
+        use proxy::{Proxy, StaticResolver};
+        use residency::{Country, RegionPolicy, ResidencyZone};
+
+        let policy = RegionPolicy::from_json(
+            r#"{"deny": ["kp", "IR"], "zones": {"eu": ["DE", "fr"]}, "unknown": "allow"}"#,
+        )
+        .unwrap();
+        let app = App::new(Ctx::with_http(Arc::new(EchoUpstream)))
+            .middleware(policy)
+            .get(
+                "/whoami",
+                |country: Option<Country>, zone: Option<ResidencyZone>| async move {
+                    format!(
+                        "{} {}",
+                        country.map_or("-".to_string(), |c| c.0),
+                        zone.map_or("-".to_string(), |z| z.0)
+                    )
+                },
+            )
+            .any(
+                "/orders",
+                Proxy::new("orders", StaticResolver::new(["http://us.orders"]))
+                    .pin_zone("eu", StaticResolver::new(["http://eu.orders"])),
+            );
+        let send = |method: Method, uri: &str, country: Option<&str>| {
+            let mut builder = http::Request::builder().method(method).uri(uri);
+            if let Some(country) = country {
+                builder = builder.header("cf-ipcountry", country);
+            }
+            app.handle(builder.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(
+            send(Method::GET, "/whoami", Some("de")).await.body(),
+            "DE eu"
+        );
+        assert_eq!(
+            send(Method::GET, "/whoami", Some("US")).await.body(),
+            "US -"
+        );
+        assert_eq!(send(Method::GET, "/whoami", Some("XX")).await.body(), "- -");
+        let res = send(Method::GET, "/whoami", Some("KP")).await;
+        assert_eq!(res.status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+
+        // EU writes go to the pinned upstreams; reads and other users don't.
+        let res = send(Method::POST, "/orders", Some("FR")).await;
+        assert_eq!(res.body(), "http://eu.orders/orders");
+        let res = send(Method::GET, "/orders", Some("FR")).await;
+        assert_eq!(res.body(), "http://us.orders/orders");
+        let res = send(Method::POST, "/orders", Some("US")).await;
+        assert_eq!(res.body(), "http://us.orders/orders");
+
+        let strict = App::new(())
+            .middleware(RegionPolicy::new().unknown(residency::UnknownCountry::Deny))
+            .get("/", || async { "ok" });
+        let res = strict
+            .handle(http::Request::get("/").body(Body::empty()).unwrap())
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+
+        assert!(RegionPolicy::from_json(r#"{"deny": ["Germany"]}"#).is_err());
+        assert!(RegionPolicy::from_json(r#"{"zones": {"eu": ["DE"], "de": ["de"]}}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_consent() {
+        use consent::{Consent, ConsentCookie, ConsentGate, Purpose};
+
+        let cookie = ConsentCookie::new().name("choices").version(2);
+        let set = cookie
+            .write(&Consent::only(&[Purpose::Analytics], 1_700_000_000))
+            .to_header_value()
+            .unwrap();
+        assert_eq!(
+            set.to_str().unwrap(),
+            "choices=v=2&preferences=0&analytics=1&marketing=0&at=1700000000; \
+             Path=/; Max-Age=15552000; Secure; SameSite=Lax"
+        );
+
+        let app = App::new(Ctx::new())
+            .middleware(cookie.clone())
+            .middleware(ConsentGate::new(
+                Purpose::Analytics,
+                RequireHeader("x-seen"),
+            ))
+            .get("/", |consent: Consent| async move {
+                format!(
+                    "{} {} {}",
+                    consent.is_decided(),
+                    consent.allows(Purpose::Analytics),
+                    consent.allows(Purpose::Marketing)
+                )
+            });
+        let send = |cookie: Option<&str>| {
+            let mut builder = http::Request::get("/").header("x-seen", "1");
+            if let Some(cookie) = cookie {
+                builder = builder.header("cookie", cookie);
+            }
+            app.handle(builder.body(Body::empty()).unwrap())
+        };
+
+        let res = send(Some(
+            "choices=v=2&preferences=0&analytics=1&marketing=0&at=1",
+        ))
+        .await;
+        assert_eq!(res.body(), "true true false");
+        assert_eq!(res.headers()["x-checked"], "x-seen");
+
+        // Undecided, outdated and malformed choices grant nothing.
+        for cookie in [
+            None,
+            Some("choices=v=1&analytics=1&at=1"),
+            Some("choices=v=2&analytics=yes&at=1"),
+            Some("choices=v=2&analytics=1"),
+        ] {
+            let res = send(cookie).await;
+            assert_eq!(res.body(), "false false false", "{:?}", cookie);
+            assert!(!res.headers().contains_key("x-checked"));
+        }
+
+        let withdraw = cookie.withdraw().to_header_value().unwrap();
+        assert!(withdraw
+            .to_str()
+            .unwrap()
+            .starts_with("choices=; Path=/; Max-Age=0"));
+    }
+
+    #[tokio::test]
+    async fn test_bulkheads() {
+        use concurrency::{Bulkhead, Bulkheads};
+
+        let mut ctx = Ctx::new();
+        ctx.insert_state(
+            Bulkheads::new()
+                .pool(Bulkhead::new("db", 1).queue(1))
+                .pool(Bulkhead::new("api", 2)),
+        );
+        let db = ctx.bulkhead("db").unwrap().clone();
+        let queued = |db: &Bulkhead| db.stats().queued;
+
+        let first = db.acquire().await.unwrap();
+        let waiting = tokio::spawn({
+            let db = db.clone();
+            async move { db.run(async { 7 }).await }
+        });
+        while queued(&db) == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // The queue is full, but the other pool is unaffected.
+        let error = ctx.bulkhead("db").unwrap().run(async {}).await.unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            ctx.bulkhead("api").unwrap().run(async { 1 }).await.unwrap(),
+            1
+        );
+
+        drop(first);
+        assert_eq!(waiting.await.unwrap().unwrap(), 7);
+        let stats = db.stats();
+        assert_eq!((stats.in_flight, stats.queued, stats.rejected), (0, 0, 1));
+
+        // A cancelled waiter leaves the queue.
+        let first = db.acquire().await.unwrap();
+        let waiting = tokio::spawn({
+            let db = db.clone();
+            async move { db.run(async {}).await }
+        });
+        while queued(&db) == 0 {
+            tokio::task::yield_now().await;
+        }
+        waiting.abort();
+        let _ = waiting.await;
+        assert_eq!(queued(&db), 0);
+        drop(first);
+        assert!(db.run(async {}).await.is_ok());
+
+        assert!(ctx.bulkhead("cache").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_plugins() {
+        struct Admin {
+            prefix: &'static str,
+        }
+
+        impl Plugin for Admin {
+            fn name(&self) -> &str {
+                "admin"
+            }
+
+            fn requires(&self) -> &[&str] {
+                &["auth"]
+            }
+
+            fn register(self, app: App) -> App {
+                app.get(
+                    &format!("{}/stats", self.prefix),
+                    TestHandler { response: "stats" },
+                )
+            }
+        }
+
+        struct Auth;
+
+        impl Plugin for Auth {
+            fn name(&self) -> &str {
+                "auth"
+            }
+
+            fn check(&self, ctx: &Ctx) -> Result<()> {
+                ctx.state::<String>()
+                    .map(|_| ())
+                    .ok_or_else(|| Error::internal("auth needs a realm"))
+            }
+
+            fn register(self, app: App) -> App {
+                app.get("/login", TestHandler { response: "login" })
+                    .middleware(RequireHeader("authorization"))
+            }
+        }
+
+        let admin = || Admin { prefix: "/admin" };
+        assert!(App::with_default_context().plugin(Auth).is_err());
+        assert!(App::with_default_context()
+            .with_state("acme".to_string())
+            .plugin(admin())
+            .is_err());
+
+        let app = App::with_default_context()
+            .with_state("acme".to_string())
+            .plugin(Auth)
+            .unwrap()
+            .plugin(admin())
+            .unwrap();
+        assert_eq!(app.plugins(), ["auth", "admin"]);
+        let res = app
+            .handle(
+                http::Request::builder()
+                    .uri("/admin/stats")
+                    .header("authorization", "Bearer t")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(res.body(), "stats");
+
+        assert!(matches!(
+            app.clone().plugin(Auth),
+            Err(plugin::PluginError::AlreadyMounted(_))
+        ));
+        let err = App::with_default_context()
+            .get("/admin/stats", TestHandler { response: "mine" })
+            .with_state("acme".to_string())
+            .plugin(Auth)
+            .unwrap()
+            .plugin(admin())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("GET /admin/stats"));
+        // Routes that compile to the same endpoint conflict too.
+        for (app, prefix) in [
+            (
+                App::with_default_context().get("/t/:org/stats", TestHandler { response: "mine" }),
+                "/t/:tenant",
+            ),
+            (
+                App::with_default_context().any("/admin/stats", TestHandler { response: "mine" }),
+                "/admin",
+            ),
+        ] {
+            let err = app
+                .with_state("acme".to_string())
+                .plugin(Auth)
+                .unwrap()
+                .plugin(Admin { prefix })
+                .err()
+                .unwrap();
+            assert!(matches!(err, plugin::PluginError::Routes { .. }), "{}", err);
+        }
+        assert!(App::with_default_context()
+            .with_state("acme".to_string())
+            .plugin(Auth)
+            .unwrap()
+            .plugin(Admin { prefix: "/ops" })
+            .is_ok());
+    }
+
+    #[test]
+    fn test_describe_app() {
+        let auth = App::new(())
+            .get("/login", || async { "login" })
+            .middleware(RequireHeader("authorization"))
+            .requires_config("JWT_SECRET", "Key for signing session tokens");
+        let app = App::with_default_context()
+            .post(
+                "/users",
+                TestHandler {
+                    response: "created",
+                },
+            )
+            .get(
+                "/admin",
+                TestHandler { response: "admin" }
+                    .with_middleware(RequireHeader("x-admin"))
+                    .with_middleware(RequireHeader("x-2fa")),
+            )
+            .get("/users", TestHandler { response: "users" })
+            .middleware_named("auth", RequireHeader("authorization"))
+            .nest_with_context("/auth", auth, |_| ())
+            .requires_config("DATABASE_URL", "Postgres connection string");
+        app.middleware_switch().set_enabled("auth", false).unwrap();
+
+        let description = app.describe();
+        let routes: Vec<_> = description
+            .routes
+            .iter()
+            .map(|r| format!("{} {}", r.method, r.path))
+            .collect();
+        assert_eq!(
+            routes,
+            [
+                "GET /admin",
+                "* /auth",
+                "* /auth/*path",
+                "GET /users",
+                "POST /users"
+            ]
+        );
+        assert_eq!(description.routes[0].middleware.len(), 2);
+        assert!(!description.middleware[0].enabled);
+        assert_eq!(description.scopes[0].prefix, "/auth");
+        assert_eq!(description.scopes[0].app.routes[0].path, "/login");
+        assert_eq!(description.scopes[0].app.config[0].name, "JWT_SECRET");
+
+        let json = description.to_json();
+        let parsed: describe::AppDescription = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, description);
+
+        let table = description.to_string();
+        assert!(table.lines().any(|line| line.starts_with("GET     /admin")
+            && line.ends_with("  [RequireHeader, RequireHeader]")));
+        assert!(table.contains("middleware /: auth (disabled)"));
+        assert!(table.contains("GET     /auth/login"));
+        assert!(table.contains("config DATABASE_URL: Postgres connection string"));
+    }
+
     #[tokio::test]
     async fn test_error_handling() {
         let ctx = Ctx::new();
@@ -195,7 +6017,7 @@ mod tests {
         let req = http::Request::builder()
             .method(Method::GET)
             .uri("/internal")
-            .body(bytes::Bytes::new())
+            .body(Body::empty())
             .unwrap();
 
         let response = app.handle(req).await;
@@ -0,0 +1,164 @@
+use crate::clock::{Clock, SystemClock};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Warn,
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        })
+    }
+}
+
+struct Event {
+    level: Level,
+    window_start: SystemTime,
+    written: usize,
+    suppressed: usize,
+    last: String,
+}
+
+type Writer = Arc<dyn Fn(Level, &str) + Send + Sync>;
+
+/// Collapses bursts of identical log events, such as an upstream timing out
+/// on every request during an incident, so they cannot saturate log IO.
+///
+/// Within each window the first `burst` occurrences of an event are written
+/// as usual and the rest are only counted. When the window is over, the last
+/// suppressed occurrence is written once with the number of occurrences it
+/// stands for, so both the first and the last one of a storm show up.
+/// Windows are closed lazily by later log calls or by [`flush`](Self::flush).
+///
+/// Events are identical when their keys are; [`warn`](Self::warn) and
+/// [`error`](Self::error) use the message itself as the key.
+#[derive(Clone)]
+pub struct LogSampler {
+    events: Arc<Mutex<HashMap<String, Event>>>,
+    window: Duration,
+    burst: usize,
+    clock: Arc<dyn Clock>,
+    writer: Writer,
+}
+
+impl LogSampler {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            events: Arc::default(),
+            window,
+            burst: 1,
+            clock: Arc::new(SystemClock),
+            writer: Arc::new(|_, line| eprintln!("{}", line)),
+        }
+    }
+
+    /// Occurrences of an event written per window before sampling kicks in,
+    /// 1 by default.
+    pub fn burst(mut self, burst: usize) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    /// Sends lines somewhere other than stderr.
+    pub fn writer(mut self, write: impl Fn(Level, &str) + Send + Sync + 'static) -> Self {
+        self.writer = Arc::new(write);
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn warn(&self, message: impl Into<String>) {
+        let message = message.into();
+        self.log(Level::Warn, &message, message.as_str());
+    }
+
+    pub fn error(&self, message: impl Into<String>) {
+        let message = message.into();
+        self.log(Level::Error, &message, message.as_str());
+    }
+
+    /// Logs `message` as an occurrence of the event `key`, for messages that
+    /// differ in details such as a request id.
+    pub fn log(&self, level: Level, key: &str, message: impl Into<String>) {
+        let message = message.into();
+        let now = self.clock.now();
+        let mut lines = self.close_windows(Some(now));
+        let mut events = self.events.lock().unwrap();
+        let event = events.entry(key.to_string()).or_insert_with(|| Event {
+            level,
+            window_start: now,
+            written: 0,
+            suppressed: 0,
+            last: String::new(),
+        });
+        if event.written < self.burst {
+            event.written += 1;
+            lines.push((level, message));
+        } else {
+            event.suppressed += 1;
+            event.level = event.level.max(level);
+            event.last = message;
+        }
+        drop(events);
+        for (level, line) in lines {
+            (self.writer)(level, &line);
+        }
+    }
+
+    /// Writes the summaries of all suppressed events, e.g. at shutdown.
+    pub fn flush(&self) {
+        let lines = self.close_windows(None);
+        for (level, line) in lines {
+            (self.writer)(level, &line);
+        }
+    }
+
+    /// Ends the windows that are over at `now`, or all of them, returning
+    /// the lines to write.
+    fn close_windows(&self, now: Option<SystemTime>) -> Vec<(Level, String)> {
+        let mut lines = Vec::new();
+        self.events.lock().unwrap().retain(|_, event| {
+            let open = now.is_some_and(|now| {
+                now.duration_since(event.window_start).unwrap_or_default() < self.window
+            });
+            if open {
+                return true;
+            }
+            if event.suppressed > 0 {
+                lines.push((
+                    event.level,
+                    format!(
+                        "{} (repeated {} more times in {:?})",
+                        event.last, event.suppressed, self.window
+                    ),
+                ));
+            }
+            false
+        });
+        lines
+    }
+}
+
+static GLOBAL: OnceLock<LogSampler> = OnceLock::new();
+
+/// The sampler the framework's own warnings go through, writing to stderr
+/// with a 10 second window unless [`set_global`] ran first.
+pub fn global() -> &'static LogSampler {
+    GLOBAL.get_or_init(|| LogSampler::new(Duration::from_secs(10)))
+}
+
+/// Replaces the framework's sampler; fails once it has been used.
+pub fn set_global(sampler: LogSampler) -> Result<(), LogSampler> {
+    GLOBAL.set(sampler)
+}
@@ -0,0 +1,228 @@
+use crate::clock::{Rng, SystemRng};
+use crate::extract::{read_body, FromRequest, MatchedPath};
+use crate::response::{Html, IntoResponse};
+use crate::{CoreRequest, CoreResponse, Ctx, Error};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::header::{HeaderValue, CACHE_CONTROL, REFERRER_POLICY};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Mints and redeems single-use, expiring tokens bound to a route and a
+/// subject, for login magic links and email verification. Tokens come from
+/// the operating system's generator, never the context's (possibly seeded)
+/// one, are stored in the context's Kv under their SHA-256 hash and are
+/// burned when redeemed.
+///
+/// Mail scanners and link previews follow links, so a link does not redeem
+/// its token: the `GET` route answers with [`confirm_page`], whose button
+/// `POST`s the token to the same path, where the [`OneTimeToken`] extractor
+/// redeems it.
+///
+/// Register it as state to change the defaults used by the [`OneTimeToken`]
+/// extractor:
+///
+/// ```ignore
+/// let tokens = OneTimeTokens::new().ttl(Duration::from_secs(600));
+/// let app = App::with_default_context()
+///     .with_state(tokens.clone())
+///     .post("/auth/email", move |ctx: Ctx, Json(form): Json<Login>| {
+///         let tokens = tokens.clone();
+///         async move {
+///             let token = tokens.mint(&ctx, "/auth/magic", &form.email).await?;
+///             send_mail(&form.email, &format!("https://example.com/auth/magic?token={}", token)).await?;
+///             Ok::<_, Error>(StatusCode::ACCEPTED)
+///         }
+///     })
+///     .get("/auth/magic", |req: CoreRequest| async move { confirm_page(&req) })
+///     .post("/auth/magic", |OneTimeToken(email): OneTimeToken| async move { login(email).await });
+/// ```
+///
+/// Redeeming is a read followed by a write, so on Kv backends without
+/// atomic updates two concurrent requests with the same token can both
+/// succeed.
+#[derive(Debug, Clone)]
+pub struct OneTimeTokens {
+    ttl: Duration,
+    prefix: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenRecord {
+    route: String,
+    subject: String,
+    expires_at: u64,
+    used: bool,
+}
+
+impl Default for OneTimeTokens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OneTimeTokens {
+    /// Tokens valid for 15 minutes, stored under `otk:`.
+    pub fn new() -> Self {
+        Self {
+            ttl: Duration::from_secs(15 * 60),
+            prefix: "otk:".to_string(),
+        }
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn key_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// A new token that only `route` (its pattern, e.g. `/auth/magic`) can
+    /// redeem, yielding `subject`.
+    pub async fn mint(&self, ctx: &Ctx, route: &str, subject: &str) -> Result<String, Error> {
+        let kv = kv(ctx)?;
+        let mut bytes = [0; 32];
+        SystemRng.fill_bytes(&mut bytes);
+        let mut token = String::with_capacity(64);
+        for byte in bytes {
+            let _ = write!(token, "{:02x}", byte);
+        }
+
+        let record = TokenRecord {
+            route: route.to_string(),
+            subject: subject.to_string(),
+            expires_at: ctx.clock.unix_secs() + self.ttl.as_secs(),
+            used: false,
+        };
+        kv.put_with_ttl(
+            &self.key(&token),
+            Bytes::from(serde_json::to_vec(&record)?),
+            self.ttl,
+        )
+        .await
+        .map_err(|e| Error::internal(format!("Failed to store token: {}", e)))?;
+        Ok(token)
+    }
+
+    /// The subject `token` was minted for, burning the token. Unknown,
+    /// expired, already used tokens and tokens minted for another route
+    /// are all `401 Unauthorized`.
+    pub async fn redeem(&self, ctx: &Ctx, route: &str, token: &str) -> Result<String, Error> {
+        let kv = kv(ctx)?;
+        if token.is_empty() || token.len() > 128 {
+            return Err(Error::unauthorized());
+        }
+        let key = self.key(token);
+        let now = ctx.clock.unix_secs();
+
+        // Expiry is checked here too, for Kv backends that ignore the TTL.
+        let mut record = kv
+            .get(&key)
+            .await
+            .and_then(|value| serde_json::from_slice::<TokenRecord>(&value).ok())
+            .filter(|record| !record.used && record.expires_at > now && record.route == route)
+            .ok_or_else(Error::unauthorized)?;
+
+        record.used = true;
+        let remaining = Duration::from_secs(record.expires_at - now);
+        kv.put_with_ttl(&key, Bytes::from(serde_json::to_vec(&record)?), remaining)
+            .await
+            .map_err(|e| Error::internal(format!("Failed to burn token: {}", e)))?;
+        Ok(record.subject)
+    }
+
+    /// The Kv key for `token`: a leaked Kv dump holds no usable tokens.
+    fn key(&self, token: &str) -> String {
+        let mut key = self.prefix.clone();
+        for byte in Sha256::digest(token.as_bytes()) {
+            let _ = write!(key, "{:02x}", byte);
+        }
+        key
+    }
+}
+
+fn kv(ctx: &Ctx) -> Result<&dyn crate::context::Kv, Error> {
+    ctx.kv
+        .as_deref()
+        .ok_or_else(|| Error::internal("One-time tokens require a Kv store"))
+}
+
+fn token_param(input: &[u8]) -> Option<String> {
+    form_urlencoded::parse(input)
+        .find(|(name, _)| name == "token")
+        .map(|(_, value)| value.into_owned())
+}
+
+/// A page asking the visitor to confirm, with a form posting the `token`
+/// query parameter back to the request's path. Not cached, and sent with
+/// `Referrer-Policy: no-referrer` so the token does not leak to other sites.
+pub fn confirm_page(req: &CoreRequest) -> Result<CoreResponse, Error> {
+    let token = req
+        .uri()
+        .query()
+        .and_then(|query| token_param(query.as_bytes()))
+        .ok_or_else(|| Error::bad_request("Missing token"))?;
+    // Minted tokens are hex; anything else cannot be redeemed, and must not
+    // reach the markup.
+    if token.is_empty() || token.len() > 128 || !token.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::unauthorized());
+    }
+    let html = format!(
+        "<!doctype html>\n<title>Confirm</title>\n\
+         <form method=\"post\" action=\"{}\">\n\
+         <input type=\"hidden\" name=\"token\" value=\"{}\">\n\
+         <button type=\"submit\">Continue</button>\n</form>\n",
+        escape(req.uri().path()),
+        token
+    );
+    let mut res = Html(html).into_response();
+    let headers = res.headers_mut();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+    Ok(res)
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The subject of a one-time token posted as the `token` form field, as
+/// [`confirm_page`] does, redeemed against the route the request matched.
+/// Only `POST` requests redeem tokens; others are `405 Method Not Allowed`.
+/// Uses the [`OneTimeTokens`] registered as state, or the defaults.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OneTimeToken(pub String);
+
+#[async_trait]
+impl FromRequest<Ctx> for OneTimeToken {
+    async fn from_request(ctx: &Ctx, req: &CoreRequest) -> Result<Self, Error> {
+        if req.method() != Method::POST {
+            return Err(Error::method_not_allowed());
+        }
+        let token = token_param(&read_body(req).await?)
+            .ok_or_else(|| Error::bad_request("Missing token"))?;
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .ok_or_else(|| Error::internal("Request was not routed"))?;
+        let tokens = ctx.state::<OneTimeTokens>().cloned().unwrap_or_default();
+        tokens.redeem(ctx, &route.0, &token).await.map(OneTimeToken)
+    }
+}
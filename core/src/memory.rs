@@ -0,0 +1,178 @@
+use crate::logging::{self, Level};
+use crate::{Body, Error};
+use bytes::Bytes;
+use futures_core::Stream;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+struct Budget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+/// A global cap on request body bytes held in memory at once, shared by all
+/// requests of a server.
+///
+/// Adapters reserve each body chunk as it is read and release it when the
+/// request has been handled. Once the budget is used up new bodies are shed
+/// with `503 Service Unavailable`, or `413 Payload Too Large` for a body that
+/// could never fit, rather than letting many concurrent uploads exhaust
+/// memory. Clones share the same budget.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    budget: Arc<Budget>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit_bytes: usize) -> Self {
+        Self {
+            budget: Arc::new(Budget {
+                limit: limit_bytes,
+                used: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.budget.limit
+    }
+
+    /// Bytes currently reserved by in-flight requests.
+    pub fn used(&self) -> usize {
+        self.budget.used.load(Ordering::Acquire)
+    }
+
+    pub fn available(&self) -> usize {
+        self.limit().saturating_sub(self.used())
+    }
+
+    /// Checks a declared `Content-Length` before reading anything.
+    pub fn admit(&self, content_length: usize) -> Result<(), Error> {
+        if content_length > self.limit() {
+            return Err(Error::payload_too_large());
+        }
+        if content_length > self.available() {
+            logging::global().log(
+                Level::Warn,
+                "memory budget shedding",
+                format!(
+                    "Shedding a {} byte body: {} of {} bytes in use",
+                    content_length,
+                    self.used(),
+                    self.limit()
+                ),
+            );
+            return Err(Error::service_unavailable());
+        }
+        Ok(())
+    }
+
+    /// Starts tracking one request; everything it reserves is released when
+    /// the reservation and all its clones are dropped.
+    pub fn reservation(&self) -> Reservation {
+        Reservation {
+            held: Arc::new(Held {
+                budget: Arc::clone(&self.budget),
+                bytes: AtomicUsize::new(0),
+            }),
+        }
+    }
+}
+
+struct Held {
+    budget: Arc<Budget>,
+    bytes: AtomicUsize,
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.budget
+            .used
+            .fetch_sub(*self.bytes.get_mut(), Ordering::AcqRel);
+    }
+}
+
+/// The bytes one request holds of a [`MemoryBudget`].
+#[derive(Clone)]
+pub struct Reservation {
+    held: Arc<Held>,
+}
+
+impl Reservation {
+    pub fn reserve(&self, bytes: usize) -> Result<(), Error> {
+        let budget = &self.held.budget;
+        let held = self.held.bytes.load(Ordering::Acquire);
+        if held + bytes > budget.limit {
+            return Err(Error::payload_too_large());
+        }
+        budget
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used + bytes <= budget.limit).then_some(used + bytes)
+            })
+            .map_err(|used| {
+                logging::global().log(
+                    Level::Warn,
+                    "memory budget shedding",
+                    format!(
+                        "Shedding a request body: {} of {} bytes in use",
+                        used, budget.limit
+                    ),
+                );
+                Error::service_unavailable()
+            })?;
+        self.held.bytes.fetch_add(bytes, Ordering::AcqRel);
+        Ok(())
+    }
+
+    pub fn held(&self) -> usize {
+        self.held.bytes.load(Ordering::Acquire)
+    }
+
+    /// Reserves every chunk of `body` as it is read.
+    pub fn track(&self, body: Body) -> Body {
+        match body {
+            Body::Full(bytes) => match self.reserve(bytes.len()) {
+                Ok(()) => Body::Full(bytes),
+                Err(error) => Body::from_stream(Tracked {
+                    body: Body::empty(),
+                    reservation: self.clone(),
+                    error: Some(error),
+                }),
+            },
+            body => Body::from_stream(Tracked {
+                body,
+                reservation: self.clone(),
+                error: None,
+            }),
+        }
+    }
+}
+
+struct Tracked {
+    body: Body,
+    reservation: Reservation,
+    error: Option<Error>,
+}
+
+impl Stream for Tracked {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(error) = self.error.take() {
+            return Poll::Ready(Some(Err(error)));
+        }
+        match Pin::new(&mut self.body).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => match self.reservation.reserve(chunk.len()) {
+                Ok(()) => Poll::Ready(Some(Ok(chunk))),
+                Err(error) => {
+                    self.body = Body::empty();
+                    Poll::Ready(Some(Err(error)))
+                }
+            },
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use crate::{
+    extract::MatchedPath,
+    handler::Handler,
+    middleware::{Middleware, Next},
+    sql::Sql,
+    CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use http::header::{HeaderName, CONTENT_TYPE};
+use http::Method;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The value that label values past a cap, unmatched paths and unknown
+/// methods collapse into.
+pub const OTHER: &str = "other";
+
+const MAX_LABEL_LEN: usize = 128;
+
+#[derive(Default)]
+struct Series {
+    count: u64,
+    duration_secs: f64,
+}
+
+/// A metric's name, help text and value per series.
+type Family = (&'static str, &'static str, fn(&Series) -> String);
+
+#[derive(Default)]
+struct Registry {
+    series: BTreeMap<Vec<String>, Series>,
+    /// Distinct values seen per label, by label index.
+    seen: HashMap<usize, HashSet<String>>,
+}
+
+/// Per-request counters and latency sums labelled by method, route pattern
+/// and status, rendered in the Prometheus text format by [`MetricsHandler`].
+///
+/// Labels are protected against cardinality blow-ups: requests that match
+/// no route are counted under the route `other` rather than their path, so
+/// a scanner probing random URLs adds a single series, and every label
+/// keeps at most `max_label_values` distinct values, later ones being
+/// collapsed into `other`. Clones share the same registry.
+#[derive(Clone)]
+pub struct RequestMetrics {
+    registry: Arc<Mutex<Registry>>,
+    headers: Vec<(String, HeaderName)>,
+    max_label_values: usize,
+    pool: Option<Arc<dyn Sql>>,
+}
+
+impl RequestMetrics {
+    pub fn new() -> Self {
+        Self {
+            registry: Arc::default(),
+            headers: Vec::new(),
+            max_label_values: 100,
+            pool: None,
+        }
+    }
+
+    /// Also exports the [`PoolStats`](crate::sql::PoolStats) of `sql`, such
+    /// as the context's backend, when it has a pool: connections by state,
+    /// waiting callers, acquisitions and the time spent waiting for them.
+    pub fn sql_pool(mut self, sql: Arc<dyn Sql>) -> Self {
+        self.pool = Some(sql);
+        self
+    }
+
+    /// Caps the distinct values of each label, 100 by default.
+    pub fn max_label_values(mut self, max: usize) -> Self {
+        self.max_label_values = max.max(1);
+        self
+    }
+
+    /// Adds a label taken from a request header, e.g. a tenant or client
+    /// version; requests without the header get an empty value.
+    pub fn label_from_header(mut self, label: &str, header: HeaderName) -> Self {
+        self.headers.push((sanitize_name(label), header));
+        self
+    }
+
+    fn label_names(&self) -> Vec<&str> {
+        ["method", "route", "status"]
+            .into_iter()
+            .chain(self.headers.iter().map(|(label, _)| label.as_str()))
+            .collect()
+    }
+
+    fn record(&self, mut values: Vec<String>, secs: f64) {
+        let mut registry = self.registry.lock().unwrap();
+        for (index, value) in values.iter_mut().enumerate() {
+            let seen = registry.seen.entry(index).or_default();
+            if seen.contains(value.as_str()) {
+                continue;
+            }
+            if seen.len() >= self.max_label_values {
+                *value = OTHER.to_string();
+            } else {
+                seen.insert(value.clone());
+            }
+        }
+        let series = registry.series.entry(values).or_default();
+        series.count += 1;
+        series.duration_secs += secs;
+    }
+
+    /// The registry in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let names = self.label_names();
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+        let metrics: [Family; 2] = [
+            ("xeno_requests_total", "Requests handled.", |s| {
+                s.count.to_string()
+            }),
+            (
+                "xeno_request_duration_seconds_sum",
+                "Total time spent handling requests.",
+                |s| s.duration_secs.to_string(),
+            ),
+        ];
+        for (metric, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {} {}", metric, help);
+            let _ = writeln!(out, "# TYPE {} counter", metric);
+            for (values, series) in &registry.series {
+                let labels = names
+                    .iter()
+                    .zip(values)
+                    .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let _ = writeln!(out, "{}{{{}}} {}", metric, labels, value(series));
+            }
+        }
+        drop(registry);
+        if let Some(stats) = self.pool.as_ref().and_then(|sql| sql.pool_stats()) {
+            let _ = writeln!(
+                out,
+                "# HELP xeno_sql_pool_connections Sql pool connections by state.\n\
+                 # TYPE xeno_sql_pool_connections gauge\n\
+                 xeno_sql_pool_connections{{state=\"idle\"}} {}\n\
+                 xeno_sql_pool_connections{{state=\"in_use\"}} {}\n\
+                 # HELP xeno_sql_pool_max_connections Sql pool size limit.\n\
+                 # TYPE xeno_sql_pool_max_connections gauge\n\
+                 xeno_sql_pool_max_connections {}\n\
+                 # HELP xeno_sql_pool_waiting Callers waiting for a Sql connection.\n\
+                 # TYPE xeno_sql_pool_waiting gauge\n\
+                 xeno_sql_pool_waiting {}\n\
+                 # HELP xeno_sql_pool_acquires_total Sql connections handed out.\n\
+                 # TYPE xeno_sql_pool_acquires_total counter\n\
+                 xeno_sql_pool_acquires_total {}\n\
+                 # HELP xeno_sql_pool_acquire_wait_seconds_total Time spent waiting for Sql connections.\n\
+                 # TYPE xeno_sql_pool_acquire_wait_seconds_total counter\n\
+                 xeno_sql_pool_acquire_wait_seconds_total {}",
+                stats.idle,
+                stats.size.saturating_sub(stats.idle),
+                stats.max_size,
+                stats.waiting,
+                stats.acquires,
+                stats.acquire_wait_micros as f64 / 1e6
+            );
+        }
+        out
+    }
+}
+
+impl Default for RequestMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keeps label values short and valid UTF-8 for the exposition format.
+fn sanitize_value(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_LABEL_LEN)
+        .collect()
+}
+
+/// Prometheus label names are `[a-zA-Z_][a-zA-Z0-9_]*`.
+fn sanitize_name(name: &str) -> String {
+    let mut out: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !out.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        out.insert(0, '_');
+    }
+    out
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn method_label(method: &Method) -> &str {
+    match *method {
+        Method::GET
+        | Method::POST
+        | Method::PUT
+        | Method::DELETE
+        | Method::PATCH
+        | Method::HEAD
+        | Method::OPTIONS => method.as_str(),
+        _ => OTHER,
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for RequestMetrics {
+    async fn handle(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let start = Instant::now();
+        let mut values = vec![method_label(req.method()).to_string()];
+        let headers: Vec<String> = self
+            .headers
+            .iter()
+            .map(|(_, header)| {
+                req.headers()
+                    .get(header)
+                    .and_then(|v| v.to_str().ok())
+                    .map_or_else(String::new, sanitize_value)
+            })
+            .collect();
+
+        let result = next.run(ctx, req).await;
+        let (route, status) = match &result {
+            Ok(res) => (
+                res.extensions().get::<MatchedPath>().map(|m| m.0.clone()),
+                res.status().as_u16(),
+            ),
+            Err(e) => (None, e.status_code().as_u16()),
+        };
+        values.push(route.map_or_else(|| OTHER.to_string(), |r| sanitize_value(&r)));
+        values.push(status.to_string());
+        values.extend(headers);
+        self.record(values, start.elapsed().as_secs_f64());
+        result
+    }
+}
+
+/// Serves [`RequestMetrics`] for Prometheus to scrape.
+///
+/// Mount behind authentication or on an internal listener.
+pub struct MetricsHandler {
+    metrics: RequestMetrics,
+}
+
+impl MetricsHandler {
+    pub fn new(metrics: RequestMetrics) -> Self {
+        Self { metrics }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Handler<C> for MetricsHandler {
+    async fn call(&self, _ctx: C, _req: CoreRequest) -> Result<CoreResponse, Error> {
+        Ok(http::Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(self.metrics.render().into())?)
+    }
+}
@@ -1,8 +1,37 @@
-use crate::{CoreRequest, CoreResponse, Error, Handler};
+use crate::{
+    explain::{self, Trace},
+    formatter::{ErrorRequest, JsonFormatter, ResponseFormatter},
+    logging, CoreRequest, CoreResponse, Error, Handler,
+};
 use async_trait::async_trait;
+use http::header::CONTENT_TYPE;
+use http::Method;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+/// Request middleware.
+///
+/// Simple middleware implements `before`/`after`. Middleware that needs full
+/// control of the downstream call (short-circuiting with its own response,
+/// timeouts, retries) overrides `handle` and decides when to call
+/// [`Next::run`].
 #[async_trait]
 pub trait Middleware<C: Send + Sync + Clone + 'static>: Send + Sync {
+    async fn handle(
+        &self,
+        ctx: C,
+        mut req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        self.before(&ctx, &mut req).await?;
+        let original = crate::body::clone_request(&req);
+        let mut res = next.run(ctx.clone(), req).await?;
+        self.after(&ctx, &original, &mut res).await?;
+        Ok(res)
+    }
+
     async fn before(&self, ctx: &C, req: &mut CoreRequest) -> Result<(), Error> {
         let _ = (ctx, req);
         Ok(())
@@ -12,56 +41,298 @@ pub trait Middleware<C: Send + Sync + Clone + 'static>: Send + Sync {
         let _ = (ctx, req, res);
         Ok(())
     }
+
+    /// Name shown in explain traces and used to toggle the middleware at
+    /// runtime; the type name by default.
+    fn name(&self) -> &str {
+        explain::short_type_name(std::any::type_name::<Self>())
+    }
+}
+
+struct Entry<C> {
+    name: String,
+    middleware: Arc<dyn Middleware<C>>,
+    /// Shared with the stack's clones and its [`MiddlewareSwitch`].
+    enabled: Arc<AtomicBool>,
+}
+
+impl<C> Clone for Entry<C> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            middleware: Arc::clone(&self.middleware),
+            enabled: Arc::clone(&self.enabled),
+        }
+    }
+}
+
+impl<C> Entry<C> {
+    fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
 }
 
+/// Every middleware added to a stack or any of its clones, by name, as
+/// toggled through a [`MiddlewareSwitch`].
+#[derive(Default)]
+struct Registry {
+    flags: Mutex<Vec<(String, Arc<AtomicBool>)>>,
+}
+
+/// An ordered middleware chain.
+///
+/// Clones are independent: middleware added to a clone does not run in the
+/// original. Enabling and disabling through a [`MiddlewareSwitch`] still
+/// applies to the stack and all its clones, e.g. the copies an adapter
+/// serves connections with.
 pub struct MiddlewareStack<C> {
-    middleware: Vec<Box<dyn Middleware<C>>>,
+    entries: Arc<Vec<Entry<C>>>,
+    registry: Arc<Registry>,
 }
 
 impl<C: Send + Sync + Clone + 'static> MiddlewareStack<C> {
     pub fn new() -> Self {
         Self {
-            middleware: Vec::new(),
+            entries: Arc::new(Vec::new()),
+            registry: Arc::new(Registry::default()),
         }
     }
 
+    /// Adds middleware under its [`Middleware::name`].
     pub fn add(&mut self, middleware: Box<dyn Middleware<C>>) {
-        self.middleware.push(middleware);
+        let name = middleware.name().to_string();
+        self.add_named(name, middleware);
+    }
+
+    pub fn add_named(&mut self, name: impl Into<String>, middleware: Box<dyn Middleware<C>>) {
+        let name = name.into();
+        let enabled = Arc::new(AtomicBool::new(true));
+        self.registry
+            .flags
+            .lock()
+            .unwrap()
+            .push((name.clone(), Arc::clone(&enabled)));
+        // Copies the list if a clone still shares it.
+        Arc::make_mut(&mut self.entries).push(Entry {
+            name,
+            middleware: Arc::from(middleware),
+            enabled,
+        });
+    }
+
+    /// This stack's middleware in order, and whether each is enabled.
+    pub fn status(&self) -> Vec<MiddlewareStatus> {
+        self.entries
+            .iter()
+            .map(|entry| MiddlewareStatus {
+                name: entry.name.clone(),
+                enabled: entry.is_enabled(),
+            })
+            .collect()
     }
 
-    pub async fn execute<H>(&self, ctx: C, mut req: CoreRequest, handler: &H) -> CoreResponse
+    /// A handle for enabling and disabling this stack's middleware by name.
+    pub fn switch(&self) -> MiddlewareSwitch {
+        MiddlewareSwitch {
+            registry: Arc::clone(&self.registry),
+        }
+    }
+
+    pub async fn execute<H>(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        handler: &H,
+        formatter: &dyn ResponseFormatter,
+    ) -> CoreResponse
     where
         H: Handler<C>,
     {
-        for middleware in &self.middleware {
-            if let Err(error) = middleware.before(&ctx, &mut req).await {
-                return self.error_to_response(error);
-            }
+        Next {
+            middleware: &self.entries,
+            handler,
+            formatter,
+            caller: None,
         }
+        .run_formatted(ctx, req)
+        .await
+    }
 
-        let mut response = match handler.call(ctx.clone(), req.clone()).await {
-            Ok(res) => res,
-            Err(error) => return self.error_to_response(error),
-        };
+    /// Like [`MiddlewareStack::execute`], but hands errors back to the caller
+    /// instead of rendering them. Middleware that renders errors itself uses
+    /// the [`JsonFormatter`].
+    pub async fn run<H>(&self, ctx: C, req: CoreRequest, handler: &H) -> Result<CoreResponse, Error>
+    where
+        H: Handler<C>,
+    {
+        Next {
+            middleware: &self.entries,
+            handler,
+            formatter: &JsonFormatter,
+            caller: None,
+        }
+        .run(ctx, req)
+        .await
+    }
+}
 
-        for middleware in self.middleware.iter().rev() {
-            if let Err(error) = middleware.after(&ctx, &req, &mut response).await {
-                return self.error_to_response(error);
-            }
+/// The remainder of the middleware chain, ending in the route handler.
+pub struct Next<'a, C> {
+    middleware: &'a [Entry<C>],
+    handler: &'a dyn Handler<C>,
+    formatter: &'a dyn ResponseFormatter,
+    /// Explain trace entry of the middleware holding this `Next`.
+    caller: Option<usize>,
+}
+
+impl<'a, C: Send + Sync + Clone + 'static> Next<'a, C> {
+    /// Like [`Next::run`], but renders an error with the app's formatter,
+    /// for middleware that adds headers to every response, error responses
+    /// included.
+    pub async fn run_formatted(self, ctx: C, req: CoreRequest) -> CoreResponse {
+        let error_req = ErrorRequest::from_request(&req).with_context(&ctx);
+        let formatter = self.formatter;
+        match self.run(ctx, req).await {
+            Ok(response) => response,
+            Err(error) => formatter.format_error_for(&error, &error_req),
         }
+    }
 
-        response
+    pub async fn run(self, ctx: C, req: CoreRequest) -> Result<CoreResponse, Error> {
+        let trace = req.extensions().get::<Trace>().cloned();
+        if let (Some(trace), Some(caller)) = (&trace, self.caller) {
+            trace.set_outcome(caller, "passed");
+        }
+
+        let mut remaining = self.middleware;
+        let current = loop {
+            match remaining.split_first() {
+                None => return self.handler.call(ctx, req).await,
+                Some((entry, rest)) => {
+                    remaining = rest;
+                    if entry.is_enabled() {
+                        break &entry.middleware;
+                    }
+                }
+            }
+        };
+        let Some(trace) = trace else {
+            let next = Next {
+                middleware: remaining,
+                handler: self.handler,
+                formatter: self.formatter,
+                caller: None,
+            };
+            return current.handle(ctx, req, next).await;
+        };
+
+        let index = trace.begin("middleware", current.name());
+        let next = Next {
+            middleware: remaining,
+            handler: self.handler,
+            formatter: self.formatter,
+            caller: Some(index),
+        };
+        let start = Instant::now();
+        let result = current.handle(ctx, req, next).await;
+        trace.end(index, start.elapsed(), &result);
+        result
     }
+}
 
-    fn error_to_response(&self, error: Error) -> CoreResponse {
-        let status = error.status_code();
-        let body = format!("{{\"error\":\"{}\"}}", error);
+impl<C> Clone for MiddlewareStack<C> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: Arc::clone(&self.entries),
+            registry: Arc::clone(&self.registry),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MiddlewareStatus {
+    pub name: String,
+    pub enabled: bool,
+}
+
+impl Registry {
+    fn set_enabled(&self, name: &str, enabled: bool) -> bool {
+        let flags = self.flags.lock().unwrap();
+        let mut found = false;
+        for (_, flag) in flags.iter().filter(|(entry, _)| entry == name) {
+            flag.store(enabled, Ordering::Release);
+            found = true;
+        }
+        found
+    }
 
-        http::Response::builder()
-            .status(status)
-            .header("content-type", "application/json; charset=utf-8")
-            .body(body.into())
+    fn status(&self) -> Vec<MiddlewareStatus> {
+        self.flags
+            .lock()
             .unwrap()
+            .iter()
+            .map(|(name, flag)| MiddlewareStatus {
+                name: name.clone(),
+                enabled: flag.load(Ordering::Acquire),
+            })
+            .collect()
+    }
+}
+
+/// Enables and disables middleware of a stack at runtime, e.g. to switch
+/// off a misbehaving WAF rule during an incident. Requests in flight skip
+/// middleware disabled before they reach it.
+#[derive(Clone)]
+pub struct MiddlewareSwitch {
+    registry: Arc<Registry>,
+}
+
+impl MiddlewareSwitch {
+    /// Toggles every middleware registered under `name`.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> Result<(), Error> {
+        if !self.registry.set_enabled(name, enabled) {
+            return Err(Error::not_found());
+        }
+        logging::global().warn(format!(
+            "Middleware {} {}",
+            name,
+            if enabled { "enabled" } else { "disabled" }
+        ));
+        Ok(())
+    }
+
+    pub fn status(&self) -> Vec<MiddlewareStatus> {
+        self.registry.status()
+    }
+}
+
+/// Admin handler listing middleware on `GET` and toggling it on `POST` with
+/// a body like `{"name": "Compression", "enabled": false}`.
+///
+/// Anyone reaching it can switch off security middleware; mount it behind
+/// authentication.
+pub struct MiddlewareAdmin {
+    switch: MiddlewareSwitch,
+}
+
+impl MiddlewareAdmin {
+    pub fn new(switch: MiddlewareSwitch) -> Self {
+        Self { switch }
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Handler<C> for MiddlewareAdmin {
+    async fn call(&self, _ctx: C, req: CoreRequest) -> Result<CoreResponse, Error> {
+        if req.method() == Method::POST {
+            let body = req.into_body().collect().await?;
+            let change: MiddlewareStatus = serde_json::from_slice(&body)
+                .map_err(|e| Error::bad_request(format!("Invalid toggle: {}", e)))?;
+            self.switch.set_enabled(&change.name, change.enabled)?;
+        }
+        Ok(http::Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&self.switch.status())?.into())?)
     }
 }
 
@@ -73,3 +344,116 @@ where
         Self::new()
     }
 }
+
+/// A handler with middleware that only applies to its own route.
+pub struct Layered<C, H> {
+    handler: H,
+    stack: MiddlewareStack<C>,
+}
+
+impl<C: Send + Sync + Clone + 'static, H: Handler<C>> Layered<C, H> {
+    /// Wraps `handler` in `stack`, which may be shared with other handlers.
+    pub(crate) fn new(handler: H, stack: MiddlewareStack<C>) -> Self {
+        Self { handler, stack }
+    }
+
+    /// Adds another middleware; earlier ones run first on the way in.
+    pub fn with_middleware(mut self, middleware: impl Middleware<C> + 'static) -> Self {
+        self.stack.add(Box::new(middleware));
+        self
+    }
+}
+
+#[async_trait]
+impl<C, H> Handler<C> for Layered<C, H>
+where
+    C: Send + Sync + Clone + 'static,
+    H: Handler<C>,
+{
+    async fn call(&self, ctx: C, req: CoreRequest) -> Result<CoreResponse, Error> {
+        self.stack.run(ctx, req, &self.handler).await
+    }
+
+    fn middleware_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .stack
+            .status()
+            .into_iter()
+            .map(|status| status.name)
+            .collect();
+        names.extend(self.handler.middleware_names());
+        names
+    }
+}
+
+/// Attaches route-scoped middleware to any handler, e.g.
+/// `app.get("/admin", AdminHandler.with_middleware(AuthMiddleware))`.
+pub trait HandlerExt<C: Send + Sync + Clone + 'static>: Handler<C> + Sized {
+    fn with_middleware(self, middleware: impl Middleware<C> + 'static) -> Layered<C, Self> {
+        let mut stack = MiddlewareStack::new();
+        stack.add(Box::new(middleware));
+        Layered {
+            handler: self,
+            stack,
+        }
+    }
+}
+
+impl<C: Send + Sync + Clone + 'static, H: Handler<C>> HandlerExt<C> for H {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{App, Body, Ctx};
+
+    struct Tag(&'static str);
+
+    #[async_trait]
+    impl Middleware<Ctx> for Tag {
+        async fn after(
+            &self,
+            _ctx: &Ctx,
+            _req: &CoreRequest,
+            res: &mut CoreResponse,
+        ) -> Result<(), Error> {
+            res.headers_mut()
+                .append("x-tag", http::HeaderValue::from_static(self.0));
+            Ok(())
+        }
+
+        fn name(&self) -> &str {
+            self.0
+        }
+    }
+
+    async fn tags(app: &App) -> Vec<String> {
+        let req = http::Request::get("/").body(Body::empty()).unwrap();
+        app.handle(req)
+            .await
+            .headers()
+            .get_all("x-tag")
+            .iter()
+            .map(|v| v.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_clones_are_independent() {
+        let app = App::new(Ctx::new())
+            .middleware(Tag("base"))
+            .get("/", || async { "ok" });
+        let tagged = app.clone().middleware(Tag("extra"));
+
+        assert_eq!(tags(&app).await, ["base"]);
+        assert_eq!(tags(&tagged).await, ["extra", "base"]);
+        assert_eq!(app.describe().middleware.len(), 1);
+
+        // Toggles still reach every clone, as adapters serve with clones.
+        let switch = app.middleware_switch();
+        switch.set_enabled("base", false).unwrap();
+        assert!(tags(&app).await.is_empty());
+        assert_eq!(tags(&tagged).await, ["extra"]);
+        switch.set_enabled("base", true).unwrap();
+        assert_eq!(tags(&tagged).await, ["extra", "base"]);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::formatter::{JsonFormatter, ResponseFormatter};
+use crate::{extract::FromRequest, Body, CoreRequest, CoreResponse, Error, IntoResponse};
+use async_trait::async_trait;
+use http::header::{HeaderMap, ACCEPT, CONTENT_TYPE, VARY};
+use http::StatusCode;
+use serde::Serialize;
+
+/// One entry of an `Accept` header, e.g. `application/json;q=0.9`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaRange {
+    /// `type/subtype` in lowercase, without parameters.
+    pub essence: String,
+    pub q: f32,
+}
+
+impl MediaRange {
+    /// How closely the range matches `mime`: 3 for an exact match, 2 for
+    /// `type/*`, 1 for `*/*` and 0 for no match.
+    fn specificity(&self, mime: &str) -> u8 {
+        if self.essence == mime {
+            return 3;
+        }
+        match self.essence.split_once('/') {
+            Some(("*", "*")) => 1,
+            Some((kind, "*")) if mime.split('/').next() == Some(kind) => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// The media types a client accepts, from its `Accept` header.
+///
+/// A request without the header accepts anything.
+#[derive(Debug, Clone, Default)]
+pub struct Accepts {
+    ranges: Vec<MediaRange>,
+}
+
+impl Accepts {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let ranges = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let essence = parts.next()?.trim().to_ascii_lowercase();
+                if !essence.contains('/') {
+                    return None;
+                }
+                let q = parts
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0)
+                    .clamp(0.0, 1.0);
+                Some(MediaRange { essence, q })
+            })
+            .collect();
+        Self { ranges }
+    }
+
+    pub fn ranges(&self) -> &[MediaRange] {
+        &self.ranges
+    }
+
+    /// The quality the client gives `mime`, taken from the most specific
+    /// range matching it; 0 when none does.
+    pub fn quality(&self, mime: &str) -> f32 {
+        if self.ranges.is_empty() {
+            return 1.0;
+        }
+        let mime = mime.to_ascii_lowercase();
+        self.ranges
+            .iter()
+            .map(|range| (range.specificity(&mime), range.q))
+            .filter(|(specificity, _)| *specificity > 0)
+            .max_by(|a, b| a.0.cmp(&b.0))
+            .map_or(0.0, |(_, q)| q)
+    }
+
+    pub fn accepts(&self, mime: &str) -> bool {
+        self.quality(mime) > 0.0
+    }
+
+    /// The offered type the client likes best, earlier ones winning ties,
+    /// or `None` when it accepts none of them.
+    pub fn preferred<'a>(&self, offered: &[&'a str]) -> Option<&'a str> {
+        let mut best: Option<(&str, f32)> = None;
+        for mime in offered {
+            let q = self.quality(mime);
+            if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+                best = Some((mime, q));
+            }
+        }
+        best.map(|(mime, _)| mime)
+    }
+
+    /// Wraps `value` in a [`Negotiate`] response for these preferences.
+    pub fn negotiate<T>(&self, value: T) -> Negotiate<T> {
+        Negotiate::new(self.clone(), value)
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for Accepts {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Ok(Self::from_headers(req.headers()))
+    }
+}
+
+/// A representation [`Negotiate`] can produce. XML and MessagePack need
+/// the `xml` and `msgpack` features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    #[cfg(feature = "xml")]
+    Xml,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+}
+
+impl Format {
+    /// Every format compiled in, JSON first.
+    pub fn all() -> Vec<Format> {
+        vec![
+            Format::Json,
+            #[cfg(feature = "xml")]
+            Format::Xml,
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack,
+        ]
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            #[cfg(feature = "xml")]
+            Format::Xml => "application/xml",
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => "application/msgpack",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json; charset=utf-8",
+            #[cfg(feature = "xml")]
+            Format::Xml => "application/xml; charset=utf-8",
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => "application/msgpack",
+        }
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            Format::Json => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "xml")]
+            Format::Xml => quick_xml::se::to_string(value)
+                .map(String::into_bytes)
+                .map_err(|e| Error::internal(format!("Failed to serialize XML: {}", e))),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => rmp_serde::to_vec_named(value)
+                .map_err(|e| Error::internal(format!("Failed to serialize MessagePack: {}", e))),
+        }
+    }
+}
+
+/// A response serialized in whichever of the offered formats the client's
+/// `Accept` header ranks highest, so one handler can serve several
+/// representations.
+///
+/// Clients accepting none of them get `406 Not Acceptable`, or the default
+/// (first offered) format with [`or_default`](Self::or_default). Clients
+/// without a preference get the default.
+///
+/// ```ignore
+/// async fn get_user(accepts: Accepts, Path(id): Path<u32>) -> Negotiate<User> {
+///     accepts.negotiate(load_user(id))
+/// }
+/// ```
+pub struct Negotiate<T> {
+    value: T,
+    accepts: Accepts,
+    formats: Vec<Format>,
+    or_default: bool,
+}
+
+impl<T> Negotiate<T> {
+    pub fn new(accepts: Accepts, value: T) -> Self {
+        Self {
+            value,
+            accepts,
+            formats: Format::all(),
+            or_default: false,
+        }
+    }
+
+    /// Offers only `formats`, the first being the default.
+    pub fn formats(mut self, formats: &[Format]) -> Self {
+        if !formats.is_empty() {
+            self.formats = formats.to_vec();
+        }
+        self
+    }
+
+    /// Answers clients accepting none of the formats with the default one
+    /// instead of `406 Not Acceptable`.
+    pub fn or_default(mut self) -> Self {
+        self.or_default = true;
+        self
+    }
+
+    /// The format the response will use, if any.
+    pub fn format(&self) -> Option<Format> {
+        let offered: Vec<&str> = self.formats.iter().map(Format::mime).collect();
+        match self.accepts.preferred(&offered) {
+            Some(mime) => self.formats.iter().copied().find(|f| f.mime() == mime),
+            None if self.or_default => self.formats.first().copied(),
+            None => None,
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Negotiate<T> {
+    fn into_response(self) -> CoreResponse {
+        match self.into_result() {
+            Ok(response) => response,
+            Err(error) => JsonFormatter.format_error(&error),
+        }
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        let format = self.format().ok_or_else(Error::not_acceptable)?;
+        let body = format.serialize(&self.value)?;
+        Ok(http::Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, format.content_type())
+            .header(VARY, "accept")
+            .body(Body::from(body))?)
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    middleware::{Middleware, Next},
+    CoreRequest, CoreResponse, Ctx, Error,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::time::Duration;
+
+/// Where the single-use nonce is read from.
+#[derive(Debug, Clone)]
+pub enum NonceSource {
+    Header(String),
+    /// A top-level string field of a JSON body. Only meaningful when the body
+    /// is covered by a signature check that runs before this middleware.
+    JsonField(String),
+}
+
+/// Middleware enforcing single-use nonces, recorded in the context's Kv.
+///
+/// A nonce seen again within `ttl` is rejected with `409 Conflict`. The check
+/// is a read followed by a write, so two concurrent requests carrying the same
+/// nonce can both pass on backends without atomic insert.
+pub struct NonceGuard {
+    source: NonceSource,
+    ttl: Duration,
+    prefix: String,
+}
+
+impl NonceGuard {
+    pub fn new(source: NonceSource) -> Self {
+        Self {
+            source,
+            ttl: Duration::from_secs(300),
+            prefix: "nonce:".to_string(),
+        }
+    }
+
+    pub fn header(name: impl Into<String>) -> Self {
+        Self::new(NonceSource::Header(name.into()))
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn key_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn nonce(&self, req: &CoreRequest) -> Result<String, Error> {
+        let nonce = match &self.source {
+            NonceSource::Header(name) => req
+                .headers()
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            NonceSource::JsonField(field) => req
+                .body()
+                .as_bytes()
+                .and_then(|body| serde_json::from_slice::<serde_json::Value>(body).ok())
+                .and_then(|body| body.get(field)?.as_str().map(str::to_string)),
+        };
+
+        match nonce {
+            Some(nonce) if !nonce.is_empty() && nonce.len() <= 256 => Ok(nonce),
+            _ => Err(Error::bad_request("Missing or invalid nonce")),
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware<Ctx> for NonceGuard {
+    async fn handle(
+        &self,
+        ctx: Ctx,
+        mut req: CoreRequest,
+        next: Next<'_, Ctx>,
+    ) -> Result<CoreResponse, Error> {
+        if matches!(self.source, NonceSource::JsonField(_)) {
+            req.body_mut().buffer().await?;
+        }
+        let kv = ctx
+            .kv
+            .clone()
+            .ok_or_else(|| Error::internal("Nonce tracking requires a Kv store"))?;
+        let key = format!("{}{}", self.prefix, self.nonce(&req)?);
+        let now = ctx.clock.unix_secs();
+
+        // The expiry is stored in the value as well, for Kv backends that
+        // ignore the TTL.
+        let seen = kv
+            .get(&key)
+            .await
+            .and_then(|v| std::str::from_utf8(&v).ok()?.parse::<u64>().ok())
+            .is_some_and(|expires_at| expires_at > now);
+        if seen {
+            return Err(Error::conflict("Nonce has already been used"));
+        }
+
+        let expires_at = now + self.ttl.as_secs();
+        kv.put_with_ttl(&key, Bytes::from(expires_at.to_string()), self.ttl)
+            .await
+            .map_err(|e| Error::internal(format!("Failed to record nonce: {}", e)))?;
+
+        next.run(ctx, req).await
+    }
+}
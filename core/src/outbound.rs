@@ -0,0 +1,589 @@
+use crate::timeout::SleepFn;
+use crate::{
+    clock::{Rng, SystemRng},
+    context::HttpClient,
+    extract::FromRequest,
+    logging::{self, Level},
+    middleware::{Middleware, Next},
+    CoreRequest, CoreResponse, Ctx, Error,
+};
+use async_trait::async_trait;
+use http::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use http::StatusCode;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Outbound counterpart of [`Middleware`]: wraps every request sent through
+/// the context's HTTP client.
+#[async_trait]
+pub trait Interceptor: Send + Sync {
+    async fn intercept(
+        &self,
+        req: CoreRequest,
+        next: OutboundNext<'_>,
+    ) -> Result<CoreResponse, Error>;
+}
+
+/// The remaining interceptors and the underlying client. It is `Copy` so an
+/// interceptor can send more than once, e.g. to retry.
+#[derive(Clone, Copy)]
+pub struct OutboundNext<'a> {
+    interceptors: &'a [Arc<dyn Interceptor>],
+    client: &'a dyn HttpClient,
+}
+
+impl OutboundNext<'_> {
+    pub async fn run(self, req: CoreRequest) -> Result<CoreResponse, Error> {
+        match self.interceptors.split_first() {
+            Some((current, rest)) => {
+                let next = OutboundNext {
+                    interceptors: rest,
+                    client: self.client,
+                };
+                current.intercept(req, next).await
+            }
+            None => self.client.send(req).await,
+        }
+    }
+}
+
+/// An [`HttpClient`] that runs requests through a chain of interceptors,
+/// outermost first.
+#[derive(Clone)]
+pub struct InterceptedClient {
+    client: Arc<dyn HttpClient>,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl InterceptedClient {
+    pub fn new(client: Arc<dyn HttpClient>) -> Self {
+        Self {
+            client,
+            interceptors: Vec::new(),
+        }
+    }
+
+    pub fn interceptor(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+}
+
+#[async_trait]
+impl HttpClient for InterceptedClient {
+    async fn send(&self, req: CoreRequest) -> Result<CoreResponse, Error> {
+        OutboundNext {
+            interceptors: &self.interceptors,
+            client: self.client.as_ref(),
+        }
+        .run(req)
+        .await
+    }
+}
+
+/// Sets a header on every outbound request that does not already carry it,
+/// e.g. an API key or bearer token.
+pub struct SetHeader {
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+impl SetHeader {
+    pub fn new(name: HeaderName, value: HeaderValue) -> Self {
+        Self { name, value }
+    }
+
+    pub fn bearer(token: &str) -> Result<Self, Error> {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| Error::internal("Invalid bearer token"))?;
+        value.set_sensitive(true);
+        Ok(Self::new(AUTHORIZATION, value))
+    }
+}
+
+#[async_trait]
+impl Interceptor for SetHeader {
+    async fn intercept(
+        &self,
+        mut req: CoreRequest,
+        next: OutboundNext<'_>,
+    ) -> Result<CoreResponse, Error> {
+        if !req.headers().contains_key(&self.name) {
+            req.headers_mut()
+                .insert(self.name.clone(), self.value.clone());
+        }
+        next.run(req).await
+    }
+}
+
+/// Retries idempotent requests that fail or come back with `429` or a `5xx`.
+///
+/// Attempts are spaced with exponential backoff and full jitter: before
+/// retry `n`, the interceptor waits a random time of up to
+/// `base_delay * 2^(n-1)`, capped at `max_delay`, so clients that failed
+/// together do not retry together. The core has no timer of its own, so the
+/// platform's sleep is passed in, e.g. `Retry::new(3, tokio::time::sleep)`.
+pub struct Retry {
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    sleep: SleepFn,
+    rng: Arc<dyn Rng>,
+}
+
+impl Retry {
+    /// Up to `max_retries` retries, backing off from 100ms to at most 5s.
+    pub fn new<S, F>(max_retries: u32, sleep: S) -> Self
+    where
+        S: Fn(Duration) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            max_retries,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            sleep: Arc::new(move |duration| Box::pin(sleep(duration))),
+            rng: Arc::new(SystemRng),
+        }
+    }
+
+    pub fn base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self
+    }
+
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// The source of jitter, e.g. a seeded one in tests.
+    pub fn rng(mut self, rng: Arc<dyn Rng>) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// A random delay of up to the backoff ceiling for retry `attempt`.
+    fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(31))
+            .min(self.max_delay);
+        let nanos = u64::try_from(ceiling.as_nanos()).unwrap_or(u64::MAX);
+        Duration::from_nanos(self.rng.next_u64() % nanos.saturating_add(1))
+    }
+}
+
+#[async_trait]
+impl Interceptor for Retry {
+    async fn intercept(
+        &self,
+        req: CoreRequest,
+        next: OutboundNext<'_>,
+    ) -> Result<CoreResponse, Error> {
+        // A streamed body can only be sent once.
+        if !req.method().is_idempotent() || req.body().is_stream() {
+            return next.run(req).await;
+        }
+
+        let mut attempt = 0;
+        loop {
+            let result = next.run(crate::body::clone_request(&req)).await;
+            let retryable = match &result {
+                Ok(res) => {
+                    res.status() == StatusCode::TOO_MANY_REQUESTS || res.status().is_server_error()
+                }
+                Err(_) => true,
+            };
+            if !retryable || attempt >= self.max_retries {
+                return result;
+            }
+            attempt += 1;
+            logging::global().log(
+                Level::Warn,
+                &format!("retry {}", req.uri().host().unwrap_or_default()),
+                format!(
+                    "Retrying {} {} (attempt {} of {})",
+                    req.method(),
+                    req.uri(),
+                    attempt,
+                    self.max_retries
+                ),
+            );
+            (self.sleep)(self.delay(attempt)).await;
+        }
+    }
+}
+
+/// Counters for outbound traffic. Clones share the same counters.
+#[derive(Clone, Default)]
+pub struct OutboundMetrics {
+    inner: Arc<MetricCounters>,
+}
+
+#[derive(Default)]
+struct MetricCounters {
+    requests: AtomicU64,
+    failures: AtomicU64,
+    latency_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OutboundSnapshot {
+    pub requests: u64,
+    /// Transport errors and `5xx` responses.
+    pub failures: u64,
+    pub total_latency_micros: u64,
+}
+
+impl OutboundMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> OutboundSnapshot {
+        OutboundSnapshot {
+            requests: self.inner.requests.load(Ordering::Relaxed),
+            failures: self.inner.failures.load(Ordering::Relaxed),
+            total_latency_micros: self.inner.latency_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[async_trait]
+impl Interceptor for OutboundMetrics {
+    async fn intercept(
+        &self,
+        req: CoreRequest,
+        next: OutboundNext<'_>,
+    ) -> Result<CoreResponse, Error> {
+        let start = Instant::now();
+        let result = next.run(req).await;
+
+        let counters = &self.inner;
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        counters
+            .latency_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        if result
+            .as_ref()
+            .map_or(true, |res| res.status().is_server_error())
+        {
+            counters.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Copies the given headers from a request onto outbound requests.
+struct CopyHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+#[async_trait]
+impl Interceptor for CopyHeaders {
+    async fn intercept(
+        &self,
+        mut req: CoreRequest,
+        next: OutboundNext<'_>,
+    ) -> Result<CoreResponse, Error> {
+        for (name, value) in &self.headers {
+            if !req.headers().contains_key(name) {
+                req.headers_mut().insert(name.clone(), value.clone());
+            }
+        }
+        next.run(req).await
+    }
+}
+
+/// Middleware forwarding tracing headers of the inbound request
+/// (`traceparent`, `tracestate` and `x-request-id` by default) on every call
+/// made through `ctx.http()` while handling it.
+pub struct PropagateHeaders {
+    names: Vec<HeaderName>,
+}
+
+impl PropagateHeaders {
+    pub fn new(names: impl IntoIterator<Item = HeaderName>) -> Self {
+        Self {
+            names: names.into_iter().collect(),
+        }
+    }
+}
+
+impl Default for PropagateHeaders {
+    fn default() -> Self {
+        Self::new([
+            HeaderName::from_static("traceparent"),
+            HeaderName::from_static("tracestate"),
+            HeaderName::from_static("x-request-id"),
+        ])
+    }
+}
+
+#[async_trait]
+impl Middleware<Ctx> for PropagateHeaders {
+    async fn handle(
+        &self,
+        mut ctx: Ctx,
+        req: CoreRequest,
+        next: Next<'_, Ctx>,
+    ) -> Result<CoreResponse, Error> {
+        let headers: Vec<_> = self
+            .names
+            .iter()
+            .filter_map(|name| Some((name.clone(), req.headers().get(name)?.clone())))
+            .collect();
+
+        if let (Some(client), false) = (ctx.http.clone(), headers.is_empty()) {
+            ctx.http = Some(Arc::new(
+                InterceptedClient::new(client).interceptor(CopyHeaders { headers }),
+            ));
+        }
+        next.run(ctx, req).await
+    }
+}
+
+/// Names of the headers carrying a [`RequestContext`] between services.
+#[derive(Debug, Clone)]
+pub struct ContextHeaders {
+    pub request_id: HeaderName,
+    pub tenant: HeaderName,
+    /// Milliseconds the receiver has left to answer.
+    pub budget: HeaderName,
+    /// Opaque auth context, e.g. the authenticated user or a signed token.
+    pub auth: HeaderName,
+}
+
+impl Default for ContextHeaders {
+    /// `x-request-id`, `x-tenant-id`, `x-request-budget-ms` and
+    /// `x-auth-context`.
+    fn default() -> Self {
+        Self {
+            request_id: HeaderName::from_static("x-request-id"),
+            tenant: HeaderName::from_static("x-tenant-id"),
+            budget: HeaderName::from_static("x-request-budget-ms"),
+            auth: HeaderName::from_static("x-auth-context"),
+        }
+    }
+}
+
+/// Who a request is for and how long is left to answer it, as propagated
+/// by [`PropagateContext`].
+///
+/// On a service receiving internal calls, extract it to pick the context
+/// back up; without the middleware it is read from the default
+/// [`ContextHeaders`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestContext {
+    pub request_id: Option<String>,
+    pub tenant: Option<String>,
+    pub auth: Option<String>,
+    deadline: Option<Instant>,
+}
+
+impl RequestContext {
+    pub fn from_headers(req: &CoreRequest, headers: &ContextHeaders) -> Self {
+        let header = |name: &HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+        };
+        let budget = header(&headers.budget)
+            .and_then(|ms| ms.parse().ok())
+            .map(Duration::from_millis);
+        Self {
+            request_id: header(&headers.request_id),
+            tenant: header(&headers.tenant),
+            auth: header(&headers.auth),
+            deadline: budget.map(|budget| Instant::now() + budget),
+        }
+    }
+
+    /// Gives the request `budget` from now to be answered.
+    pub fn with_budget(mut self, budget: Duration) -> Self {
+        self.deadline = Some(Instant::now() + budget);
+        self
+    }
+
+    /// Time left before the original caller gives up, if it said.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Sets the context's headers that `req` does not carry yet, and the
+    /// remaining budget. Fails with [`Error::RequestTimeout`] once the
+    /// budget is spent, as the caller will not wait for the answer.
+    pub fn apply(&self, req: &mut CoreRequest, headers: &ContextHeaders) -> Result<(), Error> {
+        for (name, value) in [
+            (&headers.request_id, &self.request_id),
+            (&headers.tenant, &self.tenant),
+            (&headers.auth, &self.auth),
+        ] {
+            let Some(value) = value else {
+                continue;
+            };
+            if req.headers().contains_key(name) {
+                continue;
+            }
+            let mut value = HeaderValue::from_str(value)
+                .map_err(|_| Error::internal(format!("Invalid {} header", name)))?;
+            value.set_sensitive(name == headers.auth);
+            req.headers_mut().insert(name.clone(), value);
+        }
+        if let Some(remaining) = self.remaining() {
+            if remaining.is_zero() {
+                return Err(Error::request_timeout());
+            }
+            req.headers_mut().insert(
+                headers.budget.clone(),
+                HeaderValue::from(remaining.as_millis() as u64),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for RequestContext {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        Ok(match req.extensions().get::<RequestContext>() {
+            Some(context) => context.clone(),
+            None => Self::from_headers(req, &ContextHeaders::default()),
+        })
+    }
+}
+
+struct ApplyContext {
+    context: RequestContext,
+    headers: Arc<ContextHeaders>,
+}
+
+#[async_trait]
+impl Interceptor for ApplyContext {
+    async fn intercept(
+        &self,
+        mut req: CoreRequest,
+        next: OutboundNext<'_>,
+    ) -> Result<CoreResponse, Error> {
+        self.context.apply(&mut req, &self.headers)?;
+        next.run(req).await
+    }
+}
+
+type ContextFn = Arc<dyn Fn(&CoreRequest) -> Option<String> + Send + Sync>;
+
+/// Middleware carrying the request ID, tenant, remaining time budget and
+/// auth context of the inbound request onto every call made through
+/// `ctx.http()` while handling it, and making them available as a
+/// [`RequestContext`].
+///
+/// Each value is read from the inbound [`ContextHeaders`] unless a function
+/// is configured for it; a missing request ID is generated. The budget sent
+/// on is what is left of the inbound one, or of the
+/// [default](PropagateContext::default_budget), at the time of each call.
+///
+/// ```ignore
+/// app.middleware(Authenticate)
+///     .middleware(
+///         PropagateContext::new()
+///             .tenant_from(|req| Some(req.extensions().get::<User>()?.org.clone()))
+///             .auth_from(|req| Some(req.extensions().get::<User>()?.id.to_string()))
+///             .default_budget(Duration::from_secs(5)),
+///     )
+/// ```
+///
+/// Inbound values are taken at face value, so strip these headers from
+/// requests arriving from outside, or configure functions for them, on
+/// services reachable from the internet.
+#[derive(Clone)]
+pub struct PropagateContext {
+    headers: Arc<ContextHeaders>,
+    tenant: Option<ContextFn>,
+    auth: Option<ContextFn>,
+    default_budget: Option<Duration>,
+}
+
+impl Default for PropagateContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PropagateContext {
+    pub fn new() -> Self {
+        Self::with_headers(ContextHeaders::default())
+    }
+
+    pub fn with_headers(headers: ContextHeaders) -> Self {
+        Self {
+            headers: Arc::new(headers),
+            tenant: None,
+            auth: None,
+            default_budget: None,
+        }
+    }
+
+    pub fn tenant_from(
+        mut self,
+        tenant: impl Fn(&CoreRequest) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.tenant = Some(Arc::new(tenant));
+        self
+    }
+
+    pub fn auth_from(
+        mut self,
+        auth: impl Fn(&CoreRequest) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// The budget of requests that arrive without one.
+    pub fn default_budget(mut self, budget: Duration) -> Self {
+        self.default_budget = Some(budget);
+        self
+    }
+}
+
+#[async_trait]
+impl Middleware<Ctx> for PropagateContext {
+    async fn handle(
+        &self,
+        mut ctx: Ctx,
+        mut req: CoreRequest,
+        next: Next<'_, Ctx>,
+    ) -> Result<CoreResponse, Error> {
+        let mut context = RequestContext::from_headers(&req, &self.headers);
+        if context.request_id.is_none() {
+            context.request_id = Some(ctx.rng.uuid_string());
+        }
+        if let Some(tenant) = &self.tenant {
+            context.tenant = tenant(&req);
+        }
+        if let Some(auth) = &self.auth {
+            context.auth = auth(&req);
+        }
+        if let (None, Some(budget)) = (context.deadline, self.default_budget) {
+            context = context.with_budget(budget);
+        }
+
+        if let Some(client) = ctx.http.clone() {
+            ctx.http = Some(Arc::new(InterceptedClient::new(client).interceptor(
+                ApplyContext {
+                    context: context.clone(),
+                    headers: Arc::clone(&self.headers),
+                },
+            )));
+        }
+        req.extensions_mut().insert(context);
+        next.run(ctx, req).await
+    }
+}
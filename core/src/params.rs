@@ -0,0 +1,541 @@
+use crate::Error;
+use serde::de::value::{Error as DeError, MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use std::collections::BTreeMap;
+
+/// Deepest bracket nesting accepted, as in `a[b][c][d][e]`.
+const MAX_DEPTH: usize = 5;
+
+/// Parses a query string (or a form body) into `T`.
+///
+/// Follows the conventions of `serde_qs` and HTML forms:
+///
+/// - repeated keys (`tag=a&tag=b`) and `tag[]=a&tag[]=b` fill sequences,
+///   and a single `tag=a` still makes a one-element sequence;
+/// - `range[lo]=1&range[hi]=9` fills nested structs or maps, and
+///   `ids[1]=b&ids[0]=a` a sequence ordered by index;
+/// - values are parsed into the field's type, so `page=2` fills a `u32` and
+///   `draft=true` a `bool`; an empty value is `None` for an `Option`;
+/// - a repeated key read into a single value keeps the last one.
+pub fn from_query_str<T: DeserializeOwned>(query: &str) -> Result<T, Error> {
+    let root = parse(query)?;
+    T::deserialize(Node::Map(root))
+        .map_err(|e| Error::bad_request(format!("Failed to deserialize query params: {}", e)))
+}
+
+/// Parsed parameters before deserialization.
+#[derive(Debug)]
+enum Node {
+    Leaf(String),
+    Seq(Vec<Node>),
+    Map(BTreeMap<String, Node>),
+}
+
+fn parse(query: &str) -> Result<BTreeMap<String, Node>, Error> {
+    let mut root = BTreeMap::new();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        let (name, path) = split_key(&key);
+        if path.len() > MAX_DEPTH {
+            return Err(Error::bad_request(format!(
+                "Query parameter {} is nested too deeply",
+                name
+            )));
+        }
+        insert(&mut root, name, &path, value.into_owned())
+            .map_err(|_| Error::bad_request(format!("Conflicting query parameter {}", key)))?;
+    }
+    Ok(root)
+}
+
+/// Splits `a[b][]` into `a` and `["b", ""]`. Keys that are not well formed
+/// bracket paths are used as they are.
+fn split_key(key: &str) -> (&str, Vec<&str>) {
+    let Some(open) = key.find('[').filter(|&i| i > 0 && key.ends_with(']')) else {
+        return (key, Vec::new());
+    };
+    let path: Vec<&str> = key[open + 1..key.len() - 1].split("][").collect();
+    if path.iter().any(|segment| segment.contains(['[', ']'])) {
+        return (key, Vec::new());
+    }
+    (&key[..open], path)
+}
+
+fn insert(
+    map: &mut BTreeMap<String, Node>,
+    name: &str,
+    path: &[&str],
+    value: String,
+) -> Result<(), ()> {
+    match path.split_first() {
+        // `a=x` and `a[]=x` both add to the values of `a`; anything after
+        // empty brackets is not supported.
+        None | Some((&"", _)) => {
+            let node = match map.remove(name) {
+                None if path.is_empty() => Node::Leaf(value),
+                None => Node::Seq(vec![Node::Leaf(value)]),
+                Some(Node::Leaf(first)) => Node::Seq(vec![Node::Leaf(first), Node::Leaf(value)]),
+                Some(Node::Seq(mut items)) => {
+                    items.push(Node::Leaf(value));
+                    Node::Seq(items)
+                }
+                Some(node @ Node::Map(_)) => {
+                    map.insert(name.to_string(), node);
+                    return Err(());
+                }
+            };
+            map.insert(name.to_string(), node);
+            Ok(())
+        }
+        Some((next, rest)) => match map
+            .entry(name.to_string())
+            .or_insert_with(|| Node::Map(BTreeMap::new()))
+        {
+            Node::Map(children) => insert(children, next, rest, value),
+            _ => Err(()),
+        },
+    }
+}
+
+impl Node {
+    /// The value used where a single one is expected.
+    fn into_leaf(self) -> Result<String, DeError> {
+        match self {
+            Node::Leaf(value) => Ok(value),
+            Node::Seq(mut items) => match items.pop() {
+                Some(last) => last.into_leaf(),
+                None => Ok(String::new()),
+            },
+            Node::Map(_) => Err(de::Error::custom(
+                "expected a value, found nested parameters",
+            )),
+        }
+    }
+
+    fn into_items(self) -> Result<Vec<Node>, DeError> {
+        match self {
+            Node::Leaf(value) => Ok(vec![Node::Leaf(value)]),
+            Node::Seq(items) => Ok(items),
+            // `ids[1]=b&ids[0]=a`
+            Node::Map(children) => {
+                let mut indexed = children
+                    .into_iter()
+                    .map(|(key, node)| key.parse::<usize>().map(|index| (index, node)))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| {
+                        de::Error::custom("expected a sequence, found nested parameters")
+                    })?;
+                indexed.sort_by_key(|(index, _)| *index);
+                Ok(indexed.into_iter().map(|(_, node)| node).collect())
+            }
+        }
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for Node {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                let value = self.into_leaf()?;
+                match value.trim().parse() {
+                    Ok(parsed) => visitor.$visit(parsed),
+                    Err(_) => Err(de::Error::custom(format!("invalid value {:?}", value))),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Node {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self {
+            Node::Leaf(value) => visitor.visit_string(value),
+            Node::Seq(items) => visitor.visit_seq(SeqDeserializer::new(items.into_iter())),
+            Node::Map(children) => visitor.visit_map(MapDeserializer::new(children.into_iter())),
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_string(self.into_leaf()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_string(self.into_leaf()?)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_byte_buf(self.into_leaf()?.into_bytes())
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_byte_buf(self.into_leaf()?.into_bytes())
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match &self {
+            Node::Leaf(value) if value.is_empty() => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_seq(SeqDeserializer::new(self.into_items()?.into_iter()))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self {
+            Node::Map(children) => visitor.visit_map(MapDeserializer::new(children.into_iter())),
+            _ => Err(de::Error::custom("expected nested parameters")),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_enum(self.into_leaf()?.into_deserializer())
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_unit()
+    }
+}
+
+/// Deserializes route parameters, in route order, into `T`: a single value
+/// such as `u32` when the route has one parameter, a tuple taking them in
+/// order, or a struct or map taking them by name.
+pub fn from_path_params<T: DeserializeOwned>(params: &[(String, String)]) -> Result<T, Error> {
+    T::deserialize(PathDeserializer(params.to_vec()))
+        .map_err(|e| Error::bad_request(format!("Failed to deserialize path params: {}", e)))
+}
+
+struct PathDeserializer(Vec<(String, String)>);
+
+impl PathDeserializer {
+    fn single(mut self) -> Result<ParamValue, DeError> {
+        if self.0.len() != 1 {
+            return Err(de::Error::custom(format!(
+                "expected 1 path parameter, found {}",
+                self.0.len()
+            )));
+        }
+        let (name, value) = self.0.remove(0);
+        Ok(ParamValue { name, value })
+    }
+
+    fn values(self) -> impl Iterator<Item = ParamValue> {
+        self.0
+            .into_iter()
+            .map(|(name, value)| ParamValue { name, value })
+    }
+}
+
+macro_rules! forward_to_single {
+    ($($method:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                self.single()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for PathDeserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_map(visitor)
+    }
+
+    forward_to_single! {
+        deserialize_bool,
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_i128,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64,
+        deserialize_u128,
+        deserialize_f32,
+        deserialize_f64,
+        deserialize_char,
+        deserialize_str,
+        deserialize_string,
+        deserialize_bytes,
+        deserialize_byte_buf,
+        deserialize_option,
+        deserialize_unit,
+        deserialize_identifier,
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_seq(SeqDeserializer::new(self.values()))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        if self.0.len() != len {
+            return Err(de::Error::custom(format!(
+                "expected {} path parameters, found {}",
+                len,
+                self.0.len()
+            )));
+        }
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_map(MapDeserializer::new(
+            self.values().map(|param| (param.name.clone(), param)),
+        ))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.single()?.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_unit()
+    }
+}
+
+/// One route parameter; errors name it.
+struct ParamValue {
+    name: String,
+    value: String,
+}
+
+impl ParamValue {
+    fn deserialize_with<T>(
+        self,
+        deserialize: impl FnOnce(Node) -> Result<T, DeError>,
+    ) -> Result<T, DeError> {
+        let name = self.name;
+        deserialize(Node::Leaf(self.value))
+            .map_err(|e| de::Error::custom(format!("{} (parameter {})", e, name)))
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for ParamValue {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! forward_to_node {
+    ($($method:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                self.deserialize_with(|node| node.$method(visitor))
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ParamValue {
+    type Error = DeError;
+
+    forward_to_node! {
+        deserialize_any,
+        deserialize_bool,
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_i128,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64,
+        deserialize_u128,
+        deserialize_f32,
+        deserialize_f64,
+        deserialize_char,
+        deserialize_str,
+        deserialize_string,
+        deserialize_bytes,
+        deserialize_byte_buf,
+        deserialize_option,
+        deserialize_unit,
+        deserialize_seq,
+        deserialize_map,
+        deserialize_identifier,
+        deserialize_ignored_any,
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_with(|node| node.deserialize_unit_struct(name, visitor))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_with(|node| node.deserialize_newtype_struct(name, visitor))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_with(|node| node.deserialize_tuple(len, visitor))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_with(|node| node.deserialize_tuple_struct(name, len, visitor))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_with(|node| node.deserialize_struct(name, fields, visitor))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_with(|node| node.deserialize_enum(name, variants, visitor))
+    }
+}
@@ -0,0 +1,88 @@
+use crate::router::RouteError;
+use crate::{App, Ctx, Error};
+
+/// A bundle of routes, middleware and warm-up tasks that a library ships as
+/// one unit, e.g. auth, an admin panel or a metrics endpoint.
+///
+/// Mount it with [`App::plugin`], which first checks the plugin's
+/// requirements and then rejects it if its routes conflict with the app's.
+///
+/// ```ignore
+/// struct Metrics;
+///
+/// impl Plugin for Metrics {
+///     fn name(&self) -> &str {
+///         "metrics"
+///     }
+///
+///     fn check(&self, ctx: &Ctx) -> Result<(), Error> {
+///         ctx.kv.as_ref().map(|_| ()).ok_or_else(|| Error::internal("metrics needs a Kv"))
+///     }
+///
+///     fn register(self, app: App) -> App {
+///         app.get("/metrics", render).middleware(RequestMetrics::new())
+///     }
+/// }
+///
+/// let app = App::with_default_context().plugin(Metrics)?;
+/// ```
+pub trait Plugin<C = Ctx> {
+    /// Identifies the plugin in conflict errors and for
+    /// [`Plugin::requires`]; a name can only be mounted once per app.
+    fn name(&self) -> &str;
+
+    /// Names of plugins that must be mounted before this one, e.g. a session
+    /// plugin an admin panel relies on.
+    fn requires(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Verifies the app's context provides what the plugin needs, such as a
+    /// backend or a piece of shared state, before anything is registered.
+    fn check(&self, ctx: &C) -> Result<(), Error> {
+        let _ = ctx;
+        Ok(())
+    }
+
+    /// Adds the plugin's routes, middleware and warm-up tasks to `app`.
+    fn register(self, app: App<C>) -> App<C>;
+}
+
+/// Why [`App::plugin`] refused to mount a plugin.
+///
+/// This is a startup error rather than an [`Error`], so it cannot end up
+/// formatted as a response by accident.
+#[derive(thiserror::Error, Debug)]
+pub enum PluginError {
+    #[error("Plugin {0} is already mounted")]
+    AlreadyMounted(String),
+
+    #[error("Plugin {plugin} requires plugin {requires}")]
+    Missing { plugin: String, requires: String },
+
+    #[error("Plugin {plugin} cannot use this context: {source}")]
+    Check {
+        plugin: String,
+        #[source]
+        source: Error,
+    },
+
+    /// The plugin's routes duplicate or overlap the app's, including a
+    /// route for one method next to one for every method on the same path.
+    #[error("Plugin {plugin} registers conflicting routes: {}", list(.errors))]
+    Routes {
+        plugin: String,
+        errors: Vec<RouteError>,
+    },
+
+    #[error("Plugin {0} replaces the app's fallback")]
+    Fallback(String),
+}
+
+fn list(errors: &[RouteError]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
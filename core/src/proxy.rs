@@ -0,0 +1,1044 @@
+use crate::{
+    clock::{Rng, SystemRng},
+    context::{HttpClient, Kv},
+    cookie::{Cookies, SameSite, SetCookie},
+    logging::{self, Level},
+    residency::ResidencyZone,
+    CoreRequest, CoreResponse, Ctx, Error, Handler,
+};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use http::header::{HeaderName, HeaderValue, CONNECTION, CONTENT_TYPE, HOST, SET_COOKIE};
+use http::{Method, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A backend instance, as a base URL such as `http://10.0.0.5:8080`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Upstream {
+    pub base_url: String,
+}
+
+impl Upstream {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+/// Looks up the current instances of a service. Consulted on every proxied
+/// request, so implementations should cache where lookups are expensive.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, service: &str) -> Result<Vec<Upstream>, Error>;
+}
+
+/// A fixed upstream list, ignoring the service name.
+pub struct StaticResolver {
+    upstreams: Vec<Upstream>,
+}
+
+impl StaticResolver {
+    pub fn new<I, S>(base_urls: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            upstreams: base_urls.into_iter().map(Upstream::new).collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn resolve(&self, _service: &str) -> Result<Vec<Upstream>, Error> {
+        Ok(self.upstreams.clone())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// DNS SRV lookups, provided by the platform since core has no resolver of
+/// its own.
+#[async_trait]
+pub trait SrvLookup: Send + Sync {
+    async fn lookup_srv(&self, name: &str) -> Result<Vec<SrvRecord>, Error>;
+}
+
+/// Resolves `_service._proto.domain`-style names through SRV records, using
+/// only the records with the best (lowest) priority. Weights are not applied.
+pub struct DnsSrvResolver {
+    lookup: Arc<dyn SrvLookup>,
+    scheme: &'static str,
+}
+
+impl DnsSrvResolver {
+    pub fn new(lookup: Arc<dyn SrvLookup>) -> Self {
+        Self {
+            lookup,
+            scheme: "http",
+        }
+    }
+
+    pub fn https(mut self) -> Self {
+        self.scheme = "https";
+        self
+    }
+}
+
+#[async_trait]
+impl Resolver for DnsSrvResolver {
+    async fn resolve(&self, service: &str) -> Result<Vec<Upstream>, Error> {
+        let records = self.lookup.lookup_srv(service).await?;
+        let Some(best) = records.iter().map(|r| r.priority).min() else {
+            return Ok(Vec::new());
+        };
+        Ok(records
+            .iter()
+            .filter(|r| r.priority == best)
+            .map(|r| {
+                let target = r.target.trim_end_matches('.');
+                Upstream::new(format!("{}://{}:{}", self.scheme, target, r.port))
+            })
+            .collect())
+    }
+}
+
+/// Resolves services through Consul's health API, returning passing
+/// instances only.
+pub struct ConsulResolver {
+    base_url: String,
+    http: Arc<dyn HttpClient>,
+}
+
+impl ConsulResolver {
+    pub fn new(base_url: impl Into<String>, http: Arc<dyn HttpClient>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            http,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulEntry {
+    node: ConsulNode,
+    service: ConsulService,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulNode {
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulService {
+    address: String,
+    port: u16,
+}
+
+#[async_trait]
+impl Resolver for ConsulResolver {
+    async fn resolve(&self, service: &str) -> Result<Vec<Upstream>, Error> {
+        let req = http::Request::builder()
+            .uri(format!(
+                "{}/v1/health/service/{}?passing=true",
+                self.base_url, service
+            ))
+            .body(crate::Body::empty())?;
+        let res = self.http.send(req).await?;
+        if !res.status().is_success() {
+            return Err(Error::internal(format!(
+                "Consul lookup for {} failed with status {}",
+                service,
+                res.status()
+            )));
+        }
+
+        let body = res.into_body().collect().await?;
+        let entries: Vec<ConsulEntry> = serde_json::from_slice(&body)?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| {
+                // Services registered without an address use the node's.
+                let host = if entry.service.address.is_empty() {
+                    entry.node.address
+                } else {
+                    entry.service.address
+                };
+                Upstream::new(format!("http://{}:{}", host, entry.service.port))
+            })
+            .collect())
+    }
+}
+
+/// How the proxy picks among resolved upstreams.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Balance {
+    RoundRobin,
+    /// Fewest requests in flight from this proxy.
+    LeastLoaded,
+    /// Rendezvous hashing on a request header, so the same value keeps
+    /// reaching the same upstream while the set is stable.
+    HashHeader(HeaderName),
+}
+
+/// Tracks state for the balancing strategies.
+struct Balancer {
+    strategy: Balance,
+    next: AtomicUsize,
+    in_flight: Mutex<HashMap<String, usize>>,
+}
+
+impl Balancer {
+    fn pick<'a>(&self, upstreams: &'a [Upstream], req: &CoreRequest) -> &'a Upstream {
+        match &self.strategy {
+            Balance::RoundRobin => {
+                &upstreams[self.next.fetch_add(1, Ordering::Relaxed) % upstreams.len()]
+            }
+            Balance::LeastLoaded => {
+                let in_flight = self.in_flight.lock().unwrap();
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                // Rotating the starting point spreads ties.
+                (0..upstreams.len())
+                    .map(|i| &upstreams[(start + i) % upstreams.len()])
+                    .min_by_key(|u| in_flight.get(&u.base_url).copied().unwrap_or(0))
+                    .unwrap()
+            }
+            Balance::HashHeader(name) => {
+                let key = req
+                    .headers()
+                    .get(name)
+                    .map(HeaderValue::as_bytes)
+                    .unwrap_or_default();
+                upstreams
+                    .iter()
+                    .max_by_key(|u| {
+                        let mut hasher = DefaultHasher::new();
+                        key.hash(&mut hasher);
+                        u.base_url.hash(&mut hasher);
+                        hasher.finish()
+                    })
+                    .unwrap()
+            }
+        }
+    }
+
+    fn acquire(&self, upstream: &Upstream) {
+        *self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(upstream.base_url.clone())
+            .or_insert(0) += 1;
+    }
+
+    fn release(&self, upstream: &Upstream) {
+        if let Some(count) = self.in_flight.lock().unwrap().get_mut(&upstream.base_url) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+/// Health of proxy upstreams, shared between a [`Proxy`] and whatever drives
+/// its probes. Clones share the same state.
+///
+/// Upstreams are ejected passively after `failure_threshold` consecutive
+/// failed requests (transport errors, `502`, `503` and `504`) and come back
+/// for a single trial request once the ejection period has passed; other
+/// requests keep avoiding them until the trial succeeds. Active checks
+/// run through [`UpstreamHealth::probe`]; core has no timer, so the platform
+/// calls it periodically.
+#[derive(Clone)]
+pub struct UpstreamHealth {
+    probe_path: String,
+    failure_threshold: u32,
+    ejection: Duration,
+    states: Arc<Mutex<HashMap<String, HealthState>>>,
+}
+
+/// How long a half-open trial request may take to report back before
+/// another one is let through, e.g. because the first one was dropped.
+const TRIAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct HealthState {
+    consecutive_failures: u32,
+    ejected_until: Option<Instant>,
+    /// When the trial request of a half-open upstream was let through.
+    trial_since: Option<Instant>,
+    probe_failed: bool,
+    last_error: Option<String>,
+}
+
+/// An upstream's health, as reported by [`UpstreamHealth::snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UpstreamStatus {
+    pub available: bool,
+    pub consecutive_failures: u32,
+    /// Seconds until a passively ejected upstream is tried again.
+    pub ejected_for_secs: Option<u64>,
+    pub probe_failed: bool,
+    pub last_error: Option<String>,
+}
+
+impl UpstreamHealth {
+    pub fn new() -> Self {
+        Self {
+            probe_path: "/health".to_string(),
+            failure_threshold: 5,
+            ejection: Duration::from_secs(30),
+            states: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn probe_path(mut self, path: impl Into<String>) -> Self {
+        self.probe_path = path.into();
+        self
+    }
+
+    pub fn failure_threshold(mut self, failures: u32) -> Self {
+        self.failure_threshold = failures.max(1);
+        self
+    }
+
+    pub fn ejection(mut self, duration: Duration) -> Self {
+        self.ejection = duration;
+        self
+    }
+
+    pub fn is_available(&self, upstream: &Upstream) -> bool {
+        let states = self.states.lock().unwrap();
+        states
+            .get(&upstream.base_url)
+            .map_or(true, |state| state.is_available(Instant::now()))
+    }
+
+    /// Reserves `upstream` for a request: `true` when it is available and,
+    /// if it is half-open, no other trial request is underway.
+    fn claim(&self, upstream: &Upstream) -> bool {
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        let Some(state) = states.get_mut(&upstream.base_url) else {
+            return true;
+        };
+        if !state.is_available(now) {
+            return false;
+        }
+        if state.ejected_until.is_some() {
+            state.trial_since = Some(now);
+        }
+        true
+    }
+
+    pub fn record_success(&self, upstream: &Upstream) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(upstream.base_url.clone()).or_default();
+        state.consecutive_failures = 0;
+        state.ejected_until = None;
+        state.trial_since = None;
+    }
+
+    pub fn record_failure(&self, upstream: &Upstream, reason: impl Into<String>) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(upstream.base_url.clone()).or_default();
+        state.consecutive_failures += 1;
+        state.trial_since = None;
+        state.last_error = Some(reason.into());
+        if state.consecutive_failures >= self.failure_threshold {
+            if state.ejected_until.is_none() {
+                logging::global().log(
+                    Level::Warn,
+                    &format!("upstream ejected {}", upstream.base_url),
+                    format!(
+                        "Ejecting upstream {} after {} consecutive failures",
+                        upstream.base_url, state.consecutive_failures
+                    ),
+                );
+            }
+            state.ejected_until = Some(Instant::now() + self.ejection);
+        }
+    }
+
+    /// Sends `GET {probe_path}` to each upstream. Upstreams failing the probe
+    /// are avoided until a later probe succeeds.
+    pub async fn probe(&self, http: &dyn HttpClient, upstreams: &[Upstream]) {
+        for upstream in upstreams {
+            let url = format!("{}{}", upstream.base_url, self.probe_path);
+            let outcome = match http::Request::get(url).body(crate::Body::empty()) {
+                Ok(req) => match http.send(req).await {
+                    Ok(res) if res.status().is_success() => Ok(()),
+                    Ok(res) => Err(format!("probe returned {}", res.status())),
+                    Err(e) => Err(e.to_string()),
+                },
+                Err(e) => Err(e.to_string()),
+            };
+
+            let mut states = self.states.lock().unwrap();
+            let state = states.entry(upstream.base_url.clone()).or_default();
+            match outcome {
+                Ok(()) => {
+                    if state.probe_failed {
+                        logging::global().warn(format!(
+                            "Upstream {} passed its health probe",
+                            upstream.base_url
+                        ));
+                    }
+                    state.probe_failed = false;
+                }
+                Err(reason) => {
+                    if !state.probe_failed {
+                        logging::global().log(
+                            Level::Warn,
+                            &format!("upstream probe failed {}", upstream.base_url),
+                            format!(
+                                "Upstream {} failed its health probe: {}",
+                                upstream.base_url, reason
+                            ),
+                        );
+                    }
+                    state.probe_failed = true;
+                    state.last_error = Some(reason);
+                }
+            }
+        }
+    }
+
+    /// Status of every upstream seen so far, keyed by base URL.
+    pub fn snapshot(&self) -> BTreeMap<String, UpstreamStatus> {
+        let now = Instant::now();
+        let states = self.states.lock().unwrap();
+        states
+            .iter()
+            .map(|(url, state)| {
+                let status = UpstreamStatus {
+                    available: state.is_available(now),
+                    consecutive_failures: state.consecutive_failures,
+                    ejected_for_secs: state
+                        .ejected_until
+                        .filter(|until| *until > now)
+                        .map(|until| (until - now).as_secs()),
+                    probe_failed: state.probe_failed,
+                    last_error: state.last_error.clone(),
+                };
+                (url.clone(), status)
+            })
+            .collect()
+    }
+}
+
+impl Default for UpstreamHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HealthState {
+    /// Not failing probes, and either not ejected or half-open without a
+    /// trial request underway.
+    fn is_available(&self, now: Instant) -> bool {
+        let trial_underway = self
+            .trial_since
+            .is_some_and(|since| now.duration_since(since) < TRIAL_TIMEOUT);
+        !self.probe_failed
+            && self
+                .ejected_until
+                .map_or(true, |until| until <= now && !trial_underway)
+    }
+}
+
+/// Admin handler reporting upstream health as JSON.
+pub struct UpstreamHealthStatus {
+    health: UpstreamHealth,
+}
+
+impl UpstreamHealthStatus {
+    pub fn new(health: UpstreamHealth) -> Self {
+        Self { health }
+    }
+}
+
+#[async_trait]
+impl Handler<Ctx> for UpstreamHealthStatus {
+    async fn call(&self, _ctx: Ctx, _req: CoreRequest) -> Result<CoreResponse, Error> {
+        Ok(http::Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&self.health.snapshot())?.into())?)
+    }
+}
+
+/// Where the proxy finds a client's session key for sticky sessions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AffinityKey {
+    /// A cookie the proxy issues itself when the client has none.
+    Cookie(String),
+    /// A header the client already sends, such as a session id.
+    Header(HeaderName),
+}
+
+/// What to do when a session's upstream is no longer available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failover {
+    /// Pick another upstream and bind the session to it.
+    Rebind,
+    /// Answer `503` so the client does not silently lose its session state.
+    Reject,
+}
+
+/// Session affinity: requests with the same session key reach the same
+/// upstream until the binding has been idle for the TTL.
+///
+/// At most [`max_sessions`](Sticky::max_sessions) bindings are kept. Idle
+/// ones are pruned when that limit is reached, and if none are idle the one
+/// closest to expiring is dropped.
+///
+/// An issued affinity cookie is `Secure` and `SameSite=Lax` unless
+/// configured otherwise.
+pub struct Sticky {
+    key: AffinityKey,
+    ttl: Duration,
+    failover: Failover,
+    max_sessions: usize,
+    secure: bool,
+    same_site: SameSite,
+    bindings: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Sticky {
+    pub fn cookie(name: impl Into<String>) -> Self {
+        Self::new(AffinityKey::Cookie(name.into()))
+    }
+
+    pub fn header(name: HeaderName) -> Self {
+        Self::new(AffinityKey::Header(name))
+    }
+
+    fn new(key: AffinityKey) -> Self {
+        Self {
+            key,
+            ttl: Duration::from_secs(30 * 60),
+            failover: Failover::Rebind,
+            max_sessions: 100_000,
+            secure: true,
+            same_site: SameSite::Lax,
+            bindings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn failover(mut self, failover: Failover) -> Self {
+        self.failover = failover;
+        self
+    }
+
+    /// The most session bindings kept, 100 000 by default.
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = max_sessions.max(1);
+        self
+    }
+
+    /// Whether the affinity cookie is only sent over HTTPS, true by
+    /// default; turn it off for plain-HTTP development setups.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// The affinity cookie's `SameSite` attribute, `Lax` by default.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    fn session_key(&self, req: &CoreRequest) -> Option<String> {
+        match &self.key {
+            AffinityKey::Cookie(name) => Cookies::extract(req).get(name).map(str::to_string),
+            AffinityKey::Header(name) => req
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+
+    /// The live upstream bound to `key`, refreshing its TTL.
+    fn bound(&self, key: &str, now: Instant) -> Option<String> {
+        let mut bindings = self.bindings.lock().unwrap();
+        let (upstream, expires) = bindings.get_mut(key)?;
+        if *expires <= now {
+            return None;
+        }
+        *expires = now + self.ttl;
+        Some(upstream.clone())
+    }
+
+    fn bind(&self, key: String, upstream: &Upstream, now: Instant) {
+        let mut bindings = self.bindings.lock().unwrap();
+        if !bindings.contains_key(&key) && bindings.len() >= self.max_sessions {
+            bindings.retain(|_, (_, expires)| *expires > now);
+            if bindings.len() >= self.max_sessions {
+                let oldest = bindings
+                    .iter()
+                    .min_by_key(|(_, (_, expires))| *expires)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    bindings.remove(&oldest);
+                }
+            }
+        }
+        bindings.insert(key, (upstream.base_url.clone(), now + self.ttl));
+    }
+}
+
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Response header naming the upstream set that served a request proxied
+/// through [`UpstreamSets`].
+pub const UPSTREAM_SET_HEADER: HeaderName = HeaderName::from_static("x-xeno-upstream-set");
+
+/// Named upstream sets, such as `blue` and `green`, of which one is active.
+///
+/// Switching is atomic: each request is forwarded entirely within the set
+/// that was active when it arrived. Register the sets before cloning; clones
+/// share which set is active but not sets added later.
+#[derive(Clone, Default)]
+pub struct UpstreamSets {
+    sets: BTreeMap<String, Arc<Vec<Upstream>>>,
+    active: Arc<ArcSwap<String>>,
+    kv_flag: Option<String>,
+}
+
+impl UpstreamSets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a set. The first set added starts out active.
+    pub fn set<I, S>(mut self, name: impl Into<String>, base_urls: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let name = name.into();
+        if self.sets.is_empty() {
+            self.active.store(Arc::new(name.clone()));
+        }
+        let upstreams = base_urls.into_iter().map(Upstream::new).collect();
+        self.sets.insert(name, Arc::new(upstreams));
+        self
+    }
+
+    /// Follows the set named by the Kv entry `key`, checked on every proxied
+    /// request when the context has a Kv store.
+    pub fn kv_flag(mut self, key: impl Into<String>) -> Self {
+        self.kv_flag = Some(key.into());
+        self
+    }
+
+    pub fn active(&self) -> String {
+        self.active.load().to_string()
+    }
+
+    /// Makes `name` the active set. Fails with 404 for unknown sets.
+    pub fn switch(&self, name: &str) -> Result<(), Error> {
+        if !self.sets.contains_key(name) {
+            return Err(Error::not_found());
+        }
+        let previous = self.active.swap(Arc::new(name.to_string()));
+        if *previous != name {
+            logging::global().warn(format!(
+                "Switched upstream set from {} to {}",
+                previous, name
+            ));
+        }
+        Ok(())
+    }
+
+    /// Applies the Kv flag, if one is configured and present. A flag naming
+    /// an unknown set is logged and ignored, keeping the current set.
+    pub async fn sync(&self, kv: &dyn Kv) {
+        let Some(key) = &self.kv_flag else {
+            return;
+        };
+        let Some(value) = kv.get(key).await else {
+            return;
+        };
+        let name = String::from_utf8_lossy(&value);
+        let name = name.trim();
+        if name != self.active.load().as_str() && self.switch(name).is_err() {
+            logging::global().log(
+                Level::Warn,
+                &format!("unknown upstream set {}", key),
+                format!("Kv flag {} names unknown upstream set {}", key, name),
+            );
+        }
+    }
+
+    /// The active set's name and upstreams, read together.
+    fn current(&self) -> (Arc<String>, Arc<Vec<Upstream>>) {
+        let name = self.active.load_full();
+        let upstreams = self.sets.get(name.as_str()).cloned().unwrap_or_default();
+        (name, upstreams)
+    }
+
+    fn status(&self) -> UpstreamSetsStatus {
+        UpstreamSetsStatus {
+            active: self.active(),
+            sets: self
+                .sets
+                .iter()
+                .map(|(name, upstreams)| {
+                    let urls = upstreams.iter().map(|u| u.base_url.clone()).collect();
+                    (name.clone(), urls)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct UpstreamSetsStatus {
+    active: String,
+    sets: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpstreamSwitch {
+    active: String,
+}
+
+/// Admin handler for [`UpstreamSets`]: `GET` lists the sets and the active
+/// one, `POST {"active": "green"}` switches. Mount behind authentication.
+pub struct UpstreamSetsAdmin {
+    sets: UpstreamSets,
+}
+
+impl UpstreamSetsAdmin {
+    pub fn new(sets: UpstreamSets) -> Self {
+        Self { sets }
+    }
+}
+
+#[async_trait]
+impl Handler<Ctx> for UpstreamSetsAdmin {
+    async fn call(&self, _ctx: Ctx, req: CoreRequest) -> Result<CoreResponse, Error> {
+        if req.method() == Method::POST {
+            let body = req.into_body().collect().await?;
+            let change: UpstreamSwitch = serde_json::from_slice(&body)
+                .map_err(|e| Error::bad_request(format!("Invalid switch: {}", e)))?;
+            self.sets.switch(&change.active)?;
+        }
+        Ok(http::Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&self.sets.status())?.into())?)
+    }
+}
+
+/// Handler forwarding requests to instances of a service through the
+/// context's HTTP client.
+///
+/// The request path and query are appended to the chosen upstream's base
+/// URL. Hop-by-hop headers are stripped and `x-forwarded-host` is set.
+pub struct Proxy {
+    service: String,
+    resolver: Arc<dyn Resolver>,
+    balancer: Balancer,
+    health: Option<UpstreamHealth>,
+    sticky: Option<Sticky>,
+    sets: Option<UpstreamSets>,
+    pinned: HashMap<String, Arc<dyn Resolver>>,
+}
+
+impl Proxy {
+    pub fn new(service: impl Into<String>, resolver: impl Resolver + 'static) -> Self {
+        Self {
+            service: service.into(),
+            resolver: Arc::new(resolver),
+            balancer: Balancer {
+                strategy: Balance::RoundRobin,
+                next: AtomicUsize::new(0),
+                in_flight: Mutex::new(HashMap::new()),
+            },
+            health: None,
+            sticky: None,
+            sets: None,
+            pinned: HashMap::new(),
+        }
+    }
+
+    /// Proxies to whichever of `sets` is active, naming it in the
+    /// [`UPSTREAM_SET_HEADER`] response header.
+    pub fn blue_green(service: impl Into<String>, sets: UpstreamSets) -> Self {
+        let mut proxy = Self::new(service, StaticResolver::new(Vec::<String>::new()));
+        proxy.sets = Some(sets);
+        proxy
+    }
+
+    pub fn balance(mut self, strategy: Balance) -> Self {
+        self.balancer.strategy = strategy;
+        self
+    }
+
+    /// Skips unhealthy upstreams and reports request outcomes to `health`.
+    pub fn health(mut self, health: UpstreamHealth) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    pub fn sticky(mut self, sticky: Sticky) -> Self {
+        self.sticky = Some(sticky);
+        self
+    }
+
+    /// Sends writes (anything but `GET`, `HEAD`, `OPTIONS` and `TRACE`) of
+    /// requests assigned to residency `zone` by a
+    /// [`RegionPolicy`](crate::residency::RegionPolicy) to the upstreams of
+    /// `resolver`, e.g. EU-pinned instances. Reads use the usual upstreams.
+    pub fn pin_zone(mut self, zone: impl Into<String>, resolver: impl Resolver + 'static) -> Self {
+        self.pinned.insert(zone.into(), Arc::new(resolver));
+        self
+    }
+
+    /// The resolver pinned to the request's residency zone, for writes.
+    fn pinned_resolver(&self, req: &CoreRequest) -> Option<&Arc<dyn Resolver>> {
+        let read = matches!(
+            *req.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+        );
+        if read {
+            return None;
+        }
+        let ResidencyZone(zone) = req.extensions().get::<ResidencyZone>()?;
+        self.pinned.get(zone)
+    }
+
+    /// Chooses an upstream, honouring session affinity. Returns the session
+    /// key to hand to the client when a new cookie must be issued.
+    fn choose(
+        &self,
+        upstreams: &[Upstream],
+        req: &CoreRequest,
+    ) -> Result<(Upstream, Option<String>), Error> {
+        let Some(sticky) = &self.sticky else {
+            return Ok((self.balancer.pick(upstreams, req).clone(), None));
+        };
+
+        let now = Instant::now();
+        let key = sticky.session_key(req);
+        if let Some(bound) = key.as_deref().and_then(|key| sticky.bound(key, now)) {
+            if let Some(upstream) = upstreams.iter().find(|u| u.base_url == bound) {
+                return Ok((upstream.clone(), None));
+            }
+            if sticky.failover == Failover::Reject {
+                logging::global().log(
+                    Level::Warn,
+                    &format!("sticky upstream unavailable {}", bound),
+                    format!("Upstream {} for a sticky session is unavailable", bound),
+                );
+                return Err(Error::service_unavailable());
+            }
+        }
+
+        let upstream = self.balancer.pick(upstreams, req).clone();
+        match (key, &sticky.key) {
+            (Some(key), _) => {
+                sticky.bind(key, &upstream, now);
+                Ok((upstream, None))
+            }
+            (None, AffinityKey::Cookie(_)) => {
+                let key = SystemRng.uuid_string().replace('-', "");
+                sticky.bind(key.clone(), &upstream, now);
+                Ok((upstream, Some(key)))
+            }
+            (None, AffinityKey::Header(_)) => Ok((upstream, None)),
+        }
+    }
+
+    async fn forward(
+        &self,
+        http: &dyn HttpClient,
+        upstream: &Upstream,
+        mut req: CoreRequest,
+    ) -> Result<CoreResponse, Error> {
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        *req.uri_mut() = format!("{}{}", upstream.base_url, path)
+            .parse::<Uri>()
+            .map_err(|_| Error::internal(format!("Invalid upstream URL: {}", upstream.base_url)))?;
+
+        strip_hop_by_hop(req.headers_mut());
+        if let Some(host) = req.headers_mut().remove(HOST) {
+            req.headers_mut()
+                .insert(HeaderName::from_static("x-forwarded-host"), host);
+        }
+
+        let mut res = http
+            .send(req)
+            .await
+            .map_err(|e| Error::bad_gateway(format!("{} failed: {}", upstream.base_url, e)))?;
+        strip_hop_by_hop(res.headers_mut());
+        Ok(res)
+    }
+}
+
+fn is_upstream_failure(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+fn strip_hop_by_hop(headers: &mut http::HeaderMap) {
+    // Headers named in `Connection` are hop-by-hop as well.
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+#[async_trait]
+impl Handler<Ctx> for Proxy {
+    async fn call(&self, ctx: Ctx, req: CoreRequest) -> Result<CoreResponse, Error> {
+        let http = ctx.http()?;
+        let (set, mut upstreams) = match (self.pinned_resolver(&req), &self.sets) {
+            (Some(resolver), _) => (None, resolver.resolve(&self.service).await?),
+            (None, Some(sets)) => {
+                if let Some(kv) = &ctx.kv {
+                    sets.sync(kv.as_ref()).await;
+                }
+                let (name, upstreams) = sets.current();
+                (Some(name), upstreams.to_vec())
+            }
+            (None, None) => (None, self.resolver.resolve(&self.service).await?),
+        };
+        if let Some(health) = &self.health {
+            upstreams.retain(|u| health.is_available(u));
+        }
+        let (upstream, new_session) = loop {
+            if upstreams.is_empty() {
+                logging::global().error(format!("No upstreams available for {}", self.service));
+                return Err(Error::service_unavailable());
+            }
+            let (upstream, new_session) = self.choose(&upstreams, &req)?;
+            // Another request may have taken a half-open upstream's trial.
+            match &self.health {
+                Some(health) if !health.claim(&upstream) => {
+                    upstreams.retain(|u| *u != upstream);
+                }
+                _ => break (upstream, new_session),
+            }
+        };
+        self.balancer.acquire(&upstream);
+        let result = self.forward(http, &upstream, req).await;
+        self.balancer.release(&upstream);
+
+        if let Some(health) = &self.health {
+            match &result {
+                Ok(res) if is_upstream_failure(res.status()) => {
+                    health.record_failure(&upstream, format!("responded {}", res.status()))
+                }
+                Ok(_) => health.record_success(&upstream),
+                Err(e) => health.record_failure(&upstream, e.to_string()),
+            }
+        }
+
+        let mut res = result?;
+        if let Some(set) = set.and_then(|name| HeaderValue::from_str(&name).ok()) {
+            res.headers_mut().insert(UPSTREAM_SET_HEADER, set);
+        }
+        if let (Some(key), Some(sticky)) = (new_session, &self.sticky) {
+            if let AffinityKey::Cookie(name) = &sticky.key {
+                let cookie = SetCookie::new(name.clone(), key)
+                    .path("/")
+                    .http_only(true)
+                    .secure(sticky.secure)
+                    .same_site(sticky.same_site)
+                    .max_age(sticky.ttl);
+                res.headers_mut()
+                    .append(SET_COOKIE, cookie.to_header_value()?);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_half_open_allows_one_trial() {
+        let health = UpstreamHealth::new()
+            .failure_threshold(1)
+            .ejection(Duration::ZERO);
+        let upstream = Upstream::new("http://a");
+        assert!(health.claim(&upstream));
+        health.record_failure(&upstream, "down");
+
+        // The ejection is over: one trial goes through, the rest wait for it.
+        assert!(health.claim(&upstream));
+        assert!(!health.claim(&upstream));
+        assert!(!health.is_available(&upstream));
+
+        health.record_failure(&upstream, "still down");
+        assert!(health.claim(&upstream));
+        health.record_success(&upstream);
+        assert!(health.claim(&upstream));
+        assert!(health.claim(&upstream));
+    }
+
+    #[test]
+    fn test_sticky_bindings_are_bounded() {
+        let sticky = Sticky::header(HeaderName::from_static("x-session")).max_sessions(2);
+        let now = Instant::now();
+        let (a, b) = (Upstream::new("http://a"), Upstream::new("http://b"));
+        sticky.bind("s1".to_string(), &a, now);
+        sticky.bind("s2".to_string(), &b, now + Duration::from_secs(1));
+        sticky.bind("s3".to_string(), &a, now + Duration::from_secs(2));
+        assert_eq!(sticky.bindings.lock().unwrap().len(), 2);
+        assert_eq!(sticky.bound("s1", now), None);
+        assert_eq!(sticky.bound("s2", now).as_deref(), Some("http://b"));
+        assert_eq!(sticky.bound("s3", now).as_deref(), Some("http://a"));
+    }
+}
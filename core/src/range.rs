@@ -0,0 +1,112 @@
+use crate::{
+    header::{Header, Range},
+    Body, CoreRequest, CoreResponse,
+};
+use http::header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED,
+};
+use http::{Method, StatusCode};
+
+/// What part of a body of known length a request asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeSelection {
+    /// No usable `Range` header: send everything with `200 OK`.
+    Full,
+    /// The inclusive first and last byte, sent with `206 Partial Content`.
+    Partial(u64, u64),
+    /// Answered with `416 Range Not Satisfiable`; this includes requests for
+    /// more than one range, which are not supported.
+    Unsatisfiable,
+}
+
+/// Picks the part of a `len`-byte body to send for `req`.
+///
+/// `validators` are the response's `ETag` and `Last-Modified` values; an
+/// `If-Range` header matching neither gets the full body, so clients never
+/// stitch together parts of different versions. Malformed `Range` headers are
+/// ignored, as RFC 9110 allows.
+pub fn select(req: &CoreRequest, len: u64, validators: &[&HeaderValue]) -> RangeSelection {
+    if req.method() != Method::GET {
+        return RangeSelection::Full;
+    }
+    let Some(range) = req.headers().get(http::header::RANGE) else {
+        return RangeSelection::Full;
+    };
+    if let Some(if_range) = req.headers().get(IF_RANGE) {
+        // Weak entity tags never match.
+        let weak = if_range.as_bytes().starts_with(b"W/");
+        if weak || !validators.contains(&if_range) {
+            return RangeSelection::Full;
+        }
+    }
+    let Some(Range { ranges }) = range.to_str().ok().and_then(|r| Range::decode(r).ok()) else {
+        return RangeSelection::Full;
+    };
+    match ranges.as_slice() {
+        [range] => match range.resolve(len) {
+            Some((first, last)) => RangeSelection::Partial(first, last),
+            None => RangeSelection::Unsatisfiable,
+        },
+        _ => RangeSelection::Unsatisfiable,
+    }
+}
+
+/// Turns a selection into a response: `body` must hold exactly the selected
+/// bytes (all of them for [`RangeSelection::Full`], none when unsatisfiable)
+/// of a `len`-byte representation. `res` supplies the status and headers of
+/// the full response.
+pub(crate) fn respond(
+    mut res: CoreResponse,
+    selection: RangeSelection,
+    len: u64,
+    body: Body,
+) -> CoreResponse {
+    res.headers_mut()
+        .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let (status, content_range) = match selection {
+        RangeSelection::Full => {
+            *res.body_mut() = body;
+            return res;
+        }
+        RangeSelection::Partial(first, last) => (
+            StatusCode::PARTIAL_CONTENT,
+            format!("bytes {}-{}/{}", first, last, len),
+        ),
+        RangeSelection::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            format!("bytes */{}", len),
+        ),
+    };
+    *res.status_mut() = status;
+    res.headers_mut().remove(CONTENT_LENGTH);
+    if let Ok(value) = HeaderValue::from_str(&content_range) {
+        res.headers_mut().insert(CONTENT_RANGE, value);
+    }
+    *res.body_mut() = body;
+    res
+}
+
+/// Serves the part of a buffered `200 OK` response that `req` asked for,
+/// advertising `Accept-Ranges: bytes`. Other responses, including streamed
+/// ones, are returned unchanged.
+pub fn ranged(req: &CoreRequest, res: CoreResponse) -> CoreResponse {
+    if res.status() != StatusCode::OK {
+        return res;
+    }
+    let bytes = match res.body() {
+        Body::Full(bytes) => bytes.clone(),
+        Body::Stream(_) => return res,
+    };
+    let len = bytes.len() as u64;
+    let validators: Vec<&HeaderValue> = [ETAG, LAST_MODIFIED]
+        .iter()
+        .filter_map(|name| res.headers().get(name))
+        .collect();
+    let selection = select(req, len, &validators);
+    let body = match selection {
+        RangeSelection::Full => bytes,
+        RangeSelection::Partial(first, last) => bytes.slice(first as usize..=last as usize),
+        RangeSelection::Unsatisfiable => Default::default(),
+    };
+    respond(res, selection, len, Body::from(body))
+}
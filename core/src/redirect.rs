@@ -0,0 +1,172 @@
+use crate::header::sanitize_header_value;
+use crate::{Body, CoreRequest, CoreResponse, Error, IntoResponse};
+use http::header::{HeaderValue, LOCATION};
+use http::StatusCode;
+
+/// A redirect response.
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    status: StatusCode,
+    location: HeaderValue,
+}
+
+impl Redirect {
+    /// `307 Temporary Redirect`; the client repeats the request, method and
+    /// body included, at `location`.
+    pub fn to(location: &str) -> Self {
+        Self::with_status(StatusCode::TEMPORARY_REDIRECT, location)
+    }
+
+    /// `308 Permanent Redirect`, for moved resources. Like `307`, and unlike
+    /// `301`, it keeps the method, so a moved `POST` endpoint still works.
+    pub fn permanent(location: &str) -> Self {
+        Self::with_status(StatusCode::PERMANENT_REDIRECT, location)
+    }
+
+    /// `303 See Other`, the usual answer after a form post or login.
+    pub fn see_other(location: &str) -> Self {
+        Self::with_status(StatusCode::SEE_OTHER, location)
+    }
+
+    /// A `308` to `location` as is, for targets that are already valid
+    /// URI references, such as a request's own path, and must not be
+    /// percent-encoded again.
+    pub(crate) fn permanent_raw(location: HeaderValue) -> Self {
+        Self {
+            status: StatusCode::PERMANENT_REDIRECT,
+            location,
+        }
+    }
+
+    fn with_status(status: StatusCode, location: &str) -> Self {
+        Self {
+            status,
+            location: sanitize_header_value(location),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn location(&self) -> &HeaderValue {
+        &self.location
+    }
+}
+
+impl IntoResponse for Redirect {
+    fn into_response(self) -> CoreResponse {
+        let mut response = http::Response::new(Body::empty());
+        *response.status_mut() = self.status;
+        response.headers_mut().insert(LOCATION, self.location);
+        response
+    }
+}
+
+/// Validates user-supplied redirect targets (e.g. a login `?next=`) so they
+/// cannot send visitors to an arbitrary external site.
+///
+/// Relative paths are accepted by default; absolute URLs only when their host
+/// has been allowed explicitly.
+#[derive(Debug, Clone, Default)]
+pub struct RedirectPolicy {
+    allowed_hosts: Vec<String>,
+    allowed_path_prefixes: Vec<String>,
+}
+
+impl RedirectPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow_host(mut self, host: impl Into<String>) -> Self {
+        self.allowed_hosts.push(host.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Restricts targets to paths under `prefix`. With no prefixes configured
+    /// any path is allowed.
+    pub fn allow_path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.allowed_path_prefixes.push(prefix.into());
+        self
+    }
+
+    pub fn validate<'a>(&self, target: &'a str) -> Result<&'a str, Error> {
+        if target.is_empty() || target.chars().any(|c| c.is_control() || c == '\\') {
+            return Err(Error::bad_request("Invalid redirect target"));
+        }
+
+        let path = if target.starts_with('/') {
+            // `//evil.example` is a scheme-relative URL, not a path.
+            if target.starts_with("//") {
+                return Err(Error::bad_request("Redirect target not allowed"));
+            }
+            target.to_string()
+        } else {
+            let (scheme, host, path) = parse_absolute(target)
+                .ok_or_else(|| Error::bad_request("Invalid redirect target"))?;
+            let host_allowed = matches!(scheme.as_str(), "http" | "https")
+                && self
+                    .allowed_hosts
+                    .iter()
+                    .any(|h| h.eq_ignore_ascii_case(&host));
+            if !host_allowed {
+                return Err(Error::bad_request("Redirect target not allowed"));
+            }
+            path
+        };
+
+        if !self.allowed_path_prefixes.is_empty()
+            && !self
+                .allowed_path_prefixes
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return Err(Error::bad_request("Redirect target not allowed"));
+        }
+
+        Ok(target)
+    }
+
+    pub fn redirect(&self, target: &str) -> Result<Redirect, Error> {
+        self.validate(target).map(Redirect::see_other)
+    }
+
+    /// Redirects to the `param` query value when it passes validation,
+    /// otherwise to `fallback`.
+    pub fn redirect_from_query(&self, req: &CoreRequest, param: &str, fallback: &str) -> Redirect {
+        let query = req.uri().query().unwrap_or("");
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == param)
+            .and_then(|(_, value)| self.redirect(&value).ok())
+            .unwrap_or_else(|| Redirect::see_other(fallback))
+    }
+}
+
+/// Scheme, host and normalized path of an absolute URL.
+#[cfg(feature = "url")]
+fn parse_absolute(target: &str) -> Option<(String, String, String)> {
+    let url = url::Url::parse(target).ok()?;
+    let host = url.host_str()?.to_string();
+    Some((url.scheme().to_string(), host, url.path().to_string()))
+}
+
+/// Without the `url` feature the path cannot be normalized the way a
+/// browser would, so targets with dot segments are rejected outright.
+#[cfg(not(feature = "url"))]
+fn parse_absolute(target: &str) -> Option<(String, String, String)> {
+    let uri: http::Uri = target.parse().ok()?;
+    let path = uri.path();
+    let dot_segment = path.split('/').any(|segment| {
+        let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+        segment == "." || segment == ".."
+    });
+    if dot_segment {
+        return None;
+    }
+    Some((
+        uri.scheme_str()?.to_ascii_lowercase(),
+        uri.host()?.to_string(),
+        path.to_string(),
+    ))
+}
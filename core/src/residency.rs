@@ -0,0 +1,217 @@
+use crate::extract::FromRequest;
+use crate::middleware::{Middleware, Next};
+use crate::{CoreRequest, CoreResponse, Error};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The client's ISO 3166-1 alpha-2 country code, uppercased. Adapters that
+/// know it insert it into the request extensions; otherwise
+/// [`RegionPolicy`] reads it from a header set by the edge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Country(pub String);
+
+/// The data-residency zone a request was assigned to by [`RegionPolicy`],
+/// e.g. `eu`. A [`Proxy`](crate::proxy::Proxy) with a resolver
+/// [pinned](crate::proxy::Proxy::pin_zone) to the zone sends the request's
+/// writes there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResidencyZone(pub String);
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for Country {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        req.extensions()
+            .get::<Country>()
+            .cloned()
+            .ok_or_else(|| Error::internal("Country is not available"))
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for ResidencyZone {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        req.extensions()
+            .get::<ResidencyZone>()
+            .cloned()
+            .ok_or_else(|| Error::internal("Request has no residency zone"))
+    }
+}
+
+/// What to do with requests whose country is unknown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownCountry {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Middleware blocking embargoed countries and assigning data-residency
+/// zones, configured declaratively:
+///
+/// ```json
+/// {
+///   "deny": ["CU", "IR", "KP", "SY"],
+///   "zones": {"eu": ["AT", "BE", "DE", "FR", "IE", "NL"]},
+///   "unknown": "allow"
+/// }
+/// ```
+///
+/// Denied requests are answered with `451 Unavailable For Legal Reasons`.
+/// The country comes from a [`Country`] extension, or the `CF-IPCountry`
+/// header by default, which is only trustworthy when every request passes
+/// through the edge that sets it. Cloudflare's `XX` (unknown) counts as no
+/// country.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegionPolicy {
+    #[serde(default = "default_country_header")]
+    pub country_header: String,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Zone name to the countries whose requests it takes.
+    #[serde(default)]
+    pub zones: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub unknown: UnknownCountry,
+}
+
+fn default_country_header() -> String {
+    "cf-ipcountry".to_string()
+}
+
+impl Default for RegionPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegionPolicy {
+    pub fn new() -> Self {
+        Self {
+            country_header: default_country_header(),
+            deny: Vec::new(),
+            zones: BTreeMap::new(),
+            unknown: UnknownCountry::Allow,
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let mut policy: Self = serde_json::from_str(json)
+            .map_err(|e| Error::internal(format!("Invalid region policy: {}", e)))?;
+        policy.normalize()?;
+        Ok(policy)
+    }
+
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let json = std::fs::read_to_string(&path)
+            .map_err(|e| Error::internal(format!("Failed to read {}: {}", path.display(), e)))?;
+        Self::from_json(&json)
+    }
+
+    pub fn deny<I, S>(mut self, countries: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.deny.extend(
+            countries
+                .into_iter()
+                .map(|c| c.as_ref().to_ascii_uppercase()),
+        );
+        self
+    }
+
+    pub fn zone<I, S>(mut self, name: impl Into<String>, countries: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.zones.entry(name.into()).or_default().extend(
+            countries
+                .into_iter()
+                .map(|c| c.as_ref().to_ascii_uppercase()),
+        );
+        self
+    }
+
+    pub fn unknown(mut self, unknown: UnknownCountry) -> Self {
+        self.unknown = unknown;
+        self
+    }
+
+    /// Uppercases country codes and rejects anything that is not a
+    /// two-letter code or that sits in two zones.
+    fn normalize(&mut self) -> Result<(), Error> {
+        let zoned = self.zones.values_mut().flatten();
+        for code in self.deny.iter_mut().chain(zoned) {
+            if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(Error::internal(format!(
+                    "Invalid country code in region policy: {}",
+                    code
+                )));
+            }
+            code.make_ascii_uppercase();
+        }
+        let mut seen = BTreeMap::new();
+        for (zone, countries) in &self.zones {
+            for country in countries {
+                if let Some(other) = seen.insert(country, zone) {
+                    return Err(Error::internal(format!(
+                        "Country {} is in zones {} and {}",
+                        country, other, zone
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn country(&self, req: &CoreRequest) -> Option<String> {
+        if let Some(Country(code)) = req.extensions().get::<Country>() {
+            return Some(code.clone());
+        }
+        req.headers()
+            .get(self.country_header.as_str())
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_ascii_uppercase())
+            .filter(|code| code.len() == 2 && code != "XX")
+    }
+
+    fn zone_of(&self, country: &str) -> Option<&str> {
+        self.zones
+            .iter()
+            .find(|(_, countries)| countries.iter().any(|c| c == country))
+            .map(|(zone, _)| zone.as_str())
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for RegionPolicy {
+    async fn handle(
+        &self,
+        ctx: C,
+        mut req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        match self.country(&req) {
+            Some(country) => {
+                if self.deny.contains(&country) {
+                    return Err(Error::unavailable_for_legal_reasons());
+                }
+                if let Some(zone) = self.zone_of(&country) {
+                    let zone = ResidencyZone(zone.to_string());
+                    req.extensions_mut().insert(zone);
+                }
+                req.extensions_mut().insert(Country(country));
+            }
+            None if self.unknown == UnknownCountry::Deny => {
+                return Err(Error::unavailable_for_legal_reasons());
+            }
+            None => {}
+        }
+        next.run(ctx, req).await
+    }
+}
@@ -1,10 +1,32 @@
-use crate::CoreResponse;
+use crate::formatter::{JsonFormatter, ResponseFormatter};
+use crate::header::percent_encode_char;
+#[cfg(feature = "askama")]
+use crate::logging::{self, Level};
+use crate::timeout::SleepFn;
+use crate::{Body, CoreResponse, Error};
 use bytes::Bytes;
+use futures_core::Stream;
+use http::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, LOCATION};
 use http::StatusCode;
 use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 pub trait IntoResponse {
     fn into_response(self) -> CoreResponse;
+
+    /// How a function handler's return value reaches the framework. An
+    /// [`Error`] is handed back rather than rendered, so the app's formatter
+    /// and error handler see it; everything else is converted as usual.
+    fn into_result(self) -> Result<CoreResponse, Error>
+    where
+        Self: Sized,
+    {
+        Ok(self.into_response())
+    }
 }
 
 impl IntoResponse for &str {
@@ -12,7 +34,7 @@ impl IntoResponse for &str {
         http::Response::builder()
             .status(StatusCode::OK)
             .header("content-type", "text/plain; charset=utf-8")
-            .body(Bytes::from(self.to_string()))
+            .body(Body::from(self.to_string()))
             .unwrap()
     }
 }
@@ -24,6 +46,12 @@ impl IntoResponse for String {
 }
 
 impl IntoResponse for Bytes {
+    fn into_response(self) -> CoreResponse {
+        Body::Full(self).into_response()
+    }
+}
+
+impl IntoResponse for Body {
     fn into_response(self) -> CoreResponse {
         http::Response::builder()
             .status(StatusCode::OK)
@@ -37,21 +65,21 @@ impl IntoResponse for StatusCode {
     fn into_response(self) -> CoreResponse {
         http::Response::builder()
             .status(self)
-            .body(Bytes::new())
+            .body(Body::empty())
             .unwrap()
     }
 }
 
-pub struct Json<T>(pub T);
+pub use crate::extract::Json;
 
 impl<T: Serialize> IntoResponse for Json<T> {
     fn into_response(self) -> CoreResponse {
         let body = match serde_json::to_vec(&self.0) {
-            Ok(bytes) => Bytes::from(bytes),
+            Ok(bytes) => Body::from(bytes),
             Err(_) => {
                 return http::Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Bytes::from("Failed to serialize JSON"))
+                    .body(Body::from("Failed to serialize JSON"))
                     .unwrap()
             }
         };
@@ -64,10 +92,663 @@ impl<T: Serialize> IntoResponse for Json<T> {
     }
 }
 
+#[cfg(feature = "msgpack")]
+pub use crate::extract::MsgPack;
+
+/// Serialized with named fields, so the receiving side can decode it into
+/// a struct or a map.
+#[cfg(feature = "msgpack")]
+impl<T: Serialize> IntoResponse for MsgPack<T> {
+    fn into_response(self) -> CoreResponse {
+        self.into_result()
+            .unwrap_or_else(|error| JsonFormatter.format_error(&error))
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        let body = rmp_serde::to_vec_named(&self.0)
+            .map_err(|e| Error::internal(format!("Failed to serialize MessagePack: {}", e)))?;
+        Ok(http::Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/msgpack")
+            .body(Body::from(body))?)
+    }
+}
+
+#[cfg(feature = "xml")]
+pub use crate::extract::Xml;
+
+/// Sent as `application/xml; charset=utf-8`, with the root element named
+/// after the serialized type.
+#[cfg(feature = "xml")]
+impl<T: Serialize> IntoResponse for Xml<T> {
+    fn into_response(self) -> CoreResponse {
+        self.into_result()
+            .unwrap_or_else(|error| JsonFormatter.format_error(&error))
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        let body = quick_xml::se::to_string(&self.0)
+            .map_err(|e| Error::internal(format!("Failed to serialize XML: {}", e)))?;
+        Ok(http::Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/xml; charset=utf-8")
+            .body(Body::from(body))?)
+    }
+}
+
+#[cfg(feature = "protobuf")]
+pub use crate::extract::Proto;
+
+/// Sent as `application/x-protobuf`.
+#[cfg(feature = "protobuf")]
+impl<T: prost::Message> IntoResponse for Proto<T> {
+    fn into_response(self) -> CoreResponse {
+        http::Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/x-protobuf")
+            .body(Body::from(self.0.encode_to_vec()))
+            .unwrap()
+    }
+}
+
+/// An HTML response body, sent as `text/html; charset=utf-8`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Html<T>(pub T);
+
+impl<T: Into<Body>> IntoResponse for Html<T> {
+    fn into_response(self) -> CoreResponse {
+        http::Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/html; charset=utf-8")
+            .body(self.0.into())
+            .unwrap()
+    }
+}
+
+/// An [askama](https://docs.rs/askama) template rendered as [`Html`].
+///
+/// A rendering failure becomes an [`Error::Internal`] for the app's
+/// formatter, without the template error reaching the client.
+///
+/// ```ignore
+/// #[derive(askama::Template)]
+/// #[template(path = "profile.html")]
+/// struct Profile { name: String }
+///
+/// async fn profile(Path(name): Path<String>) -> HtmlTemplate<Profile> {
+///     HtmlTemplate(Profile { name })
+/// }
+/// ```
+#[cfg(feature = "askama")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HtmlTemplate<T>(pub T);
+
+#[cfg(feature = "askama")]
+impl<T: askama::Template> IntoResponse for HtmlTemplate<T> {
+    fn into_response(self) -> CoreResponse {
+        self.into_result()
+            .unwrap_or_else(|error| JsonFormatter.format_error(&error))
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        let html = self.0.render().map_err(|e| {
+            logging::global().log(
+                Level::Error,
+                "template render failed",
+                format!("Failed to render template: {}", e),
+            );
+            Error::internal(e.to_string())
+        })?;
+        Ok(Html(html).into_response())
+    }
+}
+
 impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
     fn into_response(self) -> CoreResponse {
         let mut response = self.1.into_response();
         *response.status_mut() = self.0;
         response
     }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        let mut response = self.1.into_result()?;
+        *response.status_mut() = self.0;
+        Ok(response)
+    }
+}
+
+/// Headers set on the response, replacing any the body set, e.g. its
+/// `Content-Type`.
+impl<T: IntoResponse> IntoResponse for (HeaderMap, T) {
+    fn into_response(self) -> CoreResponse {
+        let mut response = self.1.into_response();
+        response.headers_mut().extend(self.0);
+        response
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        let mut response = self.1.into_result()?;
+        response.headers_mut().extend(self.0);
+        Ok(response)
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (StatusCode, HeaderMap, T) {
+    fn into_response(self) -> CoreResponse {
+        (self.0, (self.1, self.2)).into_response()
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        (self.0, (self.1, self.2)).into_result()
+    }
+}
+
+/// Headers appended to the response, keeping any with the same name, for
+/// repeatable headers such as `Set-Cookie` or `Link`.
+///
+/// ```ignore
+/// (
+///     StatusCode::CREATED,
+///     AppendHeaders([(LOCATION, "/users/7"), (CACHE_CONTROL, "no-store")]),
+///     Json(user),
+/// )
+/// ```
+///
+/// An invalid name or value becomes an [`Error::Internal`].
+#[derive(Debug, Clone, Copy)]
+pub struct AppendHeaders<I>(pub I);
+
+impl<I, K, V> AppendHeaders<I>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: TryInto<HeaderName>,
+    V: TryInto<HeaderValue>,
+{
+    fn apply(self, response: &mut CoreResponse) -> Result<(), Error> {
+        for (name, value) in self.0 {
+            let name = name
+                .try_into()
+                .map_err(|_| Error::internal("Invalid response header name"))?;
+            let value = value
+                .try_into()
+                .map_err(|_| Error::internal("Invalid response header value"))?;
+            response.headers_mut().append(name, value);
+        }
+        Ok(())
+    }
+}
+
+impl<I, K, V, T> IntoResponse for (AppendHeaders<I>, T)
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: TryInto<HeaderName>,
+    V: TryInto<HeaderValue>,
+    T: IntoResponse,
+{
+    fn into_response(self) -> CoreResponse {
+        self.into_result()
+            .unwrap_or_else(|error| JsonFormatter.format_error(&error))
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        let mut response = self.1.into_result()?;
+        self.0.apply(&mut response)?;
+        Ok(response)
+    }
+}
+
+impl<I, K, V, T> IntoResponse for (StatusCode, AppendHeaders<I>, T)
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: TryInto<HeaderName>,
+    V: TryInto<HeaderValue>,
+    T: IntoResponse,
+{
+    fn into_response(self) -> CoreResponse {
+        self.into_result()
+            .unwrap_or_else(|error| JsonFormatter.format_error(&error))
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        (self.0, (self.1, self.2)).into_result()
+    }
+}
+
+/// An empty `200 OK`.
+impl IntoResponse for () {
+    fn into_response(self) -> CoreResponse {
+        http::Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::empty())
+            .unwrap()
+    }
+}
+
+/// `201 Created` with a `Location` header pointing at the new resource.
+///
+/// ```ignore
+/// async fn create_user(Json(form): Json<NewUser>) -> Result<Created<Json<User>>, Error> {
+///     let user = users.insert(form).await?;
+///     Created::at_route("/users/:id", &[("id", &user.id.to_string())], Json(user))
+/// }
+/// ```
+///
+/// An invalid location becomes an [`Error::Internal`].
+#[derive(Debug, Clone)]
+pub struct Created<T = ()> {
+    location: String,
+    body: T,
+}
+
+impl<T> Created<T> {
+    pub fn new(location: impl Into<String>, body: T) -> Self {
+        Self {
+            location: location.into(),
+            body,
+        }
+    }
+
+    /// Points at a registered route, filling its `:name` and `*name`
+    /// parameters from `params`; see [`route_path`].
+    pub fn at_route(pattern: &str, params: &[(&str, &str)], body: T) -> Result<Self, Error> {
+        Ok(Self::new(route_path(pattern, params)?, body))
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for Created<T> {
+    fn into_response(self) -> CoreResponse {
+        self.into_result()
+            .unwrap_or_else(|error| JsonFormatter.format_error(&error))
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        with_location(StatusCode::CREATED, Some(self.location), self.body)
+    }
+}
+
+/// `202 Accepted` for work that finishes later, optionally with a
+/// `Location` where the client can poll the job's status.
+///
+/// ```ignore
+/// async fn start_export(ctx: Ctx) -> Result<Accepted<Json<Job>>, Error> {
+///     let job = exports.enqueue(&ctx).await?;
+///     Ok(Accepted::new(Json(job.clone())).location(format!("/jobs/{}", job.id)))
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Accepted<T = ()> {
+    location: Option<String>,
+    body: T,
+}
+
+impl<T> Accepted<T> {
+    pub fn new(body: T) -> Self {
+        Self {
+            location: None,
+            body,
+        }
+    }
+
+    pub fn location(mut self, location: impl Into<String>) -> Self {
+        self.location = Some(location.into());
+        self
+    }
+
+    /// Polls at a registered route, as with [`Created::at_route`].
+    pub fn at_route(self, pattern: &str, params: &[(&str, &str)]) -> Result<Self, Error> {
+        Ok(self.location(route_path(pattern, params)?))
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for Accepted<T> {
+    fn into_response(self) -> CoreResponse {
+        self.into_result()
+            .unwrap_or_else(|error| JsonFormatter.format_error(&error))
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        with_location(StatusCode::ACCEPTED, self.location, self.body)
+    }
+}
+
+fn with_location(
+    status: StatusCode,
+    location: Option<String>,
+    body: impl IntoResponse,
+) -> Result<CoreResponse, Error> {
+    let mut response = body.into_result()?;
+    *response.status_mut() = status;
+    if let Some(location) = location {
+        let value = HeaderValue::try_from(location)
+            .map_err(|_| Error::internal("Invalid Location header value"))?;
+        response.headers_mut().insert(LOCATION, value);
+    }
+    Ok(response)
+}
+
+/// Fills the parameters of a route pattern, e.g. `/users/:id` with
+/// `[("id", "7")]` gives `/users/7`. Values are percent-encoded, keeping
+/// `/` in catch-all `*name` parameters; a parameter missing from `params`
+/// is an [`Error::Internal`].
+pub fn route_path(pattern: &str, params: &[(&str, &str)]) -> Result<String, Error> {
+    let mut path = String::with_capacity(pattern.len());
+    for (i, segment) in pattern.split('/').enumerate() {
+        if i > 0 {
+            path.push('/');
+        }
+        let (name, catch_all) = match segment.chars().next() {
+            Some(':') => (&segment[1..], false),
+            Some('*') => (&segment[1..], true),
+            _ => {
+                path.push_str(segment);
+                continue;
+            }
+        };
+        let value = params
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| {
+                Error::internal(format!("Missing parameter {} for route {}", name, pattern))
+            })?;
+        for c in value.chars() {
+            if c.is_ascii_alphanumeric()
+                || matches!(c, '-' | '.' | '_' | '~')
+                || (catch_all && c == '/')
+            {
+                path.push(c);
+            } else {
+                percent_encode_char(&mut path, c);
+            }
+        }
+    }
+    Ok(path)
+}
+
+impl IntoResponse for CoreResponse {
+    fn into_response(self) -> CoreResponse {
+        self
+    }
+}
+
+/// Rendered by the default [`JsonFormatter`] when converted directly.
+impl IntoResponse for Error {
+    fn into_response(self) -> CoreResponse {
+        JsonFormatter.format_error(&self)
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        Err(self)
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> CoreResponse {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
+
+    fn into_result(self) -> Result<CoreResponse, Error> {
+        match self {
+            Ok(value) => value.into_result(),
+            Err(error) => error.into_result(),
+        }
+    }
+}
+
+/// A fallible wrapper around [`http::response::Builder`].
+///
+/// Invalid status codes or header values surface as an [`Error`] from
+/// [`ResponseBuilder::body`] instead of a panic on `unwrap()`.
+#[derive(Debug, Default)]
+pub struct ResponseBuilder {
+    inner: http::response::Builder,
+}
+
+impl ResponseBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn status<T>(self, status: T) -> Self
+    where
+        StatusCode: TryFrom<T>,
+        <StatusCode as TryFrom<T>>::Error: Into<http::Error>,
+    {
+        Self {
+            inner: self.inner.status(status),
+        }
+    }
+
+    pub fn header<K, V>(self, key: K, value: V) -> Self
+    where
+        HeaderName: TryFrom<K>,
+        <HeaderName as TryFrom<K>>::Error: Into<http::Error>,
+        HeaderValue: TryFrom<V>,
+        <HeaderValue as TryFrom<V>>::Error: Into<http::Error>,
+    {
+        Self {
+            inner: self.inner.header(key, value),
+        }
+    }
+
+    pub fn body(self, body: impl Into<Body>) -> Result<CoreResponse, Error> {
+        Ok(self.inner.body(body.into())?)
+    }
+
+    /// Finishes the response without a body.
+    pub fn finish(self) -> Result<CoreResponse, Error> {
+        self.body(Body::empty())
+    }
+
+    /// Serializes `value` as the body, sent as
+    /// `application/json; charset=utf-8`.
+    pub fn json_body<T: Serialize>(self, value: &T) -> Result<CoreResponse, Error> {
+        let body = serde_json::to_vec(value)?;
+        self.header(CONTENT_TYPE, "application/json; charset=utf-8")
+            .body(body)
+    }
+
+    /// `200 OK`.
+    pub fn ok() -> Self {
+        Self::new().status(StatusCode::OK)
+    }
+
+    /// `201 Created` pointing at the new resource.
+    pub fn created(location: &str) -> Self {
+        Self::new()
+            .status(StatusCode::CREATED)
+            .header(LOCATION, location)
+    }
+
+    /// `204 No Content`; finish it with [`ResponseBuilder::finish`].
+    pub fn no_content() -> Self {
+        Self::new().status(StatusCode::NO_CONTENT)
+    }
+
+    /// `200 OK` with `value` as JSON.
+    pub fn json<T: Serialize>(value: &T) -> Result<CoreResponse, Error> {
+        Self::ok().json_body(value)
+    }
+
+    /// An RFC 7807 problem document, e.g.
+    /// `{"type":"about:blank","title":"Conflict","status":409,"detail":"..."}`.
+    pub fn problem(status: StatusCode, detail: impl Into<String>) -> CoreResponse {
+        let body = serde_json::json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or("Unknown"),
+            "status": status.as_u16(),
+            "detail": detail.into(),
+        });
+        http::Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/problem+json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+}
+
+/// One Server-Sent Event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl SseEvent {
+    /// An event carrying `data`, which may span several lines.
+    pub fn data(data: impl Into<String>) -> Self {
+        Self {
+            data: data.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn json<T: Serialize>(value: &T) -> Result<Self, Error> {
+        Ok(Self::data(serde_json::to_string(value)?))
+    }
+
+    /// Line breaks are removed, as they would end the field, and so are
+    /// NUL characters, which make clients ignore the id.
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(single_line(id.into()).replace('\0', ""));
+        self
+    }
+
+    /// The event type, `message` on the client when unset. Line breaks are
+    /// removed, as they would end the field.
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(single_line(event.into()));
+        self
+    }
+
+    /// How long the client waits before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut out = String::new();
+        if let Some(event) = &self.event {
+            out.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = self.retry {
+            out.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        // Clients end a line at CRLF, a lone CR or LF alike.
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            out.push_str(&format!("data: {}\n", line));
+        }
+        out.push('\n');
+        Bytes::from(out)
+    }
+}
+
+fn single_line(value: String) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+/// A `text/event-stream` response streaming events as they are produced.
+///
+/// With [`Sse::keep_alive`], a comment line is sent whenever the stream has
+/// been quiet for the interval, so proxies do not close idle connections.
+pub struct Sse<S> {
+    events: S,
+    keep_alive: Option<(Duration, SleepFn)>,
+}
+
+impl<S> Sse<S>
+where
+    S: Stream<Item = Result<SseEvent, Error>> + Send + 'static,
+{
+    pub fn new(events: S) -> Self {
+        Self {
+            events,
+            keep_alive: None,
+        }
+    }
+
+    /// Sends keep-alive comments after `interval` without events, using the
+    /// platform's sleep, e.g. `tokio::time::sleep`.
+    pub fn keep_alive<F, Fut>(mut self, interval: Duration, sleep: F) -> Self
+    where
+        F: Fn(Duration) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.keep_alive = Some((interval, Arc::new(move |d| Box::pin(sleep(d)))));
+        self
+    }
+}
+
+struct SseBody<S> {
+    events: Pin<Box<S>>,
+    keep_alive: Option<(Duration, SleepFn)>,
+    timer: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl<S> Stream for SseBody<S>
+where
+    S: Stream<Item = Result<SseEvent, Error>> + Send,
+{
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(event) = self.events.as_mut().poll_next(cx) {
+            self.timer = None;
+            return Poll::Ready(event.map(|event| event.map(|event| event.encode())));
+        }
+        let Some((interval, sleep)) = self.keep_alive.clone() else {
+            return Poll::Pending;
+        };
+        let timer = self.timer.get_or_insert_with(|| sleep(interval));
+        if timer.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.timer = None;
+        Poll::Ready(Some(Ok(Bytes::from_static(b":\n\n"))))
+    }
+}
+
+impl<S> IntoResponse for Sse<S>
+where
+    S: Stream<Item = Result<SseEvent, Error>> + Send + 'static,
+{
+    fn into_response(self) -> CoreResponse {
+        let body = SseBody {
+            events: Box::pin(self.events),
+            keep_alive: self.keep_alive,
+            timer: None,
+        };
+        http::Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/event-stream")
+            .header("cache-control", "no-cache")
+            .header("x-accel-buffering", "no")
+            .body(Body::from_stream(body))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sse_line_breaks() {
+        let event = SseEvent::data("a\r\nb\rc\nd\r")
+            .event("tick\r\nid: 9")
+            .id("4\r2\0");
+        assert_eq!(
+            event.encode(),
+            "event: tickid: 9\nid: 42\ndata: a\ndata: b\ndata: c\ndata: d\ndata: \n\n"
+        );
+    }
 }
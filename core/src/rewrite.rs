@@ -0,0 +1,312 @@
+use crate::{
+    middleware::{Middleware, Next},
+    Body, CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use http::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_TYPE, LOCATION,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// A single response rewrite. Deserializable, so rule sets can live in
+/// configuration, e.g. `{"op": "strip_header", "name": "server"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ResponseRule {
+    StripHeader {
+        name: String,
+    },
+    RenameHeader {
+        from: String,
+        to: String,
+    },
+    /// Replaces the `from` prefix of a `Location` header with `to`, e.g.
+    /// `http://legacy:8080` with `https://api.example.com/legacy`.
+    RewriteLocation {
+        from: String,
+        to: String,
+    },
+    /// Moves the JSON value at pointer `from` to pointer `to`, creating
+    /// intermediate objects. Only applied to JSON responses.
+    MapJson {
+        from: String,
+        to: String,
+    },
+}
+
+/// Middleware applying [`ResponseRule`]s in order to the responses of the
+/// routes it wraps, typically a [`Proxy`](crate::proxy::Proxy) in front of a
+/// legacy API.
+///
+/// Header names and JSON pointers are checked when rules are added or
+/// deserialized. With [`MapJson`](ResponseRule::MapJson) rules, the request's
+/// `Accept-Encoding` is dropped so the upstream answers uncompressed; bodies
+/// that arrive encoded anyway are passed on without mapping, and bodies
+/// larger than [`max_body_bytes`](ResponseRewrite::max_body_bytes) fail with
+/// `502`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "Vec<ResponseRule>")]
+pub struct ResponseRewrite {
+    rules: Vec<ResponseRule>,
+    max_body: usize,
+}
+
+impl Default for ResponseRewrite {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            max_body: 2 * 1024 * 1024,
+        }
+    }
+}
+
+impl TryFrom<Vec<ResponseRule>> for ResponseRewrite {
+    type Error = Error;
+
+    fn try_from(rules: Vec<ResponseRule>) -> Result<Self, Error> {
+        rules.into_iter().try_fold(Self::new(), Self::rule)
+    }
+}
+
+impl ResponseRewrite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `rule`, failing if it names an invalid header or JSON pointer.
+    pub fn rule(mut self, rule: ResponseRule) -> Result<Self, Error> {
+        match &rule {
+            ResponseRule::StripHeader { name } => {
+                header_name(name)?;
+            }
+            ResponseRule::RenameHeader { from, to } => {
+                header_name(from)?;
+                header_name(to)?;
+            }
+            ResponseRule::RewriteLocation { .. } => {}
+            ResponseRule::MapJson { from, to } => {
+                for pointer in [from, to] {
+                    if !pointer.is_empty() && !pointer.starts_with('/') {
+                        return Err(Error::internal(format!(
+                            "Invalid JSON pointer: {}",
+                            pointer
+                        )));
+                    }
+                }
+            }
+        }
+        self.rules.push(rule);
+        Ok(self)
+    }
+
+    pub fn strip_header(mut self, name: HeaderName) -> Self {
+        self.rules.push(ResponseRule::StripHeader {
+            name: name.to_string(),
+        });
+        self
+    }
+
+    pub fn rename_header(mut self, from: HeaderName, to: HeaderName) -> Self {
+        self.rules.push(ResponseRule::RenameHeader {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+        self
+    }
+
+    pub fn rewrite_location(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.rules.push(ResponseRule::RewriteLocation {
+            from: from.into(),
+            to: to.into(),
+        });
+        self
+    }
+
+    /// Moves the JSON value at pointer `from` to pointer `to`; see
+    /// [`ResponseRule::MapJson`].
+    pub fn map_json(self, from: impl Into<String>, to: impl Into<String>) -> Result<Self, Error> {
+        self.rule(ResponseRule::MapJson {
+            from: from.into(),
+            to: to.into(),
+        })
+    }
+
+    /// The largest JSON body mapped, 2 MiB by default.
+    pub fn max_body_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_body = max_bytes;
+        self
+    }
+
+    fn maps_json(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| matches!(rule, ResponseRule::MapJson { .. }))
+    }
+
+    pub async fn apply(&self, res: &mut CoreResponse) -> Result<(), Error> {
+        let headers = res.headers_mut();
+        for rule in &self.rules {
+            match rule {
+                ResponseRule::StripHeader { name } => {
+                    headers.remove(header_name(name)?);
+                }
+                ResponseRule::RenameHeader { from, to } => {
+                    let values: Vec<_> = match headers.entry(header_name(from)?) {
+                        http::header::Entry::Occupied(entry) => {
+                            entry.remove_entry_mult().1.collect()
+                        }
+                        http::header::Entry::Vacant(_) => continue,
+                    };
+                    let to = header_name(to)?;
+                    for value in values {
+                        headers.append(to.clone(), value);
+                    }
+                }
+                ResponseRule::RewriteLocation { from, to } => {
+                    let rewritten = headers
+                        .get(LOCATION)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|location| location.strip_prefix(from.as_str()))
+                        .map(|rest| format!("{}{}", to, rest));
+                    if let Some(location) = rewritten {
+                        let value = HeaderValue::from_str(&location)
+                            .map_err(|_| Error::internal("Invalid rewritten Location"))?;
+                        headers.insert(LOCATION, value);
+                    }
+                }
+                ResponseRule::MapJson { .. } => {}
+            }
+        }
+
+        let mappings: Vec<_> = self
+            .rules
+            .iter()
+            .filter_map(|rule| match rule {
+                ResponseRule::MapJson { from, to } => Some((from, to)),
+                _ => None,
+            })
+            .collect();
+        if mappings.is_empty() || !is_json(res) || is_encoded(res.headers()) {
+            return Ok(());
+        }
+
+        let body = std::mem::take(res.body_mut())
+            .collect_limited(self.max_body)
+            .await
+            .map_err(|e| match e {
+                Error::PayloadTooLarge => Error::bad_gateway("Upstream JSON body is too large"),
+                e => e,
+            })?;
+        let mut json: Value = serde_json::from_slice(&body)
+            .map_err(|e| Error::bad_gateway(format!("Invalid upstream JSON: {}", e)))?;
+        for (from, to) in mappings {
+            if let Some(value) = json.pointer_mut(from).map(Value::take) {
+                remove_pointer(&mut json, from);
+                insert_pointer(&mut json, to, value)?;
+            }
+        }
+        let body = serde_json::to_vec(&json)?;
+        res.headers_mut().insert(CONTENT_LENGTH, body.len().into());
+        *res.body_mut() = Body::from(body);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for ResponseRewrite {
+    async fn handle(
+        &self,
+        ctx: C,
+        mut req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        if self.maps_json() {
+            req.headers_mut().remove(ACCEPT_ENCODING);
+        }
+        let mut res = next.run(ctx, req).await?;
+        self.apply(&mut res).await?;
+        Ok(res)
+    }
+}
+
+fn header_name(name: &str) -> Result<HeaderName, Error> {
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| Error::internal(format!("Invalid header name in rewrite rule: {}", name)))
+}
+
+/// Whether the body has a content coding other than `identity`.
+pub(crate) fn is_encoded(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_ENCODING)
+        .is_some_and(|coding| !coding.as_bytes().eq_ignore_ascii_case(b"identity"))
+}
+
+fn is_json(res: &CoreResponse) -> bool {
+    res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|mime| {
+            let mime = mime.trim();
+            mime.eq_ignore_ascii_case("application/json") || mime.ends_with("+json")
+        })
+        .unwrap_or(false)
+}
+
+/// Splits a JSON pointer into its parent pointer and unescaped last token.
+fn split_pointer(pointer: &str) -> Option<(&str, String)> {
+    let (parent, last) = pointer.rsplit_once('/')?;
+    Some((parent, last.replace("~1", "/").replace("~0", "~")))
+}
+
+fn remove_pointer(json: &mut Value, pointer: &str) {
+    let Some((parent, key)) = split_pointer(pointer) else {
+        return;
+    };
+    match json.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.remove(&key);
+        }
+        Some(Value::Array(items)) => {
+            if let Ok(index) = key.parse::<usize>() {
+                if index < items.len() {
+                    items.remove(index);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn insert_pointer(json: &mut Value, pointer: &str, value: Value) -> Result<(), Error> {
+    if pointer.is_empty() {
+        *json = value;
+        return Ok(());
+    }
+    let mut current = json;
+    let tokens: Vec<String> = pointer
+        .strip_prefix('/')
+        .ok_or_else(|| Error::internal(format!("Invalid JSON pointer: {}", pointer)))?
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect();
+    for (i, token) in tokens.iter().enumerate() {
+        if current.is_null() {
+            *current = Value::Object(Default::default());
+        }
+        let Value::Object(map) = current else {
+            return Err(Error::bad_gateway(format!(
+                "Cannot map JSON into {}: parent is not an object",
+                pointer
+            )));
+        };
+        if i == tokens.len() - 1 {
+            map.insert(token.clone(), value);
+            return Ok(());
+        }
+        current = map.entry(token.clone()).or_insert(Value::Null);
+    }
+    Ok(())
+}
@@ -0,0 +1,309 @@
+use crate::{
+    header::http_date,
+    logging::{self, Level},
+    proxy::{Proxy, StaticResolver},
+    range::{self, RangeSelection},
+    Body, CoreRequest, CoreResponse, Ctx, Error, Handler,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_core::Stream;
+use http::header::{
+    HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LAST_MODIFIED, LOCATION,
+};
+use http::{Method, StatusCode};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// How much of a file is read at a time.
+const FILE_CHUNK: u64 = 64 * 1024;
+
+/// Routes defined in configuration rather than code, e.g.
+///
+/// ```json
+/// [
+///   {"path": "/old-blog/*rest", "type": "redirect", "to": "/blog", "status": 301},
+///   {"path": "/maintenance", "type": "static", "status": 503, "body": "Back soon"},
+///   {"path": "/legacy/*rest", "type": "proxy", "upstream": "http://legacy:8080"},
+///   {"path": "/robots.txt", "type": "file", "file": "/etc/xeno/robots.txt"}
+/// ]
+/// ```
+///
+/// Merge it with [`App::route_table`](crate::App::route_table).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct RouteTable {
+    pub routes: Vec<RouteEntry>,
+}
+
+impl RouteTable {
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let table: Self = serde_json::from_str(json)
+            .map_err(|e| Error::internal(format!("Invalid route table: {}", e)))?;
+        table.validate()?;
+        Ok(table)
+    }
+
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let json = std::fs::read_to_string(&path)
+            .map_err(|e| Error::internal(format!("Failed to read {}: {}", path.display(), e)))?;
+        Self::from_json(&json)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        for entry in &self.routes {
+            if !entry.path.starts_with('/') {
+                return Err(Error::internal(format!(
+                    "Route path must start with '/': {}",
+                    entry.path
+                )));
+            }
+            entry.method()?;
+            if let RouteAction::Static { headers, .. } = &entry.action {
+                for (name, value) in headers {
+                    HeaderName::from_bytes(name.as_bytes())
+                        .ok()
+                        .zip(HeaderValue::from_str(value).ok())
+                        .ok_or_else(|| {
+                            Error::internal(format!("Invalid header {} for {}", name, entry.path))
+                        })?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteEntry {
+    pub path: String,
+    /// Any method when absent.
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(flatten)]
+    pub action: RouteAction,
+}
+
+impl RouteEntry {
+    pub(crate) fn method(&self) -> Result<Option<Method>, Error> {
+        self.method
+            .as_deref()
+            .map(|m| {
+                Method::from_bytes(m.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| Error::internal(format!("Invalid method {} for {}", m, self.path)))
+            })
+            .transpose()
+    }
+
+    pub(crate) fn handler(&self) -> Box<dyn Handler<Ctx>> {
+        match &self.action {
+            RouteAction::Proxy { upstream } => Box::new(Proxy::new(
+                self.path.clone(),
+                StaticResolver::new([upstream.clone()]),
+            )),
+            action => Box::new(ConfiguredRoute(action.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RouteAction {
+    Static {
+        #[serde(default = "default_static_status")]
+        status: u16,
+        #[serde(default)]
+        body: String,
+        #[serde(default = "default_content_type")]
+        content_type: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    Redirect {
+        to: String,
+        #[serde(default = "default_redirect_status")]
+        status: u16,
+    },
+    /// Forwards to a single upstream base URL, keeping the request path.
+    Proxy { upstream: String },
+    /// Serves a file, read on every request so it can be edited in place.
+    /// Supports single `Range` requests, so media can be seeked. The file is
+    /// streamed in chunks, read on the context's
+    /// [`BlockingPool`](crate::context::BlockingPool) if it has one.
+    File {
+        file: PathBuf,
+        #[serde(default)]
+        content_type: Option<String>,
+    },
+}
+
+fn default_static_status() -> u16 {
+    200
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+fn default_content_type() -> String {
+    "text/plain; charset=utf-8".to_string()
+}
+
+fn status(code: u16) -> Result<StatusCode, Error> {
+    StatusCode::from_u16(code).map_err(|_| Error::internal(format!("Invalid status {}", code)))
+}
+
+struct ConfiguredRoute(RouteAction);
+
+#[async_trait]
+impl Handler<Ctx> for ConfiguredRoute {
+    async fn call(&self, ctx: Ctx, req: CoreRequest) -> Result<CoreResponse, Error> {
+        match &self.0 {
+            RouteAction::Static {
+                status: code,
+                body,
+                content_type,
+                headers,
+            } => {
+                let mut res = http::Response::builder()
+                    .status(status(*code)?)
+                    .header(CONTENT_TYPE, content_type.as_str());
+                for (name, value) in headers {
+                    res = res.header(name.as_str(), value.as_str());
+                }
+                Ok(res.body(Body::from(body.clone()))?)
+            }
+            RouteAction::Redirect { to, status: code } => Ok(http::Response::builder()
+                .status(status(*code)?)
+                .header(LOCATION, to.as_str())
+                .body(Body::empty())?),
+            RouteAction::File { file, content_type } => {
+                let path = file.clone();
+                let opened = ctx
+                    .blocking(move || {
+                        let handle = File::open(&path)?;
+                        let metadata = handle.metadata()?;
+                        Ok::<_, std::io::Error>((handle, metadata))
+                    })
+                    .await?;
+                let (handle, metadata) = opened.map_err(|e| {
+                    logging::global().log(
+                        Level::Warn,
+                        "file route unreadable",
+                        format!("Failed to read {}: {}", file.display(), e),
+                    );
+                    Error::not_found()
+                })?;
+                let content_type = content_type
+                    .clone()
+                    .unwrap_or_else(|| guess_content_type(file).to_string());
+                let mut res = http::Response::builder().header(CONTENT_TYPE, content_type);
+                if let Ok(modified) = metadata.modified() {
+                    res = res.header(LAST_MODIFIED, http_date(modified));
+                }
+                let res = res.body(Body::empty())?;
+
+                // Only the requested part is read, so seeking through large
+                // media stays cheap.
+                let len = metadata.len();
+                let validators: Vec<_> = res.headers().get(LAST_MODIFIED).into_iter().collect();
+                let selection = range::select(&req, len, &validators);
+                let (start, end) = match selection {
+                    RangeSelection::Full => (0, len),
+                    RangeSelection::Partial(first, last) => (first, last + 1),
+                    RangeSelection::Unsatisfiable => (0, 0),
+                };
+                let body = Body::from_stream(FileChunks {
+                    file: Arc::new(Mutex::new(handle)),
+                    offset: start,
+                    end,
+                    ctx,
+                    read: None,
+                });
+                let mut res = range::respond(res, selection, len, body);
+                res.headers_mut()
+                    .insert(CONTENT_LENGTH, HeaderValue::from(end - start));
+                Ok(res)
+            }
+            RouteAction::Proxy { .. } => unreachable!("proxy routes use Proxy"),
+        }
+    }
+}
+
+type ChunkRead = Pin<Box<dyn Future<Output = Result<Bytes, Error>> + Send>>;
+
+/// The bytes `offset..end` of a file, read a chunk at a time.
+struct FileChunks {
+    file: Arc<Mutex<File>>,
+    offset: u64,
+    end: u64,
+    ctx: Ctx,
+    read: Option<ChunkRead>,
+}
+
+impl FileChunks {
+    fn read_next(&self) -> ChunkRead {
+        let file = Arc::clone(&self.file);
+        let offset = self.offset;
+        let len = (self.end - offset).min(FILE_CHUNK) as usize;
+        let ctx = self.ctx.clone();
+        Box::pin(async move {
+            ctx.blocking(move || {
+                let mut file = file.lock().unwrap();
+                let mut chunk = vec![0; len];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut chunk)?;
+                Ok::<_, std::io::Error>(Bytes::from(chunk))
+            })
+            .await?
+            .map_err(|e| Error::internal(format!("Failed to read file: {}", e)))
+        })
+    }
+}
+
+impl Stream for FileChunks {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.read.is_none() {
+            if self.offset >= self.end {
+                return Poll::Ready(None);
+            }
+            self.read = Some(self.read_next());
+        }
+        let Some(read) = self.read.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let Poll::Ready(result) = read.as_mut().poll(cx) else {
+            return Poll::Pending;
+        };
+        self.read = None;
+        match &result {
+            Ok(chunk) => self.offset += chunk.len() as u64,
+            // Ends the stream after the error.
+            Err(_) => self.offset = self.end,
+        }
+        Poll::Ready(Some(result))
+    }
+}
+
+fn guess_content_type(file: &std::path::Path) -> &'static str {
+    match file.extension().and_then(|e| e.to_str()) {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("json") => "application/json",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        _ => "application/octet-stream",
+    }
+}
@@ -1,144 +1,454 @@
-use crate::{CoreRequest, CoreResponse, Error, Handler};
+use crate::compose::set_path;
+use crate::describe::RouteDescription;
+use crate::explain::{self, Trace};
+use crate::extract::{MatchedPath, PathParams};
+use crate::formatter::{ErrorRequest, JsonFormatter, ResponseFormatter};
+use crate::logging;
+use crate::middleware::{Layered, Middleware, MiddlewareStack};
+use crate::redirect::Redirect;
+use crate::{CoreRequest, CoreResponse, Error, Handler, IntoHandler, IntoResponse};
+use async_trait::async_trait;
+use http::header::{HeaderValue, ALLOW};
 use http::Method;
-use matchit::{Match, Router as MatchItRouter};
-use serde_json;
+use matchit::Router as MatchItRouter;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 
-pub struct Router<C> {
-    get_routes: MatchItRouter<Arc<dyn Handler<C>>>,
-    post_routes: MatchItRouter<Arc<dyn Handler<C>>>,
-    put_routes: MatchItRouter<Arc<dyn Handler<C>>>,
-    delete_routes: MatchItRouter<Arc<dyn Handler<C>>>,
-    patch_routes: MatchItRouter<Arc<dyn Handler<C>>>,
-    head_routes: MatchItRouter<Arc<dyn Handler<C>>>,
-    options_routes: MatchItRouter<Arc<dyn Handler<C>>>,
+struct RouteDef<C> {
+    /// `None` registers the handler for every method.
+    method: Option<Method>,
+    path: String,
+    handler: Arc<dyn Handler<C>>,
 }
 
-impl<C: Send + Sync + Clone + 'static> Router<C> {
-    pub fn new() -> Self {
+impl<C> Clone for RouteDef<C> {
+    fn clone(&self) -> Self {
         Self {
-            get_routes: MatchItRouter::new(),
-            post_routes: MatchItRouter::new(),
-            put_routes: MatchItRouter::new(),
-            delete_routes: MatchItRouter::new(),
-            patch_routes: MatchItRouter::new(),
-            head_routes: MatchItRouter::new(),
-            options_routes: MatchItRouter::new(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            handler: Arc::clone(&self.handler),
         }
     }
+}
+
+/// Mutable route table collected while an application is being built.
+///
+/// Call [`RouterBuilder::freeze`] to compile it into a [`FrozenRouter`].
+pub struct RouterBuilder<C> {
+    routes: Vec<RouteDef<C>>,
+    fallback: Option<Arc<dyn Handler<C>>>,
+}
+
+impl<C: Send + Sync + Clone + 'static> RouterBuilder<C> {
+    pub const fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Handles requests no route matches under any method, instead of the
+    /// formatter's 404. Errors it returns are formatted as usual.
+    pub fn set_fallback(&mut self, handler: Box<dyn Handler<C>>) {
+        self.fallback = Some(Arc::from(handler));
+    }
 
     pub fn add_route(&mut self, method: Method, path: &str, handler: Box<dyn Handler<C>>) {
-        let handler_arc = Arc::from(handler);
-        let result = match method {
-            Method::GET => self.get_routes.insert(path, handler_arc),
-            Method::POST => self.post_routes.insert(path, handler_arc),
-            Method::PUT => self.put_routes.insert(path, handler_arc),
-            Method::DELETE => self.delete_routes.insert(path, handler_arc),
-            Method::PATCH => self.patch_routes.insert(path, handler_arc),
-            Method::HEAD => self.head_routes.insert(path, handler_arc),
-            Method::OPTIONS => self.options_routes.insert(path, handler_arc),
-            _ => {
-                eprintln!("Unsupported HTTP method: {}", method);
-                return;
-            }
-        };
+        self.routes.push(RouteDef {
+            method: Some(method),
+            path: path.to_string(),
+            handler: Arc::from(handler),
+        });
+    }
+
+    /// Registers `handler` for every method, including non-standard ones.
+    ///
+    /// Method-specific routes on the same path take precedence.
+    pub fn add_any_route(&mut self, path: &str, handler: Box<dyn Handler<C>>) {
+        self.routes.push(RouteDef {
+            method: None,
+            path: path.to_string(),
+            handler: Arc::from(handler),
+        });
+    }
+
+    /// Whether a route for `method` (or any method, for `None`) is already
+    /// registered at exactly `path`.
+    pub fn has_route(&self, method: Option<&Method>, path: &str) -> bool {
+        self.routes
+            .iter()
+            .any(|def| def.path == path && (method.is_none() || def.method.as_ref() == method))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.routes.len()
+    }
 
-        if let Err(e) = result {
-            eprintln!("Failed to insert route {} {}: {}", method, path, e);
+    pub(crate) fn fallback(&self) -> Option<&Arc<dyn Handler<C>>> {
+        self.fallback.as_ref()
+    }
+
+    /// The errors [`try_freeze`](Self::try_freeze) reports for the routes
+    /// from index `start` on, plus an overlap between one of them and an
+    /// earlier route where one is for every method and the other for a
+    /// single one, which `compile` would resolve silently by precedence.
+    pub(crate) fn errors_since(&self, start: usize) -> Vec<RouteError> {
+        let before = RouterBuilder {
+            routes: self.routes[..start].to_vec(),
+            fallback: None,
         }
+        .compile()
+        .1;
+        let mut errors = self.compile().1;
+        errors.retain(|e| !before.contains(e));
+
+        for (i, def) in self.routes.iter().enumerate().skip(start) {
+            let key = trie_key(&def.path).0;
+            let shadowed = self.routes[..i].iter().find(|other| {
+                other.method.is_none() != def.method.is_none() && trie_key(&other.path).0 == key
+            });
+            if let Some(other) = shadowed {
+                errors.push(RouteError::Conflict {
+                    path: def.path.clone(),
+                    with: other.path.clone(),
+                });
+            }
+        }
+        errors
     }
 
-    pub async fn handle(&self, ctx: C, mut req: CoreRequest) -> CoreResponse {
-        let method = req.method().clone();
-        let path = req.uri().path();
+    /// The registered routes sorted by path, then method.
+    pub(crate) fn describe(&self) -> Vec<RouteDescription> {
+        let mut routes: Vec<RouteDescription> = self
+            .routes
+            .iter()
+            .map(|def| RouteDescription {
+                method: def.method.as_ref().map_or("*", Method::as_str).to_string(),
+                path: def.path.clone(),
+                middleware: def.handler.middleware_names(),
+            })
+            .collect();
+        routes.sort_by(|a, b| (&a.path, &a.method).cmp(&(&b.path, &b.method)));
+        routes
+    }
+
+    pub fn get<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::GET, path, handler)
+    }
+
+    pub fn post<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::POST, path, handler)
+    }
+
+    pub fn put<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::PUT, path, handler)
+    }
+
+    pub fn delete<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::DELETE, path, handler)
+    }
+
+    pub fn any<M>(mut self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.add_any_route(path, handler.into_handler());
+        self
+    }
 
-        let match_result = match method {
-            Method::GET => self.get_routes.at(path),
-            Method::POST => self.post_routes.at(path),
-            Method::PUT => self.put_routes.at(path),
-            Method::DELETE => self.delete_routes.at(path),
-            Method::PATCH => self.patch_routes.at(path),
-            Method::HEAD => self.head_routes.at(path),
-            Method::OPTIONS => self.options_routes.at(path),
-            _ => return self.method_not_allowed_response(),
+    pub fn route<M>(mut self, method: Method, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.add_route(method, path, handler.into_handler());
+        self
+    }
+
+    /// Mounts every route of `child` under `prefix`.
+    ///
+    /// A fallback of `child` handles the requests under `prefix` that none
+    /// of its routes match, as with
+    /// [`App::nest_with_context`](crate::App::nest_with_context).
+    pub fn nest(&mut self, prefix: &str, child: RouterBuilder<C>) {
+        let start = self.routes.len();
+        for def in child.routes {
+            self.routes.push(RouteDef {
+                path: join_paths(prefix, &def.path),
+                ..def
+            });
+        }
+        let Some(fallback) = child.fallback else {
+            return;
         };
+        for path in [join_paths(prefix, ""), join_paths(prefix, "*path")] {
+            let taken = self.routes[start..]
+                .iter()
+                .any(|def| def.method.is_none() && def.path == path);
+            if !taken {
+                self.routes.push(RouteDef {
+                    method: None,
+                    path,
+                    handler: Arc::clone(&fallback),
+                });
+            }
+        }
+    }
+
+    /// Compiles the routes, logging routes that could not be added.
+    pub fn freeze(&self) -> FrozenRouter<C> {
+        let (router, errors) = self.compile();
+        for error in errors {
+            logging::global().error(format!("Skipping route: {}", error));
+        }
+        router
+    }
 
-        match match_result {
-            Ok(Match {
-                value: handler,
+    /// Compiles the routes, failing with every duplicate, conflicting or
+    /// invalid route instead of skipping them.
+    pub fn try_freeze(&self) -> Result<FrozenRouter<C>, Vec<RouteError>> {
+        let (router, errors) = self.compile();
+        if errors.is_empty() {
+            Ok(router)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn compile(&self) -> (FrozenRouter<C>, Vec<RouteError>) {
+        let mut errors = Vec::new();
+        let mut endpoints = Endpoints::default();
+        for def in &self.routes {
+            let (key, params) = trie_key(&def.path);
+            let route = Route {
+                handler: Arc::clone(&def.handler),
+                pattern: def.path.clone(),
                 params,
-            }) => {
-                let params_map: HashMap<String, String> = params
-                    .iter()
-                    .map(|(key, value)| (key.to_string(), value.to_string()))
-                    .collect();
-                req.extensions_mut().insert(params_map);
-
-                match handler.call(ctx, req).await {
-                    Ok(response) => response,
-                    Err(error) => self.error_to_response(error),
+                empty_wildcard: false,
+            };
+            let endpoint = endpoints.at(key, &def.path);
+            if let Some(existing) = endpoint.registered(def.method.as_ref()) {
+                errors.push(if existing.pattern == def.path {
+                    RouteError::Duplicate {
+                        method: def.method.as_ref().map_or("*", Method::as_str).to_string(),
+                        path: def.path.clone(),
+                    }
+                } else {
+                    RouteError::Conflict {
+                        path: def.path.clone(),
+                        with: existing.pattern.clone(),
+                    }
+                });
+                continue;
+            }
+            endpoint.insert(def.method.as_ref(), route);
+        }
+
+        // matchit requires a catch-all to match at least one character, so
+        // `/static/*path` gets a companion `/static/` route with an empty
+        // value, unless that path already has its own route for the method.
+        for def in &self.routes {
+            if let Some((base, _)) = split_wildcard(&def.path) {
+                let (key, _) = trie_key(base);
+                let route = Route {
+                    handler: Arc::clone(&def.handler),
+                    pattern: def.path.clone(),
+                    params: trie_key(&def.path).1,
+                    empty_wildcard: true,
+                };
+                let endpoint = endpoints.at(key, base);
+                if endpoint.registered(def.method.as_ref()).is_none() {
+                    endpoint.insert(def.method.as_ref(), route);
                 }
             }
-            Err(_) => self.not_found_response(),
         }
+
+        let mut router = FrozenRouter {
+            routes: MatchItRouter::new(),
+            fallback: self.fallback.clone(),
+            formatter: Arc::new(JsonFormatter),
+            paths: PathPolicy::default(),
+        };
+        let patterns: HashMap<String, String> = endpoints
+            .list
+            .iter()
+            .map(|(key, pattern, _)| (key.clone(), pattern.clone()))
+            .collect();
+        for (key, path, endpoint) in endpoints.list {
+            if let Err(e) = router.routes.insert(key, endpoint) {
+                errors.push(match e {
+                    matchit::InsertError::Conflict { with } => RouteError::Conflict {
+                        path,
+                        with: patterns.get(&with).cloned().unwrap_or(with),
+                    },
+                    e => RouteError::Invalid {
+                        path,
+                        reason: e.to_string(),
+                    },
+                });
+            }
+        }
+        (router, errors)
     }
+}
 
-    fn error_to_response(&self, error: Error) -> CoreResponse {
-        let status = error.status_code();
+/// What to do with a request path that only matches a route once a
+/// trailing slash is added or removed, e.g. `/users/` for `/users`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// `/users/` and `/users` are different paths.
+    #[default]
+    Strict,
+    /// Answers `308 Permanent Redirect` to the path with the route.
+    Redirect,
+    /// Serves the route as if its own path had been requested.
+    Ignore,
+}
 
-        #[cfg(debug_assertions)]
-        let message = error.debug_message();
+/// Whether route parameters are percent-decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PercentDecoding {
+    /// Parameters are passed on as sent, e.g. `a%20b`.
+    #[default]
+    Raw,
+    /// Parameters are decoded after matching, so an encoded `%2F` stays
+    /// within its segment. Values that are not UTF-8 once decoded are
+    /// `400 Bad Request`.
+    Decode,
+}
 
-        #[cfg(not(debug_assertions))]
-        let message = error.safe_message().to_string();
+/// How request paths are normalized before routing, set with
+/// [`App::path_policy`](crate::App::path_policy). The default routes paths
+/// exactly as sent.
+///
+/// A path normalized to another one is routed with the request URI
+/// rewritten to it, so handlers see the route's own path; with
+/// [`TrailingSlash::Redirect`] any normalized path is redirected to
+/// instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathPolicy {
+    trailing_slash: TrailingSlash,
+    merge_slashes: bool,
+    decoding: PercentDecoding,
+}
 
-        let body = serde_json::json!({
-            "error": message,
-            "status": status.as_u16(),
-            "timestamp": chrono::Utc::now().to_rfc3339()
-        });
+impl PathPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        http::Response::builder()
-            .status(status)
-            .header("content-type", "application/json; charset=utf-8")
-            .header("x-request-id", uuid::Uuid::new_v4().to_string())
-            .body(body.to_string().into())
-            .unwrap()
+    pub fn trailing_slash(mut self, trailing_slash: TrailingSlash) -> Self {
+        self.trailing_slash = trailing_slash;
+        self
     }
 
-    fn not_found_response(&self) -> CoreResponse {
-        http::Response::builder()
-            .status(http::StatusCode::NOT_FOUND)
-            .header("content-type", "application/json; charset=utf-8")
-            .body(r#"{"error":"Not Found"}"#.into())
-            .unwrap()
+    /// Collapses runs of slashes, so `/users//7` routes like `/users/7`.
+    pub fn merge_slashes(mut self, merge: bool) -> Self {
+        self.merge_slashes = merge;
+        self
     }
 
-    fn method_not_allowed_response(&self) -> CoreResponse {
-        http::Response::builder()
-            .status(http::StatusCode::METHOD_NOT_ALLOWED)
-            .header("content-type", "application/json; charset=utf-8")
-            .body(r#"{"error":"Method Not Allowed"}"#.into())
-            .unwrap()
+    pub fn percent_decoding(mut self, decoding: PercentDecoding) -> Self {
+        self.decoding = decoding;
+        self
     }
 }
 
-impl<C> Clone for Router<C> {
+/// Why a route could not be added to the table, reported by
+/// [`RouterBuilder::try_freeze`] and [`App::build`](crate::App::build).
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RouteError {
+    /// The same method and path were registered twice; the first wins.
+    #[error("{method} {path} is registered more than once")]
+    Duplicate { method: String, path: String },
+
+    /// The path overlaps an existing route in a way the router cannot tell
+    /// apart, e.g. `GET /users/:id` and `GET /users/:name`. Routes for
+    /// different methods may name the same parameter differently.
+    #[error("{path} conflicts with {with}")]
+    Conflict { path: String, with: String },
+
+    #[error("{path} is not a valid route: {reason}")]
+    Invalid { path: String, reason: String },
+}
+
+/// A route added to a [`RouteGroup`]: its method (`None` for any), full
+/// path and handler.
+type GroupRoute<C> = (Option<Method>, String, Box<dyn Handler<C>>);
+
+/// Routes sharing a path prefix and middleware, collected by
+/// [`App::scope`](crate::App::scope).
+///
+/// The group's middleware runs after the app's global middleware and
+/// before any middleware attached to a single route, and only for requests
+/// that match one of the group's routes; unmatched paths under the prefix
+/// get the app's usual 404 or 405.
+pub struct RouteGroup<C> {
+    routes: Vec<GroupRoute<C>>,
+    middleware: MiddlewareStack<C>,
+}
+
+impl<C: Send + Sync + Clone + 'static> RouteGroup<C> {
+    pub(crate) fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            middleware: MiddlewareStack::new(),
+        }
+    }
+
+    /// Adds middleware to every route of the group, including routes
+    /// registered before it. Earlier middleware runs first on the way in.
+    pub fn middleware(mut self, middleware: impl Middleware<C> + 'static) -> Self {
+        self.middleware.add(Box::new(middleware));
+        self
+    }
+
+    pub fn get<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::GET, path, handler)
+    }
+
+    pub fn post<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::POST, path, handler)
+    }
+
+    pub fn put<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::PUT, path, handler)
+    }
+
+    pub fn delete<M>(self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.route(Method::DELETE, path, handler)
+    }
+
+    pub fn any<M>(mut self, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.routes
+            .push((None, path.to_string(), handler.into_handler()));
+        self
+    }
+
+    pub fn route<M>(mut self, method: Method, path: &str, handler: impl IntoHandler<C, M>) -> Self {
+        self.routes
+            .push((Some(method), path.to_string(), handler.into_handler()));
+        self
+    }
+
+    /// The group's routes, each wrapped in the complete middleware stack.
+    pub(crate) fn into_routes(self) -> RouterBuilder<C> {
+        let mut routes = RouterBuilder::new();
+        for (method, path, handler) in self.routes {
+            let handler = Box::new(Layered::new(handler, self.middleware.clone()));
+            match method {
+                Some(method) => routes.add_route(method, &path, handler),
+                None => routes.add_any_route(&path, handler),
+            }
+        }
+        routes
+    }
+}
+
+impl<C> Clone for RouterBuilder<C> {
     fn clone(&self) -> Self {
         Self {
-            get_routes: self.get_routes.clone(),
-            post_routes: self.post_routes.clone(),
-            put_routes: self.put_routes.clone(),
-            delete_routes: self.delete_routes.clone(),
-            patch_routes: self.patch_routes.clone(),
-            head_routes: self.head_routes.clone(),
-            options_routes: self.options_routes.clone(),
+            routes: self.routes.clone(),
+            fallback: self.fallback.clone(),
         }
     }
 }
 
-impl<C> Default for Router<C>
+impl<C> Default for RouterBuilder<C>
 where
     C: Send + Sync + Clone + 'static,
 {
@@ -146,3 +456,354 @@ where
         Self::new()
     }
 }
+
+fn join_paths(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    let path = path.trim_start_matches('/');
+
+    match (prefix.is_empty(), path.is_empty()) {
+        (true, _) => format!("/{}", path),
+        (false, true) => format!("/{}", prefix),
+        (false, false) => format!("/{}/{}", prefix, path),
+    }
+}
+
+fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+    for c in path.chars() {
+        if !(c == '/' && merged.ends_with('/')) {
+            merged.push(c);
+        }
+    }
+    merged
+}
+
+/// Decodes `%XX` escapes, or `None` if an escape is malformed or the result
+/// is not UTF-8.
+fn percent_decode(value: &str) -> Option<String> {
+    if !value.contains('%') {
+        return Some(value.to_string());
+    }
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// The endpoints being compiled, in registration order, by trie key.
+struct Endpoints<C> {
+    /// Each endpoint's trie key and the first path registered at it.
+    list: Vec<(String, String, Endpoint<C>)>,
+    index: HashMap<String, usize>,
+}
+
+impl<C> Default for Endpoints<C> {
+    fn default() -> Self {
+        Self {
+            list: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<C> Endpoints<C> {
+    /// The endpoint collecting routes at trie key `key`, added on first use.
+    fn at(&mut self, key: String, path: &str) -> &mut Endpoint<C> {
+        let index = *self.index.entry(key.clone()).or_insert_with(|| {
+            self.list.push((key, path.to_string(), Endpoint::default()));
+            self.list.len() - 1
+        });
+        &mut self.list[index].2
+    }
+}
+
+/// The path as stored in the trie, with parameters named by position so
+/// routes for different methods can name them differently, plus the
+/// route's own parameter names in order.
+fn trie_key(path: &str) -> (String, Vec<String>) {
+    let mut params = Vec::new();
+    let key = path
+        .split('/')
+        .map(|segment| match segment.chars().next() {
+            Some(kind @ (':' | '*')) => {
+                params.push(segment[1..].to_string());
+                format!("{}p{}", kind, params.len() - 1)
+            }
+            _ => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    (key, params)
+}
+
+fn split_wildcard(path: &str) -> Option<(&str, &str)> {
+    let start = path.rfind("/*")?;
+    Some((&path[..start + 1], &path[start + 2..]))
+}
+
+struct Route<C> {
+    handler: Arc<dyn Handler<C>>,
+    /// The path as registered, e.g. `/users/:id`.
+    pattern: String,
+    /// Parameter names in path order; the trie only knows their positions.
+    params: Vec<String>,
+    /// Whether this is a catch-all's companion route, whose last parameter
+    /// matches the empty string.
+    empty_wildcard: bool,
+}
+
+/// Everything registered at one path: a handler per method plus an
+/// optional one for every other method.
+struct Endpoint<C> {
+    methods: HashMap<Method, Route<C>>,
+    any: Option<Route<C>>,
+}
+
+impl<C> Default for Endpoint<C> {
+    fn default() -> Self {
+        Self {
+            methods: HashMap::new(),
+            any: None,
+        }
+    }
+}
+
+impl<C> Endpoint<C> {
+    /// The route registered for exactly `method` (every method for `None`).
+    fn registered(&self, method: Option<&Method>) -> Option<&Route<C>> {
+        match method {
+            Some(method) => self.methods.get(method),
+            None => self.any.as_ref(),
+        }
+    }
+
+    /// Adds `route` for `method` (every method for `None`), replacing any
+    /// route registered for it.
+    fn insert(&mut self, method: Option<&Method>, route: Route<C>) {
+        match method {
+            Some(method) => {
+                self.methods.insert(method.clone(), route);
+            }
+            None => self.any = Some(route),
+        }
+    }
+
+    /// Method-specific routes take precedence over an any-method route.
+    /// `HEAD` falls back to the `GET` route, whose body the app strips.
+    fn route(&self, method: &Method) -> Option<&Route<C>> {
+        let route = self.methods.get(method).or(self.any.as_ref());
+        match route {
+            None if method == Method::HEAD => self.methods.get(&Method::GET),
+            route => route,
+        }
+    }
+
+    /// The methods with their own route, plus `HEAD` when there is a `GET`
+    /// route, standard methods first in a stable order, then the rest
+    /// alphabetically.
+    fn allowed_methods(&self) -> Vec<Method> {
+        const STANDARD: [Method; 7] = [
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::PATCH,
+            Method::HEAD,
+            Method::OPTIONS,
+        ];
+        let mut other: Vec<&Method> = self
+            .methods
+            .keys()
+            .filter(|method| !STANDARD.contains(method))
+            .collect();
+        other.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+
+        let head = self.methods.contains_key(&Method::GET);
+        STANDARD
+            .into_iter()
+            .filter(|method| self.methods.contains_key(method) || (*method == Method::HEAD && head))
+            .chain(other.into_iter().cloned())
+            .collect()
+    }
+}
+
+/// Immutable, compiled route table used to dispatch requests.
+///
+/// All routes live in one path trie. The most specific path matching a
+/// request wins; its method map then picks the handler, or answers `405`
+/// when the path has no route for the request's method.
+pub struct FrozenRouter<C> {
+    routes: MatchItRouter<Endpoint<C>>,
+    fallback: Option<Arc<dyn Handler<C>>>,
+    formatter: Arc<dyn ResponseFormatter>,
+    paths: PathPolicy,
+}
+
+impl<C: Send + Sync + Clone + 'static> FrozenRouter<C> {
+    pub fn with_formatter(mut self, formatter: Arc<dyn ResponseFormatter>) -> Self {
+        self.formatter = formatter;
+        self
+    }
+
+    pub fn with_path_policy(mut self, paths: PathPolicy) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    /// The path `path` is routed as under the path policy, if it differs.
+    fn normalized_path(&self, path: &str) -> Option<String> {
+        let mut normalized = path.to_string();
+        if self.paths.merge_slashes && path.contains("//") {
+            normalized = merge_slashes(path);
+        }
+        if self.paths.trailing_slash != TrailingSlash::Strict
+            && normalized.len() > 1
+            && self.routes.at(&normalized).is_err()
+        {
+            let alternative = match normalized.strip_suffix('/') {
+                Some(trimmed) => trimmed.to_string(),
+                None => format!("{}/", normalized),
+            };
+            if self.routes.at(&alternative).is_ok() {
+                normalized = alternative;
+            }
+        }
+        // Browsers read `//host` and `/\host` as another site.
+        let off_site = normalized.starts_with("//") || normalized.starts_with("/\\");
+        (normalized != path && !off_site).then_some(normalized)
+    }
+
+    /// Methods that have a route matching `path`, in a stable order.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        self.routes
+            .at(path)
+            .map(|matched| matched.value.allowed_methods())
+            .unwrap_or_default()
+    }
+
+    pub async fn handle(&self, ctx: C, mut req: CoreRequest) -> CoreResponse {
+        if let Some(path) = self.normalized_path(req.uri().path()) {
+            if self.paths.trailing_slash == TrailingSlash::Redirect {
+                let location = match req.uri().query() {
+                    Some(query) => format!("{}?{}", path, query),
+                    None => path.clone(),
+                };
+                // The path comes from the request URI, so it is already
+                // percent-encoded and valid in a header.
+                if let Ok(location) = HeaderValue::from_str(&location) {
+                    explain::note(&req, "route", "redirect 308");
+                    return Redirect::permanent_raw(location).into_response();
+                }
+            }
+            if let Err(error) = set_path(&mut req, &path) {
+                let error_req = ErrorRequest::from_request(&req).with_context(&ctx);
+                return self.formatter.format_error_for(&error, &error_req);
+            }
+        }
+        let path = req.uri().path();
+
+        let match_result = self.routes.at(path).ok().and_then(|matched| {
+            let route = matched.value.route(req.method())?;
+            Some((route, matched.params))
+        });
+
+        let Some((route, params)) = match_result else {
+            if let Some(fallback) = &self.fallback {
+                if self.allowed_methods(path).is_empty() {
+                    explain::note(&req, "route", "fallback");
+                    let error_req = ErrorRequest::from_request(&req).with_context(&ctx);
+                    return match fallback.call(ctx, req).await {
+                        Ok(response) => response,
+                        Err(error) => self.formatter.format_error_for(&error, &error_req),
+                    };
+                }
+            }
+            let error_req = ErrorRequest::from_request(&req).with_context(&ctx);
+            let res = self.unmatched_response(path, &error_req);
+            explain::note(&req, "route", &format!("none {}", res.status().as_u16()));
+            return res;
+        };
+
+        let mut ordered = Vec::with_capacity(route.params.len());
+        for ((_, value), key) in params.iter().zip(&route.params) {
+            let value = match self.paths.decoding {
+                PercentDecoding::Raw => value.to_string(),
+                PercentDecoding::Decode => match percent_decode(value) {
+                    Some(value) => value,
+                    None => {
+                        let error = Error::bad_request("Invalid percent-encoding in path");
+                        let error_req = ErrorRequest::from_request(&req).with_context(&ctx);
+                        return self.formatter.format_error_for(&error, &error_req);
+                    }
+                },
+            };
+            ordered.push((key.to_string(), value));
+        }
+        if route.empty_wildcard {
+            if let Some(name) = route.params.last() {
+                ordered.push((name.clone(), String::new()));
+            }
+        }
+        let params_map: HashMap<String, String> = ordered.iter().cloned().collect();
+        req.extensions_mut().insert(params_map);
+        req.extensions_mut().insert(PathParams(ordered));
+        let matched = MatchedPath(route.pattern.clone());
+        req.extensions_mut().insert(matched.clone());
+
+        let error_req = ErrorRequest::from_request(&req).with_context(&ctx);
+        let result = match req.extensions().get::<Trace>().cloned() {
+            Some(trace) => {
+                let route_name = format!("{} {}", req.method(), route.pattern);
+                let index = trace.begin("handler", &route_name);
+                trace.set_outcome(index, "responded");
+                let start = Instant::now();
+                let result = route.handler.call(ctx, req).await;
+                trace.end(index, start.elapsed(), &result);
+                result
+            }
+            None => route.handler.call(ctx, req).await,
+        };
+        let mut response = match result {
+            Ok(response) => response,
+            Err(error) => self.formatter.format_error_for(&error, &error_req),
+        };
+        response.extensions_mut().insert(matched);
+        response
+    }
+
+    fn unmatched_response(&self, path: &str, error_req: &ErrorRequest) -> CoreResponse {
+        let allowed = self.allowed_methods(path);
+        if allowed.is_empty() {
+            return self.formatter.not_found_for(error_req);
+        }
+
+        let allow = allowed
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let mut response = self.formatter.method_not_allowed_for(error_req);
+        if let Ok(value) = HeaderValue::from_str(&allow) {
+            response.headers_mut().insert(ALLOW, value);
+        }
+        response
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Handler<C> for FrozenRouter<C> {
+    async fn call(&self, ctx: C, req: CoreRequest) -> Result<CoreResponse, Error> {
+        Ok(self.handle(ctx, req).await)
+    }
+}
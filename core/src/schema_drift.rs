@@ -0,0 +1,419 @@
+use crate::{
+    extract::MatchedPath,
+    logging,
+    middleware::{Middleware, Next},
+    Body, CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use http::header::CONTENT_TYPE;
+use http::HeaderMap;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// One way an observed body differed from the declared schema.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Drift {
+    /// `METHOD /route/{param}`, in OpenAPI path syntax.
+    pub operation: String,
+    /// `request`, or `response` followed by the status code.
+    pub message: String,
+    /// JSON pointer into the body, empty for the whole body.
+    pub pointer: String,
+    pub issue: String,
+}
+
+/// Counters for [`SchemaRecorder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct DriftSnapshot {
+    pub sampled: u64,
+    /// Sampled exchanges with at least one drift.
+    pub drifted: u64,
+}
+
+#[derive(Default)]
+struct Recorded {
+    drifts: BTreeMap<Drift, u64>,
+    /// Shape of the last sampled body per operation and message.
+    shapes: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+/// Opt-in middleware sampling request and response JSON bodies, recording
+/// their shapes per route and comparing them with an OpenAPI 3 document.
+///
+/// Each new kind of drift (an undocumented field, a missing required field,
+/// a type mismatch, an undocumented operation or status) is logged once and
+/// counted; read the results with [`SchemaRecorder::drifts`] and
+/// [`SchemaRecorder::snapshot`]. Clones share what has been recorded.
+///
+/// Only buffered responses are inspected, and only routes matched by the
+/// router, so register it as global middleware.
+#[derive(Clone)]
+pub struct SchemaRecorder {
+    spec: Arc<Value>,
+    sample_every: u64,
+    seen: Arc<AtomicU64>,
+    drifted: Arc<AtomicU64>,
+    recorded: Arc<Mutex<Recorded>>,
+}
+
+impl SchemaRecorder {
+    /// Checks against `spec`, sampling one request in 100.
+    pub fn new(spec: Value) -> Self {
+        Self {
+            spec: Arc::new(spec),
+            sample_every: 100,
+            seen: Arc::new(AtomicU64::new(0)),
+            drifted: Arc::new(AtomicU64::new(0)),
+            recorded: Arc::default(),
+        }
+    }
+
+    pub fn from_json(spec: &str) -> Result<Self, Error> {
+        Ok(Self::new(serde_json::from_str(spec)?))
+    }
+
+    /// Samples one request in `n`; `1` samples everything.
+    pub fn sample_every(mut self, n: u64) -> Self {
+        self.sample_every = n.max(1);
+        self
+    }
+
+    pub fn snapshot(&self) -> DriftSnapshot {
+        DriftSnapshot {
+            sampled: self
+                .seen
+                .load(Ordering::Relaxed)
+                .div_ceil(self.sample_every),
+            drifted: self.drifted.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Every drift seen so far, with how many sampled exchanges showed it.
+    pub fn drifts(&self) -> Vec<(Drift, u64)> {
+        let recorded = self.recorded.lock().unwrap();
+        recorded
+            .drifts
+            .iter()
+            .map(|(drift, count)| (drift.clone(), *count))
+            .collect()
+    }
+
+    /// The last observed body shape per operation and message, e.g.
+    /// `{"id": "integer", "tags": ["string"]}`.
+    pub fn shapes(&self) -> BTreeMap<String, BTreeMap<String, Value>> {
+        self.recorded.lock().unwrap().shapes.clone()
+    }
+
+    fn record(&self, operation: &str, observed: Vec<(String, Value)>, drifts: Vec<Drift>) {
+        let mut recorded = self.recorded.lock().unwrap();
+        let shapes = recorded.shapes.entry(operation.to_string()).or_default();
+        for (message, body) in observed {
+            shapes.insert(message, shape(&body));
+        }
+        if drifts.is_empty() {
+            return;
+        }
+        self.drifted.fetch_add(1, Ordering::Relaxed);
+        for drift in drifts {
+            let count = recorded.drifts.entry(drift.clone()).or_insert(0);
+            if *count == 0 {
+                logging::global().warn(format!(
+                    "Schema drift in {} {} at '{}': {}",
+                    drift.operation, drift.message, drift.pointer, drift.issue
+                ));
+            }
+            *count += 1;
+        }
+    }
+
+    fn check(
+        &self,
+        operation: &str,
+        message: &str,
+        schema: Option<&Value>,
+        body: &Value,
+        drifts: &mut Vec<Drift>,
+    ) {
+        let Some(schema) = schema else {
+            drifts.push(Drift {
+                operation: operation.to_string(),
+                message: message.to_string(),
+                pointer: String::new(),
+                issue: "undocumented JSON body".to_string(),
+            });
+            return;
+        };
+        let mut issues = Vec::new();
+        compare(&self.spec, schema, body, String::new(), &mut issues);
+        drifts.extend(issues.into_iter().map(|(pointer, issue)| Drift {
+            operation: operation.to_string(),
+            message: message.to_string(),
+            pointer,
+            issue,
+        }));
+    }
+}
+
+/// The operation `method path` of an OpenAPI document, `path` being in
+/// OpenAPI syntax.
+pub(crate) fn operation<'a>(spec: &'a Value, method: &str, path: &str) -> Option<&'a Value> {
+    spec.get("paths")?
+        .get(path)?
+        .get(method.to_ascii_lowercase())
+}
+
+/// The JSON schema of a request body or response object.
+pub(crate) fn json_schema(content: Option<&Value>) -> Option<&Value> {
+    content?
+        .get("content")?
+        .as_object()?
+        .iter()
+        .find(|(mime, _)| is_json_mime(mime))?
+        .1
+        .get("schema")
+}
+
+pub(crate) fn is_json_mime(mime: &str) -> bool {
+    let mime = mime.split(';').next().unwrap_or("").trim();
+    mime == "application/json" || mime.ends_with("+json")
+}
+
+pub(crate) fn json_body(headers: &HeaderMap, body: &Body) -> Option<Value> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    if !is_json_mime(content_type) {
+        return None;
+    }
+    serde_json::from_slice(body.as_bytes()?).ok()
+}
+
+/// `/users/:id/*rest` becomes `/users/{id}/{rest}`.
+fn openapi_path(pattern: &str) -> String {
+    pattern
+        .split('/')
+        .map(|segment| match segment.strip_prefix([':', '*']) {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub(crate) fn resolve<'a>(spec: &'a Value, schema: &'a Value) -> &'a Value {
+    match schema.get("$ref").and_then(Value::as_str) {
+        Some(reference) => reference
+            .strip_prefix('#')
+            .and_then(|pointer| spec.pointer(pointer))
+            .unwrap_or(schema),
+        None => schema,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Checks `value` against `schema`, collecting `(pointer, issue)` pairs.
+pub(crate) fn compare(
+    spec: &Value,
+    schema: &Value,
+    value: &Value,
+    pointer: String,
+    issues: &mut Vec<(String, String)>,
+) {
+    let schema = resolve(spec, schema);
+    for key in ["oneOf", "anyOf"] {
+        if let Some(options) = schema.get(key).and_then(Value::as_array) {
+            let fits = options.iter().any(|option| {
+                let mut scratch = Vec::new();
+                compare(spec, option, value, String::new(), &mut scratch);
+                scratch.is_empty()
+            });
+            if !fits {
+                issues.push((pointer, format!("matches none of {}", key)));
+            }
+            return;
+        }
+    }
+    if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+        let mut merged = Map::new();
+        let mut required = Vec::new();
+        for part in all {
+            let part = resolve(spec, part);
+            if let Some(props) = part.get("properties").and_then(Value::as_object) {
+                merged.extend(props.clone());
+            }
+            if let Some(req) = part.get("required").and_then(Value::as_array) {
+                required.extend(req.clone());
+            }
+        }
+        let combined = serde_json::json!({
+            "type": "object",
+            "properties": merged,
+            "required": required,
+        });
+        compare(spec, &combined, value, pointer, issues);
+        return;
+    }
+
+    let actual = type_name(value);
+    if let Some(expected) = schema.get("type").and_then(Value::as_str) {
+        let nullable = schema.get("nullable").and_then(Value::as_bool) == Some(true);
+        let fits = actual == expected
+            || (expected == "number" && actual == "integer")
+            || (actual == "null" && nullable);
+        if !fits {
+            issues.push((pointer, format!("expected {}, found {}", expected, actual)));
+            return;
+        }
+    }
+
+    match value {
+        Value::Object(fields) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            if let Some(required) = schema.get("required").and_then(Value::as_array) {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !fields.contains_key(name) {
+                        issues.push((
+                            format!("{}/{}", pointer, name),
+                            "missing required field".to_string(),
+                        ));
+                    }
+                }
+            }
+            let Some(properties) = properties else {
+                return;
+            };
+            let additional = schema.get("additionalProperties");
+            for (name, field) in fields {
+                let field_pointer = format!("{}/{}", pointer, name);
+                match properties.get(name) {
+                    Some(field_schema) => compare(spec, field_schema, field, field_pointer, issues),
+                    None => match additional {
+                        Some(extra @ Value::Object(_)) => {
+                            compare(spec, extra, field, field_pointer, issues)
+                        }
+                        Some(Value::Bool(true)) => {}
+                        _ => issues.push((field_pointer, "undocumented field".to_string())),
+                    },
+                }
+            }
+        }
+        Value::Array(items) => {
+            let Some(item_schema) = schema.get("items") else {
+                return;
+            };
+            for (index, item) in items.iter().enumerate() {
+                compare(
+                    spec,
+                    item_schema,
+                    item,
+                    format!("{}/{}", pointer, index),
+                    issues,
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A body's structure with values replaced by their types.
+fn shape(value: &Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, field)| (name.clone(), shape(field)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.first().map(shape).into_iter().collect()),
+        other => Value::String(type_name(other).to_string()),
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for SchemaRecorder {
+    async fn handle(
+        &self,
+        ctx: C,
+        mut req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        if self.seen.fetch_add(1, Ordering::Relaxed) % self.sample_every != 0 {
+            return next.run(ctx, req).await;
+        }
+
+        let method = req.method().to_string();
+        let is_json = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(is_json_mime);
+        let request_body = if is_json {
+            req.body_mut().buffer().await?;
+            json_body(req.headers(), req.body())
+        } else {
+            None
+        };
+
+        let res = next.run(ctx, req).await?;
+        let Some(MatchedPath(pattern)) = res.extensions().get::<MatchedPath>() else {
+            return Ok(res);
+        };
+        let path = openapi_path(pattern);
+        let operation_name = format!("{} {}", method, path);
+        let mut drifts = Vec::new();
+        let mut observed = Vec::new();
+
+        let Some(operation) = operation(&self.spec, &method, &path) else {
+            drifts.push(Drift {
+                operation: operation_name.clone(),
+                message: String::new(),
+                pointer: String::new(),
+                issue: "undocumented operation".to_string(),
+            });
+            self.record(&operation_name, observed, drifts);
+            return Ok(res);
+        };
+
+        if let Some(body) = request_body {
+            let schema = json_schema(operation.get("requestBody").map(|b| resolve(&self.spec, b)));
+            self.check(&operation_name, "request", schema, &body, &mut drifts);
+            observed.push(("request".to_string(), body));
+        }
+
+        let status = res.status().as_u16().to_string();
+        let message = format!("response {}", status);
+        let responses = operation.get("responses");
+        let documented = responses
+            .and_then(|r| r.get(&status).or_else(|| r.get("default")))
+            .map(|r| resolve(&self.spec, r));
+        match (documented, json_body(res.headers(), res.body())) {
+            (None, _) => drifts.push(Drift {
+                operation: operation_name.clone(),
+                message,
+                pointer: String::new(),
+                issue: "undocumented status".to_string(),
+            }),
+            (Some(response), Some(body)) => {
+                let schema = json_schema(Some(response));
+                self.check(&operation_name, &message, schema, &body, &mut drifts);
+                observed.push((message, body));
+            }
+            (Some(_), None) => {}
+        }
+
+        self.record(&operation_name, observed, drifts);
+        Ok(res)
+    }
+}
@@ -0,0 +1,285 @@
+use crate::{
+    clock::{Rng, SystemRng},
+    cookie::{Cookies, SameSite, SetCookie},
+    crypto::{self, KeyProvider},
+    extract::FromRequest,
+    middleware::{Middleware, Next},
+    CoreRequest, CoreResponse, Ctx, Error,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use http::header::SET_COOKIE;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Binds sealed session IDs to their purpose.
+const COOKIE_AAD: &[u8] = b"xeno-session";
+
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    /// Unix seconds, also stored for Kv backends that ignore the TTL.
+    expires_at: u64,
+    data: Map<String, Value>,
+}
+
+#[derive(Default)]
+struct SessionState {
+    id: Option<String>,
+    data: Map<String, Value>,
+    ttl: Option<Duration>,
+    changed: bool,
+    destroyed: bool,
+    /// The ID replaced by [`Session::renew`], to be invalidated on save.
+    renewed_from: Option<String>,
+}
+
+/// The current request's session, loaded by [`SessionMiddleware`].
+///
+/// Values are stored as JSON. Changes are saved after the handler returns;
+/// clones refer to the same session.
+#[derive(Clone, Default)]
+pub struct Session {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl Session {
+    /// The session ID, if the session was loaded or has been saved before.
+    pub fn id(&self) -> Option<String> {
+        self.state.lock().unwrap().id.clone()
+    }
+
+    /// Reads a value, treating one of another type as missing.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let state = self.state.lock().unwrap();
+        serde_json::from_value(state.data.get(key)?.clone()).ok()
+    }
+
+    pub fn insert<T: Serialize>(&self, key: impl Into<String>, value: T) -> Result<(), Error> {
+        let value = serde_json::to_value(value)?;
+        let mut state = self.state.lock().unwrap();
+        state.data.insert(key.into(), value);
+        state.changed = true;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        let mut state = self.state.lock().unwrap();
+        let removed = state.data.remove(key);
+        state.changed |= removed.is_some();
+        removed
+    }
+
+    /// Keeps this session for `ttl` from now instead of the middleware's
+    /// default.
+    pub fn expire_in(&self, ttl: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.ttl = Some(ttl);
+        state.changed = true;
+    }
+
+    /// Issues a new session ID for the same data, e.g. after login to
+    /// prevent session fixation. The old ID stops working.
+    pub fn renew(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.changed = true;
+        if let Some(old) = state.id.take() {
+            state.renewed_from = Some(old);
+        }
+    }
+
+    /// Deletes the session and its cookie.
+    pub fn destroy(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.data.clear();
+        state.destroyed = true;
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> FromRequest<C> for Session {
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        req.extensions()
+            .get::<Session>()
+            .cloned()
+            .ok_or_else(|| Error::internal("SessionMiddleware is not installed"))
+    }
+}
+
+/// Middleware providing [`Session`]s stored in the context's Kv.
+///
+/// The cookie only carries the session ID, sealed with the given keys so it
+/// cannot be forged or enumerated; rotating keys with
+/// [`StaticKeys::with_key`](crate::crypto::StaticKeys::with_key) keeps
+/// existing sessions valid. Sessions expire after `ttl` without changes.
+pub struct SessionMiddleware {
+    keys: Arc<dyn KeyProvider>,
+    cookie_name: String,
+    ttl: Duration,
+    prefix: String,
+    secure: bool,
+}
+
+impl SessionMiddleware {
+    pub fn new(keys: Arc<dyn KeyProvider>) -> Self {
+        Self {
+            keys,
+            cookie_name: "xeno_session".to_string(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            prefix: "session:".to_string(),
+            secure: true,
+        }
+    }
+
+    pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn key_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Whether the cookie is marked `Secure`. On by default; turn off for
+    /// plain-HTTP development servers.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    fn session_id(&self, req: &CoreRequest) -> Option<String> {
+        let token = Cookies::extract(req).get(&self.cookie_name)?.to_string();
+        let sealed = URL_SAFE_NO_PAD.decode(token).ok()?;
+        let id = crypto::open(self.keys.as_ref(), COOKIE_AAD, &sealed).ok()?;
+        String::from_utf8(id).ok()
+    }
+
+    async fn load(&self, ctx: &Ctx, id: &str) -> Option<Map<String, Value>> {
+        let stored = ctx
+            .kv
+            .as_ref()?
+            .get(&format!("{}{}", self.prefix, id))
+            .await?;
+        let stored: StoredSession = serde_json::from_slice(&stored).ok()?;
+        (stored.expires_at > ctx.clock.unix_secs()).then_some(stored.data)
+    }
+
+    async fn store(
+        &self,
+        ctx: &Ctx,
+        id: &str,
+        session: StoredSession,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let kv = ctx
+            .kv
+            .as_ref()
+            .ok_or_else(|| Error::internal("Sessions require a Kv store"))?;
+        kv.put_with_ttl(
+            &format!("{}{}", self.prefix, id),
+            Bytes::from(serde_json::to_vec(&session)?),
+            ttl,
+        )
+        .await
+        .map_err(|e| Error::internal(format!("Failed to save session: {}", e)))
+    }
+
+    /// Overwrites a session with an expired one, as Kv has no delete.
+    async fn invalidate(&self, ctx: &Ctx, id: &str) -> Result<(), Error> {
+        let expired = StoredSession {
+            expires_at: 0,
+            data: Map::new(),
+        };
+        self.store(ctx, id, expired, Duration::from_secs(60)).await
+    }
+
+    fn cookie(&self, value: String) -> SetCookie {
+        SetCookie::new(self.cookie_name.clone(), value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+    }
+
+    /// Saves or deletes the session after the handler ran, returning the
+    /// cookie to send, if any.
+    async fn save(&self, ctx: &Ctx, session: &Session) -> Result<Option<SetCookie>, Error> {
+        let (id, data, ttl, destroyed, renewed_from) = {
+            let mut state = session.state.lock().unwrap();
+            if !state.changed && !state.destroyed {
+                return Ok(None);
+            }
+            (
+                state.id.clone(),
+                state.data.clone(),
+                state.ttl.unwrap_or(self.ttl),
+                state.destroyed,
+                state.renewed_from.take(),
+            )
+        };
+        if let Some(old) = renewed_from {
+            self.invalidate(ctx, &old).await?;
+        }
+
+        if destroyed {
+            let Some(id) = id else {
+                return Ok(None);
+            };
+            self.invalidate(ctx, &id).await?;
+            return Ok(Some(self.cookie(String::new()).max_age(Duration::ZERO)));
+        }
+
+        let id = id.unwrap_or_else(|| {
+            // Session IDs are bearer secrets: always from the OS generator.
+            let mut bytes = [0; 32];
+            SystemRng.fill_bytes(&mut bytes);
+            URL_SAFE_NO_PAD.encode(bytes)
+        });
+        let stored = StoredSession {
+            expires_at: ctx.clock.unix_secs() + ttl.as_secs(),
+            data,
+        };
+        self.store(ctx, &id, stored, ttl).await?;
+        let sealed = crypto::seal(self.keys.as_ref(), COOKIE_AAD, id.as_bytes())?;
+        Ok(Some(
+            self.cookie(URL_SAFE_NO_PAD.encode(sealed)).max_age(ttl),
+        ))
+    }
+}
+
+#[async_trait]
+impl Middleware<Ctx> for SessionMiddleware {
+    async fn handle(
+        &self,
+        ctx: Ctx,
+        mut req: CoreRequest,
+        next: Next<'_, Ctx>,
+    ) -> Result<CoreResponse, Error> {
+        let mut state = SessionState::default();
+        if let Some(id) = self.session_id(&req) {
+            if let Some(data) = self.load(&ctx, &id).await {
+                state.id = Some(id);
+                state.data = data;
+            }
+        }
+        let session = Session {
+            state: Arc::new(Mutex::new(state)),
+        };
+        req.extensions_mut().insert(session.clone());
+
+        let mut res = next.run(ctx.clone(), req).await?;
+        if let Some(cookie) = self.save(&ctx, &session).await? {
+            res.headers_mut()
+                .append(SET_COOKIE, cookie.to_header_value()?);
+        }
+        Ok(res)
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{
+    logging::{self, Level},
+    Body, CoreRequest, CoreResponse, Error, Handler,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use http::Method;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// Fallback handler serving a single-page application's `index.html` for
+/// client-side routes such as `/settings/profile`.
+///
+/// Only `GET` and `HEAD` requests qualify, and only when the last path
+/// segment has no file extension (a missing `/app.js` stays a 404) and the
+/// path is outside the API prefixes, `/api` by default. The page is held
+/// in memory, so serving it never touches the file system, and sent with
+/// `no-cache` so browsers pick up new builds.
+///
+/// ```ignore
+/// let spa = SpaFallback::new("dist/index.html").api_prefix("/graphql");
+/// let app = app.fallback(spa.clone());
+/// // After a deploy replaced the file:
+/// spa.reload()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct SpaFallback {
+    index: Option<PathBuf>,
+    /// Shared by clones, so reloading any of them updates the mounted one.
+    html: Arc<RwLock<Option<Bytes>>>,
+    api_prefixes: Vec<String>,
+}
+
+impl SpaFallback {
+    /// Reads `index` now; if it cannot be read, requests get 404s until a
+    /// [`reload`](Self::reload) succeeds.
+    pub fn new(index: impl Into<PathBuf>) -> Self {
+        let spa = Self {
+            index: Some(index.into()),
+            html: Arc::default(),
+            api_prefixes: vec!["/api".to_string()],
+        };
+        if let Err(e) = spa.reload() {
+            logging::global().log(Level::Error, "spa index unreadable", e.to_string());
+        }
+        spa
+    }
+
+    /// Serves `html` itself, e.g. from `include_bytes!`, where there is no
+    /// file system such as on Workers.
+    pub fn html(html: impl Into<Bytes>) -> Self {
+        Self {
+            index: None,
+            html: Arc::new(RwLock::new(Some(html.into()))),
+            api_prefixes: vec!["/api".to_string()],
+        }
+    }
+
+    /// Reads the index file again, keeping the page served so far if that
+    /// fails. This blocks, so call it from a deploy hook or
+    /// [`Ctx::blocking`](crate::Ctx::blocking) rather than a handler.
+    pub fn reload(&self) -> Result<(), Error> {
+        let Some(index) = &self.index else {
+            return Ok(());
+        };
+        let html = std::fs::read(index)
+            .map_err(|e| Error::internal(format!("Failed to read {}: {}", index.display(), e)))?;
+        *self.html.write().unwrap() = Some(html.into());
+        Ok(())
+    }
+
+    /// Another path prefix whose unmatched requests keep getting 404s.
+    pub fn api_prefix(mut self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        self.api_prefixes
+            .push(format!("/{}", prefix.trim_matches('/')));
+        self
+    }
+
+    fn applies(&self, req: &CoreRequest) -> bool {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return false;
+        }
+        let path = req.uri().path();
+        let is_api = self.api_prefixes.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        let is_file = path.rsplit('/').next().is_some_and(|s| s.contains('.'));
+        !is_api && !is_file
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Handler<C> for SpaFallback {
+    async fn call(&self, _ctx: C, req: CoreRequest) -> Result<CoreResponse, Error> {
+        if !self.applies(&req) {
+            return Err(Error::not_found());
+        }
+        let html = self
+            .html
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(Error::not_found)?;
+        Ok(http::Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(Body::from(html))?)
+    }
+}
@@ -0,0 +1,379 @@
+use crate::{
+    middleware::{Middleware, Next},
+    CoreRequest, CoreResponse, Ctx, Error,
+};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A result row, keyed by column name.
+pub type Row = serde_json::Map<String, Value>;
+
+/// SQL access provided by the platform (D1, sqlx pools). Parameters are bound
+/// positionally in the backend's placeholder syntax.
+#[async_trait]
+pub trait Sql: Send + Sync {
+    async fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Row>, Error>;
+
+    /// Runs a statement and returns the number of affected rows.
+    async fn execute(&self, sql: &str, params: &[Value]) -> Result<u64, Error>;
+
+    /// Connection pool statistics, for pooled backends.
+    fn pool_stats(&self) -> Option<PoolStats> {
+        None
+    }
+}
+
+/// A pool's state, served by [`Readiness`](crate::health::Readiness) and
+/// exported by [`RequestMetrics::sql_pool`](crate::metrics::RequestMetrics::sql_pool).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PoolStats {
+    pub size: u32,
+    pub idle: u32,
+    /// Callers currently waiting for a connection.
+    pub waiting: u32,
+    pub max_size: u32,
+    /// Connections handed out so far.
+    pub acquires: u64,
+    /// Time callers spent waiting for those connections, in microseconds.
+    pub acquire_wait_micros: u64,
+}
+
+impl PoolStats {
+    /// Whether a new caller would have to wait for a connection.
+    pub fn is_exhausted(&self) -> bool {
+        self.idle == 0 && self.size >= self.max_size
+    }
+}
+
+/// Counts connection acquisitions and the time spent waiting for them, for
+/// pooled backends to report in [`PoolStats`]. Clones share the counters.
+///
+/// ```ignore
+/// let start = Instant::now();
+/// let conn = self.pool.acquire().await?;
+/// self.waits.record(start.elapsed());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AcquireWaits {
+    acquires: Arc<AtomicU64>,
+    wait_micros: Arc<AtomicU64>,
+}
+
+impl AcquireWaits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, wait: Duration) {
+        let micros = u64::try_from(wait.as_micros()).unwrap_or(u64::MAX);
+        self.acquires.fetch_add(1, Ordering::Relaxed);
+        self.wait_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn acquires(&self) -> u64 {
+        self.acquires.load(Ordering::Relaxed)
+    }
+
+    pub fn wait_micros(&self) -> u64 {
+        self.wait_micros.load(Ordering::Relaxed)
+    }
+}
+
+/// Parameter placeholder syntax of the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Placeholder {
+    /// `?` (SQLite, D1, MySQL).
+    #[default]
+    Question,
+    /// `$1`, `$2`, ... (PostgreSQL).
+    Numbered,
+}
+
+impl Placeholder {
+    fn render(self, index: usize) -> String {
+        match self {
+            Placeholder::Question => "?".to_string(),
+            Placeholder::Numbered => format!("${}", index),
+        }
+    }
+}
+
+/// A type stored as one row of `TABLE`, with its serde fields as columns.
+pub trait Entity: Serialize + DeserializeOwned + Send + Sync {
+    const TABLE: &'static str;
+    const ID: &'static str = "id";
+    /// Integer column bumped on every update; updates whose version no
+    /// longer matches fail with `409 Conflict`.
+    const VERSION: Option<&'static str> = None;
+}
+
+/// Basic CRUD for an [`Entity`] over a [`Sql`] backend.
+pub struct Repository<T> {
+    sql: Arc<dyn Sql>,
+    placeholder: Placeholder,
+    _entity: PhantomData<fn() -> T>,
+}
+
+impl<T: Entity> Repository<T> {
+    pub fn new(sql: Arc<dyn Sql>) -> Self {
+        Self {
+            sql,
+            placeholder: Placeholder::default(),
+            _entity: PhantomData,
+        }
+    }
+
+    pub fn placeholder(mut self, placeholder: Placeholder) -> Self {
+        self.placeholder = placeholder;
+        self
+    }
+
+    pub async fn find(&self, id: impl Serialize) -> Result<Option<T>, Error> {
+        let sql = format!(
+            "SELECT * FROM {} WHERE {} = {}",
+            ident(T::TABLE)?,
+            ident(T::ID)?,
+            self.placeholder.render(1)
+        );
+        let mut rows = self.sql.query(&sql, &[serde_json::to_value(id)?]).await?;
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_value(Value::Object(
+            rows.swap_remove(0),
+        ))?))
+    }
+
+    pub async fn insert(&self, entity: &T) -> Result<(), Error> {
+        let row = to_row(entity)?;
+        let mut columns = Vec::with_capacity(row.len());
+        let mut placeholders = Vec::with_capacity(row.len());
+        let mut params = Vec::with_capacity(row.len());
+        for (column, value) in row {
+            columns.push(ident(&column)?.to_string());
+            params.push(value);
+            placeholders.push(self.placeholder.render(params.len()));
+        }
+
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            ident(T::TABLE)?,
+            columns.join(", "),
+            placeholders.join(", ")
+        );
+        self.sql.execute(&sql, &params).await?;
+        Ok(())
+    }
+
+    /// Writes every field of `entity` to its row. With a version column, the
+    /// stored version must equal the entity's and is incremented.
+    pub async fn update(&self, entity: &T) -> Result<(), Error> {
+        let mut row = to_row(entity)?;
+        let id = row
+            .remove(T::ID)
+            .ok_or_else(|| Error::internal(format!("Entity has no {} field", T::ID)))?;
+        let version = match T::VERSION {
+            Some(column) => Some((
+                column,
+                row.remove(column)
+                    .ok_or_else(|| Error::internal(format!("Entity has no {} field", column)))?,
+            )),
+            None => None,
+        };
+
+        let mut assignments = Vec::with_capacity(row.len() + 1);
+        let mut params = Vec::with_capacity(row.len() + 2);
+        for (column, value) in row {
+            params.push(value);
+            assignments.push(format!(
+                "{} = {}",
+                ident(&column)?,
+                self.placeholder.render(params.len())
+            ));
+        }
+        if let Some((column, _)) = &version {
+            let column = ident(column)?;
+            assignments.push(format!("{} = {} + 1", column, column));
+        }
+
+        params.push(id);
+        let mut sql = format!(
+            "UPDATE {} SET {} WHERE {} = {}",
+            ident(T::TABLE)?,
+            assignments.join(", "),
+            ident(T::ID)?,
+            self.placeholder.render(params.len())
+        );
+        if let Some((column, current)) = version {
+            params.push(current);
+            sql.push_str(&format!(
+                " AND {} = {}",
+                ident(column)?,
+                self.placeholder.render(params.len())
+            ));
+        }
+
+        match self.sql.execute(&sql, &params).await? {
+            0 if T::VERSION.is_some() => Err(Error::conflict(
+                "Row was modified or deleted by another writer",
+            )),
+            0 => Err(Error::not_found()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns whether a row was deleted.
+    pub async fn delete(&self, id: impl Serialize) -> Result<bool, Error> {
+        let sql = format!(
+            "DELETE FROM {} WHERE {} = {}",
+            ident(T::TABLE)?,
+            ident(T::ID)?,
+            self.placeholder.render(1)
+        );
+        Ok(self.sql.execute(&sql, &[serde_json::to_value(id)?]).await? > 0)
+    }
+}
+
+fn to_row<T: Serialize>(entity: &T) -> Result<Row, Error> {
+    match serde_json::to_value(entity)? {
+        Value::Object(row) => Ok(row),
+        _ => Err(Error::internal("Entities must serialize to a map")),
+    }
+}
+
+/// Identifiers are interpolated into SQL, so only plain names are accepted.
+fn ident(name: &str) -> Result<&str, Error> {
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(name)
+    } else {
+        Err(Error::internal(format!("Invalid SQL identifier: {}", name)))
+    }
+}
+
+/// A [`Sql`] backend that sends `SELECT`s to replicas (round robin) and
+/// everything else to the primary.
+///
+/// Replicas lag behind the primary, so a request that reads its own writes
+/// should go through [`PinAfterWrite`].
+pub struct ReadWriteSplit {
+    primary: Arc<dyn Sql>,
+    replicas: Vec<Arc<dyn Sql>>,
+    next: AtomicUsize,
+}
+
+impl ReadWriteSplit {
+    pub fn new(primary: Arc<dyn Sql>) -> Self {
+        Self {
+            primary,
+            replicas: Vec::new(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn replica(mut self, replica: Arc<dyn Sql>) -> Self {
+        self.replicas.push(replica);
+        self
+    }
+
+    fn reader(&self, sql: &str) -> &Arc<dyn Sql> {
+        if self.replicas.is_empty() || !is_read_only(sql) {
+            return &self.primary;
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.replicas.len();
+        &self.replicas[index]
+    }
+}
+
+#[async_trait]
+impl Sql for ReadWriteSplit {
+    async fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Row>, Error> {
+        self.reader(sql).query(sql, params).await
+    }
+
+    async fn execute(&self, sql: &str, params: &[Value]) -> Result<u64, Error> {
+        self.primary.execute(sql, params).await
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        self.primary.pool_stats()
+    }
+}
+
+/// Whether a statement can safely run on a replica. Anything unusual,
+/// including `SELECT ... FOR UPDATE` and data-modifying CTEs, is not.
+fn is_read_only(sql: &str) -> bool {
+    let sql = sql.trim_start().to_ascii_lowercase();
+    sql.starts_with("select")
+        && !sql.contains(" for update")
+        && !sql.contains(" for share")
+        && !sql.contains(';')
+}
+
+/// Per-request view of a [`ReadWriteSplit`] that sticks to the primary once
+/// the request has written.
+struct PinnedSql {
+    split: Arc<ReadWriteSplit>,
+    pinned: AtomicBool,
+}
+
+#[async_trait]
+impl Sql for PinnedSql {
+    async fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Row>, Error> {
+        if self.pinned.load(Ordering::Acquire) {
+            return self.split.primary.query(sql, params).await;
+        }
+        if !is_read_only(sql) {
+            self.pinned.store(true, Ordering::Release);
+        }
+        self.split.query(sql, params).await
+    }
+
+    async fn execute(&self, sql: &str, params: &[Value]) -> Result<u64, Error> {
+        self.pinned.store(true, Ordering::Release);
+        self.split.execute(sql, params).await
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        self.split.pool_stats()
+    }
+}
+
+/// Middleware installing a [`ReadWriteSplit`] as the context's Sql backend,
+/// with reads pinned to the primary after the first write of each request.
+pub struct PinAfterWrite {
+    split: Arc<ReadWriteSplit>,
+}
+
+impl PinAfterWrite {
+    pub fn new(split: ReadWriteSplit) -> Self {
+        Self {
+            split: Arc::new(split),
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware<Ctx> for PinAfterWrite {
+    async fn handle(
+        &self,
+        mut ctx: Ctx,
+        req: CoreRequest,
+        next: Next<'_, Ctx>,
+    ) -> Result<CoreResponse, Error> {
+        ctx.sql = Some(Arc::new(PinnedSql {
+            split: self.split.clone(),
+            pinned: AtomicBool::new(false),
+        }));
+        next.run(ctx, req).await
+    }
+}
@@ -0,0 +1,93 @@
+use crate::{
+    logging,
+    middleware::{Middleware, Next},
+    CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use matchit::Router as MatchItRouter;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+pub(crate) type SleepFn =
+    Arc<dyn Fn(Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Middleware failing requests with [`Error::RequestTimeout`] (408) when the
+/// rest of the chain takes longer than a deadline.
+///
+/// The core has no timer of its own, so the platform's sleep is passed in,
+/// e.g. `TimeoutMiddleware::new(Duration::from_secs(10), tokio::time::sleep)`.
+/// The timed-out handler future is dropped, cancelling its work at the next
+/// await point.
+///
+/// Individual routes can get a different deadline with [`route`], or by
+/// wrapping their handler with another `TimeoutMiddleware`, which only takes
+/// effect if it is the shorter one.
+///
+/// [`route`]: TimeoutMiddleware::route
+#[derive(Clone)]
+pub struct TimeoutMiddleware {
+    timeout: Duration,
+    sleep: SleepFn,
+    routes: MatchItRouter<Duration>,
+}
+
+impl TimeoutMiddleware {
+    pub fn new<S, F>(timeout: Duration, sleep: S) -> Self
+    where
+        S: Fn(Duration) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            timeout,
+            sleep: Arc::new(move |duration| Box::pin(sleep(duration))),
+            routes: MatchItRouter::new(),
+        }
+    }
+
+    /// Uses `timeout` instead of the default for request paths matching
+    /// `path`, a route pattern such as `/reports/*rest`.
+    pub fn route(mut self, path: &str, timeout: Duration) -> Self {
+        if let Err(e) = self.routes.insert(path, timeout) {
+            logging::global().error(format!("Failed to add timeout for {}: {}", path, e));
+        }
+        self
+    }
+
+    fn timeout_for(&self, req: &CoreRequest) -> Duration {
+        self.routes
+            .at(req.uri().path())
+            .map_or(self.timeout, |matched| *matched.value)
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for TimeoutMiddleware {
+    async fn handle(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let timeout = self.timeout_for(&req);
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+
+        let mut response = std::pin::pin!(next.run(ctx, req));
+        let mut deadline = (self.sleep)(timeout);
+        std::future::poll_fn(|cx| {
+            if let Poll::Ready(result) = response.as_mut().poll(cx) {
+                return Poll::Ready(result);
+            }
+            if deadline.as_mut().poll(cx).is_ready() {
+                logging::global()
+                    .warn(format!("{} {} timed out after {:?}", method, path, timeout));
+                return Poll::Ready(Err(Error::request_timeout()));
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
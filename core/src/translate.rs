@@ -0,0 +1,156 @@
+use crate::{
+    middleware::{Middleware, Next},
+    rewrite::is_encoded,
+    Body, CoreRequest, CoreResponse, Error,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::header::{HeaderMap, HeaderValue, ACCEPT, ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use std::sync::Arc;
+
+type ConvertFn = Arc<dyn Fn(Bytes) -> Result<Bytes, Error> + Send + Sync>;
+
+#[derive(Clone)]
+struct Conversion {
+    from: String,
+    to: HeaderValue,
+    convert: ConvertFn,
+}
+
+impl Conversion {
+    fn new<F>(from: &str, to: &'static str, convert: F) -> Self
+    where
+        F: Fn(Bytes) -> Result<Bytes, Error> + Send + Sync + 'static,
+    {
+        Self {
+            from: from.to_ascii_lowercase(),
+            to: HeaderValue::from_static(to),
+            convert: Arc::new(convert),
+        }
+    }
+
+    /// Whether the body is of the source type and not content-encoded.
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        !is_encoded(headers)
+            && headers
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(';').next())
+                .is_some_and(|mime| mime.trim().eq_ignore_ascii_case(&self.from))
+    }
+
+    async fn apply(
+        &self,
+        headers: &mut HeaderMap,
+        body: &mut Body,
+        max_bytes: usize,
+    ) -> Result<(), Error> {
+        let bytes = std::mem::take(body).collect_limited(max_bytes).await?;
+        let converted = (self.convert)(bytes)?;
+        headers.insert(CONTENT_TYPE, self.to.clone());
+        headers.insert(CONTENT_LENGTH, converted.len().into());
+        *body = Body::from(converted);
+        Ok(())
+    }
+}
+
+/// Middleware translating bodies between formats with registered converter
+/// functions, e.g. to put a JSON API in front of a SOAP upstream.
+///
+/// Request bodies whose content type matches the request conversion's
+/// source are converted before the handler runs; responses likewise on the
+/// way back. When a response conversion is set, `Accept` is rewritten to its
+/// source type and `Accept-Encoding` is dropped, so the upstream answers
+/// uncompressed. Bodies of other types, and content-encoded ones, pass
+/// through untouched. Bodies over
+/// [`max_body_bytes`](Translate::max_body_bytes) fail the request: with
+/// `413` for requests and `502` for responses.
+#[derive(Clone)]
+pub struct Translate {
+    request: Option<Conversion>,
+    response: Option<Conversion>,
+    max_body: usize,
+}
+
+impl Default for Translate {
+    fn default() -> Self {
+        Self {
+            request: None,
+            response: None,
+            max_body: 2 * 1024 * 1024,
+        }
+    }
+}
+
+impl Translate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The largest body converted, 2 MiB by default.
+    pub fn max_body_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_body = max_bytes;
+        self
+    }
+
+    /// Converts `from` request bodies to `to`. Errors from `convert`, such
+    /// as [`Error::bad_request`] for malformed input, fail the request.
+    pub fn request<F>(mut self, from: &str, to: &'static str, convert: F) -> Self
+    where
+        F: Fn(Bytes) -> Result<Bytes, Error> + Send + Sync + 'static,
+    {
+        self.request = Some(Conversion::new(from, to, convert));
+        self
+    }
+
+    /// Converts `from` response bodies to `to`.
+    pub fn response<F>(mut self, from: &str, to: &'static str, convert: F) -> Self
+    where
+        F: Fn(Bytes) -> Result<Bytes, Error> + Send + Sync + 'static,
+    {
+        self.response = Some(Conversion::new(from, to, convert));
+        self
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for Translate {
+    async fn handle(
+        &self,
+        ctx: C,
+        req: CoreRequest,
+        next: Next<'_, C>,
+    ) -> Result<CoreResponse, Error> {
+        let (mut parts, mut body) = req.into_parts();
+        if let Some(conversion) = &self.request {
+            if conversion.matches(&parts.headers) {
+                conversion
+                    .apply(&mut parts.headers, &mut body, self.max_body)
+                    .await?;
+            }
+        }
+        if let Some(conversion) = &self.response {
+            if let Ok(accept) = HeaderValue::from_str(&conversion.from) {
+                parts.headers.insert(ACCEPT, accept);
+            }
+            parts.headers.remove(ACCEPT_ENCODING);
+        }
+
+        let res = next.run(ctx, CoreRequest::from_parts(parts, body)).await?;
+        let Some(conversion) = &self.response else {
+            return Ok(res);
+        };
+        if !conversion.matches(res.headers()) {
+            return Ok(res);
+        }
+        let (mut parts, mut body) = res.into_parts();
+        conversion
+            .apply(&mut parts.headers, &mut body, self.max_body)
+            .await
+            .map_err(|e| match e {
+                Error::PayloadTooLarge => Error::bad_gateway("Upstream body is too large"),
+                e => e,
+            })?;
+        Ok(CoreResponse::from_parts(parts, body))
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{extract::FromRequest, CoreRequest, Error, Json};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Checks a deserialized value, e.g. that an email address looks like one
+/// or a quantity is positive. Used by [`ValidatedJson`].
+///
+/// ```ignore
+/// impl Validate for SignUp {
+///     fn validate(&self) -> Result<(), ValidationErrors> {
+///         let mut errors = ValidationErrors::new();
+///         if !self.email.contains('@') {
+///             errors.add("email", "must be an email address");
+///         }
+///         if self.password.len() < 12 {
+///             errors.add("password", "must be at least 12 characters");
+///         }
+///         errors.into_result()
+///     }
+/// }
+/// ```
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+/// A problem with one field of a request body.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldError {
+    /// The field's name, or a path such as `items[2].quantity` for nested
+    /// values.
+    pub field: String,
+    pub message: String,
+}
+
+/// Every field that failed validation, rendered by the default formatter as
+/// an `errors` list next to the usual message.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ValidationErrors {
+    pub fields: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) -> &mut Self {
+        self.fields.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// `Ok` when nothing was added.
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.fields.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{} {}", error.field, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ValidationErrors> for Error {
+    fn from(errors: ValidationErrors) -> Self {
+        Error::Validation(errors)
+    }
+}
+
+/// A JSON body that passed [`Validate`]. A body that fails validation is
+/// rejected with `422 Unprocessable Entity` listing the offending fields;
+/// malformed JSON is a `400` as with [`Json`].
+///
+/// ```ignore
+/// async fn sign_up(ValidatedJson(form): ValidatedJson<SignUp>) -> Result<StatusCode, Error> {
+///     create_account(form).await?;
+///     Ok(StatusCode::CREATED)
+/// }
+/// ```
+pub struct ValidatedJson<T>(pub T);
+
+impl<T> ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+{
+    pub fn extract(req: &CoreRequest) -> Result<Self, Error> {
+        let Json(value) = Json::<T>::extract(req)?;
+        value.validate()?;
+        Ok(ValidatedJson(value))
+    }
+}
+
+#[async_trait]
+impl<C, T> FromRequest<C> for ValidatedJson<T>
+where
+    C: Send + Sync + Clone + 'static,
+    T: DeserializeOwned + Validate + Send,
+{
+    async fn from_request(_ctx: &C, req: &CoreRequest) -> Result<Self, Error> {
+        crate::extract::read_body(req).await?;
+        Self::extract(req)
+    }
+}
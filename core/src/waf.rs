@@ -0,0 +1,330 @@
+use crate::{
+    context::Kv,
+    logging::{self, Level},
+    middleware::Middleware,
+    Body, CoreRequest, Error,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+const MAX_INSPECTED_BODY: usize = 64 * 1024;
+
+/// Part of the request a rule looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WafTarget {
+    Method,
+    Path,
+    Query,
+    Headers,
+    Body,
+}
+
+/// Condition evaluated against the (percent-decoded, lowercased) target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "match", rename_all = "snake_case")]
+pub enum WafCondition {
+    ContainsAny { patterns: Vec<String> },
+    NotOneOf { values: Vec<String> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WafAction {
+    /// Reject the request with `403 Forbidden`.
+    Block,
+    /// Report the match and let the request through.
+    Log,
+    /// Add to the request's anomaly score; the request is blocked once the
+    /// rule set's `block_score` is reached.
+    Score(u32),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WafRule {
+    pub id: String,
+    pub targets: Vec<WafTarget>,
+    #[serde(flatten)]
+    pub condition: WafCondition,
+    pub action: WafAction,
+}
+
+/// A set of request-filtering rules, expressed as data so they can be loaded
+/// from configuration or a Kv entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WafRuleSet {
+    pub rules: Vec<WafRule>,
+    #[serde(default = "default_block_score")]
+    pub block_score: u32,
+}
+
+fn default_block_score() -> u32 {
+    10
+}
+
+impl WafRuleSet {
+    /// A small built-in rule set covering common SQL injection, XSS and path
+    /// traversal probes plus unexpected methods.
+    pub fn recommended() -> Self {
+        let rule = |id: &str, targets: &[WafTarget], patterns: &[&str], action| WafRule {
+            id: id.to_string(),
+            targets: targets.to_vec(),
+            condition: WafCondition::ContainsAny {
+                patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            },
+            action,
+        };
+        let input = [WafTarget::Query, WafTarget::Body];
+
+        Self {
+            rules: vec![
+                rule(
+                    "sqli",
+                    &input,
+                    &[
+                        "' or '1'='1",
+                        "' or 1=1",
+                        "union select",
+                        "; drop table",
+                        "sleep(",
+                    ],
+                    WafAction::Score(10),
+                ),
+                rule(
+                    "xss",
+                    &input,
+                    &["<script", "javascript:", "onerror=", "onload="],
+                    WafAction::Score(10),
+                ),
+                rule(
+                    "path-traversal",
+                    &[WafTarget::Path, WafTarget::Query],
+                    &["../", "..\\", "/etc/passwd"],
+                    WafAction::Block,
+                ),
+                WafRule {
+                    id: "method".to_string(),
+                    targets: vec![WafTarget::Method],
+                    condition: WafCondition::NotOneOf {
+                        values: ["get", "post", "put", "delete", "patch", "head", "options"]
+                            .iter()
+                            .map(|m| m.to_string())
+                            .collect(),
+                    },
+                    action: WafAction::Block,
+                },
+            ],
+            block_score: default_block_score(),
+        }
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self, Error> {
+        Ok(serde_json::from_slice(json)?)
+    }
+
+    /// Loads a JSON rule set stored under `key`, if present.
+    pub async fn load(kv: &dyn Kv, key: &str) -> Result<Option<Self>, Error> {
+        match kv.get(key).await {
+            Some(bytes) => Self::from_json(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Evaluates every rule and returns the outcome.
+    pub fn evaluate(&self, req: &CoreRequest) -> WafVerdict {
+        let mut verdict = WafVerdict::default();
+
+        for rule in &self.rules {
+            let matched = rule
+                .targets
+                .iter()
+                .any(|target| rule.condition.matches(&target_text(req, *target)));
+            if !matched {
+                continue;
+            }
+
+            verdict.matched.push(rule.id.clone());
+            match rule.action {
+                WafAction::Block => verdict.blocked = true,
+                WafAction::Log => {}
+                WafAction::Score(points) => verdict.score += points,
+            }
+        }
+
+        if verdict.score >= self.block_score {
+            verdict.blocked = true;
+        }
+        verdict
+    }
+}
+
+impl WafCondition {
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            WafCondition::ContainsAny { patterns } => patterns
+                .iter()
+                .any(|p| text.contains(p.to_lowercase().as_str())),
+            WafCondition::NotOneOf { values } => {
+                !values.iter().any(|v| v.eq_ignore_ascii_case(text))
+            }
+        }
+    }
+}
+
+fn target_text(req: &CoreRequest, target: WafTarget) -> String {
+    let text = match target {
+        WafTarget::Method => req.method().as_str().to_string(),
+        WafTarget::Path => percent_decode(req.uri().path()),
+        WafTarget::Query => form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&"),
+        WafTarget::Headers => req
+            .headers()
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes())))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        WafTarget::Body => {
+            // `Waf` buffers streamed bodies before evaluating.
+            let body = req.body().as_bytes().unwrap_or_default();
+            String::from_utf8_lossy(&body[..body.len().min(MAX_INSPECTED_BODY)]).into_owned()
+        }
+    };
+    text.to_lowercase()
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Result of evaluating a rule set against a request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WafVerdict {
+    pub blocked: bool,
+    pub score: u32,
+    pub matched: Vec<String>,
+}
+
+/// Middleware that applies a [`WafRuleSet`] to every request.
+///
+/// The verdict is stored in the request extensions for handlers that want to
+/// react to `log`/`score` matches that did not block.
+///
+/// When a rule looks at the body, streamed bodies are buffered, up to
+/// [`max_body_bytes`](Waf::max_body_bytes), and left on the request for the
+/// handler. Larger bodies are rejected with `413` rather than let through
+/// uninspected.
+pub struct Waf {
+    rules: WafRuleSet,
+    max_body: usize,
+}
+
+impl Waf {
+    pub fn new(rules: WafRuleSet) -> Self {
+        Self {
+            rules,
+            max_body: MAX_INSPECTED_BODY,
+        }
+    }
+
+    /// The largest body inspected, 64 KiB by default.
+    pub fn max_body_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_body = max_bytes;
+        self
+    }
+
+    fn inspects_body(&self) -> bool {
+        self.rules
+            .rules
+            .iter()
+            .any(|rule| rule.targets.contains(&WafTarget::Body))
+    }
+}
+
+#[async_trait]
+impl<C: Send + Sync + Clone + 'static> Middleware<C> for Waf {
+    async fn before(&self, _ctx: &C, req: &mut CoreRequest) -> Result<(), Error> {
+        if self.inspects_body() {
+            let body = std::mem::take(req.body_mut())
+                .collect_limited(self.max_body)
+                .await?;
+            *req.body_mut() = Body::Full(body);
+        }
+
+        let verdict = self.rules.evaluate(req);
+
+        if !verdict.matched.is_empty() {
+            logging::global().log(
+                Level::Warn,
+                &format!("waf {:?}", verdict.matched),
+                format!(
+                    "WAF matched {} {}: rules={:?} score={} blocked={}",
+                    req.method(),
+                    req.uri().path(),
+                    verdict.matched,
+                    verdict.score,
+                    verdict.blocked
+                ),
+            );
+        }
+
+        if verdict.blocked {
+            return Err(Error::forbidden());
+        }
+
+        req.extensions_mut().insert(verdict);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::Chunks;
+
+    fn streamed(chunks: &[&'static str]) -> CoreRequest {
+        let body = Body::from_stream(Chunks(chunks.iter().copied().collect()));
+        http::Request::post("/comments").body(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_streamed_body_is_inspected() {
+        let waf = Waf::new(WafRuleSet::recommended());
+
+        let mut req = streamed(&["name=x' OR ", "1=1--"]);
+        let result = Middleware::<()>::before(&waf, &(), &mut req).await;
+        assert!(matches!(result, Err(Error::Forbidden)));
+
+        let mut req = streamed(&["name=ada&", "text=hello"]);
+        Middleware::<()>::before(&waf, &(), &mut req).await.unwrap();
+        assert_eq!(req.body(), "name=ada&text=hello");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_is_rejected() {
+        let waf = Waf::new(WafRuleSet::recommended()).max_body_bytes(8);
+        let mut req = streamed(&["text=", "hello"]);
+        let result = Middleware::<()>::before(&waf, &(), &mut req).await;
+        assert!(matches!(result, Err(Error::PayloadTooLarge)));
+    }
+}
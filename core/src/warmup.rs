@@ -0,0 +1,115 @@
+use crate::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+type BoxFuture = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+pub(crate) type WarmupTask<C> = Arc<dyn Fn(C) -> BoxFuture + Send + Sync>;
+
+pub(crate) fn task<C, F, Fut>(f: F) -> WarmupTask<C>
+where
+    F: Fn(C) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    Arc::new(move |ctx| Box::pin(f(ctx)))
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    started: bool,
+    done: bool,
+    total: usize,
+    completed: usize,
+    current: Option<String>,
+    /// Names of failed tasks with their safe error messages.
+    failed: Vec<(String, String)>,
+}
+
+/// How far an app's warm-up tasks have got, reported by the
+/// [`Readiness`](crate::health::Readiness) probe and available to handlers
+/// as a request extension. Clones share the same progress.
+#[derive(Debug, Clone, Default)]
+pub struct WarmupProgress {
+    progress: Arc<Mutex<Progress>>,
+}
+
+impl WarmupProgress {
+    pub(crate) fn set_total(&self, total: usize) {
+        self.progress.lock().unwrap().total = total;
+    }
+
+    /// Marks the warm-up as started; false when it already was.
+    pub(crate) fn start(&self) -> bool {
+        let mut progress = self.progress.lock().unwrap();
+        !std::mem::replace(&mut progress.started, true)
+    }
+
+    pub(crate) fn begin(&self, name: &str) {
+        self.progress.lock().unwrap().current = Some(name.to_string());
+    }
+
+    pub(crate) fn finish(&self, name: &str, result: &Result<(), Error>) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.current = None;
+        match result {
+            Ok(()) => progress.completed += 1,
+            Err(error) => progress
+                .failed
+                .push((name.to_string(), error.safe_message().to_string())),
+        }
+    }
+
+    pub(crate) fn end(&self) {
+        self.progress.lock().unwrap().done = true;
+    }
+
+    /// Whether every task has run, successfully or not. An app without
+    /// warm-up tasks is done from the start.
+    pub fn is_done(&self) -> bool {
+        let progress = self.progress.lock().unwrap();
+        progress.done || progress.total == 0
+    }
+
+    /// Done with no failed task.
+    pub fn is_ready(&self) -> bool {
+        self.is_done() && self.progress.lock().unwrap().failed.is_empty()
+    }
+
+    pub fn total(&self) -> usize {
+        self.progress.lock().unwrap().total
+    }
+
+    pub fn completed(&self) -> usize {
+        self.progress.lock().unwrap().completed
+    }
+
+    /// The task running right now.
+    pub fn current(&self) -> Option<String> {
+        self.progress.lock().unwrap().current.clone()
+    }
+
+    pub fn failed(&self) -> Vec<String> {
+        let progress = self.progress.lock().unwrap();
+        progress
+            .failed
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let progress = self.progress.lock().unwrap();
+        serde_json::json!({
+            "ok": (progress.done || progress.total == 0) && progress.failed.is_empty(),
+            "completed": progress.completed,
+            "total": progress.total,
+            "current": progress.current,
+            "failed": progress
+                .failed
+                .iter()
+                .map(|(name, error)| serde_json::json!({ "task": name, "error": error }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
@@ -1,7 +1,8 @@
 use async_trait::async_trait;
-use std::collections::HashMap;
 use xeno_adapter_hyper::HyperAdapter;
-use xeno_core::{App, CoreRequest, CoreResponse, Ctx, Error, Handler, IntoResponse};
+use xeno_core::{
+    App, CoreRequest, CoreResponse, Ctx, Error, Handler, IntoResponse, RequestExt, ResponseBuilder,
+};
 
 struct HelloHandler;
 
@@ -12,13 +13,8 @@ impl Handler<Ctx> for HelloHandler {
     }
 }
 
-struct HealthHandler;
-
-#[async_trait]
-impl Handler<Ctx> for HealthHandler {
-    async fn call(&self, _ctx: Ctx, _req: CoreRequest) -> Result<CoreResponse, Error> {
-        Ok("OK".into_response())
-    }
+async fn health(_ctx: Ctx, _req: CoreRequest) -> Result<CoreResponse, Error> {
+    Ok("OK".into_response())
 }
 
 struct UserHandler;
@@ -26,25 +22,17 @@ struct UserHandler;
 #[async_trait]
 impl Handler<Ctx> for UserHandler {
     async fn call(&self, _ctx: Ctx, req: CoreRequest) -> Result<CoreResponse, Error> {
-        let params = req
-            .extensions()
-            .get::<HashMap<String, String>>()
-            .ok_or_else(|| Error::bad_request("Missing path parameters"))?;
-
-        let user_id = params
-            .get("id")
-            .ok_or_else(|| Error::bad_request("Missing user ID"))?;
+        let user_id = req.param("id")?;
 
         let response_body = format!(
             r#"{{"user_id": "{}", "name": "User {}", "status": "active"}}"#,
             user_id, user_id
         );
 
-        Ok(http::Response::builder()
+        ResponseBuilder::new()
             .status(200)
             .header("content-type", "application/json; charset=utf-8")
-            .body(response_body.into())
-            .unwrap())
+            .body(response_body)
     }
 }
 
@@ -54,7 +42,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     let app = App::new(ctx)
         .get("/", HelloHandler)
-        .get("/health", HealthHandler)
+        .get("/health", health)
         .get("/users/:id", UserHandler);
 
     let adapter = HyperAdapter::new(app);
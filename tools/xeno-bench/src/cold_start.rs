@@ -0,0 +1,134 @@
+use http::Method;
+use std::fmt;
+use std::time::{Duration, Instant};
+use xeno_core::{App, Body};
+
+/// Measures what a fresh isolate pays before it can answer: building the
+/// app and serving the first request, next to an already warm request.
+///
+/// Every sample builds a new app with `build`, as a Workers isolate does on
+/// a cold start, so put all setup the real entry point does in there.
+///
+/// ```ignore
+/// let report = ColdStart::new(|| routes(App::with_default_context()))
+///     .path("/users/1")
+///     .samples(200)
+///     .run()
+///     .await?;
+/// println!("{}", report);
+/// ```
+pub struct ColdStart<F> {
+    build: F,
+    method: Method,
+    path: String,
+    samples: usize,
+}
+
+impl<C, F> ColdStart<F>
+where
+    C: Send + Sync + Clone + 'static,
+    F: Fn() -> App<C>,
+{
+    pub fn new(build: F) -> Self {
+        Self {
+            build,
+            method: Method::GET,
+            path: "/".to_string(),
+            samples: 100,
+        }
+    }
+
+    /// The path requested from each new app, `/` by default.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn samples(mut self, samples: usize) -> Self {
+        self.samples = samples.max(1);
+        self
+    }
+
+    /// Fails before measuring anything if the method and path do not form
+    /// a valid request.
+    pub async fn run(&self) -> Result<ColdStartReport, String> {
+        let (head, ()) = http::Request::builder()
+            .method(self.method.clone())
+            .uri(self.path.as_str())
+            .body(())
+            .map_err(|e| format!("invalid request {} {}: {}", self.method, self.path, e))?
+            .into_parts();
+
+        let mut report = ColdStartReport::default();
+        for _ in 0..self.samples {
+            let start = Instant::now();
+            let app = (self.build)();
+            report.build.push(start.elapsed());
+
+            for latencies in [&mut report.first_request, &mut report.warm_request] {
+                let req = http::Request::from_parts(head.clone(), Body::empty());
+                let sent = Instant::now();
+                app.handle(req).await;
+                latencies.push(sent.elapsed());
+            }
+        }
+        report.build.sort();
+        report.first_request.sort();
+        report.warm_request.sort();
+        Ok(report)
+    }
+}
+
+/// What a [`ColdStart`] run measured, each list sorted.
+#[derive(Debug, Clone, Default)]
+pub struct ColdStartReport {
+    /// Time to build the app.
+    pub build: Vec<Duration>,
+    /// The first request to each new app, including deferred setup such as
+    /// compiling the route table.
+    pub first_request: Vec<Duration>,
+    /// A second request to the same app.
+    pub warm_request: Vec<Duration>,
+}
+
+impl ColdStartReport {
+    /// Build time plus first request at percentile `p`, the latency a
+    /// client hitting a cold isolate sees on top of the network.
+    pub fn cold_start(&self, p: f64) -> Duration {
+        percentile(&self.build, p) + percentile(&self.first_request, p)
+    }
+}
+
+/// The value below which `p` percent of the sorted `samples` fall.
+fn percentile(samples: &[Duration], p: f64) -> Duration {
+    if samples.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p.clamp(0.0, 100.0) / 100.0 * (samples.len() - 1) as f64).round();
+    samples[rank as usize]
+}
+
+impl fmt::Display for ColdStartReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} cold starts", self.build.len())?;
+        for (name, samples) in [
+            ("build", &self.build),
+            ("first request", &self.first_request),
+            ("warm request", &self.warm_request),
+        ] {
+            writeln!(
+                f,
+                "  {}: p50 {:?}, p99 {:?}",
+                name,
+                percentile(samples, 50.0),
+                percentile(samples, 99.0)
+            )?;
+        }
+        write!(f, "cold start p50 {:?}", self.cold_start(50.0))
+    }
+}
@@ -0,0 +1,7 @@
+//! Load testing tools for Xeno applications.
+
+pub mod cold_start;
+pub mod replay;
+
+pub use cold_start::{ColdStart, ColdStartReport};
+pub use replay::{LoggedRequest, Replay, ReplayReport};
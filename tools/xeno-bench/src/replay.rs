@@ -0,0 +1,224 @@
+use chrono::{DateTime, Utc};
+use http::Method;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use xeno_core::{access_log::AccessLogEntry, App, Body};
+
+/// One request from an access log, timed relative to the first one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedRequest {
+    pub offset: Duration,
+    pub method: Method,
+    /// Path and query.
+    pub path: String,
+}
+
+/// Parses a line of either access log format: JSON objects, or the Common
+/// Log Format as written by `AccessLogFormat::Common`.
+fn parse_line(line: &str) -> Result<(DateTime<Utc>, String, String), String> {
+    if line.starts_with('{') {
+        let entry: AccessLogEntry = serde_json::from_str(line).map_err(|e| e.to_string())?;
+        return Ok((entry.timestamp, entry.method, entry.path));
+    }
+    let (_, rest) = line.split_once(" [").ok_or("missing timestamp")?;
+    let (timestamp, rest) = rest.split_once("] \"").ok_or("missing request")?;
+    let (request, _) = rest.split_once('"').ok_or("unterminated request")?;
+    let timestamp = DateTime::parse_from_str(timestamp, "%d/%b/%Y:%H:%M:%S %z")
+        .map_err(|e| format!("invalid timestamp: {}", e))?;
+    let mut parts = request.split(' ');
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().ok_or("missing path")?;
+    Ok((timestamp.into(), method.to_string(), path.to_string()))
+}
+
+/// Replays the requests of an access log against an [`App`], keeping their
+/// original spacing, optionally sped up or slowed down.
+///
+/// Logs carry no headers or bodies, so requests are sent without them;
+/// routes needing either show up as client errors in the report.
+///
+/// ```ignore
+/// let log = std::fs::read_to_string("access.log")?;
+/// let report = Replay::from_log(&log)?.speed(10.0).run(&app).await;
+/// println!("{}", report);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Replay {
+    requests: Vec<LoggedRequest>,
+    speed: f64,
+    max_in_flight: usize,
+}
+
+impl Replay {
+    pub fn new(mut requests: Vec<LoggedRequest>) -> Self {
+        requests.sort_by_key(|r| r.offset);
+        Self {
+            requests,
+            speed: 1.0,
+            max_in_flight: 1024,
+        }
+    }
+
+    /// Parses a log with one entry per line, skipping blank lines.
+    pub fn from_log(log: &str) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        for (number, line) in log.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (timestamp, method, path) =
+                parse_line(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            let method = Method::from_bytes(method.as_bytes())
+                .map_err(|_| format!("line {}: invalid method {}", number + 1, method))?;
+            entries.push((timestamp, method, path));
+        }
+        let Some(start) = entries.iter().map(|(timestamp, ..)| *timestamp).min() else {
+            return Ok(Self::new(Vec::new()));
+        };
+        let requests = entries
+            .into_iter()
+            .map(|(timestamp, method, path)| LoggedRequest {
+                offset: (timestamp - start).to_std().unwrap_or_default(),
+                method,
+                path,
+            })
+            .collect();
+        Ok(Self::new(requests))
+    }
+
+    /// Plays the log `factor` times faster than recorded; `0.5` plays it at
+    /// half speed.
+    pub fn speed(mut self, factor: f64) -> Self {
+        if factor > 0.0 && factor.is_finite() {
+            self.speed = factor;
+        }
+        self
+    }
+
+    /// Caps concurrent requests; later ones wait and their delay shows up as
+    /// send lag in the report.
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.max_in_flight = max.max(1);
+        self
+    }
+
+    pub fn requests(&self) -> &[LoggedRequest] {
+        &self.requests
+    }
+
+    pub async fn run<C: Send + Sync + Clone + 'static>(&self, app: &App<C>) -> ReplayReport {
+        let permits = Arc::new(Semaphore::new(self.max_in_flight));
+        let mut tasks = JoinSet::new();
+        let start = Instant::now();
+        let mut max_lag = Duration::ZERO;
+
+        for logged in &self.requests {
+            let scheduled = start + logged.offset.div_f64(self.speed);
+            tokio::time::sleep_until(scheduled).await;
+            let permit = Arc::clone(&permits)
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            max_lag = max_lag.max(Instant::now() - scheduled);
+
+            let app = app.clone();
+            let req = http::Request::builder()
+                .method(logged.method.clone())
+                .uri(logged.path.as_str())
+                .body(Body::empty());
+            tasks.spawn(async move {
+                let _permit = permit;
+                let Ok(req) = req else {
+                    return (None, Duration::ZERO);
+                };
+                let sent = Instant::now();
+                let status = app.handle(req).await.status().as_u16();
+                (Some(status), sent.elapsed())
+            });
+        }
+
+        let mut report = ReplayReport::default();
+        while let Some(result) = tasks.join_next().await {
+            let Ok((status, latency)) = result else {
+                report.failed += 1;
+                continue;
+            };
+            match status {
+                Some(status) => {
+                    *report.statuses.entry(status).or_default() += 1;
+                    report.latencies.push(latency);
+                }
+                None => report.failed += 1,
+            }
+        }
+        report.latencies.sort();
+        report.max_lag = max_lag;
+        report.elapsed = start.elapsed();
+        report
+    }
+}
+
+/// What happened during a [`Replay`].
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    /// Responses per status code.
+    pub statuses: BTreeMap<u16, usize>,
+    /// Requests that could not be built or whose task panicked.
+    pub failed: usize,
+    /// Handling time of every answered request, sorted.
+    pub latencies: Vec<Duration>,
+    /// The longest delay between a request's scheduled and actual send time.
+    pub max_lag: Duration,
+    pub elapsed: Duration,
+}
+
+impl ReplayReport {
+    pub fn answered(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// The latency below which `p` percent of requests were answered.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p.clamp(0.0, 100.0) / 100.0 * (self.latencies.len() - 1) as f64).round();
+        self.latencies[rank as usize]
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "{} requests in {:.2}s ({:.1} req/s), {} failed",
+            self.answered(),
+            secs,
+            if secs > 0.0 {
+                self.answered() as f64 / secs
+            } else {
+                0.0
+            },
+            self.failed
+        )?;
+        for (status, count) in &self.statuses {
+            writeln!(f, "  {}: {}", status, count)?;
+        }
+        writeln!(
+            f,
+            "latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.latencies.last().copied().unwrap_or_default()
+        )?;
+        write!(f, "max send lag {:?}", self.max_lag)
+    }
+}
@@ -0,0 +1,86 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use std::sync::Arc;
+use xeno_core::{
+    context::HttpClient,
+    contract::{ContractSuite, LiveTarget},
+    Body, CoreRequest, CoreResponse, Error,
+};
+
+const USAGE: &str =
+    "Usage: xeno-test --spec <openapi.json> --fixtures <fixtures.json> --base-url <url>";
+
+type Connector = hyper_rustls::HttpsConnector<HttpConnector>;
+
+/// Sends contract requests over HTTP or HTTPS, trusting the webpki roots.
+struct HyperClient(Client<Connector, Full<Bytes>>);
+
+impl HyperClient {
+    fn new() -> Self {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .build();
+        Self(Client::builder(TokioExecutor::new()).build(connector))
+    }
+}
+
+#[async_trait]
+impl HttpClient for HyperClient {
+    async fn send(&self, req: CoreRequest) -> Result<CoreResponse, Error> {
+        let (parts, body) = req.into_parts();
+        let req = http::Request::from_parts(parts, Full::new(body.collect().await?));
+        let res = self
+            .0
+            .request(req)
+            .await
+            .map_err(|e| Error::bad_gateway(e.to_string()))?;
+        let (parts, body) = res.into_parts();
+        let bytes = body
+            .collect()
+            .await
+            .map_err(|e| Error::bad_gateway(e.to_string()))?
+            .to_bytes();
+        Ok(CoreResponse::from_parts(parts, Body::from(bytes)))
+    }
+}
+
+fn read(path: &str) -> String {
+    std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", path, e);
+        std::process::exit(2);
+    })
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let arg = |name: &str| {
+        args.iter()
+            .position(|a| a == name)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+    let (Some(spec), Some(fixtures), Some(base_url)) =
+        (arg("--spec"), arg("--fixtures"), arg("--base-url"))
+    else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+
+    let suite = ContractSuite::from_json(&read(&spec), &read(&fixtures)).unwrap_or_else(|e| {
+        eprintln!("{}", e.debug_message());
+        std::process::exit(2);
+    });
+    let target = LiveTarget::new(Arc::new(HyperClient::new()), base_url);
+    let report = suite.run(&target).await;
+    println!("{}", report);
+    if !report.passed() {
+        std::process::exit(1);
+    }
+}